@@ -0,0 +1,634 @@
+//! APT source
+//!
+//! APT source mirrors Debian repositories listed in a YAML config, each stored
+//! under its `name`:
+//!
+//! ```yaml
+//! repos:
+//!   - name: deadsnakes
+//!     ppa: deadsnakes/ppa # Launchpad PPA, or `base` of any repo
+//!     suites: [jammy, noble] # `--suites` by default
+//!   - name: example
+//!     base: https://example.com/debian # flat repo, as it has no `suites`
+//! ```
+//!
+//! Repos with suites have the usual layout, where `dists/<suite>/InRelease`
+//! lists indexes of each component and architecture, and packages are stored
+//! in `pool`. Flat repos have no such split: `InRelease` and `Packages` are at
+//! the root, next to packages. PPAs may also be given by `--ppa owner/name`,
+//! which are stored under `owner/name`.
+//!
+//! Indexes are verified by SHA256 listed in release files, and packages by
+//! SHA256 listed in indexes. Release files are mirrored as is, so that their
+//! signatures still hold. To ensure consistency, release files and indexes are
+//! always transferred at the end.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::http::exists;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct AptConfig {
+    /// Config file listing repos to mirror.
+    pub repo_config: Option<String>,
+    /// Also mirror these Launchpad PPAs, as `owner/name`. Suites of these PPAs are given by
+    /// `--suites`.
+    #[structopt(long = "ppa", number_of_values = 1)]
+    pub ppas: Vec<String>,
+    /// Suites of PPAs without `suites` in config, e.g. `jammy,noble`.
+    #[structopt(long, use_delimiter = true)]
+    pub suites: Vec<String>,
+    /// Components of repos without `components` in config.
+    #[structopt(long, use_delimiter = true, default_value = "main")]
+    pub components: Vec<String>,
+    /// Architectures of repos without `architectures` in config. Packages of `all` are always
+    /// mirrored.
+    #[structopt(long, use_delimiter = true, default_value = "amd64")]
+    pub architectures: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct AptRepos {
+    #[serde(default)]
+    repos: Vec<AptRepo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AptRepo {
+    name: String,
+    #[serde(default)]
+    base: Option<String>,
+    /// Launchpad PPA, as `owner/name`.
+    #[serde(default)]
+    ppa: Option<String>,
+    #[serde(default)]
+    suites: Vec<String>,
+    #[serde(default)]
+    components: Vec<String>,
+    #[serde(default)]
+    architectures: Vec<String>,
+}
+
+const LAUNCHPAD_BASE: &str = "https://ppa.launchpadcontent.net";
+
+/// A distribution to mirror, which is a suite of a repo, or a flat repo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dist {
+    /// name of repo, which prefixes keys of its objects
+    name: String,
+    /// upstream URL of repo
+    base: String,
+    /// path of release files in repo, `dists/<suite>/`, or empty for flat repos
+    path: String,
+    components: Vec<String>,
+    architectures: Vec<String>,
+}
+
+/// Distributions of `repos`, with defaults from `config`.
+fn dists(repos: Vec<AptRepo>, config: &AptConfig) -> Result<Vec<Dist>> {
+    let or_default = |x: Vec<String>, default: &[String]| {
+        if x.is_empty() {
+            default.to_vec()
+        } else {
+            x
+        }
+    };
+    let mut dists = vec![];
+    for repo in repos {
+        let name = repo.name;
+        let (base, suites) = match (repo.base, &repo.ppa) {
+            (Some(base), None) => (base.trim_end_matches('/').to_string(), repo.suites),
+            (None, Some(ppa)) => {
+                if ppa.split('/').count() != 2 {
+                    return Err(Error::ConfigureError(format!(
+                        "invalid PPA {}, expected owner/name",
+                        ppa
+                    )));
+                }
+                let suites = or_default(repo.suites, &config.suites);
+                if suites.is_empty() {
+                    return Err(Error::ConfigureError(format!(
+                        "no suites specified for PPA {}",
+                        ppa
+                    )));
+                }
+                (format!("{}/{}/ubuntu", LAUNCHPAD_BASE, ppa), suites)
+            }
+            _ => {
+                return Err(Error::ConfigureError(format!(
+                    "either base or ppa is required for repo {}",
+                    name
+                )))
+            }
+        };
+        let components = or_default(repo.components, &config.components);
+        let architectures = or_default(repo.architectures, &config.architectures);
+        let paths = if suites.is_empty() {
+            vec![String::new()]
+        } else {
+            suites
+                .iter()
+                .map(|suite| format!("dists/{}/", suite))
+                .collect()
+        };
+        dists.extend(paths.into_iter().map(|path| Dist {
+            name: name.clone(),
+            base: base.clone(),
+            path,
+            components: components.clone(),
+            architectures: architectures.clone(),
+        }));
+    }
+    Ok(dists)
+}
+
+/// Upstream URL of object `key`, in the dist of the longest matching name, as
+/// names may be nested, e.g. `owner` and `owner/name`.
+fn upstream_url(dists: &[Dist], key: &str) -> Option<String> {
+    dists
+        .iter()
+        .filter_map(|dist| {
+            let path = key.strip_prefix(&dist.name)?.strip_prefix('/')?;
+            Some((dist, path))
+        })
+        .max_by_key(|(dist, _)| dist.name.len())
+        .map(|(dist, path)| format!("{}/{}", dist.base, path))
+}
+
+impl Dist {
+    /// Whether index at `path` (relative to release files) is mirrored.
+    fn is_selected(&self, path: &str) -> bool {
+        if self.path.is_empty() {
+            return !path.contains('/');
+        }
+        let mut parts = path.split('/');
+        match (parts.next(), parts.next()) {
+            (Some(component), Some(dir)) => {
+                self.components.iter().any(|x| x == component)
+                    && match dir.strip_prefix("binary-") {
+                        Some(arch) => arch == "all" || self.architectures.iter().any(|x| x == arch),
+                        None => false,
+                    }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Content of a clearsigned message, or `text` itself if it's not signed.
+fn strip_signature(text: &str) -> String {
+    let text = text.replace("\r\n", "\n");
+    match text.strip_prefix("-----BEGIN PGP SIGNED MESSAGE-----\n") {
+        Some(signed) => {
+            // armor headers end with an empty line
+            let body = signed.split_once("\n\n").map_or("", |(_, body)| body);
+            let body = body
+                .split("\n-----BEGIN PGP SIGNATURE-----")
+                .next()
+                .unwrap_or_default();
+            body.lines()
+                .map(|line| line.strip_prefix("- ").unwrap_or(line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        None => text,
+    }
+}
+
+/// Paragraphs of a deb822 control file, with continuation lines of a field
+/// joined by `\n`.
+fn parse_paragraphs(text: &str) -> Vec<HashMap<String, String>> {
+    let mut paragraphs = vec![];
+    let mut paragraph: HashMap<String, String> = HashMap::new();
+    let mut field: Option<String> = None;
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(std::mem::take(&mut paragraph));
+            }
+            field = None;
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = field.as_ref().and_then(|x| paragraph.get_mut(x)) {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            paragraph.insert(name.to_string(), value.trim().to_string());
+            field = Some(name.to_string());
+        }
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph);
+    }
+    paragraphs
+}
+
+/// An index listed in release file.
+#[derive(Debug, PartialEq, Eq)]
+struct IndexFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Release file, with its indexes.
+struct Release {
+    indexes: Vec<IndexFile>,
+    by_hash: bool,
+}
+
+fn parse_release(text: &str) -> Result<Release> {
+    let fields = parse_paragraphs(&strip_signature(text))
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::ProcessError("empty release file".to_string()))?;
+    let indexes = fields
+        .get("SHA256")
+        .map(String::as_str)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let sha256 = parts.next()?.to_string();
+            let size = parts.next()?.parse().ok()?;
+            let path = parts.next()?.to_string();
+            Some(IndexFile { path, size, sha256 })
+        })
+        .collect();
+    Ok(Release {
+        indexes,
+        by_hash: fields.get("Acquire-By-Hash").map(String::as_str) == Some("yes"),
+    })
+}
+
+/// Packages in `Packages` index, with keys prefixed by `name`.
+fn parse_packages(name: &str, text: &str) -> Vec<SnapshotMeta> {
+    parse_paragraphs(text)
+        .into_iter()
+        .filter_map(|mut fields| {
+            let filename = fields.remove("Filename")?;
+            let checksum = fields.remove("SHA256");
+            Some(SnapshotMeta {
+                key: format!("{}/{}", name, filename.trim_start_matches("./")),
+                size: fields.get("Size").and_then(|x| x.parse().ok()),
+                checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+                checksum,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Plain,
+}
+
+/// Indexes of packages which can be parsed, in order of preference.
+const PACKAGES: &[(&str, Compression)] = &[
+    ("Packages.gz", Compression::Gzip),
+    ("Packages", Compression::Plain),
+    ("Packages.bz2", Compression::Bzip2),
+];
+
+fn decompress(compression: Compression, data: &[u8]) -> std::io::Result<String> {
+    let mut reader: Box<dyn Read + '_> = match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(data)),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(data)),
+        Compression::Plain => Box::new(data),
+    };
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+#[derive(Debug)]
+pub struct Apt {
+    config: AptConfig,
+    dists: Vec<Dist>,
+}
+
+impl Apt {
+    pub fn new(config: AptConfig) -> Result<Self> {
+        let mut repos: AptRepos = match &config.repo_config {
+            Some(path) => {
+                let content = std::fs::read(path).unwrap();
+                serde_yaml::from_str(std::str::from_utf8(&content).unwrap()).unwrap()
+            }
+            None => Default::default(),
+        };
+        repos.repos.extend(config.ppas.iter().map(|ppa| AptRepo {
+            name: ppa.clone(),
+            base: None,
+            ppa: Some(ppa.clone()),
+            suites: vec![],
+            components: vec![],
+            architectures: vec![],
+        }));
+        let dists = dists(repos.repos, &config)?;
+        if dists.is_empty() {
+            return Err(Error::ConfigureError("no apt repos to mirror".to_string()));
+        }
+        let mut names: HashMap<&str, &str> = HashMap::new();
+        for dist in &dists {
+            let base = names.entry(&dist.name).or_insert(&dist.base);
+            if *base != dist.base {
+                return Err(Error::ConfigureError(format!(
+                    "duplicated repo {}",
+                    dist.name
+                )));
+            }
+        }
+        Ok(Self { config, dists })
+    }
+
+    /// Packages of `dist`, and its release files and indexes.
+    async fn dist(
+        &self,
+        client: &reqwest::Client,
+        dist: &Dist,
+    ) -> Result<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> {
+        let url = format!("{}/{}", dist.base, dist.path);
+        let key = format!("{}/{}", dist.name, dist.path);
+        let mut release_files = vec![];
+        let mut text = None;
+        for name in ["InRelease", "Release"] {
+            let response = crate::http::send(client.get(format!("{}{}", url, name))).await?;
+            if response.status().is_success() {
+                text = Some(response.text().await?);
+                release_files.push(name);
+                break;
+            }
+        }
+        let text =
+            text.ok_or_else(|| Error::ProcessError(format!("no release file found in {}", url)))?;
+        for name in ["Release", "Release.gpg"] {
+            if !release_files.contains(&name) && exists(client, &format!("{}{}", url, name)).await?
+            {
+                release_files.push(name);
+            }
+        }
+        let release = parse_release(&text)?;
+
+        let mut indexes = vec![];
+        for index in release.indexes {
+            if !dist.is_selected(&index.path)
+                || !exists(client, &format!("{}{}", url, index.path)).await?
+            {
+                continue;
+            }
+            if release.by_hash {
+                let dir = index.path.rsplit_once('/').map_or("", |(dir, _)| dir);
+                let dir = if dir.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", dir)
+                };
+                indexes.push(SnapshotMeta {
+                    key: format!("{}{}by-hash/SHA256/{}", key, dir, index.sha256),
+                    size: Some(index.size),
+                    checksum_method: Some("sha256".to_string()),
+                    checksum: Some(index.sha256.clone()),
+                    ..Default::default()
+                });
+            }
+            indexes.push(SnapshotMeta {
+                key: format!("{}{}", key, index.path),
+                size: Some(index.size),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(index.sha256),
+                ..SnapshotMeta::force(format!("{}{}", key, index.path))
+            });
+        }
+
+        // parse one index of each directory
+        let mut dirs: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for index in &indexes {
+            let path = &index.key[key.len()..];
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            if name.starts_with("Packages") && !dir.contains("by-hash") {
+                dirs.entry(dir).or_default().push(name);
+            }
+        }
+        let mut packages = vec![];
+        for (dir, names) in dirs {
+            let (name, compression) = PACKAGES
+                .iter()
+                .find(|(name, _)| names.contains(name))
+                .ok_or_else(|| {
+                    Error::ProcessError(format!("no supported Packages index in {}{}", url, dir))
+                })?;
+            let path = match dir {
+                "" => name.to_string(),
+                dir => format!("{}/{}", dir, name),
+            };
+            let response = crate::http::send(client.get(format!("{}{}", url, path))).await?;
+            if !response.status().is_success() {
+                return Err(Error::HTTPError(response.status()));
+            }
+            let text = decompress(*compression, &response.bytes().await?)?;
+            packages.extend(parse_packages(&dist.name, &text));
+        }
+
+        indexes.extend(
+            release_files
+                .into_iter()
+                .map(|name| SnapshotMeta::force(format!("{}{}", key, name))),
+        );
+        Ok((packages, indexes))
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Apt {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching {} distributions...", self.dists.len());
+        progress.set_length(self.dists.len() as u64);
+        let this = &*self;
+        let results: Vec<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> = stream::iter(self.dists.clone())
+            .map(|dist| {
+                let client = client.clone();
+                let progress = progress.clone();
+                async move {
+                    progress.set_message(&format!("{}/{}", dist.name, dist.path));
+                    let result = this.dist(&client, &dist).await;
+                    progress.inc(1);
+                    result
+                }
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        // packages in pool are shared by suites
+        let mut keys = HashSet::new();
+        let mut snapshot = vec![];
+        let mut indexes = vec![];
+        for (dist_packages, dist_indexes) in results {
+            snapshot.extend(
+                dist_packages
+                    .into_iter()
+                    .filter(|x| keys.insert(x.key.clone())),
+            );
+            indexes.extend(dist_indexes);
+        }
+        info!(logger, "{} packages", snapshot.len());
+        snapshot.extend(indexes);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("apt, {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Apt {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        upstream_url(&self.dists, &snapshot.key)
+            .map(TransferURL)
+            .ok_or_else(|| Error::ProcessError(format!("unknown repo of {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::{
+        dists, parse_packages, parse_release, upstream_url, AptConfig, AptRepos, IndexFile,
+    };
+
+    #[test]
+    fn test_dists() {
+        let config =
+            AptConfig::from_iter(["apt", "--suites", "jammy", "--architectures", "amd64,arm64"]);
+        let repos: AptRepos = serde_yaml::from_str(
+            r#"
+repos:
+  - name: deadsnakes
+    ppa: deadsnakes/ppa
+  - name: example
+    base: https://example.com/debian/
+    components: [contrib]
+"#,
+        )
+        .unwrap();
+        let dists = dists(repos.repos, &config).unwrap();
+        assert_eq!(dists.len(), 2);
+        assert_eq!(
+            dists[0].base,
+            "https://ppa.launchpadcontent.net/deadsnakes/ppa/ubuntu"
+        );
+        assert_eq!(dists[0].path, "dists/jammy/");
+        assert!(dists[0].is_selected("main/binary-amd64/Packages.gz"));
+        assert!(dists[0].is_selected("main/binary-all/Packages"));
+        assert!(!dists[0].is_selected("main/binary-i386/Packages"));
+        assert!(!dists[0].is_selected("main/source/Sources.gz"));
+        assert!(!dists[0].is_selected("universe/binary-amd64/Packages"));
+
+        assert_eq!(dists[1].base, "https://example.com/debian");
+        assert_eq!(dists[1].path, "");
+        assert_eq!(dists[1].components, vec!["contrib"]);
+        assert!(dists[1].is_selected("Packages.gz"));
+        assert!(!dists[1].is_selected("main/binary-amd64/Packages"));
+
+        let repos: AptRepos =
+            serde_yaml::from_str("repos:\n  - name: invalid\n    ppa: invalid\n").unwrap();
+        assert!(super::dists(repos.repos, &config).is_err());
+    }
+
+    #[test]
+    fn test_upstream_url() {
+        let config = AptConfig::from_iter(["apt", "--suites", "jammy"]);
+        let repos: AptRepos = serde_yaml::from_str(
+            r#"
+repos:
+  - name: owner
+    base: https://example.com/owner
+  - name: owner/name
+    ppa: owner/name
+"#,
+        )
+        .unwrap();
+        let dists = dists(repos.repos, &config).unwrap();
+        assert_eq!(
+            upstream_url(&dists, "owner/name/pool/a.deb").unwrap(),
+            "https://ppa.launchpadcontent.net/owner/name/ubuntu/pool/a.deb"
+        );
+        assert_eq!(
+            upstream_url(&dists, "owner/Packages").unwrap(),
+            "https://example.com/owner/Packages"
+        );
+        assert_eq!(upstream_url(&dists, "other/Packages"), None);
+    }
+
+    #[test]
+    fn test_parse_release() {
+        let release = parse_release(
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\n\
+             Origin: LP-PPA-deadsnakes\nSuite: jammy\nAcquire-By-Hash: yes\n\
+             MD5Sum:\n 00 10 main/binary-amd64/Packages\n\
+             SHA256:\n 0123 10 main/binary-amd64/Packages\n 4567 5 main/binary-amd64/Packages.gz\n\
+             - -dashed: line\n\
+             -----BEGIN PGP SIGNATURE-----\n\nsignature\n-----END PGP SIGNATURE-----\n",
+        )
+        .unwrap();
+        assert!(release.by_hash);
+        assert_eq!(
+            release.indexes,
+            vec![
+                IndexFile {
+                    path: "main/binary-amd64/Packages".to_string(),
+                    size: 10,
+                    sha256: "0123".to_string()
+                },
+                IndexFile {
+                    path: "main/binary-amd64/Packages.gz".to_string(),
+                    size: 5,
+                    sha256: "4567".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_packages() {
+        let packages = parse_packages(
+            "example",
+            "Package: foo\nVersion: 1.0\nFilename: ./foo_1.0_amd64.deb\nSize: 42\nSHA256: abcd\n\
+             Description: foo\n multi-line\n\n\
+             Package: bar\nFilename: pool/main/b/bar/bar_2.0_all.deb\nSize: 7\n",
+        );
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].key, "example/foo_1.0_amd64.deb");
+        assert_eq!(packages[0].size, Some(42));
+        assert_eq!(packages[0].checksum.as_deref(), Some("abcd"));
+        assert_eq!(packages[1].key, "example/pool/main/b/bar/bar_2.0_all.deb");
+        assert_eq!(packages[1].checksum, None);
+    }
+}
@@ -0,0 +1,319 @@
+//! Authentication for private upstreams
+//!
+//! Credentials are installed once at startup, and applied by `http::send` to
+//! every request to upstream, in both snapshot and transfer phase. The
+//! following credentials are supported:
+//!
+//! * Basic auth or bearer token given on command line. They are only sent
+//!   to `auth_hosts`, which are `--auth-host` if set, otherwise hosts of
+//!   upstream bases of the source.
+//! * Entries in a netrc file, which are sent to the matching host. The
+//!   default entry is only sent to `auth_hosts`.
+//! * Tokens in URL path (e.g. `https://conda.anaconda.org/t/<token>/...`),
+//!   registered by sources for URLs under a prefix. Sources and logs only see
+//!   URLs without tokens, which are added right before requests are sent.
+//! * Bearer tokens registered by sources for URLs under a prefix (e.g. tokens
+//!   of a repository in container registry), which take precedence over
+//!   credentials of the host.
+//!
+//! Requests which already have `Authorization` (e.g. registry tokens) are
+//! sent as is. API keys in headers can be set with `--header`.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Client, Request, RequestBuilder};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Basic(String, Option<String>),
+    Bearer(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub credential: Option<Credential>,
+    pub auth_hosts: Vec<String>,
+    pub netrc: HashMap<String, Credential>,
+    pub netrc_default: Option<Credential>,
+}
+
+static CREDENTIALS: OnceCell<Credentials> = OnceCell::new();
+
+/// URL prefixes, and their replacements with tokens in path.
+static PATH_TOKENS: Lazy<RwLock<Vec<(String, String)>>> = Lazy::new(Default::default);
+
+/// URL prefixes, and credentials of URLs under them.
+static PREFIX_CREDENTIALS: Lazy<RwLock<HashMap<String, Credential>>> = Lazy::new(Default::default);
+
+/// A secret given on command line, which is hidden in logs.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Replace `prefix` of URLs with `replacement`, which contains a token.
+pub fn add_path_token(prefix: String, replacement: String) {
+    PATH_TOKENS.write().unwrap().push((prefix, replacement));
+}
+
+/// `url` with token in path, if it's under a registered prefix.
+pub fn tokenized_url(url: &str) -> Option<String> {
+    PATH_TOKENS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .map(|(prefix, replacement)| format!("{}{}", replacement, &url[prefix.len()..]))
+}
+
+/// Send `credential` with requests to URLs under `prefix`, replacing the
+/// credential registered before.
+pub fn add_prefix_credential(prefix: String, credential: Credential) {
+    PREFIX_CREDENTIALS
+        .write()
+        .unwrap()
+        .insert(prefix, credential);
+}
+
+/// Credential registered for a prefix of `url`.
+fn prefix_credential(url: &str) -> Option<Credential> {
+    PREFIX_CREDENTIALS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .map(|(_, credential)| credential.clone())
+}
+
+/// Install credentials for all requests to upstream.
+pub fn install(credentials: Credentials) {
+    CREDENTIALS
+        .set(credentials)
+        .expect("credentials are installed twice");
+}
+
+impl Credentials {
+    fn credential_for(&self, host: &str) -> Option<&Credential> {
+        if !self.auth_hosts.iter().any(|x| x == host) {
+            return self.netrc.get(host);
+        }
+        self.credential
+            .as_ref()
+            .or_else(|| self.netrc.get(host))
+            .or(self.netrc_default.as_ref())
+    }
+
+    /// Load entries from netrc file at `path`.
+    pub fn load_netrc(&mut self, path: &str) -> Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        let (machines, default) = parse_netrc(&content)?;
+        self.netrc = machines;
+        self.netrc_default = default;
+        Ok(())
+    }
+}
+
+type NetrcEntries = (HashMap<String, Credential>, Option<Credential>);
+
+/// Parse netrc file. `macdef` is not supported.
+fn parse_netrc(content: &str) -> Result<NetrcEntries> {
+    let mut machines = HashMap::new();
+    let mut default = None;
+
+    let mut tokens = content.split_whitespace();
+    // host of current entry, `None` for default entry
+    let mut current: Option<Option<String>> = None;
+    let mut login = None;
+    let mut password = None;
+
+    let mut finish =
+        |current: Option<Option<String>>, login: Option<String>, password: Option<String>| {
+            if let (Some(host), Some(login)) = (current, login) {
+                let credential = Credential::Basic(login, password);
+                match host {
+                    Some(host) => {
+                        machines.insert(host, credential);
+                    }
+                    None => default = Some(credential),
+                }
+            }
+        };
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                finish(current.take(), login.take(), password.take());
+                current = Some(if token == "machine" {
+                    Some(
+                        tokens
+                            .next()
+                            .ok_or_else(|| Error::ConfigureError("netrc: missing machine".into()))?
+                            .to_string(),
+                    )
+                } else {
+                    None
+                });
+            }
+            "login" => login = tokens.next().map(str::to_string),
+            "password" => password = tokens.next().map(str::to_string),
+            "account" => {
+                tokens.next();
+            }
+            "macdef" => {
+                return Err(Error::ConfigureError(
+                    "netrc: macdef is not supported".into(),
+                ))
+            }
+            _ => {}
+        }
+    }
+    finish(current, login, password);
+
+    Ok((machines, default))
+}
+
+/// Add credential of its URL prefix or host to request, unless it has
+/// `Authorization`.
+pub fn authenticate(client: &Client, request: Request) -> reqwest::Result<Request> {
+    if request.headers().contains_key(AUTHORIZATION) {
+        return Ok(request);
+    }
+    let credential = prefix_credential(request.url().as_str()).or_else(|| {
+        CREDENTIALS
+            .get()
+            .and_then(|credentials| {
+                credentials.credential_for(request.url().host_str().unwrap_or_default())
+            })
+            .cloned()
+    });
+    let request = RequestBuilder::from_parts(client.clone(), request);
+    match credential {
+        Some(Credential::Basic(user, password)) => request.basic_auth(user, password.as_ref()),
+        Some(Credential::Bearer(token)) => request.bearer_auth(token),
+        None => request,
+    }
+    .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_hidden() {
+        let secret: Secret = "ghp_secret".parse().unwrap();
+        assert!(!format!("{:?}", Some(secret)).contains("secret"));
+    }
+
+    #[test]
+    fn test_tokenized_url() {
+        add_path_token(
+            "https://conda.example.com/lab/".to_string(),
+            "https://conda.example.com/t/tk-1/lab/".to_string(),
+        );
+        assert_eq!(
+            tokenized_url("https://conda.example.com/lab/noarch/repodata.json").as_deref(),
+            Some("https://conda.example.com/t/tk-1/lab/noarch/repodata.json")
+        );
+        assert_eq!(tokenized_url("https://conda.example.com/lab2/"), None);
+    }
+
+    #[test]
+    fn test_prefix_credential() {
+        let client = Client::new();
+        add_prefix_credential(
+            "https://registry.example.com/v2/team/app/".to_string(),
+            Credential::Bearer("tk-1".into()),
+        );
+        add_prefix_credential(
+            "https://registry.example.com/v2/team/app/".to_string(),
+            Credential::Bearer("tk-2".into()),
+        );
+        let authorization = |url: &str| {
+            let request = client.get(url).build().unwrap();
+            authenticate(&client, request)
+                .unwrap()
+                .headers()
+                .get(AUTHORIZATION)
+                .cloned()
+        };
+        assert_eq!(
+            authorization("https://registry.example.com/v2/team/app/blobs/sha256:00").unwrap(),
+            "Bearer tk-2"
+        );
+        assert_eq!(
+            authorization("https://registry.example.com/v2/team/app2/blobs/sha256:00"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_netrc() {
+        let (machines, default) = parse_netrc(
+            "machine pypi.example.com login alice password secret\n\
+             machine huggingface.co\n  login bob\n  password token\n\
+             default login anonymous",
+        )
+        .unwrap();
+        assert_eq!(machines.len(), 2);
+        match &machines["pypi.example.com"] {
+            Credential::Basic(login, password) => {
+                assert_eq!(login, "alice");
+                assert_eq!(password.as_deref(), Some("secret"));
+            }
+            _ => panic!("unexpected credential"),
+        }
+        assert!(
+            matches!(&machines["huggingface.co"], Credential::Basic(login, _) if login == "bob")
+        );
+        assert!(matches!(default, Some(Credential::Basic(login, None)) if login == "anonymous"));
+    }
+
+    #[test]
+    fn test_auth_host() {
+        let credentials = Credentials {
+            credential: Some(Credential::Bearer("token".into())),
+            auth_hosts: vec!["huggingface.co".into()],
+            netrc_default: Some(Credential::Basic("anonymous".into(), None)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            credentials.credential_for("huggingface.co"),
+            Some(Credential::Bearer(_))
+        ));
+        assert!(credentials.credential_for("cdn.example.com").is_none());
+        assert!(Credentials::default()
+            .credential_for("huggingface.co")
+            .is_none());
+    }
+
+    #[test]
+    fn test_authenticate_keeps_authorization() {
+        let client = Client::new();
+        let request = client
+            .get("https://ghcr.io/v2/")
+            .bearer_auth("registry-token")
+            .build()
+            .unwrap();
+        let request = authenticate(&client, request).unwrap();
+        let values: Vec<_> = request.headers().get_all(AUTHORIZATION).iter().collect();
+        assert_eq!(values, vec!["Bearer registry-token"]);
+    }
+}
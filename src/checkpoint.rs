@@ -0,0 +1,249 @@
+//! Snapshot checkpoint
+//!
+//! Sources which take a long time to snapshot split their snapshot into units
+//! (e.g. a PyPI project, or a conda repo). With a checkpoint path, each unit
+//! is appended to the checkpoint as it completes, and written to disk every
+//! few seconds. If the snapshot is interrupted, the next run takes completed
+//! units from the checkpoint and only scans the rest. The checkpoint is
+//! removed once the snapshot completes.
+//!
+//! A checkpoint is only reused by the same source with the same options
+//! (its scope), and if it was created within a day. Otherwise the snapshot
+//! starts over.
+//!
+//! Large intermediate files (e.g. the crates.io index archive) can be kept
+//! alongside the checkpoint as attachments.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use crate::error::Result;
+
+/// Max age of a checkpoint to be reused.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Interval between writes to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    scope: String,
+    created: u64,
+}
+
+/// Completed units and their values.
+type Units<T> = Vec<(String, T)>;
+
+struct Writer {
+    file: BufWriter<std::fs::File>,
+    flushed_at: Instant,
+}
+
+pub struct Checkpoint<T> {
+    path: Option<PathBuf>,
+    completed: HashMap<String, T>,
+    writer: Mutex<Option<Writer>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Read header and completed units. A partially written unit at the end is
+/// ignored.
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<(Header, Units<T>)>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let header: Header = match lines.next() {
+        Some(line) => match serde_json::from_str(&line?) {
+            Ok(header) => header,
+            Err(_) => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    let mut units = vec![];
+    for line in lines {
+        match serde_json::from_str(&line?) {
+            Ok(unit) => units.push(unit),
+            Err(_) => break,
+        }
+    }
+    Ok(Some((header, units)))
+}
+
+impl<T: Serialize + DeserializeOwned> Checkpoint<T> {
+    /// Open checkpoint at `path` for `scope`. If `path` is `None`, nothing is
+    /// checkpointed.
+    pub fn open(logger: &Logger, path: Option<&str>, scope: &str) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                return Ok(Self {
+                    path: None,
+                    completed: HashMap::new(),
+                    writer: Mutex::new(None),
+                })
+            }
+        };
+
+        let (created, units) = match load::<T>(&path)? {
+            Some((header, units))
+                if header.scope == scope
+                    && now().saturating_sub(header.created) < MAX_AGE.as_secs() =>
+            {
+                info!(
+                    logger,
+                    "resuming snapshot from checkpoint, {} units completed",
+                    units.len()
+                );
+                (header.created, units)
+            }
+            Some(_) => {
+                info!(logger, "checkpoint is stale, starting over");
+                Self::remove_attachments(&path)?;
+                (now(), vec![])
+            }
+            None => (now(), vec![]),
+        };
+
+        // rewrite checkpoint, so that a partially written unit is dropped
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(std::fs::File::create(&tmp)?);
+        serde_json::to_writer(
+            &mut file,
+            &Header {
+                scope: scope.to_string(),
+                created,
+            },
+        )?;
+        file.write_all(b"\n")?;
+        for unit in &units {
+            serde_json::to_writer(&mut file, unit)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        std::fs::rename(&tmp, &path)?;
+
+        let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path: Some(path),
+            completed: units.into_iter().collect(),
+            writer: Mutex::new(Some(Writer {
+                file: BufWriter::new(file),
+                flushed_at: Instant::now(),
+            })),
+        })
+    }
+
+    /// Take units completed in previous runs.
+    pub fn completed(&mut self) -> HashMap<String, T> {
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Record a completed unit.
+    pub fn record(&self, unit: &str, value: &T) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(writer) = writer.as_mut() {
+            serde_json::to_writer(&mut writer.file, &(unit, value))?;
+            writer.file.write_all(b"\n")?;
+            if writer.flushed_at.elapsed() >= FLUSH_INTERVAL {
+                writer.file.flush()?;
+                writer.flushed_at = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of attachment `name`, or `None` if nothing is checkpointed.
+    pub fn attachment(&self, name: &str) -> Option<PathBuf> {
+        self.path
+            .as_ref()
+            .map(|path| path.with_extension(format!("{}.part", name)))
+    }
+
+    fn remove_attachments(path: &Path) -> Result<()> {
+        let (dir, prefix) = match (path.parent(), path.file_stem()) {
+            (Some(dir), Some(stem)) => (dir, format!("{}.", stem.to_string_lossy())),
+            _ => return Ok(()),
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(&prefix) && name.ends_with(".part") {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Snapshot is completed. Remove checkpoint and attachments.
+    pub fn finish(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            self.writer.lock().unwrap().take();
+            std::fs::remove_file(path)?;
+            Self::remove_attachments(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-clone-checkpoint-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.checkpoint");
+        let path = path.to_str().unwrap();
+
+        let checkpoint: Checkpoint<Vec<u64>> = Checkpoint::open(&logger, Some(path), "a").unwrap();
+        checkpoint.record("x", &vec![1, 2]).unwrap();
+        checkpoint.record("y", &vec![3]).unwrap();
+        drop(checkpoint);
+        // a unit partially written by a crashed run
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"[\"z\", [4").unwrap();
+        drop(file);
+
+        let mut checkpoint: Checkpoint<Vec<u64>> =
+            Checkpoint::open(&logger, Some(path), "a").unwrap();
+        let completed = checkpoint.completed();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(completed["x"], vec![1, 2]);
+        checkpoint.record("z", &vec![4]).unwrap();
+        drop(checkpoint);
+
+        // checkpoint of another scope is not reused
+        let mut checkpoint: Checkpoint<Vec<u64>> =
+            Checkpoint::open(&logger, Some(path), "b").unwrap();
+        assert!(checkpoint.completed().is_empty());
+        checkpoint.finish().unwrap();
+        assert!(!std::path::Path::new(path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
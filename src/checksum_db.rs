@@ -0,0 +1,269 @@
+//! Checksum database of file backend
+//!
+//! When a checksum database is configured, file backend records size, sha256
+//! and last modified time of every file it writes, and removes the record
+//! when the file is deleted. `fsck` later scans files against the database,
+//! and reports drift caused by bit-rot on aging disks or writes from outside
+//! mirror-clone.
+//!
+//! A full check verifies all recorded files, and also reports files which are
+//! not recorded. A sampled check only verifies a random subset of records.
+
+use std::sync::Mutex;
+
+use filetime::FileTime;
+use indicatif::ProgressBar;
+use rand::seq::SliceRandom;
+use rusqlite::{params, Connection, OptionalExtension};
+use slog::{info, warn, Logger};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+use crate::checksum_pipe::calc_checksum;
+use crate::error::{Error, Result};
+use crate::traits::STATE_PREFIX;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    key TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    mtime INTEGER NOT NULL
+);
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub size: u64,
+    pub sha256: String,
+    pub mtime: u64,
+}
+
+#[derive(Debug)]
+pub struct ChecksumDb {
+    conn: Mutex<Connection>,
+}
+
+impl ChecksumDb {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| Error::ProcessError("checksum database lock poisoned".to_string()))
+    }
+
+    pub fn record(&self, key: &str, record: &FileRecord) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO files (key, size, sha256, mtime) VALUES (?1, ?2, ?3, ?4)",
+            params![key, record.size as i64, record.sha256, record.mtime as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<FileRecord>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT size, sha256, mtime FROM files WHERE key = ?1",
+                [key],
+                |row| {
+                    Ok(FileRecord {
+                        size: row.get::<_, i64>(0)? as u64,
+                        sha256: row.get(1)?,
+                        mtime: row.get::<_, i64>(2)? as u64,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.conn()?
+            .execute("DELETE FROM files WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT key FROM files ORDER BY key")?;
+        let keys = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(keys)
+    }
+}
+
+/// Compute record of file at `path`.
+pub async fn file_record(path: &std::path::Path) -> Result<FileRecord> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let sha256 = calc_checksum(&mut file, "sha256").await?;
+    Ok(FileRecord {
+        size: metadata.len(),
+        sha256,
+        mtime: FileTime::from_last_modification_time(&metadata).unix_seconds() as u64,
+    })
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Fsck {
+    #[structopt(
+        long,
+        help = "Only check this many randomly sampled files, 0 for all files",
+        default_value = "0"
+    )]
+    pub sample: usize,
+}
+
+/// Number of files drifted from checksum database.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub checked: u64,
+    pub missing: u64,
+    pub corrupted: u64,
+    pub modified: u64,
+    pub untracked: u64,
+}
+
+impl FsckReport {
+    pub fn drifted(&self) -> u64 {
+        self.missing + self.corrupted + self.modified + self.untracked
+    }
+}
+
+/// Check files under `base_path` against checksum database.
+pub async fn fsck(
+    logger: &Logger,
+    config: &Fsck,
+    base_path: &str,
+    db: &ChecksumDb,
+    progress: ProgressBar,
+) -> Result<FsckReport> {
+    let mut keys = db.keys()?;
+    let full = config.sample == 0 || config.sample >= keys.len();
+    if !full {
+        keys = keys
+            .choose_multiple(&mut rand::thread_rng(), config.sample)
+            .cloned()
+            .collect();
+        keys.sort();
+    }
+    info!(
+        logger,
+        "checking {} files{}",
+        keys.len(),
+        if full { "" } else { " (sampled)" }
+    );
+    progress.set_length(keys.len() as u64);
+
+    let mut report = FsckReport::default();
+    for key in &keys {
+        progress.set_message(key);
+        let expected = match db.get(key)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let path = std::path::Path::new(base_path).join(key);
+        report.checked += 1;
+        progress.inc(1);
+
+        if tokio::fs::metadata(&path).await.is_err() {
+            warn!(logger, "missing {}", key);
+            report.missing += 1;
+            continue;
+        }
+        let got = file_record(&path).await?;
+        if got.size != expected.size || got.sha256 != expected.sha256 {
+            if got.mtime == expected.mtime {
+                warn!(logger, "corrupted {}", key; "expected" => &expected.sha256, "got" => &got.sha256);
+                report.corrupted += 1;
+            } else {
+                warn!(logger, "modified {}", key; "expected" => &expected.sha256, "got" => &got.sha256);
+                report.modified += 1;
+            }
+        }
+    }
+
+    if full {
+        let base = std::path::PathBuf::from(base_path);
+        let tracked: std::collections::HashSet<String> = keys.into_iter().collect();
+        for entry in WalkDir::new(&base) {
+            let entry = entry.map_err(|err| {
+                Error::StorageError(format!("error while scanning file: {:?}", err))
+            })?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let key = entry.path().strip_prefix(&base).unwrap();
+            let key = key.to_str().unwrap();
+            if key.starts_with(STATE_PREFIX) || tracked.contains(key) {
+                continue;
+            }
+            warn!(logger, "untracked {}", key);
+            report.untracked += 1;
+        }
+    }
+
+    progress.finish_with_message("done");
+    info!(
+        logger,
+        "fsck: {} checked, {} missing, {} corrupted, {} modified, {} untracked",
+        report.checked,
+        report.missing,
+        report.corrupted,
+        report.modified,
+        report.untracked
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fsck_reports_drift() {
+        let dir =
+            std::env::temp_dir().join(format!("mirror-clone-fsck-test-{}", std::process::id()));
+        let base_path = dir.join("base");
+        std::fs::create_dir_all(&base_path).unwrap();
+        let db = ChecksumDb::open(dir.join("checksum.db").to_str().unwrap()).unwrap();
+
+        for key in ["good", "bad", "gone"] {
+            let path = base_path.join(key);
+            std::fs::write(&path, key).unwrap();
+            db.record(key, &file_record(&path).await.unwrap()).unwrap();
+        }
+        let bad = base_path.join("bad");
+        let mtime = FileTime::from_last_modification_time(&std::fs::metadata(&bad).unwrap());
+        std::fs::write(&bad, "bit-rot").unwrap();
+        filetime::set_file_mtime(&bad, mtime).unwrap();
+        std::fs::remove_file(base_path.join("gone")).unwrap();
+        std::fs::write(base_path.join("new"), "new").unwrap();
+
+        let logger = crate::utils::create_logger();
+        let report = fsck(
+            &logger,
+            &Fsck { sample: 0 },
+            base_path.to_str().unwrap(),
+            &db,
+            ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.missing, 1);
+        assert_eq!(report.corrupted, 1);
+        assert_eq!(report.modified, 0);
+        assert_eq!(report.untracked, 1);
+    }
+}
@@ -2,12 +2,15 @@
 //!
 //! A `ChecksumPipe` is a wrapper on source storages which yields `ByteStream`.
 //! It reads the snapshot checksum meta, and calculates the corresponding checksum of `ByteStream`.
-//! In case of a checksum mismatch, the pipe yields an `ChecksumError`.
+//! In case of a checksum mismatch, the object is downloaded again, as it's
+//! usually corrupted in transit. If it still mismatches after a few attempts,
+//! the pipe yields an `ChecksumError`.
 
 use std::io::{Error as IOError, ErrorKind, Result as IOResult, SeekFrom};
 
 use async_trait::async_trait;
 use sha2::Digest;
+use slog::warn;
 use tokio::fs::File;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 use tokio_io_compat::CompatHelperTrait;
@@ -15,14 +18,20 @@ use tokio_io_compat::CompatHelperTrait;
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
 use crate::stream_pipe::{ByteObject, ByteStream};
-use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+use crate::traits::{Key, Metadata, SizeStorage, SnapshotStorage, SourceStorage};
 
-async fn sha256(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
+pub async fn sha256(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
     let mut hasher = sha2::Sha256::new();
     tokio::io::copy(source, &mut hasher.tokio_io_mut()).await?;
     Ok(format!("{:x}", hasher.finalize()))
 }
 
+pub async fn md5(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
+    let mut hasher = md5::Md5::new();
+    tokio::io::copy(source, &mut hasher.tokio_io_mut()).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 pub async fn calc_checksum(
     source: &mut (impl AsyncRead + AsyncSeek + Unpin),
     method: &str,
@@ -31,6 +40,7 @@ pub async fn calc_checksum(
 
     let result = match method {
         "sha256" => sha256(source).await,
+        "md5" => md5(source).await,
         _ => Err(IOError::new(
             ErrorKind::Unsupported,
             "unsupported checksum method",
@@ -41,6 +51,44 @@ pub async fn calc_checksum(
     result
 }
 
+/// Attempts to download an object with mismatched checksum.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Verify checksum of `source` against `snapshot`, if it has one.
+async fn verify<Snapshot: Metadata>(snapshot: &Snapshot, source: &mut ByteStream) -> Result<()> {
+    if let (Some(method), Some(expected_chksum)) = (snapshot.checksum_method(), snapshot.checksum())
+    {
+        let got_chksum = match &mut source.object {
+            ByteObject::LocalFile { file: Some(f), .. } => calc_checksum(f, method).await?,
+            ByteObject::LocalFile {
+                file: None,
+                path: Some(path),
+            } => {
+                let mut f = File::open(path).await?;
+                calc_checksum(&mut f, method).await?
+            }
+            ByteObject::LocalFile {
+                file: None,
+                path: None,
+            } => {
+                return Err(Error::IoError(IOError::new(
+                    ErrorKind::NotFound,
+                    "data missing",
+                )));
+            }
+        };
+
+        if expected_chksum != got_chksum.as_str() {
+            return Err(Error::ChecksumError {
+                method: method.to_string(),
+                expected: expected_chksum.to_string(),
+                got: got_chksum,
+            });
+        }
+    };
+    Ok(())
+}
+
 pub struct ChecksumPipe<Source> {
     pub source: Source,
 }
@@ -70,6 +118,17 @@ where
     }
 }
 
+#[async_trait]
+impl<Snapshot, Source> SizeStorage<Snapshot> for ChecksumPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SizeStorage<Snapshot>,
+{
+    async fn object_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        self.source.object_size(snapshot, mission).await
+    }
+}
+
 #[async_trait]
 impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for ChecksumPipe<Source>
 where
@@ -77,38 +136,104 @@ where
     Source: SourceStorage<Snapshot, ByteStream>,
 {
     async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
-        let mut source = self.source.get_object(snapshot, mission).await?;
-        if let (Some(method), Some(expected_chksum)) =
-            (snapshot.checksum_method(), snapshot.checksum())
-        {
-            let got_chksum = match &mut source.object {
-                ByteObject::LocalFile { file: Some(f), .. } => calc_checksum(f, method).await?,
-                ByteObject::LocalFile {
-                    file: None,
-                    path: Some(path),
-                } => {
-                    let mut f = File::open(path).await?;
-                    calc_checksum(&mut f, method).await?
-                }
-                ByteObject::LocalFile {
-                    file: None,
-                    path: None,
-                } => {
-                    return Err(Error::IoError(IOError::new(
-                        ErrorKind::NotFound,
-                        "data missing",
-                    )));
+        let mut attempt = 1;
+        loop {
+            let mut source = self.source.get_object(snapshot, mission).await?;
+            match verify(snapshot, &mut source).await {
+                Ok(()) => return Ok(source),
+                Err(err @ Error::ChecksumError { .. }) if attempt < MAX_ATTEMPTS => {
+                    warn!(
+                        mission.logger,
+                        "{} is corrupted, downloading again: {:?}",
+                        snapshot.key(),
+                        err
+                    );
+                    attempt += 1;
                 }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::{calc_checksum, ChecksumPipe};
+    use crate::common::Mission;
+    use crate::error::{Error, Result};
+    use crate::metadata::SnapshotMeta;
+    use crate::stream_pipe::ByteStream;
+    use crate::traits::SourceStorage;
+
+    /// A source corrupting the first `corrupted` downloads.
+    struct FlakySource {
+        buffer_path: String,
+        corrupted: usize,
+        attempts: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SourceStorage<SnapshotMeta, ByteStream> for FlakySource {
+        async fn get_object(
+            &self,
+            snapshot: &SnapshotMeta,
+            _mission: &Mission,
+        ) -> Result<ByteStream> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            let content: &[u8] = if attempt < self.corrupted {
+                b"mirror-clon"
+            } else {
+                b"mirror-clone"
             };
+            ByteStream::from_bytes(&self.buffer_path, &snapshot.key, content).await
+        }
+    }
 
-            if expected_chksum != got_chksum.as_str() {
-                return Err(Error::ChecksumError {
-                    method: method.to_string(),
-                    expected: expected_chksum.to_string(),
-                    got: got_chksum,
-                });
-            }
+    #[tokio::test]
+    async fn test_calc_checksum() {
+        let mut data = Cursor::new(b"mirror-clone".to_vec());
+        assert_eq!(
+            calc_checksum(&mut data, "md5").await.unwrap(),
+            "95f3e00df9ac500dd502219dcc79902b"
+        );
+        assert_eq!(data.position(), 0);
+        assert!(calc_checksum(&mut data, "crc32").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_corrupted() {
+        let buffer_path =
+            std::env::temp_dir().join(format!("mirror-clone-checksum-test-{}", std::process::id()));
+        std::fs::create_dir_all(&buffer_path).unwrap();
+        let mission = Mission {
+            progress: indicatif::ProgressBar::hidden(),
+            client: reqwest::Client::new(),
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
         };
-        Ok(source)
+        let snapshot = SnapshotMeta {
+            key: "a/a-1.0.0.crate".to_string(),
+            checksum_method: Some("md5".to_string()),
+            checksum: Some("95f3e00df9ac500dd502219dcc79902b".to_string()),
+            ..Default::default()
+        };
+        for (corrupted, ok, attempts) in [(0, true, 1), (2, true, 3), (3, false, 3)] {
+            let pipe = ChecksumPipe::new(FlakySource {
+                buffer_path: buffer_path.to_str().unwrap().to_string(),
+                corrupted,
+                attempts: AtomicUsize::new(0),
+            });
+            let result = pipe.get_object(&snapshot, &mission).await;
+            assert_eq!(result.is_ok(), ok);
+            if !ok {
+                assert!(matches!(result, Err(Error::ChecksumError { .. })));
+            }
+            assert_eq!(pipe.source.attempts.load(Ordering::SeqCst), attempts);
+        }
+        std::fs::remove_dir_all(&buffer_path).unwrap();
     }
 }
@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
 use indicatif::ProgressBar;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use slog::Logger;
 
 #[derive(Clone)]
@@ -9,12 +12,14 @@ pub struct Mission {
     pub logger: Logger,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct SnapshotConfig {
     pub concurrent_resolve: usize,
+    /// Path of checkpoint of partially built snapshot.
+    pub checkpoint: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct SnapshotPath(pub String, pub bool);
 
 impl SnapshotPath {
@@ -29,3 +34,13 @@ impl SnapshotPath {
 
 #[derive(Debug)]
 pub struct TransferURL(pub String);
+
+/// Content of generated objects keyed by path, which is hidden in logs.
+#[derive(Clone, Default)]
+pub struct Generated(pub HashMap<String, Vec<u8>>);
+
+impl std::fmt::Debug for Generated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Generated({} objects)", self.0.len())
+    }
+}
@@ -0,0 +1,262 @@
+//! Compare targets
+//!
+//! `compare-targets` takes snapshots of two targets, e.g. a primary S3 mirror
+//! and a downstream file mirror, and reports their divergence: objects
+//! missing on the secondary target, extra objects on the secondary target,
+//! and objects whose size or checksum differ.
+//!
+//! The primary target is configured by the usual target options, and the
+//! secondary target by `--other-*` options. Checksums are compared only if
+//! both targets know them, which are read from object metadata of S3 with
+//! `--s3-scan-metadata`, and from checksum database of file backend.
+
+use iter_set::{classify_by, Inclusion};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use structopt::StructOpt;
+
+use crate::checksum_db::ChecksumDb;
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::Result;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, STATE_PREFIX};
+
+#[derive(StructOpt, Debug)]
+pub struct CompareTargets {
+    #[structopt(
+        long,
+        help = "Type of secondary target",
+        possible_values = &["s3", "file"]
+    )]
+    pub other_target_type: String,
+    #[structopt(long, help = "Base path of secondary file target")]
+    pub other_file_base_path: Option<String>,
+    #[structopt(long, help = "Checksum database of secondary file target")]
+    pub other_file_checksum_db: Option<String>,
+    #[structopt(long, help = "Endpoint of secondary S3 target")]
+    pub other_s3_endpoint: Option<String>,
+    #[structopt(long, help = "Bucket of secondary S3 target")]
+    pub other_s3_bucket: Option<String>,
+    #[structopt(long, help = "Prefix of secondary S3 target")]
+    pub other_s3_prefix: Option<String>,
+    #[structopt(long, help = "Scan metadata of secondary S3 target")]
+    pub other_s3_scan_metadata: bool,
+    #[structopt(long, help = "Write divergent objects to this file as JSON lines")]
+    pub report: Option<String>,
+    #[structopt(
+        long,
+        help = "Print at most this many divergent objects",
+        default_value = "50"
+    )]
+    pub print: usize,
+}
+
+/// Divergence of an object on secondary target.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Divergence {
+    Missing {
+        key: String,
+    },
+    Extra {
+        key: String,
+    },
+    Size {
+        key: String,
+        primary: u64,
+        secondary: u64,
+    },
+    Checksum {
+        key: String,
+        primary: String,
+        secondary: String,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct CompareReport {
+    pub compared: u64,
+    pub missing: u64,
+    pub extra: u64,
+    pub size: u64,
+    pub checksum: u64,
+}
+
+impl CompareReport {
+    pub fn diverged(&self) -> u64 {
+        self.missing + self.extra + self.size + self.checksum
+    }
+}
+
+/// Fill checksum of files from checksum database, if the file is not
+/// modified since it's recorded.
+pub fn fill_checksums(snapshot: &mut [SnapshotMeta], db: &ChecksumDb) -> Result<()> {
+    for item in snapshot {
+        if let Some(record) = db.get(&item.key)? {
+            if Some(record.size) == item.size && Some(record.mtime) == item.last_modified {
+                item.checksum_method = Some("sha256".to_string());
+                item.checksum = Some(record.sha256);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn diverge(primary: &SnapshotMeta, secondary: &SnapshotMeta) -> Option<Divergence> {
+    if let (Some(a), Some(b)) = (primary.size, secondary.size) {
+        if a != b {
+            return Some(Divergence::Size {
+                key: primary.key.clone(),
+                primary: a,
+                secondary: b,
+            });
+        }
+    }
+    if primary.checksum_method.is_some() && primary.checksum_method == secondary.checksum_method {
+        if let (Some(a), Some(b)) = (&primary.checksum, &secondary.checksum) {
+            if a != b {
+                return Some(Divergence::Checksum {
+                    key: primary.key.clone(),
+                    primary: a.clone(),
+                    secondary: b.clone(),
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Compare sorted snapshots of primary and secondary target.
+fn compare_snapshots(primary: Vec<SnapshotMeta>, secondary: Vec<SnapshotMeta>) -> Vec<Divergence> {
+    classify_by(primary, secondary, |a, b| a.key.cmp(&b.key))
+        .filter_map(|result| match result {
+            Inclusion::Left(primary) => Some(Divergence::Missing { key: primary.key }),
+            Inclusion::Right(secondary) => Some(Divergence::Extra { key: secondary.key }),
+            Inclusion::Both(primary, secondary) => diverge(&primary, &secondary),
+        })
+        .collect()
+}
+
+async fn take_snapshot(
+    storage: &mut dyn SnapshotStorage<SnapshotMeta>,
+    mission: Mission,
+    config: &SnapshotConfig,
+    db: Option<&ChecksumDb>,
+) -> Result<Vec<SnapshotMeta>> {
+    let mut snapshot = storage.snapshot(mission, config).await?;
+    snapshot.retain(|item| !item.key.starts_with(STATE_PREFIX));
+    if let Some(db) = db {
+        fill_checksums(&mut snapshot, db)?;
+    }
+    snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+    snapshot.dedup_by(|a, b| a.key == b.key);
+    Ok(snapshot)
+}
+
+pub async fn compare_targets(
+    logger: &Logger,
+    opts: &CompareTargets,
+    config: &SnapshotConfig,
+    primary: &mut dyn SnapshotStorage<SnapshotMeta>,
+    primary_db: Option<&ChecksumDb>,
+    secondary: &mut dyn SnapshotStorage<SnapshotMeta>,
+    secondary_db: Option<&ChecksumDb>,
+) -> Result<CompareReport> {
+    info!(logger, "comparing targets"; "primary" => primary.info(), "secondary" => secondary.info());
+    let client = reqwest::Client::new();
+    let mission = |task: &'static str| Mission {
+        client: client.clone(),
+        progress: indicatif::ProgressBar::hidden(),
+        logger: logger.new(slog::o!("task" => task)),
+    };
+    let (primary, secondary) = tokio::join!(
+        take_snapshot(primary, mission("primary"), config, primary_db),
+        take_snapshot(secondary, mission("secondary"), config, secondary_db)
+    );
+    let (primary, secondary) = (primary?, secondary?);
+    let mut report = CompareReport {
+        compared: primary.len() as u64,
+        ..Default::default()
+    };
+    info!(
+        logger,
+        "primary {} objects, secondary {} objects",
+        primary.len(),
+        secondary.len()
+    );
+
+    let divergences = compare_snapshots(primary, secondary);
+    let mut lines = String::new();
+    for (idx, divergence) in divergences.iter().enumerate() {
+        match divergence {
+            Divergence::Missing { .. } => report.missing += 1,
+            Divergence::Extra { .. } => report.extra += 1,
+            Divergence::Size { .. } => report.size += 1,
+            Divergence::Checksum { .. } => report.checksum += 1,
+        }
+        if idx < opts.print {
+            warn!(logger, "{:?}", divergence);
+        }
+        lines.push_str(&serde_json::to_string(divergence)?);
+        lines.push('\n');
+    }
+    if let Some(path) = &opts.report {
+        tokio::fs::write(path, lines).await?;
+        info!(logger, "report written to {}", path);
+    }
+
+    info!(
+        logger,
+        "compared {} objects: {} missing, {} extra, {} size mismatched, {} checksum mismatched",
+        report.compared,
+        report.missing,
+        report.extra,
+        report.size,
+        report.checksum
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(key: &str, size: u64, checksum: Option<&str>) -> SnapshotMeta {
+        SnapshotMeta {
+            key: key.to_string(),
+            size: Some(size),
+            checksum_method: checksum.map(|_| "sha256".to_string()),
+            checksum: checksum.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compare_snapshots() {
+        let primary = vec![
+            meta("a", 1, None),
+            meta("b", 2, Some("x")),
+            meta("c", 3, Some("y")),
+            meta("d", 4, None),
+        ];
+        let secondary = vec![
+            meta("b", 2, Some("z")),
+            meta("c", 3, None),
+            meta("d", 5, None),
+            meta("e", 1, None),
+        ];
+        let divergences: Vec<String> = compare_snapshots(primary, secondary)
+            .iter()
+            .map(|x| serde_json::to_string(x).unwrap())
+            .collect();
+        assert_eq!(
+            divergences,
+            vec![
+                r#"{"status":"missing","key":"a"}"#,
+                r#"{"status":"checksum","key":"b","primary":"x","secondary":"z"}"#,
+                r#"{"status":"size","key":"d","primary":4,"secondary":5}"#,
+                r#"{"status":"extra","key":"e"}"#,
+            ]
+        );
+    }
+}
@@ -1,12 +1,38 @@
 //! Conda source
 //!
 //! Conda is a source storage that scans an conda repository.
+//! Repos are listed in a config file, either under a single `base`, or
+//! grouped by channels, in which case snapshot paths are prefixed by channel
+//! name. Channels may also be given by `--channel`, with repos of `--subdirs`.
+//!
+//! Channels of anaconda.org publish packages under labels. A repo itself is
+//! the `main` label, and other labels are at `<channel>/label/<label>/<subdir>`,
+//! which are mirrored at the same path if selected by `--labels`.
+//!
+//! `channeldata.json` and package icons listed in it are mirrored at the root
+//! of each channel (the parent of its repos), if available.
+//!
+//! Private channels of anaconda.org are mirrored with an API token, given by
+//! `--anaconda-token` or by the environment variable named `token_env` of a
+//! channel. The token is put in URL path (`/t/<token>/<channel>`) only when
+//! requests are sent, so that it never shows up in logs or snapshots.
 //! This source yields a snapshot with size and checksum metadata.
 //! To ensure consistency, repository data is always transferred
 //! at the end. This is done by setting priority in snapshot metadata.
+//!
+//! Repository data is fetched as `repodata.json.zst` if available, falling
+//! back to `repodata.json.bz2` and plain `repodata.json`, and decompressed
+//! while parsing.
+//!
+//! When packages are pruned by `--keep-recent`, upstream repository data no
+//! longer matches mirrored files. In this case, repository data of each repo
+//! is regenerated with only mirrored packages, and served in all formats by
+//! `GenerateStorage`.
 
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
@@ -16,27 +42,314 @@ use slog::{info, warn};
 use structopt::StructOpt;
 use tokio_util::io::{StreamReader, SyncIoBridge};
 
+use crate::auth::Secret;
+use crate::checkpoint::Checkpoint;
 use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::conda_version::CondaVersion;
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct CondaConfig {
-    pub repo_config: String,
+    /// Config file listing repos to mirror.
+    pub repo_config: Option<String>,
+    /// Also mirror these channels, as `name` on anaconda.org or `name=url`. Repos of these
+    /// channels are given by `--subdirs`.
+    #[structopt(long = "channel", parse(try_from_str = parse_channel), number_of_values = 1)]
+    pub channels: Vec<CondaChannel>,
+    /// Only mirror repos of these subdirs (platforms), e.g. `linux-64,noarch`. Subdir of a repo
+    /// is its last path component.
+    #[structopt(long, use_delimiter = true)]
+    pub subdirs: Vec<String>,
+    /// Mirror packages of these labels of anaconda.org channels, e.g. `main,rc`. Only `main`
+    /// label is mirrored by default.
+    #[structopt(long, use_delimiter = true)]
+    pub labels: Vec<String>,
+    /// Only mirror files of recent N versions of each package in each repo. Repository data is
+    /// regenerated to list mirrored files only.
+    #[structopt(long)]
+    pub keep_recent: Option<usize>,
+    /// API token of anaconda.org, for private channels on it without `token_env`.
+    #[structopt(long, env = "ANACONDA_TOKEN", hide_env_values = true)]
+    pub anaconda_token: Option<Secret>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct CondaRepos {
+    #[serde(default)]
+    pub base: String,
+    #[serde(default)]
+    pub repos: Vec<String>,
+    #[serde(default)]
+    pub channels: Vec<CondaChannel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CondaChannel {
+    pub name: String,
     pub base: String,
+    /// Subdirs of this channel, `--subdirs` if not specified.
+    #[serde(default)]
     pub repos: Vec<String>,
+    /// Environment variable holding API token of this channel.
+    #[serde(default)]
+    pub token_env: Option<String>,
+}
+
+const ANACONDA_BASE: &str = "https://conda.anaconda.org";
+
+fn parse_channel(s: &str) -> std::result::Result<CondaChannel, String> {
+    let (name, base) = match s.split_once('=') {
+        Some((name, base)) => (name, base.trim_end_matches('/').to_string()),
+        None => (s, format!("{}/{}", ANACONDA_BASE, s)),
+    };
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("invalid channel name: {}", name));
+    }
+    Ok(CondaChannel {
+        name: name.to_string(),
+        base,
+        repos: vec![],
+        token_env: None,
+    })
+}
+
+/// URL prefix of anaconda.org channel at `base`, and its replacement with
+/// `token` in path.
+fn anaconda_token_prefix(base: &str, token: &str) -> Option<(String, String)> {
+    let channel = base
+        .strip_prefix(ANACONDA_BASE)?
+        .strip_prefix('/')?
+        .trim_end_matches('/');
+    if channel.is_empty() {
+        return None;
+    }
+    Some((
+        format!("{}/{}/", ANACONDA_BASE, channel),
+        format!("{}/t/{}/{}/", ANACONDA_BASE, token, channel),
+    ))
+}
+
+/// A repo to mirror.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Repo {
+    /// path of this repo in snapshot
+    path: String,
+    /// upstream URL of this repo
+    url: String,
+}
+
+impl CondaRepos {
+    /// All repos, with paths of channel repos prefixed by channel name.
+    fn flatten(self, subdirs: &[String]) -> Vec<Repo> {
+        let base = self.base;
+        let mut repos: Vec<Repo> = self
+            .repos
+            .into_iter()
+            .map(|repo| Repo {
+                url: format!("{}/{}", base, repo),
+                path: repo,
+            })
+            .collect();
+        for channel in self.channels {
+            let channel_repos = if channel.repos.is_empty() {
+                subdirs
+            } else {
+                &channel.repos
+            };
+            if channel_repos.is_empty() {
+                panic!("no subdirs specified for channel {}", channel.name);
+            }
+            repos.extend(channel_repos.iter().map(|subdir| Repo {
+                path: format!("{}/{}", channel.name, subdir),
+                url: format!("{}/{}", channel.base, subdir),
+            }));
+        }
+        repos
+    }
+}
+
+/// Parent of a repo path or URL, which is the root of its channel.
+fn channel_root(x: &str) -> &str {
+    x.trim_end_matches('/')
+        .rsplit_once('/')
+        .map_or("", |(parent, _)| parent)
+}
+
+#[derive(Deserialize)]
+struct ChannelData {
+    #[serde(default)]
+    packages: HashMap<String, ChannelPackage>,
+}
+
+#[derive(Deserialize)]
+struct ChannelPackage {
+    icon_url: Option<String>,
+}
+
+/// Relative paths of package icons in channel data.
+fn parse_icons(channeldata: &[u8]) -> Result<BTreeSet<String>> {
+    let channeldata: ChannelData = serde_json::from_slice(channeldata)?;
+    Ok(channeldata
+        .packages
+        .into_values()
+        .filter_map(|package| package.icon_url)
+        .filter(|url| !url.contains("://") && !url.starts_with('/'))
+        .collect())
+}
+
+/// Repos of each label in `labels`, or the repos themselves (the `main` label)
+/// if no labels are given.
+fn with_labels(repos: Vec<Repo>, labels: &[String]) -> Vec<Repo> {
+    if labels.is_empty() {
+        return repos;
+    }
+    let labelled = |x: &str, label: &str| {
+        if label == "main" {
+            return x.to_string();
+        }
+        let (channel, subdir) = match x.trim_end_matches('/').rsplit_once('/') {
+            Some((channel, subdir)) => (format!("{}/", channel), subdir),
+            None => (String::new(), x),
+        };
+        format!("{}label/{}/{}", channel, label, subdir)
+    };
+    repos
+        .iter()
+        .flat_map(|repo| {
+            labels.iter().map(move |label| Repo {
+                path: labelled(&repo.path, label),
+                url: labelled(&repo.url, label),
+            })
+        })
+        .collect()
+}
+
+/// Compression of repository data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Zstd,
+    Bzip2,
+    Plain,
+}
+
+/// Files of repository data, in order of preference.
+const REPODATA: &[(&str, Compression)] = &[
+    ("repodata.json.zst", Compression::Zstd),
+    ("repodata.json.bz2", Compression::Bzip2),
+    ("repodata.json", Compression::Plain),
+];
+
+/// Decompress repository data read from `reader`.
+fn decompress<'a>(
+    compression: Compression,
+    reader: impl Read + Send + 'a,
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(match compression {
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Plain => Box::new(reader),
+    })
+}
+
+/// Fetch the most preferred repository data available in repo at `url`.
+async fn fetch_repodata(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<(Compression, reqwest::Response)> {
+    let mut status = reqwest::StatusCode::NOT_FOUND;
+    for (filename, compression) in REPODATA {
+        let response = crate::http::send(client.get(format!("{}/{}", url, filename))).await?;
+        if response.status().is_success() {
+            return Ok((*compression, response));
+        }
+        status = response.status();
+    }
+    Err(Error::HTTPError(status))
+}
+
+/// Files of repository data regenerated for pruned repos.
+const GENERATED_REPODATA: &[&str] = &[
+    "repodata.json",
+    "repodata.json.bz2",
+    "repodata.json.zst",
+    "current_repodata.json",
+];
+
+/// Repository data regenerated in all formats.
+struct Repodata {
+    json: Vec<u8>,
+    bz2: Vec<u8>,
+    zst: Vec<u8>,
+}
+
+impl Repodata {
+    fn new(json: Vec<u8>) -> io::Result<Self> {
+        let zst = zstd::encode_all(&json[..], 0)?;
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+        bz2.write_all(&json)?;
+        let bz2 = bz2.finish()?;
+        Ok(Self { json, bz2, zst })
+    }
+}
+
+/// Remove packages not in `kept` from `repodata`.
+fn trim_repodata(repodata: &mut serde_json::Value, kept: &HashSet<String>) {
+    for key in ["packages", "packages.conda"] {
+        if let Some(packages) = repodata.get_mut(key).and_then(|x| x.as_object_mut()) {
+            packages.retain(|filename, _| kept.contains(filename));
+        }
+    }
+}
+
+/// Keep files of recent `keep_recent` versions of each package, ordered by
+/// conda version and then build number. Files of unknown name or version are
+/// always kept.
+fn truncate_to_recent(records: Vec<de::Record>, keep_recent: usize) -> Vec<SnapshotMeta> {
+    let mut groups: HashMap<&str, Vec<(CondaVersion, u64, usize)>> = HashMap::new();
+    let mut selected = vec![true; records.len()];
+    for (idx, record) in records.iter().enumerate() {
+        let version = record.version.as_deref().and_then(CondaVersion::parse);
+        if let (Some(name), Some(version)) = (&record.name, version) {
+            groups
+                .entry(name)
+                .or_default()
+                .push((version, record.build_number, idx));
+            selected[idx] = false;
+        }
+    }
+    for mut candidates in groups.into_values() {
+        candidates.sort_by(|a, b| b.cmp(a));
+        let mut versions = 0;
+        let mut prev: Option<&CondaVersion> = None;
+        for (version, _, idx) in &candidates {
+            if prev != Some(version) {
+                versions += 1;
+                prev = Some(version);
+            }
+            if versions > keep_recent {
+                break;
+            }
+            selected[*idx] = true;
+        }
+    }
+    records
+        .into_iter()
+        .zip(selected)
+        .filter_map(|(record, selected)| selected.then_some(record.meta))
+        .collect()
 }
 
 pub struct Conda {
     /// conda config path
     config: CondaConfig,
     /// parsed conda repos
-    repos: CondaRepos,
+    repos: Vec<Repo>,
+    /// mirrored package files of each pruned repo
+    pruned: HashMap<String, HashSet<String>>,
+    /// repository data regenerated for pruned repos
+    generated: HashMap<String, tokio::sync::Mutex<Option<Arc<Repodata>>>>,
 }
 
 mod de {
@@ -47,12 +360,20 @@ mod de {
 
     use crate::metadata::SnapshotMeta;
 
+    /// A package file in repository data.
+    pub struct Record {
+        pub meta: SnapshotMeta,
+        pub name: Option<String>,
+        pub version: Option<String>,
+        pub build_number: u64,
+    }
+
     pub struct Snapshot<'a> {
         pub(crate) repo: &'a str,
     }
 
     impl<'de> DeserializeSeed<'de> for Snapshot<'de> {
-        type Value = Vec<SnapshotMeta>;
+        type Value = Vec<Record>;
 
         fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
         where
@@ -63,7 +384,7 @@ mod de {
             }
 
             impl<'de> Visitor<'de> for MetadataVisitor<'de> {
-                type Value = Vec<SnapshotMeta>;
+                type Value = Vec<Record>;
 
                 fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
                     formatter.write_str("a map of conda metadata")
@@ -97,7 +418,7 @@ mod de {
     }
 
     impl<'de> DeserializeSeed<'de> for Packages<'de> {
-        type Value = Vec<SnapshotMeta>;
+        type Value = Vec<Record>;
 
         fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
         where
@@ -108,7 +429,7 @@ mod de {
             }
 
             impl<'de> Visitor<'de> for PackagesVisitor<'de> {
-                type Value = Vec<SnapshotMeta>;
+                type Value = Vec<Record>;
 
                 fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
                     formatter.write_str("a map of conda packages")
@@ -141,7 +462,7 @@ mod de {
     }
 
     impl<'de> DeserializeSeed<'de> for Package<'de> {
-        type Value = SnapshotMeta;
+        type Value = Record;
 
         fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
         where
@@ -153,7 +474,7 @@ mod de {
             }
 
             impl<'de> Visitor<'de> for PackageVisitor<'de> {
-                type Value = SnapshotMeta;
+                type Value = Record;
 
                 fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
                     formatter.write_str("a map representing a single conda package")
@@ -165,23 +486,42 @@ mod de {
                 {
                     let mut size = None;
                     let mut sha256 = None;
+                    let mut md5 = None;
+                    let mut name = None;
+                    let mut version = None;
+                    let mut build_number = 0;
                     while let Some(key) = map.next_key::<String>()? {
-                        if key == "size" {
-                            size = Some(map.next_value::<u64>()?);
-                        } else if key == "sha256" {
-                            sha256 = Some(map.next_value::<String>()?);
-                        } else {
-                            map.next_value::<IgnoredAny>()?;
+                        match key.as_str() {
+                            "size" => size = Some(map.next_value::<u64>()?),
+                            "sha256" => sha256 = Some(map.next_value::<String>()?),
+                            "md5" => md5 = Some(map.next_value::<String>()?),
+                            "name" => name = Some(map.next_value::<String>()?),
+                            "version" => version = Some(map.next_value::<String>()?),
+                            "build_number" => build_number = map.next_value::<u64>()?,
+                            _ => {
+                                map.next_value::<IgnoredAny>()?;
+                            }
                         }
                     }
 
-                    Ok(SnapshotMeta {
-                        key: format!("{}/{}", self.repo, self.name),
-                        size,
-                        last_modified: None,
-                        checksum_method: sha256.as_ref().map(|_| "sha256".to_string()),
-                        checksum: sha256,
-                        ..Default::default()
+                    // prefer sha256, as packages of old channels may only have md5
+                    let (checksum_method, checksum) = match (sha256, md5) {
+                        (Some(sha256), _) => (Some("sha256".to_string()), Some(sha256)),
+                        (None, Some(md5)) => (Some("md5".to_string()), Some(md5)),
+                        (None, None) => (None, None),
+                    };
+                    Ok(Record {
+                        meta: SnapshotMeta {
+                            key: format!("{}/{}", self.repo, self.name),
+                            size,
+                            last_modified: None,
+                            checksum_method,
+                            checksum,
+                            ..Default::default()
+                        },
+                        name,
+                        version,
+                        build_number,
                     })
                 }
             }
@@ -196,9 +536,110 @@ mod de {
 
 impl Conda {
     pub fn new(config: CondaConfig) -> Self {
-        let content = std::fs::read(&config.repo_config).unwrap();
-        let repos = serde_yaml::from_str(std::str::from_utf8(&content).unwrap()).unwrap();
-        Self { config, repos }
+        let mut repos: CondaRepos = match &config.repo_config {
+            Some(path) => {
+                let content = std::fs::read(path).unwrap();
+                serde_yaml::from_str(std::str::from_utf8(&content).unwrap()).unwrap()
+            }
+            None => Default::default(),
+        };
+        repos.channels.extend(config.channels.iter().cloned());
+        for channel in &repos.channels {
+            let token = match &channel.token_env {
+                Some(name) => Some(std::env::var(name).unwrap_or_else(|_| {
+                    panic!("token of channel {} not found in ${}", channel.name, name)
+                })),
+                None => config.anaconda_token.as_ref().map(|x| x.0.clone()),
+            };
+            if let Some(token) = token {
+                match anaconda_token_prefix(&channel.base, &token) {
+                    Some((prefix, replacement)) => crate::auth::add_path_token(prefix, replacement),
+                    None if channel.token_env.is_some() => {
+                        panic!("token of channel {} is not on anaconda.org", channel.name)
+                    }
+                    None => (),
+                }
+            }
+        }
+        let repos = with_labels(repos.flatten(&config.subdirs), &config.labels);
+        assert!(!repos.is_empty(), "no conda repos to mirror");
+        let mut paths = HashSet::new();
+        for repo in &repos {
+            assert!(paths.insert(&repo.path), "duplicated repo {}", repo.path);
+        }
+        Self {
+            config,
+            repos,
+            pruned: HashMap::new(),
+            generated: HashMap::new(),
+        }
+    }
+
+    /// Fetch repository data of `repo` and keep packages mirrored only.
+    async fn regenerate(&self, client: &reqwest::Client, repo: &str) -> Result<Repodata> {
+        let kept = self.pruned[repo].clone();
+        let url = &self.repos.iter().find(|x| x.path == repo).unwrap().url;
+        let (compression, response) = fetch_repodata(client, url).await?;
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        let reader = SyncIoBridge::new(StreamReader::new(stream));
+        tokio::task::spawn_blocking(move || -> Result<_> {
+            let reader = io::BufReader::new(decompress(compression, reader)?);
+            let mut repodata: serde_json::Value = serde_json::from_reader(reader)?;
+            trim_repodata(&mut repodata, &kept);
+            Ok(Repodata::new(serde_json::to_vec(&repodata)?)?)
+        })
+        .await
+        .expect("task panicked")
+    }
+
+    /// Roots of channels of `repos`.
+    fn channels(repos: &[Repo]) -> Vec<Repo> {
+        let mut channels: Vec<Repo> = vec![];
+        for repo in repos {
+            let channel = Repo {
+                path: channel_root(&repo.path).to_string(),
+                url: channel_root(&repo.url).to_string(),
+            };
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+        channels
+    }
+
+    /// Upstream URL of `key`, in the innermost repo or channel containing it.
+    fn upstream_url(&self, key: &str) -> Option<String> {
+        self.repos
+            .iter()
+            .chain(&Self::channels(&self.repos))
+            .filter_map(|repo| {
+                let path = if repo.path.is_empty() {
+                    Some(key)
+                } else {
+                    key.strip_prefix(repo.path.as_str())
+                        .and_then(|x| x.strip_prefix('/'))
+                };
+                path.map(|path| (repo, path))
+            })
+            .max_by_key(|(repo, _)| repo.path.len())
+            .map(|(repo, path)| format!("{}/{}", repo.url, path))
+    }
+
+    /// Repos to mirror, filtered by subdirs.
+    fn selected_repos(&self) -> Vec<Repo> {
+        self.repos
+            .iter()
+            .filter(|repo| {
+                let subdir = repo
+                    .path
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default();
+                self.config.subdirs.is_empty() || self.config.subdirs.iter().any(|x| x == subdir)
+            })
+            .cloned()
+            .collect()
     }
 }
 
@@ -213,47 +654,73 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        let fetch = |repo: String| {
-            info!(logger, "fetching {}", repo);
+        let repos = self.selected_repos();
+        if repos.len() < self.repos.len() {
+            info!(
+                logger,
+                "{} of {} repos selected by subdirs",
+                repos.len(),
+                self.repos.len()
+            );
+        }
+        let scope = format!("conda {:?} {:?}", repos, self.config.keep_recent);
+        let mut checkpoint = Checkpoint::open(&logger, config.checkpoint.as_deref(), &scope)?;
+        let mut completed = checkpoint.completed();
+        let checkpoint = &checkpoint;
+
+        let fetch = |Repo { path: repo, url }| {
+            let resumed = completed.remove(&repo);
+            if resumed.is_none() {
+                info!(logger, "fetching {}", repo);
+            }
             let progress = progress.clone();
-            let base = self.repos.base.clone();
             let client = client.clone();
             let logger = logger.clone();
             let repo_ = repo.clone();
+            let keep_recent = self.config.keep_recent;
 
             let future = async move {
+                if let Some(snapshot) = resumed {
+                    return Ok((repo, snapshot));
+                }
                 let mut snapshot = vec![];
-                let repodata = format!("{}/{}/repodata.json", base, repo);
-                let stream = client
-                    .get(&repodata)
-                    .send()
-                    .await?
+                let (compression, response) = fetch_repodata(&client, &url).await?;
+                let stream = response
                     .bytes_stream()
                     .map_err(|e| io::Error::new(ErrorKind::Other, e));
                 let reader = SyncIoBridge::new(StreamReader::new(stream));
-                let mut packages = {
+                let packages = {
                     let repo = repo.clone();
-                    tokio::task::spawn_blocking(move || {
+                    tokio::task::spawn_blocking(move || -> Result<_> {
+                        let reader = io::BufReader::new(decompress(compression, reader)?);
                         let mut deserializer = serde_json::de::Deserializer::from_reader(reader);
-                        de::Snapshot { repo: &repo }.deserialize(&mut deserializer)
+                        Ok(de::Snapshot { repo: &repo }.deserialize(&mut deserializer)?)
                     })
                     .await
                     .expect("task panicked")?
                 };
-                snapshot.append(&mut packages);
+                snapshot.extend(match keep_recent {
+                    Some(keep_recent) => truncate_to_recent(packages, keep_recent),
+                    None => packages.into_iter().map(|record| record.meta).collect(),
+                });
                 progress.set_message(&repo);
                 snapshot.append(&mut vec![
                     SnapshotMeta::force(format!("{}/repodata.json", repo)),
                     SnapshotMeta::force(format!("{}/repodata.json.bz2", repo)),
                     SnapshotMeta::force(format!("{}/current_repodata.json", repo)),
                 ]);
-                Ok::<_, Error>(snapshot)
+                // zstd repository data is always generated for pruned repos
+                if compression == Compression::Zstd || keep_recent.is_some() {
+                    snapshot.push(SnapshotMeta::force(format!("{}/repodata.json.zst", repo)));
+                }
+                checkpoint.record(&repo, &snapshot)?;
+                Ok::<_, Error>((repo, snapshot))
             };
 
             async move {
@@ -265,14 +732,55 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
             }
         };
 
-        let snapshots = stream::iter(self.repos.repos.clone())
+        let channels = Self::channels(&repos);
+        let mut fetched = stream::iter(repos)
             .map(fetch)
             .buffer_unordered(4)
             .try_collect::<Vec<_>>()
-            .await?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
+            .await?;
+
+        for channel in channels {
+            let channel_key = |path: &str| match channel.path.as_str() {
+                "" => path.to_string(),
+                root => format!("{}/{}", root, path),
+            };
+            let key = channel_key("channeldata.json");
+            if let Some(snapshot) = completed.remove(&key) {
+                fetched.push((key, snapshot));
+                continue;
+            }
+            let response =
+                crate::http::send(client.get(format!("{}/channeldata.json", channel.url))).await?;
+            if !response.status().is_success() {
+                warn!(logger, "{} not available: {}", key, response.status());
+                continue;
+            }
+            let icons = parse_icons(&response.bytes().await?)?;
+            let mut snapshot: Vec<SnapshotMeta> = icons
+                .into_iter()
+                .map(|icon| SnapshotMeta::new(channel_key(&icon)))
+                .collect();
+            snapshot.push(SnapshotMeta::force(key.clone()));
+            checkpoint.record(&key, &snapshot)?;
+            fetched.push((key, snapshot));
+        }
+        checkpoint.finish()?;
+
+        let mut snapshots = vec![];
+        for (repo, snapshot) in fetched {
+            if self.config.keep_recent.is_some() && !repo.ends_with("channeldata.json") {
+                let prefix = format!("{}/", repo);
+                let kept = snapshot
+                    .iter()
+                    .filter(|meta| !meta.flags.force)
+                    .filter_map(|meta| meta.key.strip_prefix(&prefix))
+                    .map(str::to_string)
+                    .collect();
+                self.pruned.insert(repo.clone(), kept);
+                self.generated.insert(repo, tokio::sync::Mutex::new(None));
+            }
+            snapshots.extend(snapshot);
+        }
 
         Ok(snapshots)
     }
@@ -285,6 +793,336 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Conda {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.repos.base, snapshot.key)))
+        match self.upstream_url(&snapshot.key) {
+            Some(url) => Ok(TransferURL(url)),
+            None => Err(Error::ProcessError(format!(
+                "no repo found for {}",
+                snapshot.key
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Conda {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        let (repo, filename) = match snapshot.key.rsplit_once('/') {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let generated = match self.generated.get(repo) {
+            Some(generated) if GENERATED_REPODATA.contains(&filename) => generated,
+            _ => return Ok(None),
+        };
+        let repodata = {
+            let mut generated = generated.lock().await;
+            match generated.as_ref() {
+                Some(repodata) => repodata.clone(),
+                None => {
+                    info!(mission.logger, "regenerating repodata of {}", repo);
+                    let repodata = Arc::new(self.regenerate(&mission.client, repo).await?);
+                    *generated = Some(repodata.clone());
+                    repodata
+                }
+            }
+        };
+        Ok(Some(match filename {
+            "repodata.json.bz2" => repodata.bz2.clone(),
+            "repodata.json.zst" => repodata.zst.clone(),
+            _ => repodata.json.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde::de::DeserializeSeed;
+    use structopt::StructOpt;
+
+    use super::{
+        anaconda_token_prefix, de, decompress, parse_channel, parse_icons, trim_repodata,
+        truncate_to_recent, with_labels, Compression, Conda, CondaConfig, CondaRepos, Repo,
+    };
+    use crate::metadata::SnapshotMeta;
+
+    #[test]
+    fn test_truncate_to_recent() {
+        let record = |key: &str, name: Option<&str>, version: &str, build_number| de::Record {
+            meta: SnapshotMeta::new(key.to_string()),
+            name: name.map(str::to_string),
+            version: Some(version.to_string()),
+            build_number,
+        };
+        let records = vec![
+            record("a-1.0-0.tar.bz2", Some("a"), "1.0", 0),
+            record("a-1.0-0.conda", Some("a"), "1.0", 0),
+            record("a-1.10-0.conda", Some("a"), "1.10", 0),
+            record("a-1.9-0.conda", Some("a"), "1.9", 0),
+            record("a-1.9-1.conda", Some("a"), "1.9", 1),
+            record("a-2.0rc1-0.conda", Some("a"), "2.0rc1", 0),
+            record("b-0.1-0.conda", Some("b"), "0.1", 0),
+            record("c-x-0.conda", None, "x", 0),
+        ];
+        let kept: Vec<String> = truncate_to_recent(records, 2)
+            .into_iter()
+            .map(|meta| meta.key)
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                "a-1.10-0.conda",
+                "a-2.0rc1-0.conda",
+                "b-0.1-0.conda",
+                "c-x-0.conda"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_selected_repos() {
+        let conda = Conda {
+            config: CondaConfig {
+                repo_config: None,
+                channels: vec![],
+                subdirs: vec!["linux-64".to_string(), "noarch".to_string()],
+                labels: vec![],
+                keep_recent: None,
+                anaconda_token: None,
+            },
+            repos: CondaRepos {
+                base: "https://conda.anaconda.org".to_string(),
+                repos: vec![
+                    "conda-forge/linux-64".to_string(),
+                    "conda-forge/win-64".to_string(),
+                    "conda-forge/noarch/".to_string(),
+                    "pytorch/osx-arm64".to_string(),
+                ],
+                channels: vec![],
+            }
+            .flatten(&[]),
+            pruned: Default::default(),
+            generated: Default::default(),
+        };
+        let paths: Vec<_> = conda
+            .selected_repos()
+            .into_iter()
+            .map(|repo| repo.path)
+            .collect();
+        assert_eq!(paths, vec!["conda-forge/linux-64", "conda-forge/noarch/"]);
+    }
+
+    #[test]
+    fn test_channels() {
+        let repos: CondaRepos = serde_yaml::from_str(
+            r#"
+base: https://repo.anaconda.com/pkgs
+repos: [main/linux-64]
+channels:
+  - name: pytorch
+    base: https://conda.anaconda.org/pytorch
+    repos: [linux-64]
+"#,
+        )
+        .unwrap();
+        let mut repos = repos;
+        repos.channels.push(parse_channel("bioconda").unwrap());
+        repos
+            .channels
+            .push(parse_channel("nvidia=https://mirror.example.com/nvidia/").unwrap());
+        let subdirs = ["noarch".to_string()];
+        let repos: Vec<_> = repos
+            .flatten(&subdirs)
+            .into_iter()
+            .map(|repo| (repo.path, repo.url))
+            .collect();
+        assert_eq!(
+            repos,
+            vec![
+                (
+                    "main/linux-64".to_string(),
+                    "https://repo.anaconda.com/pkgs/main/linux-64".to_string()
+                ),
+                (
+                    "pytorch/linux-64".to_string(),
+                    "https://conda.anaconda.org/pytorch/linux-64".to_string()
+                ),
+                (
+                    "bioconda/noarch".to_string(),
+                    "https://conda.anaconda.org/bioconda/noarch".to_string()
+                ),
+                (
+                    "nvidia/noarch".to_string(),
+                    "https://mirror.example.com/nvidia/noarch".to_string()
+                ),
+            ]
+        );
+        assert!(parse_channel("=https://example.com").is_err());
+        assert!(parse_channel("a/b").is_err());
+    }
+
+    #[test]
+    fn test_anaconda_token_prefix() {
+        assert_eq!(
+            anaconda_token_prefix("https://conda.anaconda.org/private-lab/", "tk-1"),
+            Some((
+                "https://conda.anaconda.org/private-lab/".to_string(),
+                "https://conda.anaconda.org/t/tk-1/private-lab/".to_string()
+            ))
+        );
+        assert_eq!(
+            anaconda_token_prefix("https://mirror.example.com/nvidia", "tk-1"),
+            None
+        );
+        assert_eq!(
+            anaconda_token_prefix("https://conda.anaconda.org", "tk-1"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_channel_files() {
+        let icons = parse_icons(
+            br#"{"channeldata_version":1,"packages":{
+                "a":{"icon_url":"icons/0123.png","version":"1.0"},
+                "b":{"icon_url":"https://example.com/b.png"},
+                "c":{"icon_url":null},
+                "d":{}
+            },"subdirs":["linux-64"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            icons.into_iter().collect::<Vec<_>>(),
+            vec!["icons/0123.png"]
+        );
+
+        let repos = CondaRepos {
+            base: "https://repo.anaconda.com/pkgs".to_string(),
+            repos: vec!["main/linux-64".to_string(), "main/noarch".to_string()],
+            channels: vec![parse_channel("nvidia").unwrap()],
+        }
+        .flatten(&["linux-64".to_string()]);
+        let channels: Vec<_> = Conda::channels(&repos)
+            .into_iter()
+            .map(|repo| (repo.path, repo.url))
+            .collect();
+        assert_eq!(
+            channels,
+            vec![
+                (
+                    "main".to_string(),
+                    "https://repo.anaconda.com/pkgs/main".to_string()
+                ),
+                (
+                    "nvidia".to_string(),
+                    "https://conda.anaconda.org/nvidia".to_string()
+                ),
+            ]
+        );
+        let conda = Conda {
+            config: CondaConfig::from_iter(["conda"]),
+            repos,
+            pruned: Default::default(),
+            generated: Default::default(),
+        };
+        for (key, url) in [
+            (
+                "main/linux-64/a-1.0-0.conda",
+                "https://repo.anaconda.com/pkgs/main/linux-64/a-1.0-0.conda",
+            ),
+            (
+                "main/icons/0123.png",
+                "https://repo.anaconda.com/pkgs/main/icons/0123.png",
+            ),
+            (
+                "nvidia/channeldata.json",
+                "https://conda.anaconda.org/nvidia/channeldata.json",
+            ),
+        ] {
+            assert_eq!(conda.upstream_url(key).as_deref(), Some(url));
+        }
+        assert!(conda.upstream_url("pytorch/channeldata.json").is_none());
+    }
+
+    #[test]
+    fn test_with_labels() {
+        let repos = vec![Repo {
+            path: "pytorch/linux-64".to_string(),
+            url: "https://conda.anaconda.org/pytorch/linux-64".to_string(),
+        }];
+        assert_eq!(with_labels(repos.clone(), &[]), repos);
+        let repos: Vec<_> = with_labels(repos, &["main".to_string(), "nightly".to_string()])
+            .into_iter()
+            .map(|repo| (repo.path, repo.url))
+            .collect();
+        assert_eq!(
+            repos,
+            vec![
+                (
+                    "pytorch/linux-64".to_string(),
+                    "https://conda.anaconda.org/pytorch/linux-64".to_string()
+                ),
+                (
+                    "pytorch/label/nightly/linux-64".to_string(),
+                    "https://conda.anaconda.org/pytorch/label/nightly/linux-64".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trim_repodata() {
+        let mut repodata = serde_json::json!({
+            "info": {"subdir": "linux-64"},
+            "packages": {"a-1.0-0.tar.bz2": {}, "a-2.0-0.tar.bz2": {}},
+            "packages.conda": {"a-2.0-0.conda": {}, "b-1.0-0.conda": {}},
+            "repodata_version": 1
+        });
+        let kept = ["a-2.0-0.tar.bz2", "a-2.0-0.conda"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        trim_repodata(&mut repodata, &kept);
+        assert_eq!(
+            repodata,
+            serde_json::json!({
+                "info": {"subdir": "linux-64"},
+                "packages": {"a-2.0-0.tar.bz2": {}},
+                "packages.conda": {"a-2.0-0.conda": {}},
+                "repodata_version": 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_decompress() {
+        let repodata = br#"{"info":{},"packages":{"a-1.0-0.tar.bz2":{"size":1,"sha256":"00"}},"packages.conda":{"b-1.0-0.conda":{"size":2,"md5":"11"}}}"#;
+        let zstd = zstd::encode_all(&repodata[..], 0).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bzip2.write_all(repodata).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+        for (compression, data) in [
+            (Compression::Zstd, zstd),
+            (Compression::Bzip2, bzip2),
+            (Compression::Plain, repodata.to_vec()),
+        ] {
+            let reader = decompress(compression, &data[..]).unwrap();
+            let mut deserializer = serde_json::de::Deserializer::from_reader(reader);
+            let snapshot = de::Snapshot { repo: "linux-64" }
+                .deserialize(&mut deserializer)
+                .unwrap();
+            let keys: Vec<_> = snapshot.iter().map(|item| item.meta.key.as_str()).collect();
+            assert_eq!(
+                keys,
+                vec!["linux-64/a-1.0-0.tar.bz2", "linux-64/b-1.0-0.conda"]
+            );
+            assert_eq!(snapshot[0].meta.checksum.as_deref(), Some("00"));
+            assert_eq!(snapshot[1].meta.checksum_method.as_deref(), Some("md5"));
+        }
     }
 }
@@ -0,0 +1,192 @@
+//! A submodule for conda source that implements conda package version semantics.
+//!
+//! Versions are ordered as conda does: a version is split into components by
+//! `.` and `_`, and each component into runs of digits and letters, which
+//! are compared in turn. Letters sort before numbers, `dev` before other
+//! letters, and `post` after everything. An optional `N!` epoch comes first,
+//! and a `+local` label last.
+use std::cmp::Ordering;
+
+/// A run of digits or letters in a version component.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    Str(String),
+    Int(u64),
+    Post,
+}
+
+#[derive(Debug, Clone)]
+pub struct CondaVersion {
+    epoch: u64,
+    version: Vec<Vec<Part>>,
+    local: Vec<Vec<Part>>,
+}
+
+fn parse_components(s: &str) -> Option<Vec<Vec<Part>>> {
+    s.split(['.', '_', '-'])
+        .map(|component| {
+            if component.is_empty() {
+                return None;
+            }
+            let mut parts = vec![];
+            let mut rest = component;
+            while let Some(first) = rest.chars().next() {
+                let is_digit = first.is_ascii_digit();
+                let len = rest
+                    .find(|c: char| c.is_ascii_digit() != is_digit)
+                    .unwrap_or(rest.len());
+                let (run, tail) = rest.split_at(len);
+                parts.push(match run {
+                    _ if is_digit => Part::Int(run.parse().ok()?),
+                    "post" => Part::Post,
+                    // uppercase sorts before lowercase
+                    "dev" => Part::Str("DEV".to_string()),
+                    _ => Part::Str(run.to_string()),
+                });
+                rest = tail;
+            }
+            // components shall start with a number
+            if !matches!(parts.first(), Some(Part::Int(_))) {
+                parts.insert(0, Part::Int(0));
+            }
+            Some(parts)
+        })
+        .collect()
+}
+
+fn components_cmp(lhs: &[Vec<Part>], rhs: &[Vec<Part>]) -> Ordering {
+    const ZERO: Part = Part::Int(0);
+    for idx in 0..lhs.len().max(rhs.len()) {
+        let (lhs, rhs) = (
+            lhs.get(idx).map_or(&[][..], Vec::as_slice),
+            rhs.get(idx).map_or(&[][..], Vec::as_slice),
+        );
+        for idx in 0..lhs.len().max(rhs.len()) {
+            let ord = lhs
+                .get(idx)
+                .unwrap_or(&ZERO)
+                .cmp(rhs.get(idx).unwrap_or(&ZERO));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+impl CondaVersion {
+    /// Parse a conda version string. Returns `None` if it's invalid.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        let (version, local) = match s.split_once('+') {
+            Some((version, local)) => (version, Some(local)),
+            None => (s.as_str(), None),
+        };
+        let (epoch, version) = match version.split_once('!') {
+            Some((epoch, version)) => (epoch.parse().ok()?, version),
+            None => (0, version),
+        };
+        Some(Self {
+            epoch,
+            version: parse_components(version)?,
+            local: match local {
+                Some(local) => parse_components(local)?,
+                None => vec![],
+            },
+        })
+    }
+}
+
+impl PartialEq for CondaVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CondaVersion {}
+
+impl PartialOrd for CondaVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CondaVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| components_cmp(&self.version, &other.version))
+            .then_with(|| components_cmp(&self.local, &other.local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rstest::rstest;
+
+    use super::CondaVersion;
+
+    #[test]
+    fn test_ord() {
+        // examples from conda documentation, in ascending order
+        let versions = [
+            "0.4",
+            "0.4.1.rc",
+            "0.4.1",
+            "0.5a1",
+            "0.5b3",
+            "0.5C1",
+            "0.5",
+            "0.9.6",
+            "0.960923",
+            "1.0.0dev",
+            "1.0.0a",
+            "1.0.0b",
+            "1.0.0rc1",
+            "1.0.0",
+            "1.0.0post",
+            "1.0.1",
+            "1.1dev1",
+            "1.1a1",
+            "1.1.0dev1",
+            "1.1.a1",
+            "1.1.0rc1",
+            "1.1.0",
+            "1.1.0post1",
+            "1.1post1",
+            "1996.07.12",
+            "1!0.4.1",
+            "1!3.1.1.6",
+            "2!0.4.1",
+        ];
+        for (lhs, rhs) in versions.iter().tuple_windows() {
+            assert!(
+                CondaVersion::parse(lhs).unwrap() < CondaVersion::parse(rhs).unwrap(),
+                "{} < {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[rstest]
+    #[case("1.0", "1.0.0")]
+    #[case("0.4.1.RC", "0.4.1.rc")]
+    #[case("1.1_0", "1.1.0")]
+    #[case("1.0+local", "1.0.0+local.0")]
+    fn test_eq(#[case] lhs: &str, #[case] rhs: &str) {
+        assert_eq!(
+            CondaVersion::parse(lhs).unwrap(),
+            CondaVersion::parse(rhs).unwrap()
+        );
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("1..0")]
+    #[case("x!1.0")]
+    fn test_invalid(#[case] input: &str) {
+        assert!(CondaVersion::parse(input).is_none());
+    }
+}
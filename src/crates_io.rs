@@ -2,23 +2,180 @@
 //!
 //! Crates.io source first download current crates.io-index zip from GitHub,
 //! and then extract downloadable crates from crates.io-index in memory.
+//! The sha256 `cksum` of each version in the index is kept in the snapshot,
+//! so that crate files are verified by `ChecksumPipe` during transfer.
+//!
+//! With a snapshot checkpoint, the downloaded zip is kept alongside the
+//! checkpoint, so that an interrupted snapshot doesn't download it again.
+//!
+//! With `--sparse-index`, crates are discovered with the sparse index instead,
+//! see `crates_io_sparse`. With `--index-base-url`, the sparse index is
+//! mirrored as well, with `config.json` rewritten to download crates from the
+//! mirror, so that cargo can use the mirror as a sparse registry directly.
+//!
+//! With `--top-crates`, only crates most downloaded recently and their
+//! transitive dependencies (except dev-dependencies) in the index are
+//! mirrored.
+//!
+//! `--yanked` decides whether yanked versions are mirrored (`keep`), only
+//! kept on target if they are already mirrored (`skip`), or removed from
+//! target (`prune`).
+//!
+//! With `--keep-recent`, only recent versions of each crate are mirrored.
+//! Index files are mirrored as is, so older versions listed in them are
+//! unavailable.
 
+use crate::checkpoint::Checkpoint;
 use crate::common::{Mission, SnapshotConfig, TransferURL};
-use crate::error::Result;
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::crates_io_sparse::{
+    fetch_crate, index_config, load_cache, names_from_zip, new_crates, save_cache, top_crates,
+    INDEX_PREFIX,
+};
+use crate::error::{Error, Result};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 
 use crate::metadata::SnapshotMeta;
 use async_trait::async_trait;
-use serde::Deserialize;
-use slog::info;
-use std::io::Read;
+use futures_util::{stream, StreamExt};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, Read};
 use structopt::StructOpt;
 
-#[derive(Deserialize, Debug)]
-pub struct CratesIoPackage {
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CratesIoDependency {
     name: String,
-    vers: String,
-    cksum: String,
+    /// actual name of a renamed dependency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    package: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CratesIoPackage {
+    pub(crate) name: String,
+    pub(crate) vers: String,
+    pub(crate) cksum: String,
+    #[serde(default)]
+    pub(crate) deps: Vec<CratesIoDependency>,
+    #[serde(default)]
+    pub(crate) yanked: bool,
+}
+
+/// Lowercase names of crates depended on by any of `packages`, except
+/// dev-dependencies.
+fn dependencies(packages: &[CratesIoPackage]) -> BTreeSet<String> {
+    packages
+        .iter()
+        .flat_map(|package| &package.deps)
+        .filter(|dep| dep.kind.as_deref() != Some("dev"))
+        .map(|dep| dep.package.as_ref().unwrap_or(&dep.name).to_lowercase())
+        .collect()
+}
+
+/// Keep recent `keep_recent` versions of each crate which are not yanked, as
+/// well as the latest stable one, ordered by semver. Versions which aren't
+/// semver are always kept.
+fn truncate_to_recent(packages: Vec<CratesIoPackage>, keep_recent: usize) -> Vec<CratesIoPackage> {
+    let mut groups: HashMap<String, Vec<(semver::Version, usize)>> = HashMap::new();
+    let mut selected = vec![true; packages.len()];
+    for (idx, package) in packages.iter().enumerate() {
+        if let Ok(version) = semver::Version::parse(&package.vers) {
+            selected[idx] = false;
+            if !package.yanked {
+                groups
+                    .entry(package.name.to_lowercase())
+                    .or_default()
+                    .push((version, idx));
+            }
+        }
+    }
+    for mut candidates in groups.into_values() {
+        candidates.sort_by(|a, b| b.cmp(a));
+        for (_, idx) in candidates.iter().take(keep_recent) {
+            selected[*idx] = true;
+        }
+        if let Some((_, idx)) = candidates.iter().find(|(x, _)| x.pre.is_empty()) {
+            selected[*idx] = true;
+        }
+    }
+    packages
+        .into_iter()
+        .zip(selected)
+        .filter_map(|(package, selected)| selected.then_some(package))
+        .collect()
+}
+
+/// Crates in `roots` and their transitive dependencies in `index`.
+fn dependency_closure(
+    roots: &[String],
+    index: &HashMap<String, Vec<CratesIoPackage>>,
+) -> HashSet<String> {
+    let mut selected: HashSet<String> = roots.iter().cloned().collect();
+    let mut pending = roots.to_vec();
+    while let Some(name) = pending.pop() {
+        if let Some(packages) = index.get(&name) {
+            for dep in dependencies(packages) {
+                if selected.insert(dep.clone()) {
+                    pending.push(dep);
+                }
+            }
+        }
+    }
+    selected
+}
+
+/// How yanked versions are mirrored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YankedPolicy {
+    /// mirror yanked versions as others
+    Keep,
+    /// don't mirror yanked versions, but keep those already mirrored
+    Skip,
+    /// remove yanked versions from target
+    Prune,
+}
+
+impl std::str::FromStr for YankedPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "skip" => Ok(Self::Skip),
+            "prune" => Ok(Self::Prune),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported yanked policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl CratesIoPackage {
+    /// Snapshot of the crate file, or `None` if it's pruned by `yanked`.
+    fn snapshot(self, yanked: YankedPolicy) -> Option<SnapshotMeta> {
+        let existing_only = match (self.yanked, yanked) {
+            (false, _) | (true, YankedPolicy::Keep) => false,
+            (true, YankedPolicy::Skip) => true,
+            (true, YankedPolicy::Prune) => return None,
+        };
+        let mut snapshot = SnapshotMeta {
+            key: format!(
+                "{crate}/{crate}-{version}.crate",
+                crate = self.name,
+                version = self.vers
+            ),
+            checksum_method: Some(String::from("sha256")),
+            checksum: Some(self.cksum),
+            ..Default::default()
+        };
+        snapshot.flags.existing_only = existing_only;
+        Some(snapshot)
+    }
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -32,6 +189,193 @@ pub struct CratesIo {
     pub crates_base: String,
     #[structopt(long)]
     pub debug: bool,
+    /// Discover crates with the sparse index at this URL (e.g. `https://index.crates.io`)
+    /// instead of the index zip.
+    #[structopt(long)]
+    pub sparse_index: Option<String>,
+    /// Keep validators and versions of sparse index files in this file, so that later runs send
+    /// conditional requests, and find new crates with crates.io API.
+    #[structopt(long)]
+    pub sparse_cache: Option<String>,
+    /// Only mirror crates listed in this file, one per line, with the sparse index.
+    #[structopt(long)]
+    pub crate_list: Option<String>,
+    #[structopt(long, default_value = "https://crates.io/api/v1")]
+    pub crates_api: String,
+    /// Also mirror the sparse index under `index/`, with `config.json` pointing at this base URL
+    /// of the mirror.
+    #[structopt(long, requires = "sparse-index")]
+    pub index_base_url: Option<String>,
+    /// Only mirror N crates most downloaded recently, ranked by crates.io API, and their
+    /// transitive dependencies.
+    #[structopt(long, conflicts_with = "crate-list")]
+    pub top_crates: Option<usize>,
+    /// Only mirror recent N versions of each crate which are not yanked, together with the latest
+    /// stable version.
+    #[structopt(long)]
+    pub keep_recent: Option<usize>,
+    /// Mirror yanked versions (`keep`), don't mirror new ones (`skip`), or remove them from target
+    /// (`prune`).
+    #[structopt(long, default_value = "keep", possible_values = &["keep", "skip", "prune"])]
+    pub yanked: YankedPolicy,
+}
+
+impl CratesIo {
+    /// Download the index zip, or reuse the one kept with `checkpoint`.
+    async fn fetch_zip(
+        &self,
+        logger: &Logger,
+        progress: &ProgressBar,
+        client: &reqwest::Client,
+        checkpoint: &mut Checkpoint<()>,
+    ) -> Result<bytes::Bytes> {
+        let attachment = checkpoint.attachment("index");
+        Ok(match &attachment {
+            Some(path) if checkpoint.completed().contains_key("index") => {
+                info!(logger, "using crates.io-index zip in checkpoint");
+                bytes::Bytes::from(tokio::fs::read(path).await?)
+            }
+            _ => {
+                info!(logger, "fetching crates.io-index zip...");
+                progress.set_message("fetching crates.io-index zip...");
+                let data = crate::http::send(client.get(&self.zip_master))
+                    .await?
+                    .bytes()
+                    .await?;
+                if let Some(path) = &attachment {
+                    tokio::fs::write(path, &data).await?;
+                    checkpoint.record("index", &())?;
+                }
+                data
+            }
+        })
+    }
+
+    /// Take snapshot with the sparse index at `sparse_index`.
+    async fn sparse_snapshot(
+        &self,
+        sparse_index: &str,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let scope = format!("crates.io sparse {} {:?}", sparse_index, self.top_crates);
+        let mut cache = match &self.sparse_cache {
+            Some(path) => load_cache(path, &scope)?,
+            None => HashMap::new(),
+        };
+        let names: BTreeSet<String> = if let Some(limit) = self.top_crates {
+            top_crates(&client, &self.crates_api, limit)
+                .await?
+                .into_iter()
+                .collect()
+        } else if let Some(path) = &self.crate_list {
+            let file = std::io::BufReader::new(std::fs::File::open(path)?);
+            let mut names = BTreeSet::new();
+            for line in file.lines() {
+                let line = line?;
+                let name = line.split('#').next().unwrap_or_default().trim();
+                if !name.is_empty() {
+                    names.insert(name.to_lowercase());
+                }
+            }
+            names
+        } else if !cache.is_empty() {
+            let known: HashSet<String> = cache.keys().cloned().collect();
+            let created = new_crates(&client, &self.crates_api, &known).await?;
+            info!(logger, "{} crates created since last run", created.len());
+            known.into_iter().chain(created).collect()
+        } else {
+            info!(logger, "listing crates from crates.io-index zip");
+            let scope = format!("crates.io {}", self.zip_master);
+            let mut checkpoint = Checkpoint::open(&logger, config.checkpoint.as_deref(), &scope)?;
+            let data = self
+                .fetch_zip(&logger, &progress, &client, &mut checkpoint)
+                .await?;
+            let names = names_from_zip(&data)?;
+            checkpoint.finish()?;
+            names.into_iter().collect()
+        };
+        let names: Vec<String> = if self.debug {
+            names.into_iter().take(100).collect()
+        } else {
+            names.into_iter().collect()
+        };
+
+        let mut seen: HashSet<String> = names.iter().cloned().collect();
+        let mut pending = names;
+        let mut crates = HashMap::new();
+        progress.set_length(0);
+        while !pending.is_empty() {
+            info!(logger, "fetching index files of {} crates", pending.len());
+            progress.inc_length(pending.len() as u64);
+            let fetched = stream::iter(pending)
+                .map(|name| {
+                    let cached = cache.remove(&name);
+                    let client = client.clone();
+                    let logger = logger.clone();
+                    let progress = progress.clone();
+                    async move {
+                        let result =
+                            fetch_crate(&client, sparse_index, &name, cached.clone()).await;
+                        progress.inc(1);
+                        match result {
+                            Ok(fetched) => (name, fetched),
+                            Err(err) => {
+                                warn!(logger, "failed to fetch index of {}: {:?}", name, err);
+                                (name, cached)
+                            }
+                        }
+                    }
+                })
+                .buffer_unordered(config.concurrent_resolve)
+                .collect::<Vec<_>>()
+                .await;
+
+            pending = vec![];
+            for (name, fetched) in fetched {
+                let fetched = match fetched {
+                    Some(fetched) => fetched,
+                    None => continue,
+                };
+                if self.top_crates.is_some() {
+                    for dep in dependencies(&fetched.packages) {
+                        if seen.insert(dep.clone()) {
+                            pending.push(dep);
+                        }
+                    }
+                }
+                crates.insert(name, fetched);
+            }
+        }
+        if let Some(path) = &self.sparse_cache {
+            save_cache(path, &scope, &crates)?;
+        }
+        progress.finish_with_message("done");
+
+        let mut snapshot = vec![];
+        for (name, cached) in crates {
+            if self.index_base_url.is_some() {
+                snapshot.push(cached.index_snapshot(&name));
+            }
+            let packages = match self.keep_recent {
+                Some(keep_recent) => truncate_to_recent(cached.packages, keep_recent),
+                None => cached.packages,
+            };
+            snapshot.extend(
+                packages
+                    .into_iter()
+                    .filter_map(|package| package.snapshot(self.yanked)),
+            );
+        }
+        if self.index_base_url.is_some() {
+            snapshot.push(SnapshotMeta::force(format!("{}config.json", INDEX_PREFIX)));
+        }
+        Ok(snapshot)
+    }
 }
 
 #[async_trait]
@@ -39,18 +383,24 @@ impl SnapshotStorage<SnapshotMeta> for CratesIo {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
+        if let Some(sparse_index) = &self.sparse_index {
+            return self.sparse_snapshot(sparse_index, mission, config).await;
+        }
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        info!(logger, "fetching crates.io-index zip...");
-        progress.set_message("fetching crates.io-index zip...");
-        let data = client.get(&self.zip_master).send().await?.bytes().await?;
+        let scope = format!("crates.io {}", self.zip_master);
+        let mut checkpoint: Checkpoint<()> =
+            Checkpoint::open(&logger, config.checkpoint.as_deref(), &scope)?;
+        let data = self
+            .fetch_zip(&logger, &progress, &client, &mut checkpoint)
+            .await?;
         let mut data = std::io::Cursor::new(data);
         let mut buf = vec![];
-        let mut snapshot = vec![];
+        let mut packages = vec![];
         info!(logger, "parsing...");
 
         let mut idx = 0;
@@ -63,23 +413,13 @@ impl SnapshotStorage<SnapshotMeta> for CratesIo {
 
                     let mut de = serde_json::Deserializer::from_reader(&buf[..]);
                     while let Ok(package) = CratesIoPackage::deserialize(&mut de) {
-                        let url = format!(
-                            "{crate}/{crate}-{version}.crate",
-                            crate = package.name,
-                            version = package.vers
-                        );
                         if is_first {
-                            progress.set_message(&url);
+                            progress.set_message(&package.name);
                             is_first = false;
                         }
                         idx += 1;
                         progress.inc(1);
-                        snapshot.push(SnapshotMeta {
-                            key: url,
-                            checksum_method: Some(String::from("sha256")),
-                            checksum: Some(package.cksum),
-                            ..Default::default()
-                        });
+                        packages.push(package);
                     }
                 }
                 Ok(None) => break,
@@ -91,9 +431,39 @@ impl SnapshotStorage<SnapshotMeta> for CratesIo {
             tokio::task::yield_now().await;
         }
 
+        checkpoint.finish()?;
         progress.finish_with_message("done");
 
-        Ok(snapshot)
+        if let Some(limit) = self.top_crates {
+            let roots = top_crates(&client, &self.crates_api, limit).await?;
+            let mut index: HashMap<String, Vec<CratesIoPackage>> = HashMap::new();
+            for package in packages {
+                index
+                    .entry(package.name.to_lowercase())
+                    .or_default()
+                    .push(package);
+            }
+            let selected = dependency_closure(&roots, &index);
+            info!(
+                logger,
+                "{} crates selected from top {} crates",
+                selected.len(),
+                limit
+            );
+            packages = index
+                .into_iter()
+                .filter(|(name, _)| selected.contains(name))
+                .flat_map(|(_, packages)| packages)
+                .collect();
+        }
+
+        if let Some(keep_recent) = self.keep_recent {
+            packages = truncate_to_recent(packages, keep_recent);
+        }
+        Ok(packages
+            .into_iter()
+            .filter_map(|package| package.snapshot(self.yanked))
+            .collect())
     }
 
     fn info(&self) -> String {
@@ -104,9 +474,107 @@ impl SnapshotStorage<SnapshotMeta> for CratesIo {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for CratesIo {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        if let (Some(sparse_index), Some(path)) =
+            (&self.sparse_index, snapshot.key.strip_prefix(INDEX_PREFIX))
+        {
+            return Ok(TransferURL(format!(
+                "{}/{}",
+                sparse_index.trim_end_matches('/'),
+                path
+            )));
+        }
         Ok(TransferURL(format!(
             "{}/{}",
             self.crates_base, snapshot.key
         )))
     }
 }
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for CratesIo {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        match &self.index_base_url {
+            Some(base) if snapshot.key == format!("{}config.json", INDEX_PREFIX) => {
+                Ok(Some(index_config(base).into_bytes()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{dependency_closure, truncate_to_recent, CratesIoPackage, YankedPolicy};
+
+    #[test]
+    fn test_dependency_closure() {
+        let index_file = r#"{"name":"a","vers":"1.0.0","cksum":"","deps":[{"name":"b","req":"^1","kind":"normal"},{"name":"c","req":"^1","kind":"dev"}]}
+{"name":"a","vers":"2.0.0","cksum":"","deps":[{"name":"d2","package":"D","req":"^1","kind":"build"}]}
+{"name":"b","vers":"1.0.0","cksum":"","deps":[{"name":"e","req":"^1","optional":true}]}
+{"name":"c","vers":"1.0.0","cksum":"","deps":[]}
+{"name":"D","vers":"1.0.0","cksum":""}
+{"name":"e","vers":"1.0.0","cksum":"","deps":[{"name":"a","req":"^1"}]}
+{"name":"f","vers":"1.0.0","cksum":"","deps":[]}"#;
+        let mut index: HashMap<String, Vec<CratesIoPackage>> = HashMap::new();
+        for line in index_file.lines() {
+            let package: CratesIoPackage = serde_json::from_str(line).unwrap();
+            index
+                .entry(package.name.to_lowercase())
+                .or_default()
+                .push(package);
+        }
+        let mut selected: Vec<_> = dependency_closure(&["a".to_string()], &index)
+            .into_iter()
+            .collect();
+        selected.sort();
+        assert_eq!(selected, vec!["a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn test_truncate_to_recent() {
+        let index_file = r#"{"name":"a","vers":"0.9.0","cksum":""}
+{"name":"a","vers":"1.0.0","cksum":""}
+{"name":"a","vers":"1.1.0","cksum":"","yanked":true}
+{"name":"a","vers":"1.2.0-rc.1","cksum":""}
+{"name":"a","vers":"1.2.0-rc.2","cksum":""}
+{"name":"A","vers":"0.1","cksum":""}
+{"name":"b","vers":"0.1.0","cksum":""}"#;
+        let packages: Vec<CratesIoPackage> = index_file
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kept: Vec<_> = truncate_to_recent(packages, 2)
+            .into_iter()
+            .map(|package| format!("{}-{}", package.name, package.vers))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                "a-1.0.0",
+                "a-1.2.0-rc.1",
+                "a-1.2.0-rc.2",
+                "A-0.1",
+                "b-0.1.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_yanked_policy() {
+        let package: CratesIoPackage =
+            serde_json::from_str(r#"{"name":"a","vers":"1.0.0","cksum":"00","yanked":true}"#)
+                .unwrap();
+        let keep = package.clone().snapshot(YankedPolicy::Keep).unwrap();
+        assert_eq!(keep.key, "a/a-1.0.0.crate");
+        assert!(!keep.flags.existing_only);
+        let skip = package.clone().snapshot(YankedPolicy::Skip).unwrap();
+        assert!(skip.flags.existing_only);
+        assert!(package.snapshot(YankedPolicy::Prune).is_none());
+    }
+}
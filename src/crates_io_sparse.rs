@@ -0,0 +1,368 @@
+//! A submodule for crates.io source that discovers crates with the sparse index.
+//!
+//! Index file of each crate is fetched from the sparse index (e.g.
+//! `https://index.crates.io`) over HTTP. With a cache file, validators and
+//! versions of index files are kept, so that later runs send conditional
+//! requests and only download changed ones.
+//!
+//! The sparse index can't be listed, so crate names come from a crate list,
+//! or from the cache together with crates created since last run according
+//! to the crates.io API. If neither is available, e.g. on the first run, names
+//! are listed from the index zip instead.
+//!
+//! Index files may also be mirrored under `index/`, with a generated
+//! `config.json` pointing cargo at the mirror.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::crates_io::CratesIoPackage;
+use crate::error::{Error, Result};
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+
+/// Prefix of mirrored index files.
+pub const INDEX_PREFIX: &str = "index/";
+
+/// Pages of new crates requested from crates.io API at most.
+const MAX_NEW_CRATE_PAGES: usize = 100;
+
+/// Path of the index file of crate `name`.
+pub fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// Validators of an index file fetched in last run, and versions in it.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CachedCrate {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    #[serde(default)]
+    size: Option<u64>,
+    pub packages: Vec<CratesIoPackage>,
+}
+
+impl CachedCrate {
+    fn new(headers: &HeaderMap, body: &str) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            size: Some(body.len() as u64),
+            packages: parse_index_file(body),
+        }
+    }
+
+    /// Snapshot of the mirrored index file of crate `name`, transferred after
+    /// crate files.
+    pub fn index_snapshot(&self, name: &str) -> SnapshotMeta {
+        let last_modified = self
+            .last_modified
+            .as_deref()
+            .and_then(|x| chrono::DateTime::parse_from_rfc2822(x).ok())
+            .map(|x| x.timestamp() as u64);
+        SnapshotMeta {
+            key: format!("{}{}", INDEX_PREFIX, index_path(name)),
+            size: self.size,
+            last_modified,
+            flags: SnapshotMetaFlag {
+                force_last: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// `config.json` of index mirrored at `base`, where crates are stored as
+/// `{crate}/{crate}-{version}.crate`.
+pub fn index_config(base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    serde_json::json!({
+        "dl": format!("{}/{{crate}}/{{crate}}-{{version}}.crate", base),
+        "api": base,
+    })
+    .to_string()
+}
+
+/// Versions in an index file, one JSON object per line.
+fn parse_index_file(body: &str) -> Vec<CratesIoPackage> {
+    body.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Fetch index file of crate `name`. If it's not modified since `cached`,
+/// `cached` is returned. Returns `None` if the crate doesn't exist.
+pub async fn fetch_crate(
+    client: &Client,
+    base: &str,
+    name: &str,
+    cached: Option<CachedCrate>,
+) -> Result<Option<CachedCrate>> {
+    let url = format!("{}/{}", base.trim_end_matches('/'), index_path(name));
+    let mut request = client.get(&url);
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = crate::http::send(request).await?;
+    match response.status() {
+        StatusCode::NOT_MODIFIED if cached.is_some() => Ok(cached),
+        // crates.io responds 403 for missing index files
+        StatusCode::NOT_FOUND | StatusCode::FORBIDDEN | StatusCode::GONE => Ok(None),
+        status if status.is_success() => {
+            let headers = response.headers().clone();
+            let body = response.text().await?;
+            Ok(Some(CachedCrate::new(&headers, &body)))
+        }
+        status => Err(Error::HTTPError(status)),
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiCrate {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiCrates {
+    crates: Vec<ApiCrate>,
+}
+
+/// A page of crates listed by crates.io API, sorted by `sort`.
+async fn list_crates(client: &Client, api: &str, sort: &str, page: usize) -> Result<Vec<String>> {
+    let url = format!(
+        "{}/crates?sort={}&per_page=100&page={}",
+        api.trim_end_matches('/'),
+        sort,
+        page
+    );
+    let response = crate::http::send(client.get(&url)).await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    let crates: ApiCrates = response.json().await?;
+    Ok(crates
+        .crates
+        .into_iter()
+        .map(|krate| krate.name.to_lowercase())
+        .collect())
+}
+
+/// Lowercase names of `limit` crates most downloaded recently (in 90 days),
+/// from crates.io API.
+pub async fn top_crates(client: &Client, api: &str, limit: usize) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for page in 1.. {
+        let crates = list_crates(client, api, "recent-downloads", page).await?;
+        if crates.is_empty() {
+            break;
+        }
+        names.extend(crates);
+        if names.len() >= limit {
+            break;
+        }
+    }
+    names.truncate(limit);
+    Ok(names)
+}
+
+/// Lowercase names of crates created since any crate in `known`, newest
+/// first, from crates.io API.
+pub async fn new_crates(
+    client: &Client,
+    api: &str,
+    known: &HashSet<String>,
+) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for page in 1..=MAX_NEW_CRATE_PAGES {
+        let crates = list_crates(client, api, "new", page).await?;
+        if crates.is_empty() {
+            return Ok(names);
+        }
+        for name in crates {
+            if known.contains(&name) {
+                return Ok(names);
+            }
+            names.push(name);
+        }
+    }
+    Err(Error::ProcessError(format!(
+        "more than {} new crates since last run",
+        MAX_NEW_CRATE_PAGES * 100
+    )))
+}
+
+/// Names of crates listed in the index zip.
+pub fn names_from_zip(data: &[u8]) -> Result<Vec<String>> {
+    let mut data = std::io::Cursor::new(data);
+    let mut names = vec![];
+    while let Some(file) = zip::read::read_zipfile_from_stream(&mut data)? {
+        if !file.is_file() {
+            continue;
+        }
+        let path = file.name();
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if !name.is_empty() && path.ends_with(&format!("/{}", index_path(name))) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Load cache of index files taken with the same `scope`.
+pub fn load_cache(path: &str, scope: &str) -> Result<HashMap<String, CachedCrate>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let cached_scope: String = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Ok(HashMap::new()),
+    };
+    if cached_scope != scope {
+        return Ok(HashMap::new());
+    }
+    let mut crates = HashMap::new();
+    for line in lines {
+        let (name, cached): (String, CachedCrate) = serde_json::from_str(&line?)?;
+        crates.insert(name, cached);
+    }
+    Ok(crates)
+}
+
+pub fn save_cache(path: &str, scope: &str, crates: &HashMap<String, CachedCrate>) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = BufWriter::new(std::fs::File::create(&tmp)?);
+    serde_json::to_writer(&mut file, scope)?;
+    file.write_all(b"\n")?;
+    for entry in crates {
+        serde_json::to_writer(&mut file, &entry)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use rstest::rstest;
+
+    use super::{
+        index_config, index_path, load_cache, names_from_zip, parse_index_file, save_cache,
+        CachedCrate,
+    };
+
+    #[rstest]
+    #[case("a", "1/a")]
+    #[case("cc", "2/cc")]
+    #[case("syn", "3/s/syn")]
+    #[case("Serde", "se/rd/serde")]
+    #[case("tokio-util", "to/ki/tokio-util")]
+    fn test_index_path(#[case] name: &str, #[case] path: &str) {
+        assert_eq!(index_path(name), path);
+    }
+
+    #[test]
+    fn test_parse_index_file() {
+        let body = r#"{"name":"syn","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false}
+{"name":"syn","vers":"2.0.0","deps":[],"cksum":"bb","features":{},"yanked":false,"v":2}
+
+not json
+"#;
+        let packages = parse_index_file(body);
+        let versions: Vec<_> = packages
+            .iter()
+            .map(|x| (x.vers.as_str(), x.cksum.as_str()))
+            .collect();
+        assert_eq!(versions, vec![("1.0.0", "aa"), ("2.0.0", "bb")]);
+
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-crates-cache-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let mut crates = std::collections::HashMap::new();
+        crates.insert(
+            "syn".to_string(),
+            CachedCrate {
+                etag: Some("\"abc\"".to_string()),
+                last_modified: Some("Thu, 15 Oct 2026 08:00:00 GMT".to_string()),
+                size: Some(body.len() as u64),
+                packages,
+            },
+        );
+        save_cache(path, "scope", &crates).unwrap();
+        let loaded = load_cache(path, "scope").unwrap();
+        assert_eq!(loaded["syn"].etag.as_deref(), Some("\"abc\""));
+        assert_eq!(loaded["syn"].packages.len(), 2);
+        let meta = loaded["syn"].index_snapshot("syn");
+        assert_eq!(meta.key, "index/3/s/syn");
+        assert_eq!(meta.size, Some(body.len() as u64));
+        assert_eq!(meta.last_modified, Some(1792051200));
+        assert!(meta.flags.force_last && !meta.flags.force);
+        assert!(load_cache(path, "another").unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_index_config() {
+        let config: serde_json::Value =
+            serde_json::from_str(&index_config("https://mirror.example.com/crates.io/")).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "dl": "https://mirror.example.com/crates.io/{crate}/{crate}-{version}.crate",
+                "api": "https://mirror.example.com/crates.io",
+            })
+        );
+    }
+
+    #[test]
+    fn test_names_from_zip() {
+        let mut data = std::io::Cursor::new(vec![]);
+        let mut zip = zip::ZipWriter::new(&mut data);
+        let options = zip::write::FileOptions::default();
+        for path in [
+            "crates.io-index-master/config.json",
+            "crates.io-index-master/.github/workflows/ci.yml",
+            "crates.io-index-master/1/a",
+            "crates.io-index-master/3/s/syn",
+            "crates.io-index-master/se/rd/serde",
+        ] {
+            zip.start_file(path, options).unwrap();
+            zip.write_all(b"{}").unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+        assert_eq!(
+            names_from_zip(data.get_ref()).unwrap(),
+            vec!["a", "syn", "serde"]
+        );
+    }
+}
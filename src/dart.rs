@@ -1,3 +1,14 @@
+//! Dart source
+//!
+//! Dart source lists all packages with `api/packages` of pub.dev (or a
+//! mirror of it), and fetches versions of each package from
+//! `api/packages/<name>`. The `archive_sha256` of each version is kept in
+//! the snapshot, so that archives are verified before they hit the target.
+//!
+//! With `--keep-recent`, only recent N versions of each package which are not
+//! retracted are mirrored, together with the latest stable one. Versions
+//! which aren't semver are always kept.
+
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
@@ -5,7 +16,7 @@ use crate::traits::{SnapshotStorage, SourceStorage};
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
-use serde_json::Value;
+use serde::Deserialize;
 use slog::{info, warn};
 use structopt::StructOpt;
 
@@ -15,6 +26,102 @@ pub struct Dart {
     pub base: String,
     #[structopt(long)]
     pub debug: bool,
+    /// Only keep recent N versions per package.
+    /// Please consider adding `--no-delete` parameter on simple diff transfer to avoid clearing
+    /// previous cache.
+    #[structopt(long)]
+    pub keep_recent: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PackageName {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct PackageList {
+    packages: Vec<PackageName>,
+    next_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PackageVersion {
+    version: String,
+    archive_url: String,
+    archive_sha256: Option<String>,
+    #[serde(default)]
+    retracted: bool,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    versions: Vec<PackageVersion>,
+}
+
+/// Keep recent `keep_recent` versions which are not retracted, as well as the
+/// latest stable one, ordered by semver. Versions which aren't semver are
+/// always kept.
+fn truncate_to_recent(versions: Vec<PackageVersion>, keep_recent: usize) -> Vec<PackageVersion> {
+    let mut candidates = vec![];
+    let mut selected = vec![true; versions.len()];
+    for (idx, version) in versions.iter().enumerate() {
+        if let Ok(semver) = semver::Version::parse(&version.version) {
+            selected[idx] = false;
+            if !version.retracted {
+                candidates.push((semver, idx));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.cmp(a));
+    for (_, idx) in candidates.iter().take(keep_recent) {
+        selected[*idx] = true;
+    }
+    if let Some((_, idx)) = candidates.iter().find(|(x, _)| x.pre.is_empty()) {
+        selected[*idx] = true;
+    }
+    versions
+        .into_iter()
+        .zip(selected)
+        .filter_map(|(version, selected)| selected.then_some(version))
+        .collect()
+}
+
+impl Dart {
+    /// Archives of package `name`.
+    async fn package(&self, client: &reqwest::Client, name: &str) -> Result<Vec<SnapshotMeta>> {
+        let base = format!("{}/", self.base);
+        let response =
+            crate::http::send(client.get(format!("{}api/packages/{}", base, name))).await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        let package: Package = serde_json::from_str(&response.text().await?)?;
+        let versions = match self.keep_recent {
+            Some(keep_recent) => truncate_to_recent(package.versions, keep_recent),
+            None => package.versions,
+        };
+        versions
+            .into_iter()
+            .map(|version| {
+                let key = version
+                    .archive_url
+                    .strip_prefix(&base)
+                    .ok_or_else(|| {
+                        Error::ProcessError(format!("unmatched base URL {}", version.archive_url))
+                    })?
+                    .to_string();
+                Ok(SnapshotMeta {
+                    key,
+                    checksum_method: version
+                        .archive_sha256
+                        .as_ref()
+                        .map(|_| "sha256".to_string()),
+                    checksum: version.archive_sha256,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
 }
 
 #[async_trait]
@@ -31,31 +138,18 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
         let api_base = format!("{}/api/packages", self.base);
 
         info!(logger, "fetching packages...");
-        let mut next_url = api_base.clone();
+        let mut next_url = Some(api_base);
         let mut package_name = vec![];
         let mut page: usize = 1;
 
-        loop {
-            let data = client.get(&next_url).send().await?.text().await?;
-            let data: Value = serde_json::from_str(&data).unwrap();
-            let data = data.as_object().unwrap();
-
-            let packages = data.get("packages").unwrap().as_array().unwrap();
-
-            for package in packages {
-                package_name.push(package.get("name").unwrap().as_str().unwrap().to_string());
-            }
-
-            let next_url_str = data.get("next_url");
-            if let Some(next_url_str) = next_url_str {
-                if !next_url_str.is_null() {
-                    next_url = next_url_str.as_str().unwrap().to_string();
-                } else {
-                    break;
-                }
-            } else {
-                break;
+        while let Some(url) = next_url {
+            let response = crate::http::send(client.get(&url)).await?;
+            if !response.status().is_success() {
+                return Err(Error::HTTPError(response.status()));
             }
+            let data: PackageList = serde_json::from_str(&response.text().await?)?;
+            package_name.extend(data.packages.into_iter().map(|package| package.name));
+            next_url = data.next_url;
             progress.set_message(&format!(
                 "fetching page {}, total packages = {}",
                 page,
@@ -70,58 +164,30 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
 
         progress.inc_length(package_name.len() as u64);
 
-        let snapshots: Result<Vec<Vec<SnapshotMeta>>> =
+        let this = &*self;
+        let snapshots: Vec<Vec<SnapshotMeta>> =
             stream::iter(package_name.into_iter().map(|name| {
                 let client = client.clone();
-                let base = format!("{}/", self.base);
                 let progress = progress.clone();
                 let logger = logger.clone();
-
-                let func = async move {
-                    progress.set_message(&name);
-                    let package = client
-                        .get(&format!("{}/api/packages/{}", base, name))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
-
-                    let data: Value = serde_json::from_str(&package).unwrap();
-                    let versions = data.get("versions").unwrap().as_array().unwrap();
-                    let archives: Vec<SnapshotMeta> = versions
-                        .iter()
-                        .filter_map(|version| version.get("archive_url"))
-                        .filter_map(|archive_url| archive_url.as_str())
-                        .map(|archive_url| {
-                            if archive_url.starts_with(&base) {
-                                SnapshotMeta {
-                                    key: archive_url[base.len()..].to_string(),
-                                    ..Default::default()
-                                }
-                            } else {
-                                panic!("Unmatched base URL {}", archive_url);
-                            }
-                        })
-                        .collect();
-
-                    progress.inc(1);
-                    Ok::<Vec<SnapshotMeta>, Error>(archives)
-                };
                 async move {
-                    match func.await {
-                        Ok(x) => Ok(x),
+                    progress.set_message(&name);
+                    let archives = match this.package(&client, &name).await {
+                        Ok(archives) => archives,
                         Err(err) => {
-                            warn!(logger, "failed to fetch package meta {:?}", err);
-                            Ok(vec![])
+                            warn!(logger, "failed to fetch package meta {}: {:?}", name, err);
+                            vec![]
                         }
-                    }
+                    };
+                    progress.inc(1);
+                    Ok::<Vec<SnapshotMeta>, Error>(archives)
                 }
             }))
             .buffer_unordered(config.concurrent_resolve)
             .try_collect()
-            .await;
+            .await?;
 
-        let snapshot: Vec<_> = snapshots?.into_iter().flatten().collect();
+        let snapshot: Vec<_> = snapshots.into_iter().flatten().collect();
 
         progress.finish_with_message("done");
 
@@ -139,3 +205,31 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Dart {
         Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{truncate_to_recent, Package};
+
+    #[test]
+    fn test_truncate_to_recent() {
+        let package: Package = serde_json::from_str(
+            r#"{"versions": [
+                {"version": "1.0.0", "archive_url": "", "archive_sha256": "00"},
+                {"version": "1.1.0", "archive_url": ""},
+                {"version": "1.2.0", "archive_url": "", "retracted": true},
+                {"version": "2.0.0-dev.1", "archive_url": ""},
+                {"version": "2.0.0-dev.2", "archive_url": ""},
+                {"version": "legacy", "archive_url": ""}
+            ]}"#,
+        )
+        .unwrap();
+        let versions: Vec<_> = truncate_to_recent(package.versions, 2)
+            .into_iter()
+            .map(|x| x.version)
+            .collect();
+        assert_eq!(
+            versions,
+            vec!["1.1.0", "2.0.0-dev.1", "2.0.0-dev.2", "legacy"]
+        );
+    }
+}
@@ -40,6 +40,10 @@ pub enum Error {
     },
     #[error("GCP Error {0}")]
     GCPError(#[from] google_bigquery2::Error),
+    #[error("SQLite Error {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("XML Error {0}")]
+    XmlError(#[from] xml::reader::Error),
 }
 
 impl<T: std::fmt::Debug> From<rusoto_core::RusotoError<T>> for Error {
@@ -5,16 +5,40 @@
 //!
 //! File backend snapshots contains metadata (size + last modified).
 //! It only accepts ByteStream.
+//!
+//! Modified time of written files is set to last modified time in source
+//! snapshot, or `Last-Modified` of the transfer if snapshot doesn't have one.
+//! Therefore, snapshots of target can be compared with source by timestamp,
+//! and downstreams syncing with rsync see stable modified times.
+//!
+//! Deduplicated objects are stored as hard links, so that they stay intact
+//! when the object they're linked from is deleted or replaced.
+//!
+//! Alternative layouts can be served by `views`, which are symbolic links
+//! over objects created after all objects are updated. See `file_view`.
+//!
+//! If `atomic_index` is set, index objects (which have negative priority, e.g.
+//! generated index pages and repodata) are written to a staging directory
+//! first, and moved into place only after all objects are updated. Clients
+//! never see an index referencing objects not yet transferred.
+//!
+//! If a checksum database is set, size, sha256 and last modified time of
+//! every written file are recorded in it. See `checksum_db`.
+
+use std::sync::{Arc, Mutex};
 
+use crate::checksum_db::{file_record, ChecksumDb};
+use crate::checksum_pipe::calc_checksum;
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::{Error, Result};
+use crate::file_view::{materialize, view_links, FileView};
 use crate::metadata::SnapshotMeta;
 use crate::stream_pipe::ByteStream;
-use crate::traits::{Key, Metadata, SnapshotStorage, TargetStorage};
+use crate::traits::{Key, Metadata, SnapshotStorage, StateStorage, TargetStorage, STATE_PREFIX};
 
 use async_trait::async_trait;
 use filetime::FileTime;
-use slog::info;
+use slog::{info, warn};
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
@@ -22,12 +46,46 @@ use walkdir::WalkDir;
 pub struct FileBackend {
     #[structopt(long)]
     pub base_path: String,
+    #[structopt(skip)]
+    pub checksum_db: Option<Arc<ChecksumDb>>,
+    #[structopt(long)]
+    pub atomic_index: bool,
+    #[structopt(skip)]
+    staged: Mutex<Vec<String>>,
+    #[structopt(skip)]
+    pub views: Vec<FileView>,
 }
 
 impl FileBackend {
     pub fn new(base_path: String) -> Self {
-        Self { base_path }
+        Self {
+            base_path,
+            checksum_db: None,
+            atomic_index: false,
+            staged: Mutex::new(vec![]),
+            views: vec![],
+        }
+    }
+
+    fn staging_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.base_path)
+            .join(STATE_PREFIX)
+            .join("staging")
     }
+
+    async fn record(&self, key: &str) -> Result<()> {
+        if let Some(db) = &self.checksum_db {
+            let path = std::path::Path::new(&self.base_path).join(key);
+            db.record(key, &file_record(&path).await?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of `to` relative to the directory of `from`, both relative to base path.
+pub fn relative_link(from: &str, to: &str) -> String {
+    let depth = from.matches('/').count();
+    format!("{}{}", "../".repeat(depth), to)
 }
 
 #[async_trait]
@@ -46,6 +104,7 @@ impl SnapshotStorage<SnapshotMeta> for FileBackend {
         tokio::task::spawn_blocking(move || {
             let mut snapshot = vec![];
             let base_path = std::path::PathBuf::from(base_path).canonicalize().unwrap();
+            let view_links = view_links(&base_path)?;
             for entry in WalkDir::new(&base_path) {
                 let entry = entry.map_err(|err| {
                     Error::StorageError(format!("error while scanning file: {:?}", err))
@@ -54,7 +113,11 @@ impl SnapshotStorage<SnapshotMeta> for FileBackend {
                 if path.is_file() {
                     let path = path.strip_prefix(&base_path).unwrap();
                     let path = path.to_str().unwrap().to_string();
-                    let metadata = entry.metadata().map_err(|err| {
+                    if view_links.contains(&path) {
+                        continue;
+                    }
+                    // follow symbolic links of deduplicated objects
+                    let metadata = std::fs::metadata(entry.path()).map_err(|err| {
                         Error::StorageError(format!("file backend fails to get metadata {:?}", err))
                     })?;
 
@@ -88,13 +151,27 @@ impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, ByteStream> for FileBacke
         byte_stream: ByteStream,
         _mission: &Mission,
     ) -> Result<()> {
-        let path = byte_stream.object.use_file();
-        let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
+        let ByteStream {
+            object,
+            modified_at,
+            ..
+        } = byte_stream;
+        let path = object.use_file();
+        let staged = self.atomic_index && snapshot.priority() < 0;
+        let target: std::path::PathBuf = if staged {
+            self.staging_path().join(snapshot.key())
+        } else {
+            format!("{}/{}", self.base_path, snapshot.key()).into()
+        };
         let parent = target.parent().unwrap();
         tokio::fs::create_dir_all(parent).await?;
         tokio::fs::rename(&path, &target).await?;
-        if let Some(last_modified) = snapshot.last_modified() {
-            filetime::set_file_mtime(&target, FileTime::from_unix_time(last_modified as i64, 0))?;
+        let last_modified = snapshot.last_modified().unwrap_or(modified_at);
+        filetime::set_file_mtime(&target, FileTime::from_unix_time(last_modified as i64, 0))?;
+        if staged {
+            self.staged.lock().unwrap().push(snapshot.key().to_string());
+        } else {
+            self.record(snapshot.key()).await?;
         }
         Ok(())
     }
@@ -102,6 +179,116 @@ impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, ByteStream> for FileBacke
     async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
         let target = format!("{}/{}", self.base_path, snapshot.key());
         tokio::fs::remove_file(target).await?;
+        if let Some(db) = &self.checksum_db {
+            db.remove(snapshot.key())?;
+        }
+        Ok(())
+    }
+
+    async fn link_object(
+        &self,
+        snapshot: &Snapshot,
+        from: &str,
+        mission: &Mission,
+    ) -> Result<bool> {
+        // staged objects are written to staging directory instead
+        if self.atomic_index && snapshot.priority() < 0 {
+            return Ok(false);
+        }
+        let source: std::path::PathBuf = format!("{}/{}", self.base_path, from).into();
+        let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
+        let parent = target.parent().unwrap();
+        tokio::fs::create_dir_all(parent).await?;
+        if tokio::fs::symlink_metadata(&target).await.is_ok() {
+            tokio::fs::remove_file(&target).await?;
+        }
+        // the object linked from may still be staged, in which case the
+        // object is written as usual
+        if let Err(err) = tokio::fs::hard_link(&source, &target).await {
+            warn!(
+                mission.logger,
+                "failed to link {} to {}, writing it instead: {:?}",
+                snapshot.key(),
+                from,
+                err
+            );
+            return Ok(false);
+        }
+        self.record(snapshot.key()).await?;
+        Ok(true)
+    }
+
+    async fn audit_object(
+        &self,
+        snapshot: &Snapshot,
+        mut item: ByteStream,
+        _mission: &Mission,
+    ) -> Result<Option<bool>>
+    where
+        Snapshot: Sync,
+        ByteStream: Send + 'async_trait,
+    {
+        let expected = item.object.sha256().await?;
+        let path = format!("{}/{}", self.base_path, snapshot.key());
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Some(false)),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(calc_checksum(&mut file, "sha256").await? == expected))
+    }
+
+    async fn publish(&self, mission: &Mission) -> Result<()> {
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        let staging_path = self.staging_path();
+        for key in &staged {
+            let target: std::path::PathBuf = format!("{}/{}", self.base_path, key).into();
+            tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+            tokio::fs::rename(staging_path.join(key), &target).await?;
+            self.record(key).await?;
+        }
+        if !staged.is_empty() {
+            info!(mission.logger, "published {} index objects", staged.len());
+        }
+        if tokio::fs::metadata(&staging_path).await.is_ok() {
+            tokio::fs::remove_dir_all(&staging_path).await?;
+        }
+        if !self.views.is_empty() {
+            let logger = mission.logger.clone();
+            let base_path = std::path::PathBuf::from(&self.base_path);
+            let views = self.views.clone();
+            tokio::task::spawn_blocking(move || materialize(&logger, &base_path, &views))
+                .await
+                .map_err(|err| Error::ProcessError(format!("error while linking: {:?}", err)))??;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StateStorage for FileBackend {
+    async fn load_state(&self, name: &str, path: &std::path::Path) -> Result<bool> {
+        let state = format!("{}/{}/{}", self.base_path, STATE_PREFIX, name);
+        if tokio::fs::metadata(&state).await.is_err() {
+            return Ok(false);
+        }
+        tokio::fs::copy(&state, path).await?;
+        Ok(true)
+    }
+
+    async fn save_state(&self, name: &str, path: &std::path::Path) -> Result<()> {
+        let state: std::path::PathBuf =
+            format!("{}/{}/{}", self.base_path, STATE_PREFIX, name).into();
+        if tokio::fs::metadata(path).await.is_err() {
+            if tokio::fs::metadata(&state).await.is_ok() {
+                tokio::fs::remove_file(&state).await?;
+            }
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(state.parent().unwrap()).await?;
+        let tmp = state.with_extension("tmp");
+        tokio::fs::copy(path, &tmp).await?;
+        tokio::fs::rename(&tmp, &state).await?;
         Ok(())
     }
 }
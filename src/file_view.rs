@@ -0,0 +1,238 @@
+//! Symlink views of file backend
+//!
+//! A view materializes an alternative directory layout over objects on file
+//! backend as relative symbolic links, so that one mirror can serve several
+//! path conventions without storing objects twice.
+//!
+//! A view is given as `<pattern>=<template>`. Every object whose key matches
+//! the regex `pattern` is linked at `template`, in which `$1`, `${name}` etc.
+//! are replaced by captures. If several objects map to the same link, the one
+//! with the largest key in natural order is linked, which makes "latest"
+//! links possible, e.g. `^tool/v([0-9.]+)/tool\.tar\.gz$=tool/latest.tar.gz`.
+//!
+//! Links created by views are recorded in `.mirror-clone/views`. They are
+//! excluded from snapshots of file backend, and removed when no object maps
+//! to them any more.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use regex::Regex;
+use slog::{info, warn, Logger};
+use walkdir::WalkDir;
+
+use crate::error::{Error, Result};
+use crate::file_backend::relative_link;
+use crate::traits::STATE_PREFIX;
+
+#[derive(Debug, Clone)]
+pub struct FileView {
+    pattern: Regex,
+    template: String,
+}
+
+impl std::str::FromStr for FileView {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (pattern, template) = s
+            .rsplit_once('=')
+            .ok_or_else(|| Error::ConfigureError(format!("invalid view {}", s)))?;
+        let pattern = Regex::new(pattern)
+            .map_err(|err| Error::ConfigureError(format!("invalid view {}: {}", s, err)))?;
+        Ok(Self {
+            pattern,
+            template: template.to_string(),
+        })
+    }
+}
+
+impl FileView {
+    fn link_for(&self, key: &str) -> Option<String> {
+        let captures = self.pattern.captures(key)?;
+        let mut link = String::new();
+        captures.expand(&self.template, &mut link);
+        Some(link)
+    }
+}
+
+/// Compare keys with digit runs compared as numbers, so that `v1.10` is
+/// larger than `v1.9`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (x, y) = match (a.chars().next(), b.chars().next()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return a.len().cmp(&b.len()),
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let len_a = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let len_b = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (num_a, num_b) = (
+                a[..len_a].trim_start_matches('0'),
+                b[..len_b].trim_start_matches('0'),
+            );
+            let ord = num_a.len().cmp(&num_b.len()).then_with(|| num_a.cmp(num_b));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = &a[len_a..];
+            b = &b[len_b..];
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+        }
+    }
+}
+
+fn views_path(base_path: &Path) -> std::path::PathBuf {
+    base_path.join(STATE_PREFIX).join("views")
+}
+
+/// Links created by views in previous run.
+pub fn view_links(base_path: &Path) -> Result<HashSet<String>> {
+    match std::fs::read_to_string(views_path(base_path)) {
+        Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Create links of all views under `base_path`, and remove stale links.
+/// Returns number of links.
+pub fn materialize(logger: &Logger, base_path: &Path, views: &[FileView]) -> Result<usize> {
+    let previous = view_links(base_path)?;
+
+    let mut objects = vec![];
+    for entry in WalkDir::new(base_path) {
+        let entry = entry
+            .map_err(|err| Error::StorageError(format!("error while scanning file: {:?}", err)))?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        let key = entry.path().strip_prefix(base_path).unwrap();
+        let key = key.to_str().unwrap().to_string();
+        if key.starts_with(STATE_PREFIX) || previous.contains(&key) {
+            continue;
+        }
+        objects.push(key);
+    }
+
+    // link -> object
+    let mut links: BTreeMap<String, String> = BTreeMap::new();
+    for key in &objects {
+        for view in views {
+            if let Some(link) = view.link_for(key) {
+                match links.get(&link) {
+                    Some(current) if natural_cmp(current, key) != Ordering::Less => {}
+                    _ => {
+                        links.insert(link, key.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    let objects: HashSet<String> = objects.into_iter().collect();
+    let mut created = vec![];
+    for (link, key) in &links {
+        if objects.contains(link) {
+            warn!(logger, "view link {} conflicts with an object", link);
+            continue;
+        }
+        let path = base_path.join(link);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let target = relative_link(link, key);
+        let up_to_date = std::fs::read_link(&path)
+            .map(|x| x == Path::new(&target))
+            .unwrap_or(false);
+        if !up_to_date {
+            let tmp = path.with_extension("mirror-clone-tmp");
+            if std::fs::symlink_metadata(&tmp).is_ok() {
+                std::fs::remove_file(&tmp)?;
+            }
+            std::os::unix::fs::symlink(&target, &tmp)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+        created.push(link.clone());
+    }
+
+    let current: HashSet<&String> = created.iter().collect();
+    let mut removed = 0;
+    for link in &previous {
+        if !current.contains(link) && !objects.contains(link) {
+            let path = base_path.join(link);
+            if std::fs::symlink_metadata(&path).is_ok() {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+
+    let path = views_path(base_path);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, created.join("\n"))?;
+    std::fs::rename(&tmp, &path)?;
+
+    info!(
+        logger,
+        "views: {} links, {} removed",
+        created.len(),
+        removed
+    );
+    Ok(created.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("v1.10", "v1.9"), Ordering::Greater);
+        assert_eq!(natural_cmp("v1.09", "v1.9"), Ordering::Equal);
+        assert_eq!(natural_cmp("a", "ab"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_materialize() {
+        let dir =
+            std::env::temp_dir().join(format!("mirror-clone-view-test-{}", std::process::id()));
+        for version in ["1.9", "1.10"] {
+            let path = dir.join(format!("tool/v{}/tool.tar.gz", version));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, version).unwrap();
+        }
+        let views = vec![
+            "^tool/v([0-9.]+)/tool\\.tar\\.gz$=tool/latest.tar.gz"
+                .parse()
+                .unwrap(),
+            "^tool/v([0-9.]+)/(.*)$=by-version/$1/$2".parse().unwrap(),
+        ];
+        let logger = crate::utils::create_logger();
+        assert_eq!(materialize(&logger, &dir, &views).unwrap(), 3);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("tool/latest.tar.gz")).unwrap(),
+            "1.10"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("by-version/1.9/tool.tar.gz")).unwrap(),
+            "1.9"
+        );
+
+        // links are not treated as objects, and stale links are removed
+        std::fs::remove_dir_all(dir.join("tool/v1.10")).unwrap();
+        assert_eq!(materialize(&logger, &dir, &views).unwrap(), 2);
+        assert!(std::fs::symlink_metadata(dir.join("by-version/1.10/tool.tar.gz")).is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("tool/latest.tar.gz")).unwrap(),
+            "1.9"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
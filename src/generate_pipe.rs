@@ -0,0 +1,79 @@
+//! GeneratePipe serves objects generated by the source itself.
+//!
+//! Some sources yield objects which don't exist upstream, such as status
+//! files or rewritten indexes. These sources include generated keys in their
+//! snapshot as usual, and implement `GenerateStorage` to provide the content.
+//!
+//! A `GeneratePipe` wraps a source which yields `ByteStream` (usually a
+//! `ByteStreamPipe`). Generated objects are buffered to local file system,
+//! while all other objects are fetched from the underlying source.
+
+use async_trait::async_trait;
+
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::Result;
+use crate::stream_pipe::ByteStream;
+use crate::traits::{GenerateStorage, Key, SizeStorage, SnapshotStorage, SourceStorage};
+
+pub struct GeneratePipe<Source> {
+    pub source: Source,
+    pub buffer_path: String,
+}
+
+impl<Source> GeneratePipe<Source> {
+    pub fn new(source: Source, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+        }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for GeneratePipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        format!("GeneratePipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SizeStorage<Snapshot> for GeneratePipe<Source>
+where
+    Snapshot: Key,
+    Source: SizeStorage<Snapshot> + GenerateStorage<Snapshot>,
+{
+    async fn object_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        match self.source.generate(snapshot, mission).await? {
+            Some(content) => Ok(Some(content.len() as u64)),
+            None => self.source.object_size(snapshot, mission).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for GeneratePipe<Source>
+where
+    Snapshot: Key,
+    Source: SourceStorage<Snapshot, ByteStream> + GenerateStorage<Snapshot>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
+        match self.source.generate(snapshot, mission).await? {
+            Some(content) => {
+                ByteStream::from_bytes(&self.buffer_path, snapshot.key(), &content).await
+            }
+            None => self.source.get_object(snapshot, mission).await,
+        }
+    }
+}
@@ -48,9 +48,7 @@ impl SnapshotStorage<SnapshotMeta> for GhcupPackages {
 
         progress.set_message("downloading yaml config");
         let latest_yaml_blob_url = get_raw_blob_url(&client, repo_config, latest_yaml_obj).await?;
-        let yaml_data = client
-            .get(latest_yaml_blob_url.url)
-            .send()
+        let yaml_data = crate::http::send(client.get(latest_yaml_blob_url.url))
             .await?
             .bytes()
             .await?;
@@ -107,7 +107,10 @@ pub async fn list_files(
         config.repo, commit
     );
 
-    let tree_meta: TreeMeta = client.get(tree_url).send().await?.json().await?;
+    let tree_meta: TreeMeta = crate::http::send(client.get(tree_url))
+        .await?
+        .json()
+        .await?;
     Ok(tree_meta
         .tree
         .into_iter()
@@ -137,15 +140,13 @@ pub async fn get_raw_blob_url(
     config: &GhcupRepoConfig,
     object: ObjectInfo,
 ) -> Result<ObjectInfoWithUrl> {
-    let content: ContentMeta = client
-        .get(format!(
-            "https://api.github.com/repos/{}/contents/{}",
-            config.repo, object.path
-        ))
-        .send()
-        .await?
-        .json()
-        .await?;
+    let content: ContentMeta = crate::http::send(client.get(format!(
+        "https://api.github.com/repos/{}/contents/{}",
+        config.repo, object.path
+    )))
+    .await?
+    .json()
+    .await?;
     Ok(ObjectInfoWithUrl {
         name: object.name,
         path: object.path,
@@ -78,6 +78,7 @@ impl SnapshotStorage<SnapshotMeta> for GhcupYaml {
                 flags: SnapshotMetaFlag {
                     force: true,
                     force_last: true,
+                    ..Default::default()
                 },
                 ..Default::default()
             })
@@ -2,28 +2,60 @@
 //!
 //! GitHubRelease source will fetch the GitHub API when taking snapshots.
 //! Then, it will construct a list of downloadable URLs.
+//!
+//! Instead of a single repo, an organization can be given. Repositories of
+//! the organization are enumerated when taking snapshots (optionally filtered
+//! by name), so that new projects are mirrored automatically. In this case,
+//! objects are stored under the name of their repo.
+//!
+//! Requests to GitHub API are sent with `--github-token` (or `GITHUB_TOKEN`)
+//! if given, which has a much higher rate limit than anonymous requests.
+//! Requests are paced by `http::send` when the rate limit is exhausted.
+//!
+//! With `--source-archives`, the source tarball and zipball generated by
+//! GitHub are mirrored under `archive/refs/tags/`. With `--release-metadata`,
+//! a `release.json` (tag, name, body, published time) is generated for each
+//! release under `releases/tag/<tag>/`.
+//!
+//! With `--verify-checksums`, checksum files attached to a release (e.g.
+//! `checksums.txt` or `SHA256SUMS`) are parsed, and SHA-256 of sibling assets
+//! are kept in the snapshot. Assets which mismatch are not mirrored, and are
+//! counted as failed at the end of the run.
+//!
+//! Many repos can also be listed in a YAML config (`--config`), each with
+//! its own rules, and objects are stored under `owner/repo`:
+//!
+//! ```yaml
+//! repos:
+//!   - repo: owner/repo
+//!     assets: ["*.tar.gz", "*-linux-*"] # glob patterns of asset names, all by default
+//!     version_to_retain: 3 # `--version-to-retain` by default
+//!     prerelease: false # whether to mirror prereleases, true by default
+//! ```
 
-use crate::common::{Mission, SnapshotConfig, TransferURL};
-use crate::error::Result;
+use crate::auth::Secret;
+use crate::common::{Generated, Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use slog::info;
+use futures_util::{stream, StreamExt};
+use indicatif::ProgressBar;
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::collections::HashMap;
 use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(Deserialize, Debug)]
 pub struct GitHubReleaseAsset {
-    url: String,
-    id: u64,
     name: String,
-    content_type: String,
     size: u64,
-    created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     browser_download_url: String,
 }
@@ -31,44 +63,213 @@ pub struct GitHubReleaseAsset {
 #[derive(Deserialize, Debug)]
 pub struct GitHubReleaseItem {
     tag_name: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    published_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    prerelease: bool,
     assets: Vec<GitHubReleaseAsset>,
 }
 
+/// Content of generated `release.json`.
+#[derive(Serialize)]
+struct ReleaseMetadata<'a> {
+    tag_name: &'a str,
+    name: Option<&'a str>,
+    body: Option<&'a str>,
+    published_at: Option<DateTime<Utc>>,
+    prerelease: bool,
+}
+
+/// Names of assets listing SHA-256 of other assets, e.g. `checksums.txt`,
+/// `foo_1.0_checksums.txt` or `SHA256SUMS`.
+static CHECKSUM_ASSET: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)^(.*[-_.])?(checksums|sha256sums?)(\.txt)?$").unwrap());
+
+/// SHA-256 of files in a checksum file, in the format of `sha256sum`, keyed
+/// by file name.
+fn parse_checksums(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (checksum, name) = line.trim().split_once(char::is_whitespace)?;
+            if checksum.len() != 64 || !checksum.chars().all(|x| x.is_ascii_hexdigit()) {
+                return None;
+            }
+            let name = name.trim_start().trim_start_matches('*');
+            let name = name.rsplit('/').next().unwrap_or(name);
+            Some((name.to_string(), checksum.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Objects in snapshot, with their content if generated.
+type Objects = Vec<(SnapshotMeta, Option<Vec<u8>>)>;
+
+#[derive(Deserialize, Debug)]
+pub struct GitHubRepo {
+    name: String,
+    #[serde(default)]
+    archived: bool,
+}
+
+/// Rules of a repo in config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RepoRule {
+    pub repo: String,
+    #[serde(default)]
+    pub assets: Vec<String>,
+    pub version_to_retain: Option<usize>,
+    #[serde(default = "default_prerelease")]
+    pub prerelease: bool,
+}
+
+fn default_prerelease() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+struct RepoConfig {
+    repos: Vec<RepoRule>,
+}
+
+fn parse_config(content: &str) -> Result<Vec<RepoRule>> {
+    let config: RepoConfig = serde_yaml::from_str(content)
+        .map_err(|err| Error::ConfigureError(format!("invalid repo config: {}", err)))?;
+    Ok(config.repos)
+}
+
+/// Filter of releases and assets in a repo.
+struct ReleaseFilter {
+    assets: Option<RegexSet>,
+    version_to_retain: usize,
+    prerelease: bool,
+}
+
+impl ReleaseFilter {
+    fn new(rule: &RepoRule, version_to_retain: usize) -> Result<Self> {
+        let assets = if rule.assets.is_empty() {
+            None
+        } else {
+            let patterns = rule.assets.iter().map(|x| crate::utils::glob_regex(x));
+            Some(RegexSet::new(patterns).map_err(|err| Error::ConfigureError(err.to_string()))?)
+        };
+        Ok(Self {
+            assets,
+            version_to_retain: rule.version_to_retain.unwrap_or(version_to_retain),
+            prerelease: rule.prerelease,
+        })
+    }
+
+    /// Recent releases selected, with only selected assets.
+    fn select(&self, releases: Vec<GitHubReleaseItem>) -> Vec<GitHubReleaseItem> {
+        releases
+            .into_iter()
+            .filter(|release| self.prerelease || !release.prerelease)
+            .take(self.version_to_retain)
+            .map(|mut release| {
+                if let Some(assets) = &self.assets {
+                    release.assets.retain(|asset| assets.is_match(&asset.name));
+                }
+                release
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, StructOpt)]
 pub struct GitHubRelease {
-    #[structopt(long, help = "GitHub Repo")]
-    pub repo: String,
+    #[structopt(long, help = "GitHub Repo", required_unless_one = &["org", "config"])]
+    pub repo: Option<String>,
+    #[structopt(
+        long,
+        help = "GitHub organization, whose repos are all mirrored",
+        conflicts_with = "repo"
+    )]
+    pub org: Option<String>,
+    #[structopt(
+        long,
+        help = "YAML config listing repos to mirror with their rules",
+        conflicts_with_all = &["repo", "org"]
+    )]
+    pub config: Option<String>,
+    #[structopt(long, help = "Only mirror repos of organization matching this regex")]
+    pub repo_include: Option<Regex>,
+    #[structopt(long, help = "Don't mirror repos of organization matching this regex")]
+    pub repo_exclude: Option<Regex>,
+    #[structopt(long, help = "Mirror archived repos of organization")]
+    pub include_archived: bool,
     #[structopt(long, help = "Version numbers to retain")]
     pub version_to_retain: usize,
+    #[structopt(
+        long,
+        help = "Token of GitHub API",
+        env = "GITHUB_TOKEN",
+        hide_env_values = true
+    )]
+    pub github_token: Option<Secret>,
+    #[structopt(long, help = "Mirror source tarballs and zipballs of releases")]
+    pub source_archives: bool,
+    #[structopt(long, help = "Generate release.json of each release")]
+    pub release_metadata: bool,
+    #[structopt(
+        long,
+        help = "Verify assets against checksum files (e.g. checksums.txt) of each release"
+    )]
+    pub verify_checksums: bool,
+    #[structopt(skip)]
+    generated: Generated,
 }
 
 impl GitHubRelease {
     pub fn new(repo: String, version_to_retain: usize) -> Self {
         Self {
-            repo,
+            repo: Some(repo),
+            org: None,
+            config: None,
+            repo_include: None,
+            repo_exclude: None,
+            include_archived: false,
             version_to_retain,
+            github_token: std::env::var("GITHUB_TOKEN").ok().map(Secret),
+            source_archives: false,
+            release_metadata: false,
+            verify_checksums: false,
+            generated: Generated::default(),
         }
     }
-}
 
-#[async_trait]
-impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
-    async fn snapshot(
-        &mut self,
-        mission: Mission,
-        _config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotMeta>> {
-        let logger = mission.logger;
-        let progress = mission.progress;
-        let client = mission.client;
+    /// Request to GitHub API, with token if given.
+    fn api(&self, client: &reqwest::Client, url: &str) -> reqwest::RequestBuilder {
+        let request = client
+            .get(url)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json");
+        match &self.github_token {
+            Some(token) => request.bearer_auth(&token.0),
+            None => request,
+        }
+    }
 
-        info!(logger, "fetching GitHub json...");
-        let data = client
-            .get(&format!(
-                "https://api.github.com/repos/{}/releases",
-                self.repo
+    /// Enumerate repos of organization, filtered by name.
+    async fn org_repos(
+        &self,
+        logger: &Logger,
+        client: &reqwest::Client,
+        org: &str,
+    ) -> Result<Vec<String>> {
+        info!(logger, "fetching repos of {}...", org);
+        let mut repos = vec![];
+        for page in 1.. {
+            let data = crate::http::send(self.api(
+                client,
+                &format!(
+                    "https://api.github.com/orgs/{}/repos?per_page=100&page={}",
+                    org, page
+                ),
             ))
-            .send()
             .timeout(Duration::from_secs(60))
             .await
             .into_result()?
@@ -76,29 +277,232 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
             .timeout(Duration::from_secs(60))
             .await
             .into_result()?;
+            let page = serde_json::from_str::<Vec<GitHubRepo>>(&data)?;
+            if page.is_empty() {
+                break;
+            }
+            repos.extend(
+                page.into_iter()
+                    .filter(|repo| self.include_archived || !repo.archived)
+                    .map(|repo| repo.name),
+            );
+        }
+        if let Some(include) = &self.repo_include {
+            repos.retain(|name| include.is_match(name));
+        }
+        if let Some(exclude) = &self.repo_exclude {
+            repos.retain(|name| !exclude.is_match(name));
+        }
+        info!(logger, "{} repos to mirror", repos.len());
+        Ok(repos)
+    }
+
+    /// Assets of recent releases in `repo` selected by `filter`, with keys
+    /// prefixed by `prefix`.
+    async fn releases(
+        &self,
+        logger: &Logger,
+        client: &reqwest::Client,
+        progress: &ProgressBar,
+        repo: &str,
+        prefix: &str,
+        filter: &ReleaseFilter,
+    ) -> Result<Objects> {
+        info!(logger, "fetching GitHub json of {}...", repo);
+        let data = crate::http::send(self.api(
+            client,
+            &format!("https://api.github.com/repos/{}/releases", repo),
+        ))
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?
+        .text()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?;
 
-        info!(logger, "parsing...");
         let releases = serde_json::from_str::<Vec<GitHubReleaseItem>>(&data)?;
-        let replace_string = format!("https://github.com/{}/", self.repo);
-        let snapshot: Vec<SnapshotMeta> = releases
-            .into_iter()
-            .map(|release| {
-                progress.set_message(&release.tag_name);
-                release.assets
-            })
-            .take(self.version_to_retain)
-            .flatten()
-            .map(|asset| SnapshotMeta {
-                key: if asset.browser_download_url.starts_with(&replace_string) {
-                    asset.browser_download_url[replace_string.len()..].to_string()
-                } else {
-                    panic!("Unmatched base URL: {:?}", asset)
-                },
+        // checksum files may not be selected themselves
+        let mut checksum_files: HashMap<String, Vec<String>> = HashMap::new();
+        if self.verify_checksums {
+            for release in &releases {
+                checksum_files.insert(
+                    release.tag_name.clone(),
+                    release
+                        .assets
+                        .iter()
+                        .filter(|asset| CHECKSUM_ASSET.is_match(&asset.name))
+                        .map(|asset| asset.browser_download_url.clone())
+                        .collect(),
+                );
+            }
+        }
+        let mut objects = vec![];
+        for release in filter.select(releases) {
+            progress.set_message(&release.tag_name);
+            let mut checksums = HashMap::new();
+            for url in checksum_files
+                .get(&release.tag_name)
+                .map_or(&[][..], |x| x.as_slice())
+            {
+                match self.checksum_file(client, url).await {
+                    Ok(content) => checksums.extend(parse_checksums(&content)),
+                    Err(err) => warn!(logger, "failed to fetch {}: {:?}", url, err),
+                }
+            }
+            objects.extend(self.release_objects(repo, prefix, release, &checksums)?);
+        }
+        Ok(objects)
+    }
+
+    /// Content of checksum file at `url`.
+    async fn checksum_file(&self, client: &reqwest::Client, url: &str) -> Result<String> {
+        let response = crate::http::send(client.get(url))
+            .timeout(Duration::from_secs(60))
+            .await
+            .into_result()?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        response
+            .text()
+            .timeout(Duration::from_secs(60))
+            .await
+            .into_result()
+    }
+
+    /// Objects of `release` in `repo`, with keys prefixed by `prefix`. Assets
+    /// listed in `checksums` are verified with their SHA-256.
+    fn release_objects(
+        &self,
+        repo: &str,
+        prefix: &str,
+        release: GitHubReleaseItem,
+        checksums: &HashMap<String, String>,
+    ) -> Result<Objects> {
+        let replace_string = format!("https://github.com/{}/", repo);
+        let published_at = release.published_at.map(|x| x.timestamp() as u64);
+        let mut objects = vec![];
+        if self.source_archives {
+            for extension in ["tar.gz", "zip"] {
+                let key = format!(
+                    "{}archive/refs/tags/{}.{}",
+                    prefix, release.tag_name, extension
+                );
+                objects.push((SnapshotMeta::new(key), None));
+            }
+        }
+        if self.release_metadata {
+            let metadata = ReleaseMetadata {
+                tag_name: &release.tag_name,
+                name: release.name.as_deref(),
+                body: release.body.as_deref(),
+                published_at: release.published_at,
+                prerelease: release.prerelease,
+            };
+            let content = serde_json::to_vec_pretty(&metadata)?;
+            let snapshot = SnapshotMeta {
+                key: format!("{}releases/tag/{}/release.json", prefix, release.tag_name),
+                size: Some(content.len() as u64),
+                last_modified: published_at,
+                ..Default::default()
+            };
+            objects.push((snapshot, Some(content)));
+        }
+        for asset in release.assets {
+            let path = asset
+                .browser_download_url
+                .strip_prefix(&replace_string)
+                .ok_or_else(|| Error::ProcessError(format!("unmatched base URL: {:?}", asset)))?;
+            let checksum = checksums.get(&asset.name).cloned();
+            let snapshot = SnapshotMeta {
+                key: format!("{}{}", prefix, path),
                 size: Some(asset.size),
                 last_modified: Some(asset.updated_at.timestamp() as u64),
+                checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+                checksum,
                 ..Default::default()
-            })
-            .collect();
+            };
+            objects.push((snapshot, None));
+        }
+        Ok(objects)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let default_rule = RepoRule {
+            repo: String::new(),
+            assets: vec![],
+            version_to_retain: None,
+            prerelease: true,
+        };
+        let default_filter = ReleaseFilter::new(&default_rule, self.version_to_retain)?;
+
+        let objects: Objects = match (&self.repo, &self.org, &self.config) {
+            (Some(repo), _, _) => {
+                self.releases(&logger, &client, &progress, repo, "", &default_filter)
+                    .await?
+            }
+            (None, None, Some(config)) => {
+                let rules = parse_config(&std::fs::read_to_string(config)?)?;
+                let mut snapshot = vec![];
+                for rule in rules {
+                    let filter = ReleaseFilter::new(&rule, self.version_to_retain)?;
+                    let prefix = format!("{}/", rule.repo);
+                    snapshot.extend(
+                        self.releases(&logger, &client, &progress, &rule.repo, &prefix, &filter)
+                            .await?,
+                    );
+                }
+                snapshot
+            }
+            (None, Some(org), _) => {
+                let repos = self.org_repos(&logger, &client, org).await?;
+                let this = &*self;
+                let (logger, client, progress) = (&logger, &client, &progress);
+                let default_filter = &default_filter;
+                stream::iter(repos)
+                    .map(|name| async move {
+                        let repo = format!("{}/{}", org, name);
+                        let prefix = format!("{}/", name);
+                        match this
+                            .releases(logger, client, progress, &repo, &prefix, default_filter)
+                            .await
+                        {
+                            Ok(snapshot) => snapshot,
+                            Err(err) => {
+                                warn!(logger, "failed to fetch releases of {}: {:?}", repo, err);
+                                vec![]
+                            }
+                        }
+                    })
+                    .buffer_unordered(config.concurrent_resolve)
+                    .collect::<Vec<_>>()
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            }
+            (None, None, None) => unreachable!("either repo, org or config is required"),
+        };
+
+        let mut snapshot = vec![];
+        for (object, content) in objects {
+            if let Some(content) = content {
+                self.generated.0.insert(object.key.clone(), content);
+            }
+            snapshot.push(object);
+        }
 
         progress.finish_with_message("done");
 
@@ -110,12 +514,157 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
     }
 }
 
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for GitHubRelease {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.generated.0.get(&snapshot.key).cloned())
+    }
+}
+
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for GitHubRelease {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        let owner = match (&self.repo, &self.org) {
+            (Some(repo), _) => repo,
+            (None, Some(org)) => org,
+            // keys are namespaced by `owner/repo` already
+            (None, None) => return Ok(TransferURL(format!("https://github.com/{}", snapshot.key))),
+        };
         Ok(TransferURL(format!(
             "https://github.com/{}/{}",
-            self.repo, snapshot.key
+            owner, snapshot.key
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use structopt::StructOpt;
+
+    use super::{
+        parse_checksums, parse_config, GitHubRelease, GitHubReleaseItem, ReleaseFilter,
+        CHECKSUM_ASSET,
+    };
+
+    #[test]
+    fn test_release_filter() {
+        let rules = parse_config(
+            r#"
+repos:
+  - repo: owner/a
+  - repo: owner/b
+    assets: ["*.tar.gz"]
+    version_to_retain: 1
+    prerelease: false
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].prerelease);
+        assert!(parse_config("repos: 1").is_err());
+
+        let asset = |name: &str| {
+            format!(
+                r#"{{"url": "", "id": 0, "name": "{}", "content_type": "", "size": 0,
+                    "created_at": "2024-01-01T00:00:00Z", "updated_at": "2024-01-01T00:00:00Z",
+                    "browser_download_url": ""}}"#,
+                name
+            )
+        };
+        let releases = format!(
+            r#"[{{"tag_name": "v2-rc", "prerelease": true, "assets": [{}]}},
+                {{"tag_name": "v1", "assets": [{}, {}]}},
+                {{"tag_name": "v0", "assets": [{}]}}]"#,
+            asset("a-2.tar.gz"),
+            asset("a-1.tar.gz"),
+            asset("a-1.zip"),
+            asset("a-0.tar.gz")
+        );
+        let select = |filter: ReleaseFilter| {
+            let releases: Vec<GitHubReleaseItem> = serde_json::from_str(&releases).unwrap();
+            filter
+                .select(releases)
+                .into_iter()
+                .flat_map(|x| x.assets)
+                .map(|x| x.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            select(ReleaseFilter::new(&rules[0], 2).unwrap()),
+            vec!["a-2.tar.gz", "a-1.tar.gz", "a-1.zip"]
+        );
+        assert_eq!(
+            select(ReleaseFilter::new(&rules[1], 2).unwrap()),
+            vec!["a-1.tar.gz"]
+        );
+    }
+
+    #[test]
+    fn test_release_objects() {
+        let source = GitHubRelease::from_iter([
+            "github-release",
+            "--repo",
+            "owner/repo",
+            "--version-to-retain",
+            "1",
+            "--source-archives",
+            "--release-metadata",
+        ]);
+        let release: GitHubReleaseItem = serde_json::from_str(
+            r#"{"tag_name": "v1", "name": "v1", "body": "notes",
+                "published_at": "2024-01-01T00:00:00Z", "assets": [{
+                    "url": "", "id": 0, "name": "a.zip", "content_type": "", "size": 1,
+                    "created_at": "2024-01-01T00:00:00Z", "updated_at": "2024-01-01T00:00:00Z",
+                    "browser_download_url": "https://github.com/owner/repo/releases/download/v1/a.zip"
+                }]}"#,
+        )
+        .unwrap();
+        let objects = source
+            .release_objects("owner/repo", "repo/", release, &HashMap::new())
+            .unwrap();
+        let keys: Vec<_> = objects.iter().map(|(x, _)| x.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "repo/archive/refs/tags/v1.tar.gz",
+                "repo/archive/refs/tags/v1.zip",
+                "repo/releases/tag/v1/release.json",
+                "repo/releases/download/v1/a.zip",
+            ]
+        );
+        let metadata: serde_json::Value =
+            serde_json::from_slice(objects[2].1.as_ref().unwrap()).unwrap();
+        assert_eq!(metadata["body"], "notes");
+        assert_eq!(objects[2].0.last_modified, Some(1704067200));
+    }
+
+    #[test]
+    fn test_parse_checksums() {
+        for name in [
+            "checksums.txt",
+            "foo_1.0_checksums.txt",
+            "SHA256SUMS",
+            "sha256sum.txt",
+        ] {
+            assert!(CHECKSUM_ASSET.is_match(name), "{}", name);
+        }
+        assert!(!CHECKSUM_ASSET.is_match("checksums.txt.sig"));
+        assert!(!CHECKSUM_ASSET.is_match("mychecksums.go"));
+
+        let a = "a".repeat(64);
+        let b = "B".repeat(64);
+        let checksums = parse_checksums(&format!(
+            "{}  foo-linux.tar.gz\n{} *dist/foo.exe\nd41d8cd98f00b204e9800998ecf8427e  foo.zip\n\n",
+            a, b
+        ));
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(checksums["foo-linux.tar.gz"], a);
+        assert_eq!(checksums["foo.exe"], "b".repeat(64));
+    }
+}
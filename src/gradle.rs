@@ -1,11 +1,27 @@
-use crate::common::{Mission, SnapshotConfig, TransferURL};
-use crate::error::Result;
+//! Gradle source
+//!
+//! Gradle source lists releases with services.gradle.org API, and mirrors
+//! distributions of the given `--variants` (`bin`, `all` or `src`), skipping
+//! release candidates and releases before `--versions-since`. The `.sha256`
+//! file of each distribution is fetched to verify it, and mirrored as well.
+//!
+//! With `--rewrite-base`, version metadata (`versions/all` and
+//! `versions/current`) is also mirrored, with URLs of mirrored files rewritten
+//! to the given base, together with checksums of wrapper jars. This way,
+//! `distributionUrl` in `gradle-wrapper.properties` can be pointed at the
+//! mirror, and tools reading version metadata resolve to the mirror as well.
+
+use crate::common::{Generated, Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 use async_trait::async_trait;
-use serde_json::Value;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::StatusCode;
+use serde::Deserialize;
 use slog::info;
+use std::collections::HashSet;
 use std::time::Duration;
 use structopt::StructOpt;
 
@@ -15,6 +31,157 @@ pub struct Gradle {
     pub api_base: String,
     #[structopt(long, default_value = "https://services.gradle.org/distributions/")]
     pub distribution_base: String,
+    /// Variants of distributions to mirror.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "bin",
+        possible_values = &["bin", "all", "src"]
+    )]
+    pub variants: Vec<String>,
+    /// Only mirror releases since this version, e.g. `7.0`.
+    #[structopt(long)]
+    pub versions_since: Option<String>,
+    /// Mirror version metadata with URLs rewritten to distributions at this base, e.g.
+    /// `https://mirrors.example.com/gradle/`.
+    #[structopt(long)]
+    pub rewrite_base: Option<String>,
+    #[structopt(skip)]
+    generated: Generated,
+}
+
+/// Version metadata mirrored with `--rewrite-base`, as siblings of `--api-base`.
+const VERSION_ENDPOINTS: &[&str] = &["all", "current"];
+
+/// Fields of version metadata which are URLs of distribution files.
+const URL_FIELDS: &[&str] = &["downloadUrl", "checksumUrl", "wrapperChecksumUrl"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GradleRelease {
+    version: String,
+    #[serde(default)]
+    rc_for: String,
+    download_url: Option<String>,
+    wrapper_checksum_url: Option<String>,
+}
+
+/// Numeric components of a gradle version, e.g. `[7, 0]` for `7.0-milestone-1`.
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('-')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map_while(|x| x.parse().ok())
+        .collect()
+}
+
+/// File name of distribution `variant` of a release, given the `bin` one.
+fn variant_name(bin: &str, variant: &str) -> Option<String> {
+    let stem = bin.strip_suffix("-bin.zip")?;
+    Some(format!("{}-{}.zip", stem, variant))
+}
+
+impl Gradle {
+    /// Releases to mirror.
+    fn selected<'a>(&self, releases: &'a [GradleRelease]) -> Vec<&'a GradleRelease> {
+        let since = self.versions_since.as_deref().map(version_key);
+        releases
+            .iter()
+            .filter(|release| release.rc_for.is_empty())
+            .filter(|release| {
+                since
+                    .as_ref()
+                    .is_none_or(|since| version_key(&release.version) >= *since)
+            })
+            .collect()
+    }
+
+    /// Distributions of releases to mirror, relative to distribution base.
+    fn distributions(&self, releases: &[GradleRelease]) -> Vec<String> {
+        self.selected(releases)
+            .into_iter()
+            .filter_map(|release| release.download_url.as_deref())
+            .filter_map(|url| url.strip_prefix(&self.distribution_base))
+            .flat_map(|bin| {
+                self.variants
+                    .iter()
+                    .filter_map(|variant| variant_name(bin, variant))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Checksums of wrapper jars of releases to mirror, relative to
+    /// distribution base.
+    fn wrapper_checksums(&self, releases: &[GradleRelease]) -> Vec<String> {
+        self.selected(releases)
+            .into_iter()
+            .filter_map(|release| release.wrapper_checksum_url.as_deref())
+            .filter_map(|url| url.strip_prefix(&self.distribution_base))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Version metadata `data` with URLs of `mirrored` files rewritten to
+    /// `base`. Other URLs are kept, as they're not available on the mirror.
+    fn rewrite_versions(
+        &self,
+        data: &str,
+        mirrored: &HashSet<&str>,
+        base: &str,
+    ) -> Result<Vec<u8>> {
+        let base = format!("{}/", base.trim_end_matches('/'));
+        let mut versions: serde_json::Value = serde_json::from_str(data)?;
+        let releases = match &mut versions {
+            serde_json::Value::Array(releases) => releases.iter_mut().collect(),
+            release => vec![release],
+        };
+        for release in releases {
+            for field in URL_FIELDS {
+                let url = match release.get_mut(*field) {
+                    Some(serde_json::Value::String(url)) => url,
+                    _ => continue,
+                };
+                if let Some(key) = url.strip_prefix(&self.distribution_base) {
+                    if mirrored.contains(key) {
+                        *url = format!("{}{}", base, key);
+                    }
+                }
+            }
+        }
+        Ok(serde_json::to_vec_pretty(&versions)?)
+    }
+
+    /// Snapshot of distribution `name` with its checksum, and its `.sha256`
+    /// file. Checksum is absent if there's no `.sha256` file upstream.
+    async fn distribution(
+        &self,
+        client: &reqwest::Client,
+        name: String,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let checksum_name = format!("{}.sha256", name);
+        let response =
+            crate::http::send(client.get(format!("{}{}", self.distribution_base, checksum_name)))
+                .await?;
+        let checksum = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => None,
+            status if status.is_success() => Some(response.text().await?.trim().to_string()),
+            status => return Err(Error::HTTPError(status)),
+        };
+        let mut snapshot = vec![];
+        if checksum.is_some() {
+            snapshot.push(SnapshotMeta::new(checksum_name));
+        }
+        snapshot.push(SnapshotMeta {
+            key: name,
+            checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+            checksum,
+            ..Default::default()
+        });
+        Ok(snapshot)
+    }
 }
 
 #[async_trait]
@@ -22,58 +189,64 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
         info!(logger, "fetching API json...");
-        let data = client
-            .get(&self.api_base)
-            .send()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?
-            .text()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?;
+        let data = fetch(&client, &self.api_base).await?;
 
         info!(logger, "parsing...");
-        let json: Value = serde_json::from_str(&data).unwrap();
-        let packages = json.as_array().unwrap();
-        let snapshot: Vec<SnapshotMeta> = packages
-            .iter()
-            .filter_map(|package| package.as_object())
-            .filter_map(|package| {
-                progress.set_message(
-                    package
-                        .get("version")
-                        .and_then(|version| version.as_str())
-                        .unwrap_or(""),
-                );
-                if let Some(rc_for) = package.get("rcFor") {
-                    if let Some(rc_for) = rc_for.as_str() {
-                        if !rc_for.is_empty() {
-                            return None;
-                        }
-                    }
-                }
-                package.get("downloadUrl")
-            })
-            .filter_map(|url| url.as_str())
-            .filter(|url| url.starts_with(&self.distribution_base))
-            .map(|url| url.to_string())
-            .map(|url| {
-                if url.starts_with(&self.distribution_base) {
-                    url[self.distribution_base.len()..].to_string()
-                } else {
-                    panic!("package doesn't lay at its base {}", url)
+        let releases: Vec<GradleRelease> = serde_json::from_str(&data)?;
+        let distributions = self.distributions(&releases);
+
+        info!(logger, "fetching checksums...");
+        progress.set_length(distributions.len() as u64);
+        let this = &*self;
+        let snapshot: Vec<Vec<SnapshotMeta>> = stream::iter(distributions)
+            .map(|name| {
+                let client = client.clone();
+                let progress = progress.clone();
+                async move {
+                    progress.set_message(&name);
+                    let snapshot = this.distribution(&client, name).await;
+                    progress.inc(1);
+                    snapshot
                 }
             })
-            .map(SnapshotMeta::new)
-            .collect();
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        let mut snapshot: Vec<SnapshotMeta> = snapshot.into_iter().flatten().collect();
+
+        if let Some(base) = &self.rewrite_base {
+            info!(logger, "rewriting version metadata...");
+            snapshot.extend(
+                self.wrapper_checksums(&releases)
+                    .into_iter()
+                    .map(SnapshotMeta::new),
+            );
+            let mirrored: HashSet<&str> = snapshot.iter().map(|x| x.key.as_str()).collect();
+            let mut generated = Generated::default();
+            for endpoint in VERSION_ENDPOINTS {
+                let key = format!("versions/{}", endpoint);
+                let data = match endpoint {
+                    &"all" => data.clone(),
+                    _ => {
+                        let parent = self.api_base.rsplit_once('/').map_or("", |x| x.0);
+                        fetch(&client, &format!("{}/{}", parent, endpoint)).await?
+                    }
+                };
+                generated
+                    .0
+                    .insert(key, self.rewrite_versions(&data, &mirrored, base)?);
+            }
+            snapshot.extend(generated.0.keys().cloned().map(SnapshotMeta::force));
+            self.generated = generated;
+        }
 
         progress.finish_with_message("done");
 
@@ -85,6 +258,29 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
     }
 }
 
+/// Body of `url`.
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<String> {
+    crate::http::send(client.get(url))
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?
+        .text()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()
+}
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Gradle {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.generated.0.get(&snapshot.key).cloned())
+    }
+}
+
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Gradle {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
@@ -94,3 +290,105 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Gradle {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use structopt::StructOpt;
+
+    use super::{version_key, Gradle, GradleRelease};
+
+    #[test]
+    fn test_distributions() {
+        let releases = || -> Vec<GradleRelease> {
+            serde_json::from_str(
+                r#"[
+                {"version": "8.5", "rcFor": "", "downloadUrl": "https://services.gradle.org/distributions/gradle-8.5-bin.zip"},
+                {"version": "8.5-rc-1", "rcFor": "8.5", "downloadUrl": "https://services.gradle.org/distributions/gradle-8.5-rc-1-bin.zip"},
+                {"version": "8.6-20231201-nightly", "downloadUrl": "https://services.gradle.org/distributions-snapshots/gradle-8.6-bin.zip"},
+                {"version": "7.0-milestone-1", "rcFor": "", "downloadUrl": "https://services.gradle.org/distributions/gradle-7.0-milestone-1-bin.zip"},
+                {"version": "0.7", "rcFor": "", "downloadUrl": "https://services.gradle.org/distributions/gradle-0.7-bin.zip"}
+            ]"#,
+            )
+            .unwrap()
+        };
+        let gradle = Gradle::from_iter(["gradle"]);
+        assert_eq!(
+            gradle.distributions(&releases()),
+            vec![
+                "gradle-8.5-bin.zip",
+                "gradle-7.0-milestone-1-bin.zip",
+                "gradle-0.7-bin.zip"
+            ]
+        );
+        let gradle =
+            Gradle::from_iter(["gradle", "--variants", "bin,all", "--versions-since", "7.0"]);
+        assert_eq!(
+            gradle.distributions(&releases()),
+            vec![
+                "gradle-8.5-bin.zip",
+                "gradle-8.5-all.zip",
+                "gradle-7.0-milestone-1-bin.zip",
+                "gradle-7.0-milestone-1-all.zip"
+            ]
+        );
+        assert_eq!(version_key("10.2.1"), vec![10, 2, 1]);
+        assert!(version_key("0.9") < version_key("0.10"));
+    }
+
+    #[test]
+    fn test_rewrite_versions() {
+        let gradle = Gradle::from_iter([
+            "gradle",
+            "--rewrite-base",
+            "https://mirror.example.com/gradle",
+        ]);
+        let releases: Vec<GradleRelease> = serde_json::from_str(
+            r#"[{"version": "8.5", "downloadUrl": "https://services.gradle.org/distributions/gradle-8.5-bin.zip",
+                 "wrapperChecksumUrl": "https://services.gradle.org/distributions/gradle-8.5-wrapper.jar.sha256"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            gradle.wrapper_checksums(&releases),
+            vec!["gradle-8.5-wrapper.jar.sha256"]
+        );
+
+        let mirrored: HashSet<&str> = ["gradle-8.5-bin.zip", "gradle-8.5-bin.zip.sha256"].into();
+        let current = r#"{"version": "8.5",
+            "downloadUrl": "https://services.gradle.org/distributions/gradle-8.5-bin.zip",
+            "checksumUrl": "https://services.gradle.org/distributions/gradle-8.5-bin.zip.sha256",
+            "wrapperChecksumUrl": "https://services.gradle.org/distributions/gradle-8.5-wrapper.jar.sha256"}"#;
+        let rewritten: serde_json::Value = serde_json::from_slice(
+            &gradle
+                .rewrite_versions(current, &mirrored, "https://mirror.example.com/gradle")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            rewritten["downloadUrl"],
+            "https://mirror.example.com/gradle/gradle-8.5-bin.zip"
+        );
+        assert_eq!(
+            rewritten["checksumUrl"],
+            "https://mirror.example.com/gradle/gradle-8.5-bin.zip.sha256"
+        );
+        // not mirrored
+        assert_eq!(
+            rewritten["wrapperChecksumUrl"],
+            "https://services.gradle.org/distributions/gradle-8.5-wrapper.jar.sha256"
+        );
+
+        let all = format!("[{}, {{}}]", current);
+        let rewritten: serde_json::Value = serde_json::from_slice(
+            &gradle
+                .rewrite_versions(&all, &mirrored, "https://mirror.example.com/gradle/")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            rewritten[0]["downloadUrl"],
+            "https://mirror.example.com/gradle/gradle-8.5-bin.zip"
+        );
+    }
+}
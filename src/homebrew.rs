@@ -3,13 +3,32 @@
 //! Homebrew source will use brew.sh API to fetch all available bottles.
 //! It will generate a list of URLs.
 //!
+//! Bottles are hosted as blobs on the GHCR OCI registry, and addressed by
+//! their digests in the API. Registries require a bearer token even for
+//! anonymous pulls, which is requested from the realm in the
+//! `WWW-Authenticate` challenge and cached for each repository (see
+//! `registry`). Bottles are
+//! stored by their file names, which is the layout brew uses for
+//! `HOMEBREW_BOTTLE_DOMAIN` other than GHCR.
+//!
+//! With `--casks`, installers of casks (optionally only those in
+//! `--cask-list`) are mirrored instead. They're stored by their original URLs
+//! (e.g. `https://example.com/app.dmg`), which is the layout brew uses for
+//! `HOMEBREW_ARTIFACT_DOMAIN`.
+//!
+//! With `--mirror-api`, payloads of formulae.brew.sh API (e.g. `formula.json`
+//! and the signed `formula.jws.json`) are mirrored under `api/`, for use with
+//! `HOMEBREW_API_DOMAIN`. Bottle URLs in the unsigned `formula.json` are
+//! rewritten to `--api-bottle-domain` if given.
+//!
 //! Reference: https://github.com/ustclug/ustcmirror-images/blob/master/homebrew-bottles/bottles-json/src/main.rs
 //! MIT License, Copyright (c) 2017 Jian Zeng
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
-use crate::error::{Error, Result};
+use crate::error::Result;
+use crate::registry::Registry;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 
 use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
@@ -26,11 +45,32 @@ pub struct HomebrewConfig {
     pub api_base: String,
     #[structopt(long, default_value = "all")]
     pub arch: String,
+    /// Only mirror bottles of these tags, e.g. `arm64_sonoma,x86_64_linux`.
+    /// Bottles for all platforms (tagged `all`) are always mirrored.
+    #[structopt(long, use_delimiter = true)]
+    pub bottle_tags: Vec<String>,
+    /// Mirror installers of casks instead of bottles.
+    #[structopt(long)]
+    pub casks: bool,
+    #[structopt(long, default_value = "https://formulae.brew.sh/api/cask.json")]
+    pub cask_api_base: String,
+    /// Only mirror these casks.
+    #[structopt(long, use_delimiter = true)]
+    pub cask_list: Vec<String>,
+    /// Mirror API payloads under `api/`.
+    #[structopt(long)]
+    pub mirror_api: bool,
+    /// Rewrite bottle URLs in mirrored `formula.json` to this base.
+    #[structopt(long, requires = "mirror-api")]
+    pub api_bottle_domain: Option<String>,
 }
 
 pub struct Homebrew {
     pub config: HomebrewConfig,
     url_mapping: BTreeMap<String, String>,
+    /// Content of generated API payloads.
+    generated: HashMap<String, Vec<u8>>,
+    registry: Registry,
 }
 
 #[derive(Deserialize)]
@@ -61,6 +101,41 @@ struct Formula {
 #[derive(Deserialize)]
 struct Formulae(Vec<Formula>);
 
+#[derive(Deserialize)]
+struct CaskDownload {
+    url: Option<String>,
+    sha256: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Cask {
+    token: String,
+    #[serde(flatten)]
+    download: CaskDownload,
+    /// Downloads of other macOS versions and architectures.
+    #[serde(default)]
+    variations: HashMap<String, CaskDownload>,
+}
+
+impl Cask {
+    /// Distinct installers of all variations, with their sha256 if checked.
+    fn downloads(self) -> BTreeMap<String, Option<String>> {
+        std::iter::once(self.download)
+            .chain(self.variations.into_values())
+            .filter_map(|download| {
+                let sha256 = download.sha256.filter(|x| x != "no_check");
+                Some((download.url?, sha256))
+            })
+            .collect()
+    }
+}
+
+/// Repository of a registry blob URL, e.g. `homebrew/core/wget`.
+fn blob_repository(url: &str) -> Option<&str> {
+    let path = url.split_once("/v2/")?.1;
+    Some(&path[..path.find("/blobs/")?])
+}
+
 #[derive(Deserialize)]
 struct BottleInfo {
     url: String,
@@ -68,12 +143,73 @@ struct BottleInfo {
     sha256: String,
 }
 
+/// Rewrite bottle URLs in `formula.json` by `urls`.
+fn rewrite_formulae(data: &str, urls: &HashMap<String, String>) -> Result<Vec<u8>> {
+    let mut formulae: serde_json::Value = serde_json::from_str(data)?;
+    for formula in formulae.as_array_mut().into_iter().flatten() {
+        let files = formula
+            .pointer_mut("/bottle/stable/files")
+            .and_then(serde_json::Value::as_object_mut);
+        for file in files.into_iter().flat_map(|files| files.values_mut()) {
+            if let Some(url) = file.get_mut("url") {
+                if let Some(rewritten) = url.as_str().and_then(|x| urls.get(x)) {
+                    *url = rewritten.clone().into();
+                }
+            }
+        }
+    }
+    Ok(serde_json::to_vec(&formulae)?)
+}
+
+impl HomebrewConfig {
+    /// Whether bottles of `platform` should be mirrored.
+    fn selected(&self, platform: &str) -> bool {
+        (self.arch.is_empty() || self.arch == "all" || platform == self.arch)
+            && (self.bottle_tags.is_empty()
+                || platform == "all"
+                || self.bottle_tags.iter().any(|x| x == platform))
+    }
+}
+
 impl Homebrew {
     pub fn new(config: HomebrewConfig) -> Self {
         Self {
             config,
             url_mapping: BTreeMap::new(),
+            generated: HashMap::new(),
+            registry: Registry::default(),
+        }
+    }
+
+    /// API payloads along with `api_base` (e.g. `.../api/formula.json`)
+    /// fetched as `data`.
+    fn api_snapshot(&mut self, api_base: &str, data: &str) -> Result<Vec<SnapshotMeta>> {
+        let (dir, name) = api_base.rsplit_once('/').unwrap_or(("", api_base));
+        let stem = name.strip_suffix(".json").unwrap_or(name);
+        if let (Some(domain), false) = (&self.config.api_bottle_domain, self.config.casks) {
+            let urls = self
+                .url_mapping
+                .iter()
+                .map(|(key, url)| {
+                    let rewritten = format!("{}/{}", domain.trim_end_matches('/'), key);
+                    (url.clone(), rewritten)
+                })
+                .collect();
+            self.generated
+                .insert(format!("api/{}", name), rewrite_formulae(data, &urls)?);
+        }
+        let mut snapshots = vec![];
+        for file in [
+            name.to_string(),
+            format!("{}.jws.json", stem),
+            format!("{}_tap_migrations.jws.json", stem),
+        ] {
+            let key = format!("api/{}", file);
+            self.url_mapping
+                .insert(key.clone(), format!("{}/{}", dir, file));
+            snapshots.push(SnapshotMeta::force(key));
         }
+        Ok(snapshots)
     }
 }
 
@@ -88,12 +224,15 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
         let progress = mission.progress;
         let client = mission.client;
         let gen_map = crate::utils::generate_s3_url_reverse_encode_map();
+        let api_base = if self.config.casks {
+            self.config.cask_api_base.clone()
+        } else {
+            self.config.api_base.clone()
+        };
 
         info!(logger, "fetching API json...");
         progress.set_message("fetching API json...");
-        let data = client
-            .get(&self.config.api_base)
-            .send()
+        let data = crate::http::send(client.get(&api_base))
             .timeout(Duration::from_secs(60))
             .await
             .into_result()?
@@ -103,6 +242,31 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
             .into_result()?;
 
         info!(logger, "parsing...");
+        if self.config.casks {
+            let casks: Vec<Cask> = serde_json::from_str(&data)?;
+            let mut snapshots = vec![];
+            for cask in casks {
+                if !self.config.cask_list.is_empty() && !self.config.cask_list.contains(&cask.token)
+                {
+                    continue;
+                }
+                for (url, sha256) in cask.downloads() {
+                    let key = crate::utils::rewrite_url_string(&gen_map, &url);
+                    self.url_mapping.insert(key.clone(), url);
+                    snapshots.push(SnapshotMeta {
+                        key,
+                        checksum_method: sha256.as_ref().map(|_| String::from("sha256")),
+                        checksum: sha256,
+                        ..Default::default()
+                    });
+                }
+            }
+            if self.config.mirror_api {
+                snapshots.extend(self.api_snapshot(&api_base, &data)?);
+            }
+            progress.finish_with_message("done");
+            return Ok(snapshots);
+        }
         let formulae: Formulae = serde_json::from_str(&data).unwrap();
         let mut snapshots = vec![];
         for f in formulae.0 {
@@ -112,10 +276,7 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
                 if let Some(versions_stable) = f.versions.stable {
                     if let Some(bs) = f.bottle.stable {
                         for (platform, v) in bs.files {
-                            if self.config.arch.is_empty()
-                                || self.config.arch == "all"
-                                || platform == self.config.arch
-                            {
+                            if self.config.selected(&platform) {
                                 let key = format!(
                                     "{name}-{version}{revision}.{platform}.bottle{rebuild}.tar.gz",
                                     name = f.name,
@@ -147,6 +308,10 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
             }
         }
 
+        if self.config.mirror_api {
+            snapshots.extend(self.api_snapshot(&api_base, &data)?);
+        }
+
         progress.finish_with_message("done");
 
         Ok(snapshots)
@@ -157,26 +322,129 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
     }
 }
 
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Homebrew {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.generated.get(&snapshot.key).cloned())
+    }
+}
+
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Homebrew {
     async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<TransferURL> {
         let url = self
             .url_mapping
             .get(&snapshot.key)
-            .expect("no URL for bottle");
-        let resp = mission
-            .client
-            .get(url)
-            .header(reqwest::header::AUTHORIZATION, "Bearer QQ==")
-            .header(
-                reqwest::header::ACCEPT,
-                "application/vnd.oci.image.index.v1+json",
+            .expect("no URL for object");
+        let repository = blob_repository(url).unwrap_or(url);
+        let resp = self
+            .registry
+            .get(
+                &mission.client,
+                url,
+                repository,
+                "application/vnd.oci.image.layer.v1.tar+gzip",
             )
-            .send()
             .await?;
-        if !resp.status().is_success() {
-            return Err(Error::HTTPError(resp.status()));
-        }
         Ok(TransferURL(resp.url().as_str().to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::{blob_repository, rewrite_formulae, Cask, Homebrew, HomebrewConfig};
+
+    #[test]
+    fn test_cask_downloads() {
+        let cask: Cask = serde_json::from_str(
+            r#"{
+                "token": "app",
+                "url": "https://example.com/app-arm.dmg",
+                "sha256": "00",
+                "variations": {
+                    "sonoma": {"url": "https://example.com/app-intel.dmg", "sha256": "no_check"},
+                    "arm64_sonoma": {"sha256": "00"},
+                    "ventura": {"url": "https://example.com/app-intel.dmg", "sha256": "no_check"}
+                }
+            }"#,
+        )
+        .unwrap();
+        let downloads: Vec<_> = cask.downloads().into_iter().collect();
+        assert_eq!(
+            downloads,
+            vec![
+                (
+                    "https://example.com/app-arm.dmg".to_string(),
+                    Some("00".to_string())
+                ),
+                ("https://example.com/app-intel.dmg".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bottle_tags() {
+        let config = HomebrewConfig::from_iter(["homebrew"]);
+        assert!(config.selected("ventura"));
+        let config =
+            HomebrewConfig::from_iter(["homebrew", "--bottle-tags", "arm64_sonoma,x86_64_linux"]);
+        assert!(config.selected("arm64_sonoma"));
+        assert!(config.selected("x86_64_linux"));
+        assert!(config.selected("all"));
+        assert!(!config.selected("ventura"));
+    }
+
+    #[test]
+    fn test_api_snapshot() {
+        let config = HomebrewConfig::from_iter([
+            "homebrew",
+            "--mirror-api",
+            "--api-bottle-domain",
+            "https://mirror/homebrew-bottles/",
+        ]);
+        let mut homebrew = Homebrew::new(config);
+        let bottle = "https://ghcr.io/v2/homebrew/core/wget/blobs/sha256:00";
+        homebrew
+            .url_mapping
+            .insert("wget-1.0.all.bottle.tar.gz".to_string(), bottle.to_string());
+        let data = format!(
+            r#"[{{"name":"wget","bottle":{{"stable":{{"files":{{"all":{{"url":"{}"}}}}}}}}}}]"#,
+            bottle
+        );
+        let snapshots = homebrew
+            .api_snapshot("https://formulae.brew.sh/api/formula.json", &data)
+            .unwrap();
+        let keys: Vec<_> = snapshots.iter().map(|x| x.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "api/formula.json",
+                "api/formula.jws.json",
+                "api/formula_tap_migrations.jws.json"
+            ]
+        );
+        assert_eq!(
+            homebrew.url_mapping["api/formula.jws.json"],
+            "https://formulae.brew.sh/api/formula.jws.json"
+        );
+        let rewritten = String::from_utf8(homebrew.generated["api/formula.json"].clone()).unwrap();
+        assert!(rewritten
+            .contains(r#""url":"https://mirror/homebrew-bottles/wget-1.0.all.bottle.tar.gz""#));
+        assert!(rewrite_formulae("{", &Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_blob_repository() {
+        assert_eq!(
+            blob_repository("https://ghcr.io/v2/homebrew/core/wget/blobs/sha256:00"),
+            Some("homebrew/core/wget")
+        );
+        assert_eq!(blob_repository("https://example.com/wget.tar.gz"), None);
+    }
+}
@@ -3,7 +3,6 @@ use crate::error::Result;
 use crate::traits::SnapshotStorage;
 
 use async_trait::async_trait;
-use regex::Regex;
 use slog::info;
 
 #[derive(Debug)]
@@ -23,17 +22,18 @@ impl SnapshotStorage<SnapshotPath> for HtmlScanner {
         let client = mission.client;
 
         info!(logger, "downloading web content...");
-        let index = client.get(&self.url).send().await?.text().await?;
-        let matcher = Regex::new(r#"<a.*href="(.*?)".*"#).unwrap();
-
-        let snapshot: Vec<String> = matcher
-            .captures_iter(&index)
-            .map(|cap| cap[1].to_string())
+        let index = crate::http::send(client.get(&self.url))
+            .await?
+            .text()
+            .await?;
+        let snapshot: Vec<String> = crate::utils::parse_link_list(&index)
+            .iter()
+            .filter_map(|link| link.href().map(str::to_string))
             .collect();
 
         progress.finish_with_message("done");
 
-        Ok(crate::utils::snapshot_string_to_path(snapshot))
+        Ok(snapshot.into_iter().map(SnapshotPath::new).collect())
     }
 
     fn info(&self) -> String {
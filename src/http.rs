@@ -0,0 +1,186 @@
+//! HTTP helpers
+//!
+//! All requests to upstream should be sent with `send`. When upstream responds
+//! with 429 (Too Many Requests) or 503 (Service Unavailable), the host is put
+//! into cooldown for the duration in `Retry-After` header, or an exponential
+//! backoff if the header is absent. During cooldown, requests to the same
+//! host wait until cooldown ends, while requests to other hosts are not
+//! affected. The request is then retried instead of failing the object.
+//!
+//! Rate limits in the style of GitHub API are handled likewise. When a
+//! response reports `X-RateLimit-Remaining: 0`, the host is put into cooldown
+//! until `X-RateLimit-Reset`, so that later requests are paced instead of
+//! failing. If the response itself is rejected (403 or 429), it's retried
+//! after cooldown.
+//!
+//! Credentials of upstream are also added to requests here, see `auth`. URLs
+//! with tokens in path are stripped from errors, so that tokens never get
+//! logged.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+const MAX_RETRIES: u32 = 5;
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+const MAX_COOLDOWN: Duration = Duration::from_secs(600);
+/// Rate limits of GitHub API are reset hourly.
+const MAX_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(3600);
+
+lazy_static! {
+    static ref COOLDOWN: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Parse `Retry-After` header, which is either seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let seconds = date.timestamp() - chrono::Utc::now().timestamp();
+    Some(Duration::from_secs(seconds.max(0) as u64))
+}
+
+/// Duration until rate limit is reset, if it's exhausted.
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let remaining = headers.get("x-ratelimit-remaining")?.to_str().ok()?;
+    if remaining.trim() != "0" {
+        return None;
+    }
+    let reset: i64 = headers
+        .get("x-ratelimit-reset")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let seconds = reset - chrono::Utc::now().timestamp();
+    Some(Duration::from_secs(seconds.max(0) as u64 + 1))
+}
+
+fn cooldown_until(host: &str) -> Option<Instant> {
+    COOLDOWN.lock().unwrap().get(host).copied()
+}
+
+fn set_cooldown(host: &str, duration: Duration, max: Duration) {
+    let until = Instant::now() + duration.min(max);
+    let mut cooldown = COOLDOWN.lock().unwrap();
+    let entry = cooldown.entry(host.to_string()).or_insert(until);
+    if *entry < until {
+        *entry = until;
+    }
+}
+
+/// Send request, with credential of upstream and token in URL path if
+/// registered.
+async fn execute(request: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = request.build_split();
+    let mut request = crate::auth::authenticate(&client, request?)?;
+    match crate::auth::tokenized_url(request.url().as_str()).and_then(|x| x.parse().ok()) {
+        Some(url) => {
+            *request.url_mut() = url;
+            client
+                .execute(request)
+                .await
+                .map_err(|err| err.without_url())
+        }
+        None => client.execute(request).await,
+    }
+}
+
+/// Send request, waiting for cooldown of the host, and retrying on 429 and
+/// 503 responses, as well as 403 responses of exhausted rate limit.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let current = match request.try_clone() {
+            Some(current) => current,
+            // request with streaming body can't be retried
+            None => return execute(request).await,
+        };
+        let host = current
+            .try_clone()
+            .and_then(|x| x.build().ok())
+            .and_then(|x| x.url().host_str().map(str::to_string))
+            .unwrap_or_default();
+
+        if let Some(until) = cooldown_until(&host) {
+            let now = Instant::now();
+            if until > now {
+                tokio::time::sleep(until - now).await;
+            }
+        }
+
+        let response = execute(current).await?;
+        let status = response.status();
+        let rate_limit = rate_limit_reset(response.headers());
+        if let Some(duration) = rate_limit {
+            set_cooldown(&host, duration, MAX_RATE_LIMIT_COOLDOWN);
+        }
+        let rejected = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+            // secondary rate limits of GitHub only come with `Retry-After`
+            StatusCode::FORBIDDEN => {
+                rate_limit.is_some() || response.headers().contains_key(RETRY_AFTER)
+            }
+            _ => false,
+        };
+        if rejected && attempt < MAX_RETRIES {
+            if rate_limit.is_none() {
+                let duration = retry_after(response.headers())
+                    .unwrap_or_else(|| DEFAULT_COOLDOWN * 2u32.pow(attempt));
+                set_cooldown(&host, duration, MAX_COOLDOWN);
+            }
+            attempt += 1;
+            continue;
+        }
+        return Ok(response);
+    }
+}
+
+/// Whether `url` exists upstream, found with HEAD request.
+pub async fn exists(client: &reqwest::Client, url: &str) -> crate::error::Result<bool> {
+    let response = send(client.head(url)).await?;
+    match response.status() {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(false),
+        status => Err(crate::error::Error::HTTPError(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_rate_limit_reset() {
+        let mut headers = HeaderMap::new();
+        let reset = (chrono::Utc::now().timestamp() + 60).to_string();
+        headers.insert("x-ratelimit-reset", HeaderValue::from_str(&reset).unwrap());
+        assert_eq!(rate_limit_reset(&headers), None);
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("10"));
+        assert_eq!(rate_limit_reset(&headers), None);
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        let duration = rate_limit_reset(&headers).unwrap();
+        assert!(duration > Duration::from_secs(55) && duration <= Duration::from_secs(61));
+    }
+}
@@ -3,15 +3,12 @@
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::Result;
 use crate::metadata::SnapshotMeta;
-use crate::stream_pipe::{ByteObject, ByteStream};
-use crate::traits::{Key, SnapshotStorage, SourceStorage};
-use crate::utils::{hash_string, unix_time};
+use crate::stream_pipe::ByteStream;
+use crate::traits::{Key, SizeStorage, SnapshotStorage, SourceStorage};
 
 use async_trait::async_trait;
 use itertools::Itertools;
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::Path;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
 static LIST_URL: &str = "mirror_clone_list.html";
 pub struct IndexPipe<Source> {
@@ -252,6 +249,22 @@ where
     }
 }
 
+#[async_trait]
+impl<Snapshot, Source> SizeStorage<Snapshot> for IndexPipe<Source>
+where
+    Snapshot: Key,
+    Source: SizeStorage<Snapshot>,
+{
+    async fn object_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        if snapshot.key().ends_with(LIST_URL) {
+            // index is generated, and is usually small
+            Ok(None)
+        } else {
+            self.source.object_size(snapshot, mission).await
+        }
+    }
+}
+
 #[async_trait]
 impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for IndexPipe<Source>
 where
@@ -265,30 +278,8 @@ where
                 .index
                 .index_for(prefix, &[&self.base_path], LIST_URL)
                 .into_bytes();
-            let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
-            let path = Path::new(&self.buffer_path).join(pipe_file);
-            let mut f = BufWriter::new(
-                tokio::fs::OpenOptions::default()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .read(true)
-                    .open(&path)
-                    .await?,
-            );
-            f.write_all(&content).await?;
-            f.flush().await?;
-            let mut f = f.into_inner();
-            f.seek(std::io::SeekFrom::Start(0)).await?;
-            Ok(ByteStream {
-                object: ByteObject::LocalFile {
-                    file: Some(f),
-                    path: Some(path),
-                },
-                length: content.len() as u64,
-                modified_at: unix_time(),
-                content_type: None, // use `text/html` by default
-            })
+            // use `text/html` by default
+            ByteStream::from_bytes(&self.buffer_path, key, &content).await
         } else {
             self.source.get_object(snapshot, mission).await
         }
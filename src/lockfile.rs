@@ -0,0 +1,701 @@
+//! Lockfile source
+//!
+//! Lockfile is a source storage which mirrors exactly the artifacts
+//! referenced by lockfiles, so that builds can be reproduced in an air-gapped
+//! environment. Supported lockfiles are:
+//!
+//! * `requirements.txt` with pinned versions (`name==version`), `poetry.lock`
+//!   and `uv.lock`. Files of each release are found with PyPI JSON API. If
+//!   hashes are given, only files with these hashes are mirrored.
+//! * `Cargo.lock`. Only crates from crates.io are mirrored.
+//! * `package-lock.json` and `npm-shrinkwrap.json`. Only packages resolved
+//!   from npm registry are mirrored.
+//!
+//! Objects are stored as `<ecosystem>/<path on upstream>`, i.e.
+//! `pypi/packages/...`, `crates/<name>/<name>-<version>.crate` and
+//! `npm/<name>/-/<name>-<version>.tgz`. Snapshot contains sha256 checksums
+//! whenever the lockfile or upstream provides them.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use slog::{info, warn, Logger};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+const CRATES_IO_INDEX: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+const NPM_REGISTRY: &str = "https://registry.npmjs.org";
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Lockfile {
+    #[structopt(
+        required = true,
+        help = "Lockfiles to mirror: requirements.txt, poetry.lock, uv.lock, Cargo.lock or package-lock.json"
+    )]
+    pub lockfiles: Vec<String>,
+    #[structopt(
+        long,
+        default_value = "https://pypi.org",
+        help = "Base of PyPI JSON API"
+    )]
+    pub pypi_base: String,
+    #[structopt(
+        long,
+        default_value = "https://files.pythonhosted.org",
+        help = "Base of PyPI files"
+    )]
+    pub pypi_files_base: String,
+    #[structopt(long, default_value = "https://static.crates.io/crates")]
+    pub crates_base: String,
+    #[structopt(long, default_value = "https://registry.npmjs.org")]
+    pub npm_registry: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PypiRequirement {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    /// sha256 of allowed files
+    pub(crate) hashes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Crate {
+    name: String,
+    version: String,
+    checksum: Option<String>,
+}
+
+/// Parse pinned requirements. Requirements not pinned to a version are
+/// returned separately.
+fn parse_requirements(content: &str) -> (Vec<PypiRequirement>, Vec<String>) {
+    let mut requirements = vec![];
+    let mut unpinned = vec![];
+    let content = content.replace("\\\n", " ");
+    for line in content.lines() {
+        let line = match line.find(" #") {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let spec = parts.next().unwrap();
+        let spec = spec.split(';').next().unwrap();
+        let hashes = parts
+            .filter_map(|x| x.strip_prefix("--hash="))
+            .filter_map(|x| x.strip_prefix("sha256:"))
+            .map(str::to_string)
+            .collect();
+        match spec.split_once("==") {
+            Some((name, version)) if !version.contains(['*', ',']) => {
+                let name = name.split('[').next().unwrap().trim();
+                requirements.push(PypiRequirement {
+                    name: name.to_string(),
+                    version: version.trim().to_string(),
+                    hashes,
+                });
+            }
+            _ => unpinned.push(spec.to_string()),
+        }
+    }
+    (requirements, unpinned)
+}
+
+#[derive(Deserialize)]
+struct PoetryLock {
+    #[serde(default)]
+    package: Vec<PoetryPackage>,
+    #[serde(default)]
+    metadata: PoetryMetadata,
+}
+
+#[derive(Deserialize)]
+struct PoetryPackage {
+    name: String,
+    version: String,
+    source: Option<PoetrySource>,
+    #[serde(default)]
+    files: Vec<PoetryFile>,
+}
+
+#[derive(Deserialize)]
+struct PoetrySource {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize, Default)]
+struct PoetryMetadata {
+    /// Files of each package, in lockfiles before poetry 1.5.
+    #[serde(default)]
+    files: HashMap<String, Vec<PoetryFile>>,
+}
+
+#[derive(Deserialize)]
+struct PoetryFile {
+    hash: String,
+}
+
+/// Parse packages from package index in poetry.lock.
+fn parse_poetry_lock(content: &str) -> Result<Vec<PypiRequirement>> {
+    let lock: PoetryLock = toml::from_str(content)
+        .map_err(|err| Error::ProcessError(format!("invalid poetry.lock: {}", err)))?;
+    let sha256 = |files: &[PoetryFile]| -> Vec<String> {
+        files
+            .iter()
+            .filter_map(|file| file.hash.strip_prefix("sha256:"))
+            .map(str::to_string)
+            .collect()
+    };
+    let metadata_files = lock.metadata.files;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|package| {
+            package
+                .source
+                .as_ref()
+                .is_none_or(|source| source.kind == "legacy")
+        })
+        .map(|package| {
+            let mut hashes = sha256(&package.files);
+            if let Some(files) = metadata_files.get(&package.name) {
+                hashes.extend(sha256(files));
+            }
+            PypiRequirement {
+                name: package.name,
+                version: package.version,
+                hashes,
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct UvLock {
+    #[serde(default)]
+    package: Vec<UvPackage>,
+}
+
+#[derive(Deserialize)]
+struct UvPackage {
+    name: String,
+    version: Option<String>,
+    source: HashMap<String, toml::Value>,
+    sdist: Option<UvFile>,
+    #[serde(default)]
+    wheels: Vec<UvFile>,
+}
+
+#[derive(Deserialize)]
+struct UvFile {
+    hash: Option<String>,
+}
+
+/// Parse packages from package index in uv.lock.
+fn parse_uv_lock(content: &str) -> Result<Vec<PypiRequirement>> {
+    let lock: UvLock = toml::from_str(content)
+        .map_err(|err| Error::ProcessError(format!("invalid uv.lock: {}", err)))?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|package| package.source.contains_key("registry"))
+        .filter_map(|package| {
+            let hashes = package
+                .sdist
+                .iter()
+                .chain(&package.wheels)
+                .filter_map(|file| file.hash.as_deref()?.strip_prefix("sha256:"))
+                .map(str::to_string)
+                .collect();
+            Some(PypiRequirement {
+                name: package.name,
+                version: package.version?,
+                hashes,
+            })
+        })
+        .collect())
+}
+
+/// Parse pinned python requirements in requirements.txt, poetry.lock or
+/// uv.lock at `path`. Requirements not pinned to a version are returned
+/// separately.
+pub(crate) fn parse_python_lockfile(
+    path: &str,
+    content: &str,
+) -> Result<(Vec<PypiRequirement>, Vec<String>)> {
+    let file_name = std::path::Path::new(path)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match file_name.as_str() {
+        "poetry.lock" => Ok((parse_poetry_lock(content)?, vec![])),
+        "uv.lock" => Ok((parse_uv_lock(content)?, vec![])),
+        _ if file_name.ends_with(".txt") => Ok(parse_requirements(content)),
+        _ => Err(Error::ConfigureError(format!(
+            "unsupported python lockfile {}",
+            path
+        ))),
+    }
+}
+
+/// Parse crates.io packages in Cargo.lock.
+fn parse_cargo_lock(content: &str) -> Vec<Crate> {
+    let mut crates = vec![];
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut flush = |fields: &mut Vec<(String, String)>| {
+        let get = |name: &str| {
+            fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        if let (Some(name), Some(version), Some(source)) =
+            (get("name"), get("version"), get("source"))
+        {
+            if CRATES_IO_INDEX.contains(&source.as_str()) {
+                crates.push(Crate {
+                    name,
+                    version,
+                    checksum: get("checksum"),
+                });
+            }
+        }
+        fields.clear();
+    };
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            flush(&mut fields);
+        } else if let Some((key, value)) = line.split_once(" = ") {
+            if let Some(value) = value.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+                fields.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+    flush(&mut fields);
+    crates
+}
+
+/// Collect `resolved` URLs in package-lock.json of any lockfile version.
+fn parse_package_lock(content: &str) -> Result<Vec<String>> {
+    fn collect_dependencies(value: &serde_json::Value, resolved: &mut Vec<String>) {
+        if let Some(dependencies) = value.get("dependencies").and_then(|x| x.as_object()) {
+            for dependency in dependencies.values() {
+                if let Some(url) = dependency.get("resolved").and_then(|x| x.as_str()) {
+                    resolved.push(url.to_string());
+                }
+                collect_dependencies(dependency, resolved);
+            }
+        }
+    }
+
+    let lock: serde_json::Value = serde_json::from_str(content)?;
+    let mut resolved = vec![];
+    match lock.get("packages").and_then(|x| x.as_object()) {
+        Some(packages) => {
+            for package in packages.values() {
+                if let Some(url) = package.get("resolved").and_then(|x| x.as_str()) {
+                    resolved.push(url.to_string());
+                }
+            }
+        }
+        None => collect_dependencies(&lock, &mut resolved),
+    }
+    resolved.sort();
+    resolved.dedup();
+    Ok(resolved)
+}
+
+#[derive(Deserialize)]
+struct PypiRelease {
+    urls: Vec<PypiFile>,
+}
+
+#[derive(Deserialize)]
+struct PypiFile {
+    url: String,
+    size: Option<u64>,
+    digests: PypiDigests,
+}
+
+#[derive(Deserialize)]
+struct PypiDigests {
+    sha256: Option<String>,
+}
+
+fn sha256_meta(key: String, size: Option<u64>, sha256: Option<String>) -> SnapshotMeta {
+    SnapshotMeta {
+        key,
+        size,
+        checksum_method: sha256.as_ref().map(|_| "sha256".to_string()),
+        checksum: sha256,
+        ..Default::default()
+    }
+}
+
+impl Lockfile {
+    async fn pypi_files(
+        &self,
+        logger: &Logger,
+        client: &reqwest::Client,
+        requirement: &PypiRequirement,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let url = format!(
+            "{}/pypi/{}/{}/json",
+            self.pypi_base.trim_end_matches('/'),
+            requirement.name,
+            requirement.version
+        );
+        let release: PypiRelease = crate::http::send(client.get(&url)).await?.json().await?;
+        let mut files = vec![];
+        for file in release.urls {
+            if !requirement.hashes.is_empty()
+                && !file
+                    .digests
+                    .sha256
+                    .as_ref()
+                    .is_some_and(|x| requirement.hashes.contains(x))
+            {
+                continue;
+            }
+            let path = match url::Url::parse(&file.url) {
+                Ok(url) => url.path().trim_start_matches('/').to_string(),
+                Err(err) => {
+                    warn!(logger, "invalid url {}: {:?}", file.url, err);
+                    continue;
+                }
+            };
+            files.push(sha256_meta(
+                format!("pypi/{}", path),
+                file.size,
+                file.digests.sha256,
+            ));
+        }
+        if files.is_empty() {
+            warn!(
+                logger,
+                "no file found for {}=={}", requirement.name, requirement.version
+            );
+        }
+        Ok(files)
+    }
+
+    fn npm_key(&self, resolved: &str) -> Option<String> {
+        let path = resolved
+            .strip_prefix(NPM_REGISTRY)
+            .or_else(|| resolved.strip_prefix(self.npm_registry.trim_end_matches('/')))?;
+        let path = path.split(['?', '#']).next().unwrap();
+        Some(format!("npm/{}", path.trim_start_matches('/')))
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Lockfile {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut snapshot = vec![];
+        let mut requirements = vec![];
+        for path in &self.lockfiles {
+            let content = tokio::fs::read_to_string(path).await?;
+            let file_name = std::path::Path::new(path)
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if file_name == "Cargo.lock" {
+                let crates = parse_cargo_lock(&content);
+                info!(logger, "{}: {} crates", path, crates.len());
+                snapshot.extend(crates.into_iter().map(|x| {
+                    sha256_meta(
+                        format!("crates/{name}/{name}-{}.crate", x.version, name = x.name),
+                        None,
+                        x.checksum,
+                    )
+                }));
+            } else if file_name == "package-lock.json" || file_name == "npm-shrinkwrap.json" {
+                let resolved = parse_package_lock(&content)?;
+                info!(logger, "{}: {} packages", path, resolved.len());
+                for url in resolved {
+                    match self.npm_key(&url) {
+                        Some(key) => snapshot.push(SnapshotMeta::new(key)),
+                        None => warn!(logger, "{} isn't from npm registry, skipped", url),
+                    }
+                }
+            } else if file_name.ends_with(".txt")
+                || file_name == "poetry.lock"
+                || file_name == "uv.lock"
+            {
+                let (pinned, unpinned) = parse_python_lockfile(path, &content)?;
+                info!(logger, "{}: {} requirements", path, pinned.len());
+                for spec in unpinned {
+                    warn!(logger, "{} isn't pinned to a version, skipped", spec);
+                }
+                requirements.extend(pinned);
+            } else {
+                return Err(Error::ConfigureError(format!(
+                    "unsupported lockfile {}",
+                    path
+                )));
+            }
+        }
+
+        info!(
+            logger,
+            "resolving {} python requirements",
+            requirements.len()
+        );
+        progress.set_length(requirements.len() as u64);
+        let this = &*self;
+        let (logger, client, progress) = (&logger, &client, &progress);
+        let files: Vec<Vec<SnapshotMeta>> = stream::iter(requirements)
+            .map(|requirement| async move {
+                progress.set_message(&requirement.name);
+                let files = this.pypi_files(logger, client, &requirement).await;
+                progress.inc(1);
+                files.map_err(|err| {
+                    warn!(
+                        logger,
+                        "failed to resolve {}=={}: {:?}",
+                        requirement.name,
+                        requirement.version,
+                        err
+                    );
+                    err
+                })
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        snapshot.extend(files.into_iter().flatten());
+
+        progress.finish_with_message("done");
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("lockfile, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Lockfile {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        let (ecosystem, path) = snapshot
+            .key
+            .split_once('/')
+            .ok_or_else(|| Error::ProcessError(format!("invalid key {}", snapshot.key)))?;
+        let base = match ecosystem {
+            "pypi" => &self.pypi_files_base,
+            "crates" => &self.crates_base,
+            "npm" => &self.npm_registry,
+            _ => return Err(Error::ProcessError(format!("invalid key {}", snapshot.key))),
+        };
+        Ok(TransferURL(format!(
+            "{}/{}",
+            base.trim_end_matches('/'),
+            path
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requirements() {
+        let content = "\
+# comment
+--index-url https://pypi.org/simple
+numpy==1.26.4 \\
+    --hash=sha256:abc \\
+    --hash=sha256:def
+requests[socks]==2.31.0 ; python_version >= \"3.7\"  # via foo
+flask>=2.0
+";
+        let (pinned, unpinned) = parse_requirements(content);
+        assert_eq!(
+            pinned,
+            vec![
+                PypiRequirement {
+                    name: "numpy".to_string(),
+                    version: "1.26.4".to_string(),
+                    hashes: vec!["abc".to_string(), "def".to_string()],
+                },
+                PypiRequirement {
+                    name: "requests".to_string(),
+                    version: "2.31.0".to_string(),
+                    hashes: vec![],
+                },
+            ]
+        );
+        assert_eq!(unpinned, vec!["flask>=2.0"]);
+    }
+
+    #[test]
+    fn test_parse_poetry_lock() {
+        let content = r#"
+[[package]]
+name = "certifi"
+version = "2024.2.2"
+description = "Python package for providing Mozilla's CA Bundle."
+optional = false
+python-versions = ">=3.6"
+files = [
+    {file = "certifi-2024.2.2-py3-none-any.whl", hash = "sha256:abc"},
+    {file = "certifi-2024.2.2.tar.gz", hash = "sha256:def"},
+]
+
+[[package]]
+name = "foo"
+version = "0.1.0"
+description = ""
+optional = false
+python-versions = "*"
+files = []
+
+[package.source]
+type = "git"
+url = "https://github.com/example/foo.git"
+reference = "HEAD"
+resolved_reference = "0000000"
+
+[[package]]
+name = "idna"
+version = "3.6"
+description = "Internationalized Domain Names in Applications (IDNA)"
+optional = false
+python-versions = ">=3.5"
+
+[metadata]
+lock-version = "1.1"
+python-versions = "^3.9"
+content-hash = "00"
+
+[metadata.files]
+idna = [
+    {file = "idna-3.6-py3-none-any.whl", hash = "sha256:123"},
+]
+"#;
+        assert_eq!(
+            parse_poetry_lock(content).unwrap(),
+            vec![
+                PypiRequirement {
+                    name: "certifi".to_string(),
+                    version: "2024.2.2".to_string(),
+                    hashes: vec!["abc".to_string(), "def".to_string()],
+                },
+                PypiRequirement {
+                    name: "idna".to_string(),
+                    version: "3.6".to_string(),
+                    hashes: vec!["123".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_uv_lock() {
+        let content = r#"
+version = 1
+requires-python = ">=3.9"
+
+[[package]]
+name = "app"
+version = "0.1.0"
+source = { editable = "." }
+dependencies = [
+    { name = "idna" },
+]
+
+[[package]]
+name = "idna"
+version = "3.6"
+source = { registry = "https://pypi.org/simple" }
+sdist = { url = "https://files.pythonhosted.org/packages/bf/3f/idna-3.6.tar.gz", hash = "sha256:def", size = 175426 }
+wheels = [
+    { url = "https://files.pythonhosted.org/packages/c2/e7/idna-3.6-py3-none-any.whl", hash = "sha256:abc", size = 61567 },
+]
+"#;
+        assert_eq!(
+            parse_uv_lock(content).unwrap(),
+            vec![PypiRequirement {
+                name: "idna".to_string(),
+                version: "3.6".to_string(),
+                hashes: vec!["def".to_string(), "abc".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_cargo_lock() {
+        let content = r#"
+version = 3
+
+[[package]]
+name = "mirror-clone"
+version = "0.2.0"
+dependencies = [
+ "serde",
+]
+
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "abc"
+
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "git+https://github.com/foo/foo#abc"
+"#;
+        assert_eq!(
+            parse_cargo_lock(content),
+            vec![Crate {
+                name: "serde".to_string(),
+                version: "1.0.0".to_string(),
+                checksum: Some("abc".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_package_lock() {
+        let v3 = r#"{"lockfileVersion": 3, "packages": {
+            "": {"name": "app"},
+            "node_modules/lodash": {"version": "4.17.21", "resolved": "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz"}
+        }}"#;
+        let v1 = r#"{"lockfileVersion": 1, "dependencies": {
+            "a": {"resolved": "https://registry.npmjs.org/a/-/a-1.0.0.tgz", "dependencies": {
+                "b": {"resolved": "https://registry.npmjs.org/b/-/b-2.0.0.tgz"}
+            }}
+        }}"#;
+        assert_eq!(
+            parse_package_lock(v3).unwrap(),
+            vec!["https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz"]
+        );
+        assert_eq!(parse_package_lock(v1).unwrap().len(), 2);
+    }
+}
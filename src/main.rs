@@ -9,43 +9,63 @@ use structopt::StructOpt;
 use common::SnapshotConfig;
 use error::Result;
 use file_backend::FileBackend;
+use metadata::SnapshotMeta;
 use opts::{Source, Target};
 use s3::S3Backend;
-use simple_diff_transfer::SimpleDiffTransfer;
+use traits::SnapshotStorage;
+use transfer::Transfer;
 
 use crate::github_release::GitHubRelease;
 use crate::homebrew::Homebrew;
 
+mod apt;
+mod auth;
+mod checkpoint;
+mod checksum_db;
 mod checksum_pipe;
 mod common;
+mod compare;
 mod conda;
+mod conda_version;
 mod crates_io;
+mod crates_io_sparse;
 mod dart;
 mod error;
 mod file_backend;
+mod file_view;
 mod filter_pipe;
+mod generate_pipe;
 mod ghcup;
 mod github_release;
 mod gradle;
 mod homebrew;
 mod html_scanner;
+mod http;
 mod index_pipe;
 #[macro_use]
 mod merge_pipe;
 mod lean;
+mod lockfile;
 mod metadata;
+mod oci;
 mod opts;
 mod pypi;
+mod pypi_changelog;
+mod pypi_ranking;
 mod python_version;
+mod registry;
 mod rewrite_pipe;
 mod rsync;
 mod rustup;
 mod s3;
-mod simple_diff_transfer;
 mod stream_pipe;
 mod timeout;
 mod traits;
+mod transfer;
 mod utils;
+mod wheel_name;
+mod yum;
+mod zypper;
 
 macro_rules! index_bytes_pipe {
     ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr) => {
@@ -84,6 +104,27 @@ macro_rules! index_checksum_bytes_pipe {
     };
 }
 
+macro_rules! index_checksum_generate_bytes_pipe {
+    ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr) => {
+        |source| {
+            let bytestream = stream_pipe::ByteStreamPipe::new(
+                source,
+                $buffer_path.clone().unwrap(),
+                $use_snapshot_last_modified,
+            );
+            let generate =
+                generate_pipe::GeneratePipe::new(bytestream, $buffer_path.clone().unwrap());
+            let checksum = checksum_pipe::ChecksumPipe::new(generate);
+            index_pipe::IndexPipe::new(
+                checksum,
+                $buffer_path.clone().unwrap(),
+                $prefix.clone().unwrap(),
+                $max_depth,
+            )
+        }
+    };
+}
+
 macro_rules! id_pipe {
     () => {
         |src| src
@@ -97,14 +138,16 @@ macro_rules! transfer {
                 let target: S3Backend = $opts.s3_config.clone().into();
                 let pipes = $pipes;
                 let source = pipes($source);
-                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                let strategy = transfer::build_strategy(&$transfer_config);
+                let transfer = Transfer::new(source, target, strategy, $transfer_config);
                 transfer.transfer().await.unwrap();
             }
             Target::File => {
                 let target: FileBackend = $opts.file_config.clone().into();
                 let pipes = $pipes;
                 let source = pipes($source);
-                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                let strategy = transfer::build_strategy(&$transfer_config);
+                let transfer = Transfer::new(source, target, strategy, $transfer_config);
                 transfer.transfer().await.unwrap();
             }
         }
@@ -121,6 +164,8 @@ const HASKELL_URL: &str = "https://downloads.haskell.org";
 
 fn main() {
     let opts: opts::Opts = opts::Opts::from_args();
+    opts.validate().unwrap();
+    auth::install(opts.credentials().unwrap());
 
     // create runtime
     let mut runtime = tokio::runtime::Builder::new_multi_thread();
@@ -132,16 +177,49 @@ fn main() {
     let runtime = runtime.build().unwrap();
 
     // parse config
+    let (concurrent_resolve, concurrent_transfer) = opts.source.default_concurrency();
     let snapshot_config = SnapshotConfig {
-        concurrent_resolve: opts.concurrent_resolve,
+        concurrent_resolve: opts.concurrent_resolve.unwrap_or(concurrent_resolve),
+        checkpoint: opts.snapshot_checkpoint.clone(),
     };
-    let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
-        progress: opts.progress,
-        concurrent_transfer: opts.transfer_config.concurrent_transfer,
+    let transfer_config = transfer::TransferConfig {
+        progress: opts.progress && !opts.quiet,
+        concurrent_transfer: opts
+            .transfer_config
+            .concurrent_transfer
+            .unwrap_or(concurrent_transfer),
         no_delete: opts.transfer_config.no_delete,
         print_plan: opts.transfer_config.print_plan,
         dry_run: opts.transfer_config.dry_run,
         force_all: opts.transfer_config.force_all,
+        strategy: opts.transfer_config.strategy,
+        manifest_path: opts.transfer_config.manifest_path.clone(),
+        journal_path: opts.transfer_config.journal_path.clone(),
+        snapshot_db: opts.transfer_config.snapshot_db.clone(),
+        dedup: opts.transfer_config.dedup,
+        queue_path: opts.transfer_config.queue_path.clone(),
+        max_attempts: opts.transfer_config.max_attempts,
+        retry_delay: opts.transfer_config.retry_delay,
+        coordinator_listen: opts.transfer_config.coordinator_listen.clone(),
+        coordinator_url: opts.transfer_config.coordinator_url.clone(),
+        coordinator_secret: opts.transfer_config.coordinator_secret.clone(),
+        state_on_target: opts.transfer_config.state_on_target,
+        user_agent: opts
+            .user_agent
+            .clone()
+            .unwrap_or_else(crate::utils::user_agent),
+        headers: opts.headers.clone(),
+        estimate: opts.transfer_config.estimate,
+        estimate_samples: opts.transfer_config.estimate_samples,
+        verify_report: opts.transfer_config.verify_report.clone(),
+        repair_from: opts.transfer_config.repair_from.clone(),
+        audit_sample: opts.transfer_config.audit_sample,
+        feed: opts.transfer_config.feed,
+        feed_size: opts.transfer_config.feed_size,
+        feed_base_url: opts.transfer_config.feed_base_url.clone(),
+        stats: opts.transfer_config.stats,
+        source_name: opts.source.name().to_string(),
+        progress_interval: opts.progress_interval.or(opts.quiet.then_some(60)),
         snapshot_config,
     };
 
@@ -159,7 +237,14 @@ fn main() {
         match opts.source {
             Source::Pypi(source) => {
                 let pipe = |source| {
-                    stream_pipe::ByteStreamPipe::new(source, buffer_path.clone().unwrap(), false)
+                    checksum_pipe::ChecksumPipe::new(generate_pipe::GeneratePipe::new(
+                        stream_pipe::ByteStreamPipe::new(
+                            source,
+                            buffer_path.clone().unwrap(),
+                            false,
+                        ),
+                        buffer_path.clone().unwrap(),
+                    ))
                 };
                 transfer!(opts, source, transfer_config, pipe);
             }
@@ -169,7 +254,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::CratesIo(source) => {
@@ -177,7 +262,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::Conda(config) => {
@@ -186,7 +271,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::Rsync(source) => {
@@ -202,7 +287,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, true, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, true, 999)
                 );
             }
             Source::DartPub(source) => {
@@ -210,7 +295,50 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                );
+            }
+            Source::Lockfile(source) => {
+                transfer!(
+                    opts,
+                    source,
+                    transfer_config,
+                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                );
+            }
+            Source::Apt(config) => {
+                let source = apt::Apt::new(config).unwrap();
+                transfer!(
+                    opts,
+                    source,
+                    transfer_config,
+                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                );
+            }
+            Source::Yum(config) => {
+                let source = yum::Yum::new(config);
+                transfer!(
+                    opts,
+                    source,
+                    transfer_config,
+                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                );
+            }
+            Source::Zypper(source) => {
+                transfer!(
+                    opts,
+                    source,
+                    transfer_config,
+                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
+                );
+            }
+            Source::Oci(config) => {
+                let source = oci::Oci::new(config);
+                transfer!(
+                    opts,
+                    source,
+                    transfer_config,
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::Gradle(source) => {
@@ -218,7 +346,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::Ghcup(source) => {
@@ -319,7 +447,7 @@ fn main() {
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    index_checksum_generate_bytes_pipe!(buffer_path, prefix, false, 999)
                 );
             }
             Source::Elan(source) => {
@@ -352,6 +480,97 @@ fn main() {
 
                 transfer!(opts, indexed, transfer_config, id_pipe!());
             }
+            Source::Fsck(config) => {
+                let logger = utils::create_logger();
+                let base_path = opts
+                    .file_config
+                    .file_base_path
+                    .as_ref()
+                    .expect("fsck requires file backend");
+                let db = checksum_db::ChecksumDb::open(
+                    opts.file_config
+                        .file_checksum_db
+                        .as_ref()
+                        .expect("fsck requires checksum database"),
+                )
+                .unwrap();
+                let progress = if opts.progress && !opts.quiet {
+                    utils::progress_bar(0)
+                } else {
+                    indicatif::ProgressBar::hidden()
+                };
+                progress.set_style(utils::bar());
+                progress.set_prefix("fsck");
+                let report = checksum_db::fsck(&logger, &config, base_path, &db, progress)
+                    .await
+                    .unwrap();
+                if report.drifted() > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Source::CompareTargets(config) => {
+                let logger = utils::create_logger();
+                let open_db = |path: &Option<String>| {
+                    path.as_ref()
+                        .map(|path| checksum_db::ChecksumDb::open(path).unwrap())
+                };
+                let (mut primary, primary_db): (Box<dyn SnapshotStorage<SnapshotMeta>>, _) =
+                    match opts.target_type {
+                        Target::S3 => {
+                            let target: S3Backend = opts.s3_config.clone().into();
+                            (Box::new(target), None)
+                        }
+                        Target::File => {
+                            let target: FileBackend = opts.file_config.clone().into();
+                            (
+                                Box::new(target),
+                                open_db(&opts.file_config.file_checksum_db),
+                            )
+                        }
+                    };
+                let (mut secondary, secondary_db): (Box<dyn SnapshotStorage<SnapshotMeta>>, _) =
+                    match config.other_target_type.as_str() {
+                        "s3" => {
+                            let mut s3_config = s3::S3Config::new_jcloud(
+                                config
+                                    .other_s3_prefix
+                                    .clone()
+                                    .expect("secondary S3 target requires prefix"),
+                                config.other_s3_scan_metadata,
+                            );
+                            if let Some(endpoint) = &config.other_s3_endpoint {
+                                s3_config.endpoint = endpoint.clone();
+                            }
+                            if let Some(bucket) = &config.other_s3_bucket {
+                                s3_config.bucket = bucket.clone();
+                            }
+                            (Box::new(S3Backend::new(s3_config)), None)
+                        }
+                        _ => {
+                            let target = FileBackend::new(
+                                config
+                                    .other_file_base_path
+                                    .clone()
+                                    .expect("secondary file target requires base path"),
+                            );
+                            (Box::new(target), open_db(&config.other_file_checksum_db))
+                        }
+                    };
+                let report = compare::compare_targets(
+                    &logger,
+                    &config,
+                    &transfer_config.snapshot_config,
+                    primary.as_mut(),
+                    primary_db.as_ref(),
+                    secondary.as_mut(),
+                    secondary_db.as_ref(),
+                )
+                .await
+                .unwrap();
+                if report.diverged() > 0 {
+                    std::process::exit(1);
+                }
+            }
         }
     });
 }
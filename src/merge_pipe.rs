@@ -17,7 +17,7 @@ use slog::info;
 
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
-use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::traits::{Key, SizeStorage, SnapshotStorage, SourceStorage};
 
 /// Generate MergePipe from a list of sources.
 macro_rules! merge_pipe {
@@ -100,6 +100,26 @@ where
     }
 }
 
+#[async_trait]
+impl<Source1, Source2, SnapshotItem> SizeStorage<SnapshotItem> for MergePipe<Source1, Source2>
+where
+    SnapshotItem: Key + Clone,
+    Source1: SizeStorage<SnapshotItem> + Send + 'static,
+    Source2: SizeStorage<SnapshotItem> + Send + 'static,
+{
+    async fn object_size(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<Option<u64>> {
+        let path = snapshot.key();
+
+        if let Some(key) = path.strip_prefix(&self.prefix) {
+            let mut snapshot = snapshot.clone();
+            *snapshot.key_mut() = String::from(key);
+            self.s1.object_size(&snapshot, mission).await
+        } else {
+            self.s2.object_size(snapshot, mission).await
+        }
+    }
+}
+
 pub struct NilPipe;
 
 #[async_trait]
@@ -119,3 +139,10 @@ impl<T: Sync, U> SourceStorage<T, U> for NilPipe {
         Err(Error::PipeError(String::from("unexpected prefix")))
     }
 }
+
+#[async_trait]
+impl<T: Sync> SizeStorage<T> for NilPipe {
+    async fn object_size(&self, _: &T, _: &Mission) -> Result<Option<u64>> {
+        Err(Error::PipeError(String::from("unexpected prefix")))
+    }
+}
@@ -1,16 +1,20 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::Result;
 use crate::traits::{Diff, Key, Metadata, SnapshotStorage};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMetaFlag {
     pub force: bool,
     pub force_last: bool,
+    /// Only kept if it already exists on target, never transferred.
+    #[serde(default)]
+    pub existing_only: bool,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMeta {
     pub key: String,
     pub size: Option<u64>,
@@ -33,6 +37,7 @@ impl SnapshotMeta {
             flags: SnapshotMetaFlag {
                 force: true,
                 force_last: true,
+                ..Default::default()
             },
             ..Default::default()
         }
@@ -100,6 +105,10 @@ impl Diff for SnapshotMeta {
         }
         false
     }
+
+    fn existing_only(&self) -> bool {
+        self.flags.existing_only
+    }
 }
 
 impl Metadata for SnapshotMeta {
@@ -115,6 +124,10 @@ impl Metadata for SnapshotMeta {
         self.last_modified
     }
 
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
     fn checksum(&self) -> Option<&str> {
         self.checksum.as_deref()
     }
@@ -0,0 +1,478 @@
+//! OCI source
+//!
+//! OCI source mirrors container images of given references (e.g.
+//! `alpine:3.19` or `ghcr.io/owner/image:tag`) from their registries. Images
+//! are stored in the layout of registry API, so that a static file server can
+//! serve the target as a read-only registry:
+//!
+//! * `v2/<repository>/manifests/<tag>`, the manifest (or index) of a tag
+//! * `v2/<repository>/manifests/<digest>`, manifests by digest
+//! * `v2/<repository>/blobs/<digest>`, config and layer blobs
+//!
+//! Images of Docker Hub are stored under their full repository names, e.g.
+//! `library/alpine`. Manifests of all platforms in a multi-arch index are
+//! mirrored, unless filtered by `--platforms`; the index itself is mirrored
+//! as is. Blobs and manifests are verified by their digests, and manifests of
+//! tags are always transferred at the end.
+//!
+//! Note that clients check `Content-Type` of manifests, which should be set by
+//! the file server to the `mediaType` in each manifest.
+
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use sha2::Digest;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::auth::Credential;
+use crate::common::{Generated, Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::registry::Registry;
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
+
+/// Media types of manifests and indexes accepted.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+const DOCKER_HUB: &str = "docker.io";
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct OciConfig {
+    /// File listing images to mirror, one reference per line.
+    pub image_list: Option<String>,
+    /// Also mirror these images, e.g. `alpine:3.19` or `ghcr.io/owner/image:tag`.
+    #[structopt(long = "image", parse(try_from_str = parse_reference), number_of_values = 1)]
+    pub images: Vec<Reference>,
+    /// Only mirror these platforms of multi-arch images, e.g. `linux/amd64,linux/arm64/v8`.
+    #[structopt(long, use_delimiter = true)]
+    pub platforms: Vec<String>,
+}
+
+/// Reference of an image, e.g. `ghcr.io/owner/image:tag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    registry: String,
+    repository: String,
+    /// tag or digest
+    tag: String,
+}
+
+fn parse_reference(s: &str) -> std::result::Result<Reference, String> {
+    let (name, tag) = match s.split_once('@') {
+        Some((name, digest)) => (name, digest),
+        None => match s.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag),
+            _ => (s, "latest"),
+        },
+    };
+    let (registry, repository) = match name.split_once('/') {
+        Some((host, repository))
+            if host.contains('.') || host.contains(':') || host == "localhost" =>
+        {
+            (host, repository.to_string())
+        }
+        _ => (DOCKER_HUB, name.to_string()),
+    };
+    let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+        format!("library/{}", repository)
+    } else {
+        repository
+    };
+    if repository.is_empty() || tag.is_empty() {
+        return Err(format!("invalid image reference {}", s));
+    }
+    Ok(Reference {
+        registry: registry.to_string(),
+        repository,
+        tag: tag.to_string(),
+    })
+}
+
+impl Reference {
+    /// Base URL of registry API.
+    fn api_base(&self) -> String {
+        match self.registry.as_str() {
+            DOCKER_HUB => "https://registry-1.docker.io".to_string(),
+            registry => format!("https://{}", registry),
+        }
+    }
+
+    fn is_digest(&self) -> bool {
+        self.tag.contains(':')
+    }
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+    #[serde(default)]
+    variant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+/// An image manifest, or an index of manifests.
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+/// Whether manifest of `platform` is selected by `platforms`, which selects
+/// all if empty.
+fn is_selected(platforms: &[String], platform: Option<&Platform>) -> bool {
+    if platforms.is_empty() {
+        return true;
+    }
+    let platform = match platform {
+        Some(platform) => platform,
+        None => return false,
+    };
+    let name = format!("{}/{}", platform.os, platform.architecture);
+    platforms.iter().any(|x| {
+        *x == name
+            || platform
+                .variant
+                .as_ref()
+                .is_some_and(|variant| *x == format!("{}/{}", name, variant))
+    })
+}
+
+/// Snapshot of an object addressed by `digest`, verified if it's SHA256.
+fn by_digest(key: String, digest: &str, size: u64) -> SnapshotMeta {
+    let checksum = digest.strip_prefix("sha256:").map(str::to_string);
+    SnapshotMeta {
+        key,
+        size: Some(size),
+        checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+        checksum,
+        ..Default::default()
+    }
+}
+
+/// Objects in snapshot, with their content if generated.
+type Objects = Vec<(SnapshotMeta, Option<Vec<u8>>)>;
+
+pub struct Oci {
+    config: OciConfig,
+    images: Vec<Reference>,
+    registry: Registry,
+    generated: Generated,
+}
+
+impl Oci {
+    pub fn new(config: OciConfig) -> Self {
+        let mut images = config.images.clone();
+        if let Some(path) = &config.image_list {
+            let content = std::fs::read_to_string(path).unwrap();
+            images.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| parse_reference(line).unwrap()),
+            );
+        }
+        assert!(!images.is_empty(), "no images to mirror");
+        for image in &images {
+            assert!(
+                images
+                    .iter()
+                    .all(|x| x.repository != image.repository || x.registry == image.registry),
+                "repository {} of different registries",
+                image.repository
+            );
+        }
+        Self {
+            config,
+            images,
+            registry: Registry::default(),
+            generated: Generated::default(),
+        }
+    }
+
+    /// Manifest `reference` (tag or digest) of `image`.
+    async fn manifest(
+        &self,
+        client: &reqwest::Client,
+        image: &Reference,
+        reference: &str,
+    ) -> Result<Vec<u8>> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            image.api_base(),
+            image.repository,
+            reference
+        );
+        let response = self
+            .registry
+            .get(client, &url, &image.repository, MANIFEST_TYPES)
+            .await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Objects of manifest `data` under `prefix`, which are the manifest
+    /// itself by digest and blobs it references, and digests of manifests of
+    /// selected platforms if it's an index.
+    fn manifest_objects(&self, prefix: &str, data: Vec<u8>) -> Result<(Objects, Vec<String>)> {
+        let manifest: Manifest = serde_json::from_slice(&data)?;
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(&data));
+        let mut objects = vec![];
+        for blob in manifest.config.iter().chain(&manifest.layers) {
+            objects.push((
+                by_digest(
+                    format!("{}blobs/{}", prefix, blob.digest),
+                    &blob.digest,
+                    blob.size,
+                ),
+                None,
+            ));
+        }
+        let children = manifest
+            .manifests
+            .into_iter()
+            .filter(|x| is_selected(&self.config.platforms, x.platform.as_ref()))
+            .map(|x| x.digest)
+            .collect();
+        let snapshot = by_digest(
+            format!("{}manifests/{}", prefix, digest),
+            &digest,
+            data.len() as u64,
+        );
+        objects.push((snapshot, Some(data)));
+        Ok((objects, children))
+    }
+
+    /// Objects of `image`, with manifest of its tag last.
+    async fn image(&self, client: &reqwest::Client, image: &Reference) -> Result<Objects> {
+        let prefix = format!("v2/{}/", image.repository);
+        let data = self.manifest(client, image, &image.tag).await?;
+        let tag = if image.is_digest() {
+            None
+        } else {
+            let snapshot = SnapshotMeta {
+                size: Some(data.len() as u64),
+                ..SnapshotMeta::force(format!("{}manifests/{}", prefix, image.tag))
+            };
+            Some((snapshot, Some(data.clone())))
+        };
+        let (mut objects, mut children) = self.manifest_objects(&prefix, data)?;
+        while let Some(digest) = children.pop() {
+            let data = self.manifest(client, image, &digest).await?;
+            let (child_objects, child_children) = self.manifest_objects(&prefix, data)?;
+            objects.extend(child_objects);
+            children.extend(child_children);
+        }
+        objects.extend(tag);
+        Ok(objects)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Oci {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(
+            logger,
+            "fetching manifests of {} images...",
+            self.images.len()
+        );
+        progress.set_length(self.images.len() as u64);
+        let this = &*self;
+        let results: Vec<Objects> = stream::iter(self.images.clone())
+            .map(|image| {
+                let client = client.clone();
+                let progress = progress.clone();
+                async move {
+                    progress.set_message(&format!("{}:{}", image.repository, image.tag));
+                    let result = this.image(&client, &image).await;
+                    progress.inc(1);
+                    result
+                }
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        // blobs are shared by images, and manifests of tags are kept last
+        let mut keys = HashSet::new();
+        let mut snapshot = vec![];
+        let mut tags = vec![];
+        for (object, content) in results.into_iter().flatten() {
+            if !keys.insert(object.key.clone()) {
+                continue;
+            }
+            if let Some(content) = content {
+                self.generated.0.insert(object.key.clone(), content);
+            }
+            if object.flags.force_last {
+                tags.push(object);
+            } else {
+                snapshot.push(object);
+            }
+        }
+        snapshot.extend(tags);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("oci, {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Oci {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.generated.0.get(&snapshot.key).cloned())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Oci {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<TransferURL> {
+        let image = self
+            .images
+            .iter()
+            .find(|image| {
+                snapshot
+                    .key
+                    .strip_prefix("v2/")
+                    .and_then(|x| x.strip_prefix(&image.repository))
+                    .is_some_and(|x| x.starts_with("/blobs/"))
+            })
+            .ok_or_else(|| Error::ProcessError(format!("unknown blob {}", snapshot.key)))?;
+        let url = format!("{}/{}", image.api_base(), snapshot.key);
+        // blobs may be served by registry itself instead of a CDN, so the
+        // token is sent in transfer as well, and is dropped on redirect
+        if let Some(token) = self
+            .registry
+            .token(&mission.client, &url, &image.repository)
+            .await?
+        {
+            crate::auth::add_prefix_credential(
+                format!("{}/v2/{}/", image.api_base(), image.repository),
+                Credential::Bearer(token),
+            );
+        }
+        Ok(TransferURL(url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::{parse_reference, Oci, OciConfig, Reference};
+
+    #[test]
+    fn test_parse_reference() {
+        let reference = |registry: &str, repository: &str, tag: &str| Reference {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            tag: tag.to_string(),
+        };
+        assert_eq!(
+            parse_reference("alpine").unwrap(),
+            reference("docker.io", "library/alpine", "latest")
+        );
+        assert_eq!(
+            parse_reference("bitnami/redis:7.2").unwrap(),
+            reference("docker.io", "bitnami/redis", "7.2")
+        );
+        assert_eq!(
+            parse_reference("ghcr.io/owner/image@sha256:00").unwrap(),
+            reference("ghcr.io", "owner/image", "sha256:00")
+        );
+        assert_eq!(
+            parse_reference("localhost:5000/image").unwrap(),
+            reference("localhost:5000", "image", "latest")
+        );
+        assert_eq!(
+            parse_reference("alpine").unwrap().api_base(),
+            "https://registry-1.docker.io"
+        );
+        assert!(parse_reference("alpine:").is_err());
+    }
+
+    #[test]
+    fn test_manifest_objects() {
+        let oci = Oci::new(OciConfig::from_iter([
+            "oci",
+            "--image",
+            "alpine:3.19",
+            "--platforms",
+            "linux/amd64,linux/arm64/v8",
+        ]));
+        let index = br#"{"schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {"digest": "sha256:aa", "size": 1, "platform": {"architecture": "amd64", "os": "linux"}},
+                {"digest": "sha256:bb", "size": 1, "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}},
+                {"digest": "sha256:cc", "size": 1, "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}},
+                {"digest": "sha256:dd", "size": 1, "platform": {"architecture": "unknown", "os": "unknown"}}
+            ]}"#;
+        let (objects, children) = oci
+            .manifest_objects("v2/library/alpine/", index.to_vec())
+            .unwrap();
+        assert_eq!(children, vec!["sha256:aa", "sha256:bb"]);
+        assert_eq!(objects.len(), 1);
+        assert!(objects[0]
+            .0
+            .key
+            .starts_with("v2/library/alpine/manifests/sha256:"));
+        assert_eq!(
+            objects[0].0.key.rsplit_once("sha256:").map(|x| x.1),
+            objects[0].0.checksum.as_deref()
+        );
+
+        let manifest = br#"{"schemaVersion": 2,
+            "config": {"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:01", "size": 10},
+            "layers": [{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:02", "size": 20}]}"#;
+        let (objects, children) = oci
+            .manifest_objects("v2/library/alpine/", manifest.to_vec())
+            .unwrap();
+        assert!(children.is_empty());
+        let keys: Vec<_> = objects.iter().map(|(x, _)| x.key.as_str()).collect();
+        assert_eq!(
+            &keys[..2],
+            &[
+                "v2/library/alpine/blobs/sha256:01",
+                "v2/library/alpine/blobs/sha256:02"
+            ]
+        );
+        assert_eq!(objects[1].0.size, Some(20));
+        assert_eq!(objects[1].0.checksum.as_deref(), Some("02"));
+        assert!(objects[0].1.is_none() && objects[2].1.is_some());
+    }
+}
@@ -1,21 +1,34 @@
+use crate::apt::AptConfig;
+use crate::auth::{Credential, Credentials, Secret};
+use crate::checksum_db::{ChecksumDb, Fsck};
+use crate::compare::CompareTargets;
 use crate::conda::CondaConfig;
 use crate::crates_io::CratesIo as CratesIoConfig;
 use crate::dart::Dart;
 use crate::file_backend::FileBackend;
+use crate::file_view::FileView;
 use crate::ghcup::Ghcup as GhcupConfig;
 use crate::github_release::GitHubRelease;
 use crate::gradle::Gradle;
 use crate::homebrew::HomebrewConfig;
 use crate::lean::elan::ElanConfig;
+use crate::lockfile::Lockfile;
+use crate::oci::OciConfig;
 use crate::pypi::Pypi as PypiConfig;
 use crate::rsync::Rsync as RsyncConfig;
 use crate::rustup::Rustup as RustupConfig;
+use crate::transfer::Strategy;
+use crate::utils::HttpHeader;
+use crate::yum::YumConfig;
+use crate::zypper::Zypper;
 use crate::{
     error::{Error, Result},
     s3::S3Backend,
 };
 use structopt::StructOpt;
 
+// parsed once from command line, so the size of variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 pub enum Source {
     #[structopt(about = "PyPI index")]
@@ -40,6 +53,169 @@ pub enum Source {
     Rustup(RustupConfig),
     #[structopt(about = "elan")]
     Elan(ElanConfig),
+    #[structopt(about = "APT repositories and PPAs")]
+    Apt(AptConfig),
+    #[structopt(about = "yum and dnf repositories")]
+    Yum(YumConfig),
+    #[structopt(about = "openSUSE repositories")]
+    Zypper(Zypper),
+    #[structopt(about = "OCI container images")]
+    Oci(OciConfig),
+    #[structopt(about = "Artifacts referenced by lockfiles")]
+    Lockfile(Lockfile),
+    #[structopt(about = "Check files of file backend against checksum database")]
+    Fsck(Fsck),
+    #[structopt(about = "Compare target with a secondary target")]
+    CompareTargets(CompareTargets),
+}
+
+impl Source {
+    /// Name of source, same as its subcommand.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Pypi(_) => "pypi",
+            Source::Homebrew(_) => "homebrew",
+            Source::CratesIo(_) => "crates-io",
+            Source::Conda(_) => "conda",
+            Source::Rsync(_) => "rsync",
+            Source::GithubRelease(_) => "github-release",
+            Source::DartPub(_) => "dart-pub",
+            Source::Ghcup(_) => "ghcup",
+            Source::Gradle(_) => "gradle",
+            Source::Rustup(_) => "rustup",
+            Source::Elan(_) => "elan",
+            Source::Apt(_) => "apt",
+            Source::Yum(_) => "yum",
+            Source::Zypper(_) => "zypper",
+            Source::Oci(_) => "oci",
+            Source::Lockfile(_) => "lockfile",
+            Source::Fsck(_) => "fsck",
+            Source::CompareTargets(_) => "compare-targets",
+        }
+    }
+
+    /// Default concurrency of snapshot and transfer, tuned to tolerance of
+    /// each upstream. Returns `(concurrent_resolve, concurrent_transfer)`.
+    pub fn default_concurrency(&self) -> (usize, usize) {
+        match self {
+            // PyPI simple index and files are served by CDN
+            Source::Pypi(_) => (64, 16),
+            Source::Homebrew(_) => (8, 8),
+            Source::CratesIo(_) => (8, 32),
+            Source::Conda(_) => (8, 4),
+            Source::Rsync(_) => (1, 8),
+            // GitHub API and release downloads are rate-limited
+            Source::GithubRelease(_) => (4, 4),
+            Source::DartPub(_) => (16, 8),
+            Source::Ghcup(_) => (8, 4),
+            Source::Gradle(_) => (8, 8),
+            Source::Rustup(_) => (16, 8),
+            Source::Elan(_) => (4, 4),
+            Source::Apt(_) => (4, 8),
+            Source::Yum(_) => (4, 8),
+            Source::Zypper(_) => (4, 8),
+            // Docker Hub limits pulls of manifests
+            Source::Oci(_) => (4, 4),
+            Source::Lockfile(_) => (16, 8),
+            Source::Fsck(_) => (1, 1),
+            Source::CompareTargets(_) => (1, 1),
+        }
+    }
+
+    /// Whether some objects of source are generated from its snapshot, which
+    /// workers of distributed transfer never take.
+    pub fn generates_content(&self) -> bool {
+        match self {
+            Source::Pypi(config) => config.simple_index || config.status_files,
+            // other sources are wrapped in `IndexPipe`, generating index pages
+            _ => true,
+        }
+    }
+
+    /// Hosts of upstream given on command line, which credentials are sent
+    /// to if `--auth-host` is not set.
+    pub fn upstream_hosts(&self) -> Vec<String> {
+        let bases: Vec<&str> = match self {
+            Source::Pypi(config) => config
+                .simple_base
+                .iter()
+                .chain(config.package_base.iter())
+                .map(String::as_str)
+                .collect(),
+            Source::CratesIo(config) => std::iter::once(config.crates_base.as_str())
+                .chain(config.sparse_index.as_deref())
+                .collect(),
+            Source::Conda(config) => config.channels.iter().map(|x| x.base.as_str()).collect(),
+            Source::Rsync(config) => vec![&config.http_base],
+            Source::DartPub(config) => vec![&config.base],
+            Source::Gradle(config) => vec![&config.api_base, &config.distribution_base],
+            Source::Rustup(config) => vec![&config.base],
+            Source::Yum(config) => config.repos.iter().map(|x| x.base.as_str()).collect(),
+            Source::Zypper(config) => vec![&config.base],
+            _ => vec![],
+        };
+        let mut hosts: Vec<String> = bases
+            .into_iter()
+            .filter_map(|base| reqwest::Url::parse(base).ok())
+            .filter_map(|url| url.host_str().map(str::to_string))
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+}
+
+impl Opts {
+    /// Check options which can't be checked by parser.
+    pub fn validate(&self) -> Result<()> {
+        let config = &self.transfer_config;
+        if config.coordinator_listen.is_some() || config.coordinator_url.is_some() {
+            if config.coordinator_secret.is_none() {
+                return Err(Error::ConfigureError(
+                    "coordinator secret is required by distributed transfer".to_string(),
+                ));
+            }
+            if self.source.generates_content() {
+                return Err(Error::ConfigureError(format!(
+                    "{} generates content from snapshot, which is not supported by distributed transfer",
+                    self.source.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn credentials(&self) -> Result<Credentials> {
+        let mut credentials = Credentials {
+            auth_hosts: if self.auth_hosts.is_empty() {
+                self.source.upstream_hosts()
+            } else {
+                self.auth_hosts.clone()
+            },
+            ..Default::default()
+        };
+        if let Some(Secret(basic)) = &self.auth_basic {
+            credentials.credential = Some(match basic.split_once(':') {
+                Some((user, password)) => {
+                    Credential::Basic(user.to_string(), Some(password.to_string()))
+                }
+                None => Credential::Basic(basic.to_string(), None),
+            });
+        }
+        if let Some(Secret(token)) = &self.auth_bearer {
+            credentials.credential = Some(Credential::Bearer(token.to_string()));
+        }
+        if credentials.credential.is_some() && credentials.auth_hosts.is_empty() {
+            return Err(Error::ConfigureError(format!(
+                "--auth-host is required by {}, which has no upstream host on command line",
+                self.source.name()
+            )));
+        }
+        if let Some(netrc) = &self.netrc {
+            credentials.load_netrc(netrc)?;
+        }
+        Ok(credentials)
+    }
 }
 
 #[derive(Debug)]
@@ -60,13 +236,26 @@ impl From<S3CliConfig> for S3Backend {
         }
         s3_config.max_keys = config.s3_max_keys;
         s3_config.prefix_hint_mode = config.s3_prefix_hint_mode;
+        s3_config.sse = config.s3_sse;
+        s3_config.sse_kms_key_id = config.s3_sse_kms_key_id;
+        s3_config.request_payer = config.s3_request_payer;
+        s3_config.multipart_threshold = config.s3_multipart_threshold;
+        s3_config.virtual_host = config.s3_virtual_host;
+        // S3 rejects parts smaller than 5 MiB
+        s3_config.multipart_part_size = config.s3_multipart_part_size.max(5 << 20);
         S3Backend::new(s3_config)
     }
 }
 
 impl From<FileBackendConfig> for FileBackend {
     fn from(config: FileBackendConfig) -> Self {
-        FileBackend::new(config.file_base_path.unwrap())
+        let mut backend = FileBackend::new(config.file_base_path.unwrap());
+        backend.atomic_index = config.file_atomic_index;
+        backend.views = config.file_view;
+        backend.checksum_db = config.file_checksum_db.map(|path| {
+            std::sync::Arc::new(ChecksumDb::open(&path).expect("failed to open checksum database"))
+        });
+        backend
     }
 }
 
@@ -86,6 +275,37 @@ pub struct S3CliConfig {
     pub s3_max_keys: u64,
     #[structopt(long, help = "Scan metadata (Greatly increase requests)")]
     pub s3_scan_metadata: bool,
+    #[structopt(
+        long,
+        help = "Server-side encryption of uploaded objects (AES256 or aws:kms)",
+        possible_values = &["AES256", "aws:kms"]
+    )]
+    pub s3_sse: Option<String>,
+    #[structopt(
+        long,
+        help = "KMS key id for aws:kms server-side encryption",
+        requires = "s3_sse"
+    )]
+    pub s3_sse_kms_key_id: Option<String>,
+    #[structopt(long, help = "Send requester-pays header with all requests")]
+    pub s3_request_payer: bool,
+    #[structopt(
+        long,
+        help = "Upload objects larger than this size in bytes in parts",
+        default_value = "5368709120"
+    )]
+    pub s3_multipart_threshold: u64,
+    #[structopt(
+        long,
+        help = "Part size in bytes of multipart upload, at least 5 MiB",
+        default_value = "67108864"
+    )]
+    pub s3_multipart_part_size: u64,
+    #[structopt(
+        long,
+        help = "Address bucket in virtual-hosted style (<bucket>.<endpoint>) instead of path style"
+    )]
+    pub s3_virtual_host: bool,
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -102,6 +322,23 @@ pub struct FileBackendConfig {
         required_if("target_type", "file")
     )]
     pub file_buffer_path: Option<String>,
+    #[structopt(
+        long,
+        help = "SQLite database recording size and checksum of files written by file backend"
+    )]
+    pub file_checksum_db: Option<String>,
+    #[structopt(
+        long,
+        help = "Stage index objects, and move them into place after all objects are updated",
+        conflicts_with_all = &["coordinator_listen", "coordinator_url"]
+    )]
+    pub file_atomic_index: bool,
+    #[structopt(
+        long,
+        help = "Link objects matching regex at another path, as <pattern>=<template>",
+        number_of_values = 1
+    )]
+    pub file_view: Vec<FileView>,
 }
 
 impl std::str::FromStr for Target {
@@ -118,8 +355,8 @@ impl std::str::FromStr for Target {
 
 #[derive(StructOpt, Debug)]
 pub struct TransferConfig {
-    #[structopt(long, help = "Concurrent transfer tasks", default_value = "8")]
-    pub concurrent_transfer: usize,
+    #[structopt(long, help = "Concurrent transfer tasks (default depends on source)")]
+    pub concurrent_transfer: Option<usize>,
     #[structopt(long, help = "Don't delete files")]
     pub no_delete: bool,
     #[structopt(long, help = "Enable dry run mode")]
@@ -132,6 +369,113 @@ pub struct TransferConfig {
     pub print_plan: usize,
     #[structopt(long, help = "Force transfer all objects")]
     pub force_all: bool,
+    #[structopt(
+        long,
+        help = "Transfer strategy (full-diff, incremental, verify, repair, database)",
+        default_value = "full-diff"
+    )]
+    pub strategy: Strategy,
+    #[structopt(
+        long,
+        help = "Manifest of previous run, for incremental transfer",
+        required_if("strategy", "incremental")
+    )]
+    pub manifest_path: Option<String>,
+    #[structopt(long, help = "Record operations in a write-ahead journal at this path")]
+    pub journal_path: Option<String>,
+    #[structopt(
+        long,
+        help = "SQLite database of snapshots, for database transfer",
+        required_if("strategy", "database")
+    )]
+    pub snapshot_db: Option<String>,
+    #[structopt(
+        long,
+        help = "Link objects with identical content instead of transferring"
+    )]
+    pub dedup: bool,
+    #[structopt(
+        long,
+        help = "Persistent queue of objects to update, for retrying failures"
+    )]
+    pub queue_path: Option<String>,
+    #[structopt(
+        long,
+        help = "Max attempts of each object in queue",
+        default_value = "3"
+    )]
+    pub max_attempts: u64,
+    #[structopt(
+        long,
+        help = "Delay in seconds before first retry in queue, doubled after each attempt",
+        default_value = "30"
+    )]
+    pub retry_delay: u64,
+    #[structopt(
+        long,
+        help = "Serve objects to update to workers at this address, e.g. 0.0.0.0:8000"
+    )]
+    pub coordinator_listen: Option<String>,
+    #[structopt(
+        long,
+        help = "Run as a worker, pulling objects to update from coordinator at this URL",
+        conflicts_with = "coordinator_listen"
+    )]
+    pub coordinator_url: Option<String>,
+    #[structopt(
+        long,
+        help = "Secret shared by coordinator and workers",
+        env = "MIRROR_CLONE_COORDINATOR_SECRET",
+        hide_env_values = true
+    )]
+    pub coordinator_secret: Option<Secret>,
+    #[structopt(
+        long,
+        help = "Load state files from target before transfer, and save them back after transfer"
+    )]
+    pub state_on_target: bool,
+    #[structopt(
+        long,
+        help = "Only take snapshot of source, and report number and size of objects"
+    )]
+    pub estimate: bool,
+    #[structopt(
+        long,
+        help = "Objects without size to sample in estimate mode",
+        default_value = "100"
+    )]
+    pub estimate_samples: usize,
+    #[structopt(
+        long,
+        help = "Write objects missing or different on target to this file, for verify transfer"
+    )]
+    pub verify_report: Option<String>,
+    #[structopt(
+        long,
+        help = "Transfer objects listed in this corruption report again, skipping snapshot and diff",
+        conflicts_with = "coordinator_url"
+    )]
+    pub repair_from: Option<String>,
+    #[structopt(
+        long,
+        help = "Compare N random objects on target with upstream, and estimate corruption rate",
+        conflicts_with = "coordinator_url"
+    )]
+    pub audit_sample: Option<usize>,
+    #[structopt(
+        long,
+        help = "Publish newly added objects in a feed under .mirror-clone/ on target"
+    )]
+    pub feed: bool,
+    #[structopt(long, help = "Max items in feed", default_value = "500")]
+    pub feed_size: usize,
+    #[structopt(long, help = "Base URL of mirror, for links in feed")]
+    pub feed_base_url: Option<String>,
+    #[structopt(
+        long,
+        help = "Publish statistics and dashboard under .mirror-clone/ on target"
+    )]
+    pub stats: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -147,10 +491,62 @@ pub struct Opts {
     pub file_config: FileBackendConfig,
     #[structopt(long, help = "Enable progress bar")]
     pub progress: bool,
+    #[structopt(
+        long,
+        help = "Disable progress bar, and log a status line every progress interval"
+    )]
+    pub quiet: bool,
+    #[structopt(
+        long,
+        help = "Seconds between status lines of transfer progress (60 with --quiet)"
+    )]
+    pub progress_interval: Option<u64>,
     #[structopt(long, help = "Worker threads")]
     pub workers: Option<usize>,
-    #[structopt(long, help = "Concurrent resolve tasks", default_value = "64")]
-    pub concurrent_resolve: usize,
+    #[structopt(
+        long,
+        help = "Concurrent resolve tasks in snapshot (default depends on source)"
+    )]
+    pub concurrent_resolve: Option<usize>,
+    #[structopt(
+        long,
+        help = "Checkpoint partially built snapshot of source to this file, and resume from it"
+    )]
+    pub snapshot_checkpoint: Option<String>,
+    #[structopt(
+        long,
+        help = "User-Agent of requests to upstream (default identifies MIRROR_CLONE_SITE)"
+    )]
+    pub user_agent: Option<String>,
+    #[structopt(
+        long = "header",
+        help = "Extra header of requests to upstream, in `Name: value` form",
+        number_of_values = 1
+    )]
+    pub headers: Vec<HttpHeader>,
+    #[structopt(
+        long,
+        help = "Basic auth of upstream, in `user:password` form",
+        env = "MIRROR_CLONE_AUTH_BASIC",
+        hide_env_values = true
+    )]
+    pub auth_basic: Option<Secret>,
+    #[structopt(
+        long,
+        help = "Bearer token of upstream",
+        env = "MIRROR_CLONE_AUTH_BEARER",
+        hide_env_values = true,
+        conflicts_with = "auth_basic"
+    )]
+    pub auth_bearer: Option<Secret>,
+    #[structopt(
+        long = "auth-host",
+        help = "Send basic auth or bearer token to this host instead of hosts of upstream bases",
+        number_of_values = 1
+    )]
+    pub auth_hosts: Vec<String>,
+    #[structopt(long, help = "Read credentials of upstream hosts from this netrc file")]
+    pub netrc: Option<String>,
     #[structopt(flatten)]
     pub transfer_config: TransferConfig,
 }
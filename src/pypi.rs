@@ -4,11 +4,35 @@
 //! scanning the package index, then scanning index of every package. This only takes
 //! about 5 minutes on SJTUG server, where we fetch data from TUNA mirrors.
 //! A PyPI link may contain checksum in its URL, and when taking snapshot, this source
-//! will remove checksums from URL.
+//! will remove checksums from URL, and keep sha256 digests in the snapshot, so that
+//! downloaded files are verified by `ChecksumPipe`. Metadata files of
+//! distributions (PEP 658) advertised by upstream are mirrored along with them.
 //!
-//! Pypi supports path snapshot, and TransferURL source object.
+//! Pypi supports meta snapshot, and TransferURL source object.
+//!
+//! When `--status-files` is set, Pypi also generates bandersnatch-style status
+//! files at the root of the target: `last-serial` holds the PyPI serial the
+//! mirror was synchronized to, `web/last-modified` holds the time of the sync,
+//! and `web/serial/<project>` holds the serial of every mirrored project.
+//! As bandersnatch, `status` holds the serial and `generation` holds the
+//! layout generation of the mirror, and `status.json` summarizes the sync.
+//!
+//! When `--simple-index` is set, Pypi also generates a simple index describing
+//! exactly the mirrored files under `simple/`, which is useful when only part
+//! of PyPI is mirrored.
+//!
+//! When `--json-api` is set, Pypi also mirrors the JSON API of every project at
+//! `pypi/<project>/json`. Documents are fetched when transferring, and
+//! rewritten to only list mirrored files.
+//!
+//! When `--incremental-state` is set, Pypi saves the snapshot together with the
+//! changelog serial of PyPI. Later runs only scan projects changed since that
+//! serial, and reuse the saved index of other projects.
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
@@ -21,18 +45,27 @@ use google_bigquery2::oauth2::{
 };
 use google_bigquery2::{hyper, Bigquery};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
-use regex::Regex;
+use regex::{Regex, RegexSet};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use slog::{info, warn, Logger};
 use structopt::StructOpt;
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::checkpoint::Checkpoint;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
-use crate::python_version::Version;
-use crate::traits::{SnapshotStorage, SourceStorage};
-use crate::utils::bar;
+use crate::lockfile::parse_python_lockfile;
+use crate::metadata::SnapshotMeta;
+use crate::pypi_changelog;
+use crate::pypi_ranking;
+use crate::python_version::{SpecifierSet, Version};
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
+use crate::utils::{bar, glob_regex};
+use crate::wheel_name::{split_name_version, SdistName, WheelName, SDIST_EXTENSIONS};
 
+/// Default ranking query, where `{days}` and `{limit}` are replaced by `--bq-days` and
+/// `--bq-limit`.
 const BQ_QUERY: &str = r#"
     SELECT file.project, COUNT(*) AS num_downloads
     FROM `bigquery-public-data.pypi.file_downloads`
@@ -40,11 +73,11 @@ const BQ_QUERY: &str = r#"
       details.installer.name = 'pip'
       AND
       DATE(timestamp)
-        BETWEEN DATE_SUB(CURRENT_DATE(), INTERVAL 1 DAY)
+        BETWEEN DATE_SUB(CURRENT_DATE(), INTERVAL {days} DAY)
         AND CURRENT_DATE()
     GROUP BY file.project
     ORDER BY num_downloads DESC
-    LIMIT 1000;
+    LIMIT {limit};
     "#;
 
 #[derive(Debug, Clone, StructOpt)]
@@ -53,57 +86,313 @@ pub struct Pypi {
     #[structopt(
         long,
         default_value = "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/simple",
-        help = "Base of simple index"
+        number_of_values = 1,
+        help = "Base of simple index, repeated for fallback bases tried in order"
     )]
-    pub simple_base: String,
+    pub simple_base: Vec<String>,
     /// Base of package base
     #[structopt(
         long,
         default_value = "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/packages",
-        help = "Base of package index"
+        number_of_values = 1,
+        help = "Base of package index, repeated for fallback bases tried in order"
     )]
-    pub package_base: String,
-    /// When set, the source will query bigquery for indexing and only the first 1000 most
-    /// downloaded packages will be selected.
+    pub package_base: Vec<String>,
+    /// Fall back to official PyPI (`pypi.org` and `files.pythonhosted.org`) after all
+    /// `--simple-base` and `--package-base`, so that projects not yet synced by upstream mirrors
+    /// are still mirrored.
+    #[structopt(long)]
+    pub official_fallback: bool,
+    /// When set, the source will query bigquery for indexing and only the most downloaded
+    /// packages (1000 in the last day by default) will be selected.
     /// Please consider adding `--no-delete` parameter on simple diff transfer to avoid clearing
     /// previous cache.
     #[structopt(long)]
     pub bq_query: bool,
-    /// Only keep recent N versions per package.
+    /// Only the N most downloaded packages will be selected, according to download statistics
+    /// published at `--top-downloads-url`. Unlike `--bq-query`, this requires no credentials.
+    #[structopt(long, conflicts_with = "bq-query")]
+    pub top_downloads: Option<usize>,
+    /// Download statistics of PyPI, as JSON of top-pypi-packages.
+    #[structopt(
+        long,
+        default_value = "https://hugovk.github.io/top-pypi-packages/top-pypi-packages-30-days.min.json"
+    )]
+    pub top_downloads_url: String,
+    /// Select projects most downloaded from our own mirror, counted from these nginx (combined
+    /// format) or Caddy (JSON format) access logs. Logs ending with `.gz` are decompressed.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with_all = &["bq-query", "top-downloads", "from-lockfiles"]
+    )]
+    pub ranking_from_logs: Vec<String>,
+    /// Select this many most downloaded projects with `--ranking-from-logs`.
+    #[structopt(long, default_value = "1000")]
+    pub ranking_limit: usize,
+    /// Count downloads of this many days in bigquery mode.
+    #[structopt(long, default_value = "1")]
+    pub bq_days: u64,
+    /// Select this many most downloaded packages in bigquery mode.
+    #[structopt(long, default_value = "1000")]
+    pub bq_limit: u64,
+    /// Run SQL in this file instead of the default ranking query in bigquery mode. The first
+    /// column of its result should be project names. `{days}` and `{limit}` in the file are
+    /// replaced by `--bq-days` and `--bq-limit`.
+    #[structopt(long)]
+    pub bq_query_file: Option<String>,
+    /// In bigquery mode, also mirror dependencies of selected projects, transitively. Dependencies
+    /// are read from `requires_dist` of latest releases in JSON API (`--json-api-base`), and
+    /// optional dependencies (extras) are not included.
+    #[structopt(long)]
+    pub bq_with_deps: bool,
+    /// Cache project list of bigquery in this file, so that runs within `--bq-cache-ttl` don't
+    /// execute the query again.
+    #[structopt(long)]
+    pub bq_cache: Option<String>,
+    /// Max age of bigquery cache, e.g. `3600`, `30m`, `24h` or `7d`.
+    #[structopt(long, default_value = "24h", parse(try_from_str = parse_duration))]
+    pub bq_cache_ttl: Duration,
+    /// Only keep recent N versions per package. Versions are counted separately for wheels of
+    /// each python and platform tag, and for source distributions.
     /// Please consider adding `--no-delete` parameter on simple diff transfer to avoid clearing
     /// previous cache.
     #[structopt(long)]
     pub keep_recent: Option<usize>,
+    /// Override `--keep-recent` for some packages with a TOML file mapping project names or glob
+    /// patterns to numbers of versions, e.g. `numpy = 50` or `"scikit-*" = 10`. Exact names take
+    /// precedence over patterns, and longer patterns over shorter ones.
+    #[structopt(long)]
+    pub keep_recent_overrides: Option<String>,
+    /// Only mirror files pinned by these lockfiles (`requirements.txt`, `poetry.lock` or
+    /// `uv.lock`), i.e. files of pinned versions, restricted to pinned hashes if given.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with_all = &["bq-query", "top-downloads"]
+    )]
+    pub from_lockfiles: Vec<String>,
+    /// Never prune files of versions pinned by these files from `--keep-recent` or
+    /// `--keep-since`, e.g. versions used by CI images. Each file is either `project==version`
+    /// lines (as `requirements.txt`), `poetry.lock` or `uv.lock`.
+    #[structopt(long, use_delimiter = true)]
+    pub pin_file: Vec<String>,
+    /// Keep all files uploaded within this duration, e.g. `90d` or `2y`, according to upload
+    /// time in JSON simple index (PEP 700). Together with `--keep-recent`, files satisfying
+    /// either are kept. Files of unknown upload time are always kept.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub keep_since: Option<Duration>,
+    /// Request JSON simple index (PEP 691), and use upload time of files as their last modified
+    /// time in the snapshot. Objects recorded with another modified time on the target will be
+    /// transferred again.
+    #[structopt(long)]
+    pub use_upload_time: bool,
     /// When debug mode is enabled, only first 1000 packages will be selected.
     /// Please add `--no-delete` parameter on simple diff transfer when enabling
     /// debug mode on a production endpoint.
     #[structopt(long)]
     pub debug: bool,
+    /// Generate bandersnatch-compatible status files at the root of the target.
+    #[structopt(long)]
+    pub status_files: bool,
+    /// Drop files yanked by upstream (with `data-yanked` attribute in simple index) from the
+    /// snapshot.
+    #[structopt(long)]
+    pub exclude_yanked: bool,
+    /// Only mirror files installable by these python versions (e.g. `3.9,3.10`), according to
+    /// `data-requires-python` attribute in simple index. Files without it are always mirrored.
+    #[structopt(long, use_delimiter = true)]
+    pub python_versions: Vec<String>,
+    /// Generate simple index (PEP 503 HTML at `simple/<project>/index.html`, and PEP 691 JSON at
+    /// `simple/<project>/index.v1_json`) of mirrored files at the root of the target, so that
+    /// pip can use the target directly when the mirror is truncated.
+    #[structopt(long)]
+    pub simple_index: bool,
+    /// Only mirror projects listed in this file, one name or glob pattern (e.g. `scikit-*`) per
+    /// line.
+    #[structopt(long)]
+    pub package_list: Option<String>,
+    /// Don't mirror projects listed in this file, one name or glob pattern per line.
+    #[structopt(long)]
+    pub package_exclude: Option<String>,
+    /// Only mirror wheels.
+    #[structopt(long)]
+    pub only_wheels: bool,
+    /// Don't mirror pre-releases and development releases (e.g. `2.0a1`, `2.0rc1` or
+    /// `2.0.dev1`), regardless of `--keep-recent`.
+    #[structopt(long)]
+    pub no_prereleases: bool,
+    /// Don't mirror source distributions.
+    #[structopt(long)]
+    pub no_sdist: bool,
+    /// Only mirror wheels of these platform tags (PEP 425) or glob patterns, e.g.
+    /// `manylinux2014_x86_64,macosx_*`. Pure python wheels are always mirrored, and legacy
+    /// installers (`.exe`, `.msi` and `.dmg`) are dropped.
+    #[structopt(long, use_delimiter = true)]
+    pub platform_tags: Vec<String>,
+    /// Skip files larger than this size in bytes. Sizes are taken from JSON simple index
+    /// (PEP 700) if upstream supports it, or from HEAD requests otherwise.
+    #[structopt(long)]
+    pub max_file_size: Option<u64>,
+    /// Keep snapshot and changelog serial of PyPI in this file. On next run, only projects
+    /// changed since then (according to changelog of XML-RPC API) are scanned again.
+    #[structopt(long)]
+    pub incremental_state: Option<String>,
+    /// Keep ETag and Last-Modified of project pages in this file, and send conditional requests
+    /// on next run, so that files of unchanged projects are reused.
+    #[structopt(long)]
+    pub page_cache: Option<String>,
+    /// XML-RPC API of PyPI, used by incremental snapshot.
+    #[structopt(long, default_value = "https://pypi.org/pypi")]
+    pub xmlrpc_url: String,
+    /// Mirror JSON API of every project (`/pypi/<project>/json`) at `pypi/<project>/json` of the
+    /// target, with URLs of files rewritten to the mirror, and files not mirrored removed.
+    #[structopt(long)]
+    pub json_api: bool,
+    /// Base of JSON API.
+    #[structopt(long, default_value = "https://pypi.org/pypi")]
+    pub json_api_base: String,
+    /// URL of mirrored packages used in JSON API, e.g. `https://mirror.example.com/pypi/packages/`.
+    /// By default, URLs are relative to the JSON document.
+    #[structopt(long, default_value = "../../")]
+    pub json_api_package_url: String,
+    #[structopt(skip)]
+    status: PypiStatus,
+    #[structopt(skip)]
+    index: SimpleIndex,
+}
+
+/// Mirrored files of each project, with their keys, used to generate simple index and JSON API.
+type SimpleIndex = BTreeMap<String, Vec<(String, PypiFile)>>;
+
+/// Serials collected while taking snapshot, used to generate status files.
+#[derive(Debug, Clone, Default)]
+struct PypiStatus {
+    last_serial: Option<u64>,
+    project_serials: BTreeMap<String, u64>,
+    synced_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Patch releases of a python version considered when matching `requires-python`.
+const PYTHON_MAX_PATCH: u64 = 30;
+
+const LAST_SERIAL_KEY: &str = "last-serial";
+/// Serial of the mirror, read by bandersnatch and tools monitoring it.
+const STATUS_KEY: &str = "status";
+/// Layout generation of bandersnatch mirror.
+const GENERATION_KEY: &str = "generation";
+const BANDERSNATCH_GENERATION: u64 = 5;
+const STATUS_JSON_KEY: &str = "status.json";
+const LAST_MODIFIED_KEY: &str = "web/last-modified";
+const PROJECT_SERIAL_PREFIX: &str = "web/serial/";
+const SIMPLE_PREFIX: &str = "simple/";
+const OFFICIAL_SIMPLE_BASE: &str = "https://pypi.org/simple";
+const OFFICIAL_PACKAGE_BASE: &str = "https://files.pythonhosted.org/packages";
+const SIMPLE_HTML: &str = "index.html";
+const SIMPLE_JSON: &str = "index.v1_json";
+const SIMPLE_JSON_CONTENT_TYPE: &str = "application/vnd.pypi.simple.v1+json";
+const JSON_API_PREFIX: &str = "pypi/";
+const JSON_API_SUFFIX: &str = "/json";
+
+fn serial_from_response(headers: &reqwest::header::HeaderMap, body: &str) -> Option<u64> {
+    static RE_SERIAL: once_cell::sync::Lazy<Regex> =
+        once_cell::sync::Lazy::new(|| Regex::new(r"<!--\s*SERIAL\s+(\d+)\s*-->").unwrap());
+    headers
+        .get("x-pypi-last-serial")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse().ok())
+        .or_else(|| RE_SERIAL.captures(body).and_then(|cap| cap[1].parse().ok()))
+}
+
+/// A page fetched from upstream.
+enum Page {
+    /// URL, headers and content of page.
+    Found(Box<(url::Url, reqwest::header::HeaderMap, String)>),
+    /// Not modified since it's cached.
+    NotModified,
+    NotFound,
+}
+
+/// Fetch `path` from each of `bases` in order, until one of them succeeds.
+/// If `cached` is fetched from the same URL, a conditional request is sent.
+async fn fetch_page(
+    logger: &Logger,
+    client: &Client,
+    bases: &[String],
+    path: &str,
+    accept: Option<&str>,
+    cached: Option<&CachedPage>,
+) -> Result<Page> {
+    let mut last_err = None;
+    for base in bases {
+        let url = url::Url::parse(&format!("{}/{}", base.trim_end_matches('/'), path))
+            .map_err(|err| Error::ConfigureError(err.to_string()))?;
+        let mut request = client.get(url.clone());
+        if let Some(accept) = accept {
+            request = request.header(reqwest::header::ACCEPT, accept);
+        }
+        if let Some(cached) = cached.filter(|cached| cached.url == url.as_str()) {
+            if let Some(etag) = &cached.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let err = match crate::http::send(request).await {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_MODIFIED => {
+                return Ok(Page::NotModified)
+            }
+            Ok(response) if response.status().is_success() => {
+                let headers = response.headers().clone();
+                match response.text().await {
+                    Ok(body) => return Ok(Page::Found(Box::new((url, headers, body)))),
+                    Err(err) => err.into(),
+                }
+            }
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => continue,
+            Ok(response) => Error::HTTPError(response.status()),
+            Err(err) => err.into(),
+        };
+        if bases.len() > 1 {
+            warn!(logger, "failed to fetch {}: {:?}", url, err);
+        }
+        last_err = Some(err);
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => Ok(Page::NotFound),
+    }
 }
 
 async fn pypi_index(
     logger: &Logger,
     client: &Client,
-    simple_base: &str,
+    simple_bases: &[String],
     debug: bool,
-) -> Result<Vec<String>> {
+) -> Result<(Vec<String>, Option<u64>)> {
     info!(logger, "downloading pypi index...");
-    let mut index = client
-        .get(&format!("{}/", simple_base))
-        .send()
-        .await?
-        .text()
-        .await?;
+    let (headers, index) = match fetch_page(logger, client, simple_bases, "", None, None).await? {
+        Page::Found(page) => {
+            let (_, headers, index) = *page;
+            (headers, index)
+        }
+        _ => return Err(Error::HTTPError(reqwest::StatusCode::NOT_FOUND)),
+    };
+    let serial = serial_from_response(&headers, &index);
 
     info!(logger, "parsing index...");
-    let matcher = Regex::new(r#"<a.*href=".*?".*>(.*?)</a>"#).unwrap();
-    if debug {
-        index = index[..1000].to_string();
-    }
-    Ok(matcher
-        .captures_iter(&index)
-        .map(|cap| cap[1].to_string())
-        .collect())
+    let links = crate::utils::parse_link_list(&index);
+    let limit = if debug { 1000 } else { links.len() };
+    Ok((
+        links
+            .into_iter()
+            .filter(|link| link.href().is_some())
+            .take(limit)
+            .map(|link| link.text)
+            .collect(),
+        serial,
+    ))
 }
 
 macro_rules! append_proxy_from_env {
@@ -158,7 +447,202 @@ async fn bigquery_hub() -> Result<Bigquery<ProxyConnector<HttpsConnector<HttpCon
     Ok(Bigquery::new(hyper, auth))
 }
 
-async fn bigquery_index(logger: &Logger) -> Result<Vec<String>> {
+/// Parse a duration in seconds, optionally suffixed by `s`, `m`, `h`, `d`, `w` or `y`
+/// (365 days).
+fn parse_duration(duration: &str) -> std::result::Result<Duration, String> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => duration.split_at(idx),
+        None => (duration, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("invalid unit of duration: {}", duration)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", duration))?;
+    Ok(Duration::from_secs(number * unit))
+}
+
+/// Project list of bigquery, cached on disk.
+#[derive(Serialize, Deserialize)]
+struct BigqueryCache {
+    query: String,
+    created: u64,
+    projects: Vec<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Load project list cached for the same query within `ttl`.
+fn load_bq_cache(path: &str, query: &str, ttl: Duration) -> Option<Vec<String>> {
+    let cache: BigqueryCache = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    if cache.query == query && unix_now().saturating_sub(cache.created) <= ttl.as_secs() {
+        Some(cache.projects)
+    } else {
+        None
+    }
+}
+
+fn save_bq_cache(path: &str, query: String, projects: Vec<String>) -> Result<()> {
+    let cache = BigqueryCache {
+        query,
+        created: unix_now(),
+        projects,
+    };
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_vec(&cache)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TopPackages {
+    rows: Vec<TopPackage>,
+}
+
+#[derive(Deserialize)]
+struct TopPackage {
+    project: String,
+    download_count: u64,
+}
+
+/// Parse download statistics of top-pypi-packages, and take `limit` most downloaded projects.
+fn parse_top_packages(data: &str, limit: usize) -> Result<Vec<String>> {
+    let mut rows = serde_json::from_str::<TopPackages>(data)?.rows;
+    rows.sort_by_key(|row| std::cmp::Reverse(row.download_count));
+    Ok(rows
+        .into_iter()
+        .take(limit)
+        .map(|row| row.project)
+        .collect())
+}
+
+async fn top_downloads_index(
+    logger: &Logger,
+    client: &Client,
+    url: &str,
+    limit: usize,
+) -> Result<Vec<String>> {
+    info!(logger, "downloading download statistics...");
+    let response = crate::http::send(client.get(url)).await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    let projects = parse_top_packages(&response.text().await?, limit)?;
+    info!(logger, "{} most downloaded projects", projects.len());
+    Ok(projects)
+}
+
+#[derive(Deserialize)]
+struct JsonApiProject {
+    info: JsonApiInfo,
+}
+
+#[derive(Deserialize)]
+struct JsonApiInfo {
+    requires_dist: Option<Vec<String>>,
+}
+
+/// Names of required projects in `requires_dist` (PEP 508), excluding
+/// requirements only needed by extras.
+fn parse_requires_dist(requires_dist: &[String]) -> Vec<String> {
+    static RE_NAME: once_cell::sync::Lazy<Regex> =
+        once_cell::sync::Lazy::new(|| Regex::new(r"^\s*([A-Za-z0-9][A-Za-z0-9._-]*)").unwrap());
+    requires_dist
+        .iter()
+        .filter(|requirement| {
+            requirement
+                .split_once(';')
+                .is_none_or(|(_, marker)| !marker.contains("extra"))
+        })
+        .filter_map(|requirement| RE_NAME.captures(requirement))
+        .map(|cap| cap[1].to_string())
+        .collect()
+}
+
+async fn project_dependencies(
+    client: &Client,
+    json_api_base: &str,
+    name: &str,
+) -> Result<Vec<String>> {
+    let url = format!("{}/{}/json", json_api_base.trim_end_matches('/'), name);
+    let response = crate::http::send(client.get(&url)).await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    let project: JsonApiProject = serde_json::from_str(&response.text().await?)?;
+    Ok(parse_requires_dist(
+        &project.info.requires_dist.unwrap_or_default(),
+    ))
+}
+
+/// Add dependencies of `projects` transitively.
+async fn dependency_closure(
+    logger: &Logger,
+    client: &Client,
+    json_api_base: &str,
+    projects: Vec<String>,
+    concurrency: usize,
+) -> Vec<String> {
+    let mut projects = dedup_projects(projects);
+    let mut seen: HashSet<String> = projects.iter().cloned().collect();
+    let mut frontier = projects.clone();
+    let selected = projects.len();
+    info!(logger, "resolving dependencies of {} projects...", selected);
+    while !frontier.is_empty() {
+        let dependencies: Vec<Vec<String>> = stream::iter(frontier)
+            .map(|name| async move {
+                match project_dependencies(client, json_api_base, &name).await {
+                    Ok(dependencies) => dependencies,
+                    Err(err) => {
+                        warn!(
+                            logger,
+                            "failed to resolve dependencies of {}: {:?}", name, err
+                        );
+                        vec![]
+                    }
+                }
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+        frontier = vec![];
+        for dependency in dependencies.into_iter().flatten() {
+            let dependency = normalize_name(&dependency);
+            if seen.insert(dependency.clone()) {
+                projects.push(dependency.clone());
+                frontier.push(dependency);
+            }
+        }
+    }
+    info!(
+        logger,
+        "{} dependencies added to {} projects",
+        projects.len() - selected,
+        selected
+    );
+    projects
+}
+
+/// Fill `{days}` and `{limit}` of bigquery query.
+fn render_bq_query(query: &str, days: u64, limit: u64) -> String {
+    query
+        .replace("{days}", &days.to_string())
+        .replace("{limit}", &limit.to_string())
+}
+
+async fn bigquery_index(logger: &Logger, query: String) -> Result<Vec<String>> {
     info!(logger, "executing bigquery query...");
     let prj_id = env::var("PROJECT_ID").expect("Environment variable PROJECT_ID");
 
@@ -168,7 +652,7 @@ async fn bigquery_index(logger: &Logger) -> Result<Vec<String>> {
         .jobs()
         .query(
             QueryRequest {
-                query: Some(BQ_QUERY.to_string()),
+                query: Some(query),
                 use_legacy_sql: Some(false),
                 ..Default::default()
             },
@@ -191,141 +675,1288 @@ async fn bigquery_index(logger: &Logger) -> Result<Vec<String>> {
         .collect())
 }
 
-fn version_from_filename(filename: &str) -> Option<Version> {
-    static RE_VERSION: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
-        Regex::new(r"^\w+-([\w.-_+]+).*(.tar.gz|tar.bz2|.zip|.whl|.exe|.egg)$").unwrap()
+/// A file listed in simple index of a project.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PypiFile {
+    /// URL of file, without digest.
+    url: String,
+    filename: String,
+    sha256: Option<String>,
+    /// Value of `data-requires-python`.
+    requires_python: Option<String>,
+    /// Reason of yanking (PEP 592), which may be empty.
+    yanked: Option<String>,
+    /// Size of file, only known from JSON simple index (PEP 700) or HEAD requests.
+    #[serde(default)]
+    size: Option<u64>,
+    /// Upload time in RFC 3339, only known from JSON simple index (PEP 700).
+    #[serde(default)]
+    upload_time: Option<String>,
+    /// Sha256 of metadata file at `<url>.metadata` (PEP 658), which may be
+    /// empty if upstream doesn't provide it.
+    #[serde(default)]
+    core_metadata: Option<String>,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Parse files listed in simple index page of a project at `page_url`.
+fn parse_project_page(page_url: &url::Url, page: &str) -> Vec<PypiFile> {
+    crate::utils::parse_link_list(page)
+        .into_iter()
+        .filter_map(|link| {
+            let core_metadata = link
+                .attr("data-core-metadata")
+                .or_else(|| link.attr("data-dist-info-metadata"))
+                .and_then(html_core_metadata);
+            let mut url = page_url.join(link.href()?).ok()?;
+            let sha256 = url
+                .fragment()
+                .and_then(|fragment| fragment.strip_prefix("sha256="))
+                .map(str::to_string);
+            url.set_fragment(None);
+            url.set_query(None);
+            Some(PypiFile {
+                url: url.to_string(),
+                filename: link.text.clone(),
+                sha256,
+                requires_python: link.attr("data-requires-python").map(str::to_string),
+                yanked: link.attr("data-yanked").map(str::to_string),
+                size: None,
+                upload_time: None,
+                core_metadata,
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct JsonProjectPage {
+    files: Vec<JsonFile>,
+}
+
+#[derive(Deserialize)]
+struct JsonFile {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+    #[serde(rename = "requires-python")]
+    requires_python: Option<String>,
+    #[serde(default)]
+    yanked: serde_json::Value,
+    size: Option<u64>,
+    #[serde(rename = "upload-time")]
+    upload_time: Option<String>,
+    #[serde(rename = "core-metadata", default)]
+    core_metadata: serde_json::Value,
+    /// Name of `core-metadata` before PEP 714.
+    #[serde(rename = "dist-info-metadata", default)]
+    dist_info_metadata: serde_json::Value,
+}
+
+/// Parse `core-metadata` of JSON simple index, which is either a boolean or
+/// hashes of metadata file.
+fn json_core_metadata(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(true) => Some(String::new()),
+        Value::Object(hashes) => Some(
+            hashes
+                .get("sha256")
+                .and_then(|x| x.as_str())
+                .unwrap_or_default()
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+/// Parse `data-core-metadata` of simple index, which is either `true` or a
+/// hash of metadata file.
+fn html_core_metadata(value: &str) -> Option<String> {
+    match value {
+        "false" => None,
+        value => Some(
+            value
+                .strip_prefix("sha256=")
+                .unwrap_or_default()
+                .to_string(),
+        ),
+    }
+}
+
+/// Parse files listed in JSON simple index page (PEP 691) of a project at
+/// `page_url`.
+fn parse_project_json(page_url: &url::Url, page: &str) -> Result<Vec<PypiFile>> {
+    let page: JsonProjectPage = serde_json::from_str(page)?;
+    Ok(page
+        .files
+        .into_iter()
+        .filter_map(|file| {
+            let mut url = page_url.join(&file.url).ok()?;
+            url.set_fragment(None);
+            url.set_query(None);
+            let core_metadata = json_core_metadata(&file.core_metadata)
+                .or_else(|| json_core_metadata(&file.dist_info_metadata));
+            let yanked = match file.yanked {
+                Value::Bool(true) => Some(String::new()),
+                Value::String(reason) => Some(reason),
+                _ => None,
+            };
+            Some(PypiFile {
+                url: url.to_string(),
+                filename: file.filename,
+                sha256: file.hashes.get("sha256").cloned(),
+                requires_python: file.requires_python,
+                yanked,
+                size: file.size,
+                upload_time: file.upload_time,
+                core_metadata,
+            })
+        })
+        .collect())
+}
+
+/// Whether a file requiring `requires_python` can be installed by any of
+/// `pythons`, each given as patch releases of a python version. Results are
+/// cached in `cache`.
+fn matches_python(
+    requires_python: Option<&str>,
+    pythons: &[Vec<Version>],
+    cache: &mut HashMap<String, bool>,
+) -> bool {
+    let requires_python = match requires_python {
+        Some(requires_python) => requires_python,
+        None => return true,
+    };
+    if let Some(matches) = cache.get(requires_python) {
+        return *matches;
+    }
+    let matches = match SpecifierSet::parse(requires_python) {
+        Some(specifiers) => pythons
+            .iter()
+            .any(|patches| patches.iter().any(|python| specifiers.contains(python))),
+        // don't drop files with invalid specifiers
+        None => true,
+    };
+    cache.insert(requires_python.to_string(), matches);
+    matches
+}
+
+/// Read project names or glob patterns (`*` and `?`) from a file, one per line.
+/// Empty lines and comments starting with `#` are ignored.
+fn read_package_list(path: &str) -> Result<RegexSet> {
+    let content = std::fs::read_to_string(path)?;
+    parse_package_list(&content)
+}
+
+fn parse_package_list(content: &str) -> Result<RegexSet> {
+    let patterns = content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|pattern| glob_regex(&normalize_name(pattern)));
+    RegexSet::new(patterns).map_err(|err| Error::ConfigureError(err.to_string()))
+}
+
+/// Versions pinned by lockfiles of each project, with sha256 of allowed files
+/// (empty if any file is allowed).
+type Pins = HashMap<String, Vec<(Version, Vec<String>)>>;
+
+fn read_pins(logger: &Logger, paths: &[String]) -> Result<Pins> {
+    let mut pins = Pins::new();
+    for path in paths {
+        let content = std::fs::read_to_string(path)?;
+        let (pinned, unpinned) = parse_python_lockfile(path, &content)?;
+        info!(logger, "{}: {} requirements", path, pinned.len());
+        for spec in unpinned {
+            warn!(logger, "{} isn't pinned to a version, skipped", spec);
+        }
+        for requirement in pinned {
+            match Version::parse(&requirement.version) {
+                Ok(version) => pins
+                    .entry(normalize_name(&requirement.name))
+                    .or_default()
+                    .push((version, requirement.hashes)),
+                Err(_) => warn!(
+                    logger,
+                    "invalid version {}=={}, skipped", requirement.name, requirement.version
+                ),
+            }
+        }
+    }
+    Ok(pins)
+}
+
+/// Whether file of `package` is a pre-release or development release. Files
+/// whose version can't be parsed are not considered pre-releases.
+fn is_prerelease(package: &str, filename: &str) -> bool {
+    version_from_filename(package, filename).is_some_and(|version| !version.is_stable())
+}
+
+/// Whether `file` of `package` is pinned by any of `pins`.
+fn matches_pins(package: &str, file: &PypiFile, pins: &[(Version, Vec<String>)]) -> bool {
+    let version = match version_from_filename(package, &file.filename) {
+        Some(version) => version,
+        None => return false,
+    };
+    pins.iter().any(|(pinned, hashes)| {
+        *pinned == version
+            && (hashes.is_empty()
+                || file
+                    .sha256
+                    .as_ref()
+                    .is_some_and(|sha256| hashes.contains(sha256)))
+    })
+}
+
+/// Number of recent versions to keep for each package.
+struct KeepRecent {
+    default: Option<usize>,
+    /// Patterns and their numbers of versions, sorted by precedence.
+    overrides: Vec<(Regex, usize)>,
+}
+
+impl KeepRecent {
+    fn new(pypi: &Pypi) -> Result<Self> {
+        let overrides = match &pypi.keep_recent_overrides {
+            Some(path) => parse_keep_recent_overrides(&std::fs::read_to_string(path)?)?,
+            None => vec![],
+        };
+        Ok(Self {
+            default: pypi.keep_recent,
+            overrides,
+        })
+    }
+
+    fn get(&self, package: &str) -> Option<usize> {
+        let package = normalize_name(package);
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(&package))
+            .map(|(_, keep_recent)| *keep_recent)
+            .or(self.default)
+    }
+}
+
+fn parse_keep_recent_overrides(content: &str) -> Result<Vec<(Regex, usize)>> {
+    let table: BTreeMap<String, usize> = toml::from_str(content)
+        .map_err(|err| Error::ConfigureError(format!("invalid keep-recent overrides: {}", err)))?;
+    let mut overrides = table
+        .into_iter()
+        .map(|(pattern, keep_recent)| (normalize_name(&pattern), keep_recent))
+        .collect::<Vec<_>>();
+    overrides.sort_by_key(|(pattern, _)| {
+        (
+            pattern.contains(['*', '?']),
+            std::cmp::Reverse(pattern.len()),
+        )
+    });
+    overrides
+        .into_iter()
+        .map(|(pattern, keep_recent)| {
+            Regex::new(&glob_regex(&pattern))
+                .map(|regex| (regex, keep_recent))
+                .map_err(|err| Error::ConfigureError(err.to_string()))
+        })
+        .collect()
+}
+
+const INSTALLER_EXTENSIONS: &[&str] = &[".exe", ".msi", ".dmg"];
+
+/// Filter of files by kind of distribution and platform tags.
+struct FileFilter {
+    only_wheels: bool,
+    no_sdist: bool,
+    platform_tags: Option<RegexSet>,
+}
+
+impl FileFilter {
+    fn new(pypi: &Pypi) -> Result<Self> {
+        let platform_tags = if pypi.platform_tags.is_empty() {
+            None
+        } else {
+            let patterns = pypi.platform_tags.iter().map(|tag| glob_regex(tag));
+            Some(RegexSet::new(patterns).map_err(|err| Error::ConfigureError(err.to_string()))?)
+        };
+        Ok(Self {
+            only_wheels: pypi.only_wheels,
+            no_sdist: pypi.no_sdist,
+            platform_tags,
+        })
+    }
+
+    fn matches(&self, filename: &str) -> bool {
+        let is_wheel = filename.ends_with(".whl");
+        if self.only_wheels && !is_wheel {
+            return false;
+        }
+        let is_sdist = SDIST_EXTENSIONS
+            .iter()
+            .any(|extension| filename.ends_with(extension));
+        if self.no_sdist && is_sdist {
+            return false;
+        }
+        if let Some(platform_tags) = &self.platform_tags {
+            if INSTALLER_EXTENSIONS
+                .iter()
+                .any(|extension| filename.ends_with(extension))
+            {
+                return false;
+            }
+            if let Some(wheel) = WheelName::parse(filename) {
+                // a wheel may be tagged with several platforms, e.g.
+                // `manylinux_2_17_x86_64.manylinux2014_x86_64`
+                return wheel
+                    .platforms()
+                    .any(|tag| tag == "any" || platform_tags.is_match(tag));
+            }
+            if filename.ends_with(".egg") {
+                let (_, platform) = distribution_tags(filename);
+                return platform.is_empty()
+                    || platform
+                        .split('.')
+                        .any(|tag| tag == "any" || platform_tags.is_match(tag));
+            }
+        }
+        true
+    }
+}
+
+/// Header of incremental state, followed by index of each project.
+#[derive(Serialize, Deserialize)]
+struct IncrementalHeader {
+    scope: String,
+    serial: u64,
+    /// Projects failed to scan, which are scanned again in next run.
+    pending: Vec<String>,
+}
+
+/// Serial and files of a project.
+type ProjectIndex = (Option<u64>, Vec<PypiFile>);
+
+/// Validators of a project page fetched in last run, and files selected from it.
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedPage {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    serial: Option<u64>,
+    files: Vec<PypiFile>,
+}
+
+impl CachedPage {
+    /// Cache page at `url` if it has any validator.
+    fn new(
+        url: &url::Url,
+        headers: &reqwest::header::HeaderMap,
+        (serial, files): ProjectIndex,
+    ) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+        if etag.is_none() && last_modified.is_none() {
+            return None;
+        }
+        Some(Self {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            serial,
+            files,
+        })
+    }
+}
+
+/// Load cache of project pages taken with the same `scope`.
+fn load_page_cache(path: &str, scope: &str) -> Result<HashMap<String, CachedPage>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let cached_scope: String = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Ok(HashMap::new()),
+    };
+    if cached_scope != scope {
+        return Ok(HashMap::new());
+    }
+    let mut pages = HashMap::new();
+    for line in lines {
+        let (name, page): (String, CachedPage) = serde_json::from_str(&line?)?;
+        pages.insert(name, page);
+    }
+    Ok(pages)
+}
+
+fn save_page_cache(path: &str, scope: &str, pages: &HashMap<String, CachedPage>) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = BufWriter::new(std::fs::File::create(&tmp)?);
+    serde_json::to_writer(&mut file, scope)?;
+    file.write_all(b"\n")?;
+    for page in pages {
+        serde_json::to_writer(&mut file, &page)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Load incremental state taken with the same `scope`.
+fn load_incremental(
+    path: &str,
+    scope: &str,
+) -> Result<Option<(IncrementalHeader, HashMap<String, ProjectIndex>)>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let header: IncrementalHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Ok(None),
+    };
+    if header.scope != scope {
+        return Ok(None);
+    }
+    let mut projects = HashMap::new();
+    for line in lines {
+        let (name, index): (String, ProjectIndex) = serde_json::from_str(&line?)?;
+        projects.insert(name, index);
+    }
+    Ok(Some((header, projects)))
+}
+
+/// Save incremental state. Projects without files are not saved, and they are
+/// only scanned again when they change.
+fn save_incremental(
+    path: &str,
+    header: &IncrementalHeader,
+    packages: &[(String, Option<u64>, Vec<PypiFile>)],
+) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = BufWriter::new(std::fs::File::create(&tmp)?);
+    serde_json::to_writer(&mut file, header)?;
+    file.write_all(b"\n")?;
+    for (name, serial, files) in packages {
+        if files.is_empty() || header.pending.contains(name) {
+            continue;
+        }
+        serde_json::to_writer(&mut file, &(name, (serial, files)))?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Normalize project name as PEP 503.
+pub(crate) fn normalize_name(name: &str) -> String {
+    static RE_SEPARATOR: once_cell::sync::Lazy<Regex> =
+        once_cell::sync::Lazy::new(|| Regex::new(r"[-_.]+").unwrap());
+    RE_SEPARATOR.replace_all(name, "-").to_lowercase()
+}
+
+/// Normalize project names, and remove duplicates after normalization, e.g.
+/// `Flask` and `flask`. The order of projects is kept.
+fn dedup_projects(projects: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    projects
+        .into_iter()
+        .map(|name| normalize_name(&name))
+        .filter(|name| seen.insert(name.clone()))
+        .collect()
+}
+
+/// Extract version from file name of a distribution of `package`.
+///
+/// Wheels and eggs have the version as their second `-` separated component.
+/// For source distributions and legacy installers, the project name is
+/// stripped from the file name, where the name may contain `-` itself, and
+/// platform tags of installers are stripped after the version.
+fn version_from_filename(package: &str, filename: &str) -> Option<Version> {
+    const EXTENSIONS: &[&str] = &[".egg", ".exe", ".msi", ".rpm", ".dmg", ".deb"];
+    static RE_PLATFORM: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
+        Regex::new(
+            r"(\.(win[\w-]*|linux-\w+|macosx-[\w.-]+|cygwin-[\w.-]+|noarch|src|i[36]86|x86_64|amd64))?(-py\d+(\.\d+)?(-[\w.]+)?)?$",
+        )
+        .unwrap()
     });
-    RE_VERSION
-        .captures(filename)
-        .and_then(|cap| cap.get(1))
-        .and_then(|cap| Version::parse(cap.as_str()).ok())
+
+    if let Some(wheel) = WheelName::parse(filename) {
+        return Version::parse(&wheel.version).ok();
+    }
+    if let Some(sdist) = SdistName::parse(package, filename) {
+        return Version::parse(&sdist.version).ok();
+    }
+    // legacy eggs and installers
+    let (stem, extension) = EXTENSIONS.iter().find_map(|extension| {
+        filename
+            .strip_suffix(extension)
+            .map(|stem| (stem, *extension))
+    })?;
+    if extension == ".egg" {
+        return Version::parse(stem.split('-').nth(1)?).ok();
+    }
+    let (_, version) = split_name_version(package, stem)?;
+    Version::parse(&RE_PLATFORM.replace(version, "")).ok()
 }
 
+/// Python tag and platform tag of a distribution, e.g. `("cp312", "win_amd64")`
+/// for a wheel. Source distributions and other files have empty tags.
+fn distribution_tags(filename: &str) -> (String, String) {
+    if let Some(wheel) = WheelName::parse(filename) {
+        return (wheel.python, wheel.platform);
+    } else if let Some(stem) = filename.strip_suffix(".egg") {
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.len() >= 3 {
+            return (parts[2].to_string(), parts[3..].join("-"));
+        }
+    }
+    (String::new(), String::new())
+}
+
+/// Select files of recent `keep_recent` versions, picking at most half of them
+/// from unstable versions. Returns indices of selected files.
+fn select_recent(mut candidates: Vec<(usize, Version)>, keep_recent: usize) -> Vec<usize> {
+    candidates.sort_by(|(_, a), (_, b)| a.cmp(b));
+    let mut result = vec![];
+    let at_most_unstable = keep_recent / 2;
+    let mut selected_count = 0;
+    let mut selected_unstable_count = 0;
+    let mut prev = None;
+    for (idx, version) in candidates.into_iter().rev() {
+        if prev.as_ref() == Some(&version) {
+            // Another file of this version is already selected. Select this too.
+            result.push(idx);
+            continue;
+        }
+        if selected_count >= keep_recent {
+            // There's enough versions, stop here.
+            break;
+        }
+
+        // A new version is encountered.
+        if version.is_stable() {
+            // We'd like to pick stable versions first.
+            result.push(idx);
+        } else {
+            // If it's not an unstable version, pick it only if we haven't selected enough.
+            if selected_unstable_count >= at_most_unstable {
+                continue;
+            }
+            result.push(idx);
+            selected_unstable_count += 1;
+        }
+        prev = Some(version);
+        selected_count += 1;
+    }
+    result
+}
+
+/// Upload time of `file`, if known.
+fn upload_time(file: &PypiFile) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    file.upload_time
+        .as_deref()
+        .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+}
+
+/// Whether `file` was uploaded at or after `since`. Files of unknown upload time
+/// are considered recent.
+fn uploaded_after(file: &PypiFile, since: &chrono::DateTime<chrono::Utc>) -> bool {
+    upload_time(file).is_none_or(|time| time >= *since)
+}
+
+/// Keep files of recent `keep_recent` versions, files uploaded after
+/// `uploaded_since`, and files pinned by `protected`.
+fn retain_recent(
+    logger: &Logger,
+    package: &str,
+    entries: Vec<PypiFile>,
+    keep_recent: Option<usize>,
+    uploaded_since: Option<&chrono::DateTime<chrono::Utc>>,
+    protected: &[(Version, Vec<String>)],
+) -> Vec<PypiFile> {
+    if keep_recent.is_none() && uploaded_since.is_none() {
+        return entries;
+    }
+    let recent: Option<HashSet<String>> = keep_recent.map(|keep_recent| {
+        truncate_to_recent(logger, package, entries.clone(), keep_recent)
+            .into_iter()
+            .map(|file| file.filename)
+            .collect()
+    });
+    entries
+        .into_iter()
+        .filter(|file| {
+            recent
+                .as_ref()
+                .is_some_and(|recent| recent.contains(&file.filename))
+                || uploaded_since.is_some_and(|since| uploaded_after(file, since))
+                || matches_pins(package, file, protected)
+        })
+        .collect()
+}
+
+/// Keep files of recent `keep_recent` versions. Files are grouped by their
+/// python and platform tags, and versions are counted in each group, so that
+/// a platform keeps its recent wheels even if newer versions dropped it.
 fn truncate_to_recent(
     logger: &Logger,
     package: &str,
-    entries: Vec<(String, String)>,
+    entries: Vec<PypiFile>,
     keep_recent: usize,
-) -> Vec<(String, String)> {
-    let candidates: Option<Vec<_>> = entries
-        .iter()
-        .map(|(url, name)| {
-            if let Some(version) = version_from_filename(name) {
-                Some((url, name, version))
-            } else {
-                warn!(logger, "failed to parse version from filename: {}", name);
-                None
+) -> Vec<PypiFile> {
+    let mut groups: BTreeMap<(String, String), Vec<(usize, Version)>> = BTreeMap::new();
+    for (idx, file) in entries.iter().enumerate() {
+        match version_from_filename(package, &file.filename) {
+            Some(version) => groups
+                .entry(distribution_tags(&file.filename))
+                .or_default()
+                .push((idx, version)),
+            None => {
+                warn!(
+                    logger,
+                    "failed to parse version from filename: {}", file.filename
+                );
+                warn!(logger, "give up keep_recent for package: {}", package);
+                return entries;
             }
-        })
-        .collect();
-    if let Some(mut candidates) = candidates {
-        candidates.sort_by_key(|(_, _, version)| version.clone());
-        let mut result = vec![];
-        let at_most_unstable = keep_recent / 2;
-        let mut selected_count = 0;
-        let mut selected_unstable_count = 0;
-        let mut prev = None;
-        for (url, name, version) in candidates.into_iter().rev() {
-            if prev.as_ref() == Some(&version) {
-                // Another file of this version is already selected. Select this too.
-                result.push((url.clone(), name.clone()));
-                continue;
-            }
-            if selected_count >= keep_recent {
-                // There's enough versions, stop here.
-                break;
+        }
+    }
+    let mut selected = vec![false; entries.len()];
+    for candidates in groups.into_values() {
+        for idx in select_recent(candidates, keep_recent) {
+            selected[idx] = true;
+        }
+    }
+    entries
+        .into_iter()
+        .zip(selected)
+        .filter_map(|(entry, selected)| selected.then_some(entry))
+        .collect()
+}
+
+/// Rewrite JSON API of a project, so that files point to `package_url` joined with their keys.
+/// Files not in `keys` are removed.
+fn rewrite_project_json(data: &str, keys: &HashSet<&str>, package_url: &str) -> Result<String> {
+    let mut json: serde_json::Value = serde_json::from_str(data)?;
+    let rewrite = |files: &mut serde_json::Value| {
+        if let Some(files) = files.as_array_mut() {
+            files.retain_mut(|file| {
+                let key = file["url"]
+                    .as_str()
+                    .and_then(|url| url.split_once("/packages/"))
+                    .map(|(_, key)| key.to_string());
+                match key {
+                    Some(key) if keys.contains(key.as_str()) => {
+                        file["url"] = format!("{}{}", package_url, key).into();
+                        true
+                    }
+                    _ => false,
+                }
+            });
+        }
+    };
+    rewrite(&mut json["urls"]);
+    if let Some(releases) = json["releases"].as_object_mut() {
+        releases.values_mut().for_each(rewrite);
+    }
+    Ok(serde_json::to_string(&json)?)
+}
+
+/// `bases` followed by `official`, if `official_fallback` is set and it's not
+/// among `bases` yet.
+fn with_fallback(bases: &[String], official: &str, official_fallback: bool) -> Vec<String> {
+    let mut bases = bases.to_vec();
+    if official_fallback
+        && !bases
+            .iter()
+            .any(|base| base.trim_end_matches('/') == official)
+    {
+        bases.push(official.to_string());
+    }
+    bases
+}
+
+impl Pypi {
+    /// Bases of simple index, tried in order.
+    fn simple_bases(&self) -> Vec<String> {
+        with_fallback(
+            &self.simple_base,
+            OFFICIAL_SIMPLE_BASE,
+            self.official_fallback,
+        )
+    }
+
+    /// Bases of packages, tried in order.
+    fn package_bases(&self) -> Vec<String> {
+        with_fallback(
+            &self.package_base,
+            OFFICIAL_PACKAGE_BASE,
+            self.official_fallback,
+        )
+    }
+
+    /// Fetch JSON API of `project` from upstream, and rewrite it to the mirror.
+    async fn project_json(&self, client: &Client, project: &str) -> Result<Option<String>> {
+        let files = match self.index.get(project) {
+            Some(files) => files,
+            None => return Ok(None),
+        };
+        let url = format!(
+            "{}/{}/json",
+            self.json_api_base.trim_end_matches('/'),
+            project
+        );
+        let response = crate::http::send(client.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        let keys = files.iter().map(|(key, _)| key.as_str()).collect();
+        let package_url = if self.json_api_package_url.ends_with('/') {
+            self.json_api_package_url.clone()
+        } else {
+            format!("{}/", self.json_api_package_url)
+        };
+        Ok(Some(rewrite_project_json(
+            &response.text().await?,
+            &keys,
+            &package_url,
+        )?))
+    }
+
+    /// Render status file at `key`.
+    fn status_file(&self, key: &str) -> Result<Option<String>> {
+        let last_modified = self
+            .status
+            .synced_at
+            .map(|time| time.format("%Y%m%dT%H:%M:%S").to_string());
+        let content = if key == LAST_SERIAL_KEY {
+            self.status
+                .last_serial
+                .map(|serial| format!("{}\n", serial))
+        } else if key == STATUS_KEY {
+            // bandersnatch writes serial without newline
+            self.status.last_serial.map(|serial| serial.to_string())
+        } else if key == GENERATION_KEY {
+            Some(BANDERSNATCH_GENERATION.to_string())
+        } else if key == STATUS_JSON_KEY {
+            Some(serde_json::to_string(&serde_json::json!({
+                "last-serial": self.status.last_serial,
+                "last-modified": last_modified,
+                "generation": BANDERSNATCH_GENERATION,
+                "projects": self.status.project_serials.len(),
+            }))?)
+        } else if key == LAST_MODIFIED_KEY {
+            last_modified.map(|time| format!("{}\n", time))
+        } else if let Some(project) = key.strip_prefix(PROJECT_SERIAL_PREFIX) {
+            self.status
+                .project_serials
+                .get(project)
+                .map(|serial| format!("{}\n", serial))
+        } else {
+            None
+        };
+        Ok(content)
+    }
+
+    /// Render page at `path` of simple index, relative to `simple/`.
+    fn simple_page(&self, path: &str) -> Result<Option<String>> {
+        if path == SIMPLE_HTML {
+            let mut page = String::from(
+                "<!DOCTYPE html>\n<html>\n  <head>\n    \
+                 <meta name=\"pypi:repository-version\" content=\"1.0\">\n    \
+                 <title>Simple index</title>\n  </head>\n  <body>\n",
+            );
+            for project in self.index.keys() {
+                page += &format!("    <a href=\"{0}/\">{0}</a>\n", escape_html(project));
             }
+            page += "  </body>\n</html>\n";
+            return Ok(Some(page));
+        }
+        if path == SIMPLE_JSON {
+            let projects: Vec<_> = self
+                .index
+                .keys()
+                .map(|project| serde_json::json!({ "name": project }))
+                .collect();
+            return Ok(Some(serde_json::to_string(&serde_json::json!({
+                "meta": { "api-version": "1.0" },
+                "projects": projects,
+            }))?));
+        }
 
-            // A new version is encountered.
-            if version.is_stable() {
-                // We'd like to pick stable versions first.
-                result.push((url.clone(), name.clone()));
-            } else {
-                // If it's not an unstable version, pick it only if we haven't selected enough.
-                if selected_unstable_count >= at_most_unstable {
-                    continue;
+        let (project, page) = match path.split_once('/') {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let files = match self.index.get(project) {
+            Some(files) => files,
+            None => return Ok(None),
+        };
+        if page == SIMPLE_HTML {
+            let mut page = format!(
+                "<!DOCTYPE html>\n<html>\n  <head>\n    \
+                 <meta name=\"pypi:repository-version\" content=\"1.0\">\n    \
+                 <title>Links for {0}</title>\n  </head>\n  <body>\n    \
+                 <h1>Links for {0}</h1>\n",
+                escape_html(project)
+            );
+            for (key, file) in files {
+                let mut href = format!("../../{}", key);
+                if let Some(sha256) = &file.sha256 {
+                    href += &format!("#sha256={}", sha256);
                 }
-                result.push((url.clone(), name.clone()));
-                selected_unstable_count += 1;
+                page += &format!("    <a href=\"{}\"", escape_html(&href));
+                if let Some(requires_python) = &file.requires_python {
+                    page += &format!(" data-requires-python=\"{}\"", escape_html(requires_python));
+                }
+                if let Some(yanked) = &file.yanked {
+                    page += &format!(" data-yanked=\"{}\"", escape_html(yanked));
+                }
+                if let Some(core_metadata) = &file.core_metadata {
+                    let value = if core_metadata.is_empty() {
+                        "true".to_string()
+                    } else {
+                        format!("sha256={}", core_metadata)
+                    };
+                    // both names are given for clients before PEP 714
+                    page += &format!(
+                        " data-dist-info-metadata=\"{0}\" data-core-metadata=\"{0}\"",
+                        escape_html(&value)
+                    );
+                }
+                page += &format!(">{}</a><br />\n", escape_html(&file.filename));
             }
-            prev = Some(version);
-            selected_count += 1;
+            page += "  </body>\n</html>\n";
+            Ok(Some(page))
+        } else if page == SIMPLE_JSON {
+            let files: Vec<_> = files
+                .iter()
+                .map(|(key, file)| {
+                    let hashes: BTreeMap<_, _> = file
+                        .sha256
+                        .iter()
+                        .map(|sha256| ("sha256", sha256))
+                        .collect();
+                    let mut item = serde_json::json!({
+                        "filename": file.filename,
+                        "url": format!("../../{}", key),
+                        "hashes": hashes,
+                    });
+                    if let Some(requires_python) = &file.requires_python {
+                        item["requires-python"] = requires_python.as_str().into();
+                    }
+                    if let Some(yanked) = &file.yanked {
+                        item["yanked"] = if yanked.is_empty() {
+                            true.into()
+                        } else {
+                            yanked.as_str().into()
+                        };
+                    }
+                    if let Some(core_metadata) = &file.core_metadata {
+                        let value = if core_metadata.is_empty() {
+                            true.into()
+                        } else {
+                            serde_json::json!({ "sha256": core_metadata })
+                        };
+                        item["dist-info-metadata"] = value.clone();
+                        item["core-metadata"] = value;
+                    }
+                    item
+                })
+                .collect();
+            Ok(Some(serde_json::to_string(&serde_json::json!({
+                "meta": { "api-version": "1.0" },
+                "name": project,
+                "files": files,
+            }))?))
+        } else {
+            Ok(None)
         }
-        result
-    } else {
-        warn!(logger, "give up keep_recent for package: {}", package);
-        entries
     }
 }
 
 #[async_trait]
-impl SnapshotStorage<SnapshotPath> for Pypi {
+impl SnapshotStorage<SnapshotMeta> for Pypi {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
+    ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        let projects = if self.bq_query {
+        let simple_bases = self.simple_bases();
+        let scope = format!(
+            "pypi {} {} {:?} {} {} {:?} {} {:?} {:?} {:?} {} {:?} {:?} {:?} {} {} {} {:?} {} {} {} {:?} {:?} v2",
+            simple_bases.join(" "),
+            self.bq_query,
+            self.top_downloads,
+            self.bq_days,
+            self.bq_limit,
+            self.bq_query_file,
+            self.bq_with_deps,
+            self.keep_recent,
+            self.keep_recent_overrides,
+            self.keep_since,
+            self.use_upload_time,
+            self.from_lockfiles,
+            self.pin_file,
+            self.ranking_from_logs,
+            self.ranking_limit,
+            self.debug,
+            self.exclude_yanked,
+            self.python_versions,
+            self.only_wheels,
+            self.no_prereleases,
+            self.no_sdist,
+            self.platform_tags,
+            self.max_file_size
+        );
+
+        // projects unchanged since last run, and serial of PyPI before this run
+        let mut unchanged = HashMap::new();
+        let mut incremental_projects = None;
+        let mut incremental_serial = None;
+        if let Some(path) = &self.incremental_state {
+            match load_incremental(path, &scope)? {
+                Some((header, saved)) => {
+                    let (changed, serial) =
+                        pypi_changelog::since_serial(&client, &self.xmlrpc_url, header.serial)
+                            .await?;
+                    info!(
+                        logger,
+                        "{} projects changed since serial {}",
+                        changed.len(),
+                        header.serial
+                    );
+                    incremental_serial = Some(serial.unwrap_or(header.serial).max(header.serial));
+                    // projects failed in last run are scanned again
+                    let changed: Vec<String> = changed.into_iter().chain(header.pending).collect();
+                    let changed_normalized: HashSet<String> =
+                        changed.iter().map(|name| normalize_name(name)).collect();
+                    let saved_normalized: HashSet<String> =
+                        saved.keys().map(|name| normalize_name(name)).collect();
+                    let mut projects: Vec<String> = saved.keys().cloned().collect();
+                    projects.extend(
+                        changed
+                            .into_iter()
+                            .filter(|name| !saved_normalized.contains(&normalize_name(name))),
+                    );
+                    unchanged = saved
+                        .into_iter()
+                        .filter(|(name, _)| !changed_normalized.contains(&normalize_name(name)))
+                        .collect();
+                    incremental_projects = Some(projects);
+                }
+                None => {
+                    info!(logger, "no incremental state, scanning all projects");
+                    incremental_serial =
+                        Some(pypi_changelog::last_serial(&client, &self.xmlrpc_url).await?);
+                }
+            }
+        }
+
+        let pins = read_pins(&logger, &self.from_lockfiles)?;
+        let protected = read_pins(&logger, &self.pin_file)?;
+        let (projects, index_serial) = if !self.from_lockfiles.is_empty() {
+            (pins.keys().cloned().collect(), None)
+        } else if self.bq_query {
             if self.debug {
                 warn!(logger, "debug mode is ignored in bigquery mode");
             }
-            bigquery_index(&logger).await?
+            let query = match &self.bq_query_file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => BQ_QUERY.to_string(),
+            };
+            let query = render_bq_query(&query, self.bq_days, self.bq_limit);
+            let cached = self
+                .bq_cache
+                .as_ref()
+                .and_then(|path| load_bq_cache(path, &query, self.bq_cache_ttl));
+            let projects = match cached {
+                Some(projects) => {
+                    info!(logger, "{} projects from bigquery cache", projects.len());
+                    projects
+                }
+                None => {
+                    let projects = bigquery_index(&logger, query.clone()).await?;
+                    if let Some(path) = &self.bq_cache {
+                        save_bq_cache(path, query, projects.clone())?;
+                    }
+                    projects
+                }
+            };
+            let projects = if self.bq_with_deps {
+                dependency_closure(
+                    &logger,
+                    &client,
+                    &self.json_api_base,
+                    projects,
+                    config.concurrent_resolve,
+                )
+                .await
+            } else {
+                projects
+            };
+            (projects, None)
+        } else if let Some(limit) = self.top_downloads {
+            if self.debug {
+                warn!(logger, "debug mode is ignored when selecting top downloads");
+            }
+            let projects =
+                top_downloads_index(&logger, &client, &self.top_downloads_url, limit).await?;
+            (projects, None)
+        } else if !self.ranking_from_logs.is_empty() {
+            if self.debug {
+                warn!(
+                    logger,
+                    "debug mode is ignored when ranking from access logs"
+                );
+            }
+            let projects =
+                pypi_ranking::rank_from_logs(&self.ranking_from_logs, self.ranking_limit)?;
+            info!(
+                logger,
+                "{} most downloaded projects in access logs",
+                projects.len()
+            );
+            (projects, None)
+        } else if let Some(projects) = incremental_projects {
+            (projects, incremental_serial)
         } else {
-            pypi_index(&logger, &client, &self.simple_base, self.debug).await?
+            pypi_index(&logger, &client, &simple_bases, self.debug).await?
+        };
+        let listed = projects.len();
+        let mut projects = dedup_projects(projects);
+        if projects.len() < listed {
+            info!(
+                logger,
+                "{} duplicated project names merged",
+                listed - projects.len()
+            );
+        }
+        if let Some(path) = &self.package_list {
+            let list = read_package_list(path)?;
+            projects.retain(|name| list.is_match(&normalize_name(name)));
+            info!(logger, "{} projects in package list", projects.len());
+        }
+        if let Some(path) = &self.package_exclude {
+            let list = read_package_list(path)?;
+            projects.retain(|name| !list.is_match(&normalize_name(name)));
+            info!(logger, "{} projects after exclusion", projects.len());
+        }
+
+        let pythons = self
+            .python_versions
+            .iter()
+            .map(|python| {
+                let minor = Version::parse(python).map_err(|_| {
+                    Error::ConfigureError(format!("invalid python version {}", python))
+                })?;
+                Ok((0..=PYTHON_MAX_PATCH)
+                    .map(|patch| Version {
+                        chunks: minor.chunks.iter().copied().chain([patch]).collect(),
+                        ..minor.clone()
+                    })
+                    .collect())
+            })
+            .collect::<Result<Vec<Vec<Version>>>>()?;
+
+        let file_filter = FileFilter::new(self)?;
+        let keep_recent = KeepRecent::new(self)?;
+        let uploaded_since = match self.keep_since {
+            Some(keep_since) => Some(
+                chrono::Utc::now()
+                    - chrono::Duration::from_std(keep_since)
+                        .map_err(|err| Error::ConfigureError(err.to_string()))?,
+            ),
+            None => None,
         };
 
+        let skipped = std::sync::Mutex::new(vec![]);
+        let failed = std::sync::Mutex::new(vec![]);
+        let page_cache = match &self.page_cache {
+            Some(path) => load_page_cache(path, &scope)?,
+            None => HashMap::new(),
+        };
+        if !page_cache.is_empty() {
+            info!(logger, "{} project pages cached", page_cache.len());
+        }
+        let new_page_cache = std::sync::Mutex::new(HashMap::new());
+        let mut checkpoint = Checkpoint::open(&logger, config.checkpoint.as_deref(), &scope)?;
+        let mut completed = checkpoint.completed();
+
         info!(logger, "downloading package index...");
         progress.set_length(projects.len() as u64);
         progress.set_style(bar());
 
-        let matcher = Regex::new(r#"<a.*href="(.*?)".*>(.*?)</a>"#).unwrap();
-        let packages: Result<Vec<Vec<(String, String)>>> =
+        let packages: Result<Vec<(String, Option<u64>, Vec<PypiFile>)>> =
             stream::iter(projects.into_iter().map(|name| {
                 let client = client.clone();
-                let simple_base = self.simple_base.clone();
-                let keep_recent = self.keep_recent;
+                let simple_bases = &simple_bases;
+                let keep_recent = keep_recent.get(&name);
+                let exclude_yanked = self.exclude_yanked;
+                let no_prereleases = self.no_prereleases;
                 let progress = progress.clone();
-                let matcher = matcher.clone();
                 let logger = logger.clone();
+                let resumed = completed.remove(&name).or_else(|| unchanged.remove(&name));
+                let failed = &failed;
+                let checkpoint = &checkpoint;
+                let pythons = &pythons;
+                let file_filter = &file_filter;
+                let max_file_size = self.max_file_size;
+                let use_upload_time = self.use_upload_time;
+                let skipped = &skipped;
+                let uploaded_since = &uploaded_since;
+                let pins = pins.get(&normalize_name(&name));
+                let protected = protected
+                    .get(&normalize_name(&name))
+                    .map_or(&[][..], Vec::as_slice);
+                let cached = page_cache.get(&name);
+                let new_page_cache = &new_page_cache;
 
                 let func = {
                     let logger = logger.clone();
+                    let name = name.clone();
                     async move {
+                        if let Some(resumed) = resumed {
+                            if let Some(cached) = cached {
+                                new_page_cache
+                                    .lock()
+                                    .unwrap()
+                                    .insert(name.clone(), cached.clone());
+                            }
+                            progress.inc(1);
+                            return Ok(resumed);
+                        }
                         progress.set_message(&name);
-                        let package = client
-                            .get(&format!("{}/{}/", simple_base, name))
-                            .send()
-                            .await?
-                            .text()
-                            .await?;
-                        let caps: Vec<(String, String)> = matcher
-                            .captures_iter(&package)
-                            .map(|cap| {
-                                let url = format!("{}/{}/{}", simple_base, name, &cap[1]);
-                                let parsed = url::Url::parse(&url).unwrap();
-                                let cleaned: &str = &parsed[..url::Position::AfterPath];
-                                (cleaned.to_string(), cap[2].to_string())
-                            })
-                            .collect();
-                        let caps = if let Some(keep_recent) = keep_recent {
-                            truncate_to_recent(&logger, &name, caps, keep_recent)
+                        // JSON simple index has file sizes and upload time
+                        let accept = (max_file_size.is_some()
+                            || uploaded_since.is_some()
+                            || use_upload_time)
+                            .then(|| format!("{}, text/html;q=0.1", SIMPLE_JSON_CONTENT_TYPE));
+                        let (page_url, headers, package) = match fetch_page(
+                            &logger,
+                            &client,
+                            simple_bases,
+                            &format!("{}/", name),
+                            accept.as_deref(),
+                            cached,
+                        )
+                        .await?
+                        {
+                            Page::Found(page) => *page,
+                            Page::NotModified => {
+                                let cached = cached.unwrap().clone();
+                                let index = (cached.serial, cached.files.clone());
+                                new_page_cache.lock().unwrap().insert(name.clone(), cached);
+                                progress.inc(1);
+                                checkpoint.record(&name, &index)?;
+                                return Ok(index);
+                            }
+                            Page::NotFound => {
+                                // project removed from upstream
+                                progress.inc(1);
+                                return Ok((None, vec![]));
+                            }
+                        };
+                        let serial = serial_from_response(&headers, &package);
+                        let is_json = headers
+                            .get(reqwest::header::CONTENT_TYPE)
+                            .and_then(|x| x.to_str().ok())
+                            .is_some_and(|x| x.starts_with(SIMPLE_JSON_CONTENT_TYPE));
+                        let files = if is_json {
+                            parse_project_json(&page_url, &package)?
                         } else {
-                            caps
+                            parse_project_page(&page_url, &package)
                         };
+                        let mut requires_python = HashMap::new();
+                        let caps: Vec<PypiFile> = files
+                            .into_iter()
+                            .filter(|file| !(exclude_yanked && file.yanked.is_some()))
+                            .filter(|file| file_filter.matches(&file.filename))
+                            .filter(|file| {
+                                !(no_prereleases && is_prerelease(&name, &file.filename))
+                            })
+                            .filter(|file| pins.is_none_or(|pins| matches_pins(&name, file, pins)))
+                            .filter(|file| {
+                                pythons.is_empty()
+                                    || matches_python(
+                                        file.requires_python.as_deref(),
+                                        pythons,
+                                        &mut requires_python,
+                                    )
+                            })
+                            .collect();
+                        let mut caps = retain_recent(
+                            &logger,
+                            &name,
+                            caps,
+                            keep_recent,
+                            uploaded_since.as_ref(),
+                            protected,
+                        );
+                        if let Some(max_file_size) = max_file_size {
+                            for file in caps.iter_mut().filter(|file| file.size.is_none()) {
+                                // size of files failed to HEAD is unknown, and they are kept
+                                if let Ok(response) =
+                                    crate::http::send(client.head(&file.url)).await
+                                {
+                                    if !response.status().is_success() {
+                                        continue;
+                                    }
+                                    file.size = response
+                                        .headers()
+                                        .get(reqwest::header::CONTENT_LENGTH)
+                                        .and_then(|x| x.to_str().ok())
+                                        .and_then(|x| x.parse().ok());
+                                }
+                            }
+                            caps.retain(|file| match file.size {
+                                Some(size) if size > max_file_size => {
+                                    skipped.lock().unwrap().push((file.filename.clone(), size));
+                                    false
+                                }
+                                _ => true,
+                            });
+                        }
+                        if let Some(page) =
+                            CachedPage::new(&page_url, &headers, (serial, caps.clone()))
+                        {
+                            new_page_cache.lock().unwrap().insert(name.clone(), page);
+                        }
                         progress.inc(1);
-                        Ok::<Vec<(String, String)>, Error>(caps)
+                        checkpoint.record(&name, &(serial, caps.clone()))?;
+                        Ok::<_, Error>((serial, caps))
                     }
                 };
                 async move {
                     match func.await {
-                        Ok(x) => Ok(x),
+                        Ok((serial, caps)) => Ok((name, serial, caps)),
                         Err(err) => {
                             warn!(logger, "failed to fetch index {:?}", err);
-                            Ok(vec![])
+                            failed.lock().unwrap().push(name.clone());
+                            Ok((name, None, vec![]))
                         }
                     }
                 }
@@ -334,28 +1965,133 @@ impl SnapshotStorage<SnapshotPath> for Pypi {
             .try_collect()
             .await;
 
-        let package_base = if self.package_base.ends_with('/') {
-            self.package_base.clone()
-        } else {
-            format!("{}/", self.package_base)
-        };
+        let package_bases: Vec<String> = self
+            .package_bases()
+            .iter()
+            .map(|base| format!("{}/", base.trim_end_matches('/')))
+            .collect();
 
-        let snapshot = packages?
-            .into_iter()
-            .flatten()
-            .filter_map(|(url, _)| {
-                if url.starts_with(&package_base) {
-                    Some(url[package_base.len()..].to_string())
+        let packages = packages?;
+        checkpoint.finish()?;
+
+        if let Some(path) = &self.page_cache {
+            save_page_cache(path, &scope, &new_page_cache.into_inner().unwrap())?;
+        }
+
+        if let (Some(path), Some(serial)) = (&self.incremental_state, incremental_serial) {
+            let header = IncrementalHeader {
+                scope,
+                serial,
+                pending: failed.into_inner().unwrap(),
+            };
+            save_incremental(path, &header, &packages)?;
+            info!(logger, "incremental state saved at serial {}", serial);
+        }
+
+        let mut skipped = skipped.into_inner().unwrap();
+        if !skipped.is_empty() {
+            skipped.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+            info!(
+                logger,
+                "skipped {} files larger than {} bytes, {} bytes in total",
+                skipped.len(),
+                self.max_file_size.unwrap_or_default(),
+                skipped.iter().map(|(_, size)| size).sum::<u64>()
+            );
+            for (filename, size) in skipped.iter().take(10) {
+                info!(logger, "skipped {} ({} bytes)", filename, size);
+            }
+        }
+
+        let mut snapshot: Vec<SnapshotMeta> = vec![];
+        let mut index = SimpleIndex::new();
+        for (name, _, caps) in &packages {
+            for file in caps {
+                if let Some(key) = package_bases
+                    .iter()
+                    .find_map(|base| file.url.strip_prefix(base.as_str()))
+                {
+                    snapshot.push(SnapshotMeta {
+                        key: key.to_string(),
+                        checksum_method: file.sha256.as_ref().map(|_| "sha256".to_string()),
+                        checksum: file.sha256.clone(),
+                        size: file.size,
+                        last_modified: upload_time(file)
+                            .filter(|_| self.use_upload_time)
+                            .map(|time| time.timestamp() as u64),
+                        ..Default::default()
+                    });
+                    if let Some(core_metadata) = &file.core_metadata {
+                        let checksum = Some(core_metadata.clone()).filter(|x| !x.is_empty());
+                        snapshot.push(SnapshotMeta {
+                            key: format!("{}.metadata", key),
+                            checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+                            checksum,
+                            ..Default::default()
+                        });
+                    }
+                    if self.simple_index || self.json_api {
+                        index
+                            .entry(normalize_name(name))
+                            .or_default()
+                            .push((key.to_string(), file.clone()));
+                    }
                 } else {
-                    warn!(logger, "PyPI package isn't stored on base: {:?}", url);
-                    None
+                    warn!(logger, "PyPI package isn't stored on base: {:?}", file.url);
                 }
-            })
-            .collect();
+            }
+        }
+        if self.simple_index {
+            let mut index_keys = vec![
+                format!("{}{}", SIMPLE_PREFIX, SIMPLE_HTML),
+                format!("{}{}", SIMPLE_PREFIX, SIMPLE_JSON),
+            ];
+            for project in index.keys() {
+                index_keys.push(format!("{}{}/{}", SIMPLE_PREFIX, project, SIMPLE_HTML));
+                index_keys.push(format!("{}{}/{}", SIMPLE_PREFIX, project, SIMPLE_JSON));
+            }
+            snapshot.extend(index_keys.into_iter().map(SnapshotMeta::force));
+        }
+        if self.json_api {
+            snapshot.extend(index.keys().map(|project| {
+                SnapshotMeta::force(format!("{}{}{}", JSON_API_PREFIX, project, JSON_API_SUFFIX))
+            }));
+        }
+        self.index = index;
+
+        let mut status_keys = vec![];
+        if self.status_files {
+            let project_serials: BTreeMap<String, u64> = packages
+                .into_iter()
+                .filter(|(_, _, caps)| !caps.is_empty())
+                .filter_map(|(name, serial, _)| serial.map(|serial| (name, serial)))
+                .collect();
+            let last_serial = index_serial.or_else(|| project_serials.values().max().copied());
+            if last_serial.is_none() {
+                warn!(logger, "no serial found in upstream, skip last-serial");
+            } else {
+                status_keys.push(LAST_SERIAL_KEY.to_string());
+                status_keys.push(STATUS_KEY.to_string());
+            }
+            status_keys.push(LAST_MODIFIED_KEY.to_string());
+            status_keys.push(GENERATION_KEY.to_string());
+            status_keys.push(STATUS_JSON_KEY.to_string());
+            status_keys.extend(
+                project_serials
+                    .keys()
+                    .map(|name| format!("{}{}", PROJECT_SERIAL_PREFIX, name)),
+            );
+            self.status = PypiStatus {
+                last_serial,
+                project_serials,
+                synced_at: Some(chrono::Utc::now()),
+            };
+        }
 
         progress.finish_with_message("done");
 
-        Ok(crate::utils::snapshot_string_to_path(snapshot))
+        snapshot.extend(status_keys.into_iter().map(SnapshotMeta::force));
+        Ok(snapshot)
     }
 
     fn info(&self) -> String {
@@ -364,8 +2100,824 @@ impl SnapshotStorage<SnapshotPath> for Pypi {
 }
 
 #[async_trait]
-impl SourceStorage<SnapshotPath, TransferURL> for Pypi {
-    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.package_base, snapshot.0)))
+impl SourceStorage<SnapshotMeta, TransferURL> for Pypi {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<TransferURL> {
+        let urls: Vec<String> = self
+            .package_bases()
+            .iter()
+            .map(|base| format!("{}/{}", base.trim_end_matches('/'), snapshot.key))
+            .collect();
+        if urls.len() > 1 {
+            // find the first base having this file, as upstream mirrors may lag
+            for url in &urls {
+                if let Ok(response) = crate::http::send(mission.client.head(url)).await {
+                    if response.status().is_success() {
+                        return Ok(TransferURL(url.clone()));
+                    }
+                }
+            }
+        }
+        Ok(TransferURL(urls[0].clone()))
+    }
+}
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Pypi {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = snapshot.key.as_str();
+        if self.json_api {
+            if let Some(project) = key
+                .strip_prefix(JSON_API_PREFIX)
+                .and_then(|key| key.strip_suffix(JSON_API_SUFFIX))
+            {
+                let json = self.project_json(&mission.client, project).await?;
+                return Ok(json.map(String::into_bytes));
+            }
+        }
+        if let Some(path) = key.strip_prefix(SIMPLE_PREFIX) {
+            if self.simple_index {
+                return Ok(self.simple_page(path)?.map(String::into_bytes));
+            }
+        }
+        if !self.status_files {
+            return Ok(None);
+        }
+        Ok(self.status_file(key)?.map(String::into_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use structopt::StructOpt;
+
+    use super::{
+        dedup_projects, is_prerelease, load_bq_cache, load_incremental, load_page_cache,
+        matches_pins, matches_python, normalize_name, parse_duration, parse_keep_recent_overrides,
+        parse_package_list, parse_project_json, parse_project_page, parse_requires_dist,
+        parse_top_packages, render_bq_query, retain_recent, rewrite_project_json, save_bq_cache,
+        save_incremental, save_page_cache, truncate_to_recent, upload_time, version_from_filename,
+        CachedPage, FileFilter, IncrementalHeader, KeepRecent, Pypi, PypiFile, PypiStatus,
+        BQ_QUERY,
+    };
+    use crate::python_version::Version;
+    use std::time::Duration;
+
+    #[rstest]
+    #[case(
+        "numpy",
+        "numpy-1.26.4-cp312-cp312-manylinux_2_17_x86_64.manylinux2014_x86_64.whl",
+        "1.26.4"
+    )]
+    #[case("numpy", "numpy-1.26.4.tar.gz", "1.26.4")]
+    #[case("numpy", "numpy-2.0.0rc1-cp39-cp39-win_amd64.whl", "2.0.0rc1")]
+    #[case("numpy", "numpy-1.6.2.win32-py2.7.exe", "1.6.2")]
+    #[case("numpy", "numpy-1.3.0-py2.5-macosx10.5.dmg", "1.3.0")]
+    #[case(
+        "torch",
+        "torch-2.1.0+cu121-cp311-cp311-linux_x86_64.whl",
+        "2.1.0+cu121"
+    )]
+    #[case(
+        "typing-extensions",
+        "typing_extensions-4.9.0-py3-none-any.whl",
+        "4.9.0"
+    )]
+    #[case("typing-extensions", "typing_extensions-4.9.0.tar.gz", "4.9.0")]
+    #[case("zope-interface", "zope.interface-6.1.tar.gz", "6.1")]
+    #[case("python-dateutil", "python-dateutil-2.8.2.tar.gz", "2.8.2")]
+    #[case(
+        "python-dateutil",
+        "python_dateutil-2.8.2-py2.py3-none-any.whl",
+        "2.8.2"
+    )]
+    #[case("setuptools", "setuptools-0.6c11-py2.7.egg", "0.6c11")]
+    #[case("setuptools", "setuptools-0.6c11.win32-py2.7.exe", "0.6c11")]
+    #[case("pywin32", "pywin32-214.win-amd64-py2.6.exe", "214")]
+    #[case("pyobjc", "pyobjc-2.2b3.tar.gz", "2.2b3")]
+    #[case("django", "Django-5.0.1.tar.gz", "5.0.1")]
+    #[case("django", "Django-1.4a1.tar.gz", "1.4a1")]
+    #[case(
+        "tensorflow",
+        "tensorflow-2.16.0rc0-cp310-cp310-macosx_10_15_x86_64.whl",
+        "2.16.0rc0"
+    )]
+    #[case("pip", "pip-24.0.dev0.tar.gz", "24.0.dev0")]
+    #[case("black", "black-22.1.0.post1.tar.gz", "22.1.0.post1")]
+    #[case("jupyterlab", "jupyterlab-4.1.0b0-py3-none-any.whl", "4.1.0b0")]
+    #[case("tzdata", "tzdata-2024.1-py2.py3-none-any.whl", "2024.1")]
+    #[case("apache-airflow", "apache_airflow-2.8.1.tar.gz", "2.8.1")]
+    #[case("apache-airflow", "apache-airflow-1!2.0.0.tar.gz", "1!2.0.0")]
+    #[case(
+        "tb-nightly",
+        "tb_nightly-2.17.0a20240214-py3-none-any.whl",
+        "2.17.0a20240214"
+    )]
+    #[case("pytz", "pytz-2024.1.tar.gz", "2024.1")]
+    #[case("pyopenssl", "pyOpenSSL-0.13.winxp32-py2.7.msi", "0.13")]
+    #[case("m2crypto", "M2Crypto-0.21.1-py2.7-win32.egg", "0.21.1")]
+    #[case("pyyaml", "PyYAML-5.4.1.1.zip", "5.4.1.1")]
+    #[case("sentry-sdk", "sentry-sdk-0.1.0.tar.gz", "0.1.0")]
+    #[case("ruff", "ruff-0.2.1-py3-none-musllinux_1_2_x86_64.whl", "0.2.1")]
+    fn test_version_from_filename(
+        #[case] package: &str,
+        #[case] filename: &str,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(
+            version_from_filename(package, filename),
+            Some(Version::parse(expected).unwrap()),
+        );
+    }
+
+    #[test]
+    fn test_truncate_to_recent() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let files = [
+            "foo-1.0.tar.gz",
+            "foo-1.0-cp38-cp38-win32.whl",
+            "foo-1.0-cp39-cp39-manylinux1_x86_64.whl",
+            "foo-1.1.tar.gz",
+            "foo-1.1-cp38-cp38-win32.whl",
+            "foo-1.1-cp39-cp39-manylinux1_x86_64.whl",
+            "foo-1.1-cp39-cp39-win32.whl",
+            "foo-2.0.tar.gz",
+            "foo-2.0-cp39-cp39-manylinux1_x86_64.whl",
+            "foo-2.0-cp39-cp39-win32.whl",
+            "foo-2.1a1.tar.gz",
+        ];
+        let entries = files
+            .iter()
+            .map(|name| PypiFile {
+                url: format!("https://example.com/{}", name),
+                filename: name.to_string(),
+                sha256: None,
+                requires_python: None,
+                yanked: None,
+                size: None,
+                upload_time: None,
+                core_metadata: None,
+            })
+            .collect();
+        let kept: Vec<String> = truncate_to_recent(&logger, "foo", entries, 2)
+            .into_iter()
+            .map(|file| file.filename)
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                "foo-1.0-cp38-cp38-win32.whl",
+                "foo-1.1-cp38-cp38-win32.whl",
+                "foo-1.1-cp39-cp39-manylinux1_x86_64.whl",
+                "foo-1.1-cp39-cp39-win32.whl",
+                "foo-2.0.tar.gz",
+                "foo-2.0-cp39-cp39-manylinux1_x86_64.whl",
+                "foo-2.0-cp39-cp39-win32.whl",
+                "foo-2.1a1.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_project_page() {
+        let page = r#"<!DOCTYPE html>
+<html>
+  <body>
+    <h1>Links for foo</h1>
+    <a href="../../packages/ab/foo-1.0.tar.gz#sha256=00">foo-1.0.tar.gz</a><br />
+    <a href="../../packages/cd/foo-1.1.tar.gz#sha256=11" data-requires-python="&gt;=3.6" data-yanked="">foo-1.1.tar.gz</a><br />
+    <a href="https://example.com/foo-1.2.tar.gz" data-yanked="broken">foo-1.2.tar.gz</a><br />
+    <a data-yanked href="../../packages/ef/foo-1.3.tar.gz">foo-1.3.tar.gz</a><br />
+  </body>
+</html>
+"#;
+        let page_url = url::Url::parse("https://pypi.org/simple/foo/").unwrap();
+        let files: Vec<_> = parse_project_page(&page_url, page)
+            .into_iter()
+            .map(|file| {
+                (
+                    file.url,
+                    file.filename,
+                    file.sha256,
+                    file.requires_python,
+                    file.yanked,
+                )
+            })
+            .collect();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "https://pypi.org/packages/ab/foo-1.0.tar.gz".to_string(),
+                    "foo-1.0.tar.gz".to_string(),
+                    some("00"),
+                    None,
+                    None
+                ),
+                (
+                    "https://pypi.org/packages/cd/foo-1.1.tar.gz".to_string(),
+                    "foo-1.1.tar.gz".to_string(),
+                    some("11"),
+                    some(">=3.6"),
+                    some("")
+                ),
+                (
+                    "https://example.com/foo-1.2.tar.gz".to_string(),
+                    "foo-1.2.tar.gz".to_string(),
+                    None,
+                    None,
+                    some("broken")
+                ),
+                (
+                    "https://pypi.org/packages/ef/foo-1.3.tar.gz".to_string(),
+                    "foo-1.3.tar.gz".to_string(),
+                    None,
+                    None,
+                    some("")
+                ),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(None, true)]
+    #[case(Some(">=3.6"), true)]
+    #[case(Some(">=3.10"), false)]
+    #[case(Some("<3.9.2"), true)]
+    #[case(Some(">=2.7, !=3.*"), false)]
+    #[case(Some("~=2.7"), false)]
+    #[case(Some(">=3.6.*"), true)]
+    #[case(Some(">=3.x"), true)]
+    fn test_matches_python(#[case] requires_python: Option<&str>, #[case] expected: bool) {
+        let pythons: Vec<Vec<Version>> = ["3.8", "3.9"]
+            .iter()
+            .map(|minor| {
+                (0..=30)
+                    .map(|patch| Version::parse(&format!("{}.{}", minor, patch)).unwrap())
+                    .collect()
+            })
+            .collect();
+        let mut cache = std::collections::HashMap::new();
+        assert_eq!(
+            matches_python(requires_python, &pythons, &mut cache),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_simple_page() {
+        let mut pypi = Pypi::from_iter(["pypi", "--simple-index"]);
+        pypi.index.insert(
+            "foo".to_string(),
+            vec![(
+                "ab/foo-1.0.tar.gz".to_string(),
+                PypiFile {
+                    url: "https://files.pythonhosted.org/packages/ab/foo-1.0.tar.gz".to_string(),
+                    filename: "foo-1.0.tar.gz".to_string(),
+                    sha256: Some("00".to_string()),
+                    requires_python: Some(">=3.6".to_string()),
+                    yanked: Some(String::new()),
+                    size: Some(1),
+                    upload_time: None,
+                    core_metadata: None,
+                },
+            )],
+        );
+        let html = pypi.simple_page("foo/index.html").unwrap().unwrap();
+        assert!(html.contains(
+            r#"<a href="../../ab/foo-1.0.tar.gz#sha256=00" data-requires-python="&gt;=3.6" data-yanked="">foo-1.0.tar.gz</a>"#
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&pypi.simple_page("foo/index.v1_json").unwrap().unwrap()).unwrap();
+        assert_eq!(
+            json["files"][0],
+            serde_json::json!({
+                "filename": "foo-1.0.tar.gz",
+                "url": "../../ab/foo-1.0.tar.gz",
+                "hashes": { "sha256": "00" },
+                "requires-python": ">=3.6",
+                "yanked": true,
+            })
+        );
+        let root = pypi.simple_page("index.html").unwrap().unwrap();
+        assert!(root.contains(r#"<a href="foo/">foo</a>"#));
+        assert!(pypi.simple_page("bar/index.html").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_package_list() {
+        let list =
+            parse_package_list("# scientific\nnumpy\nSciPy  # core\nscikit-*\n\npy?\n").unwrap();
+        let matches = |name: &str| list.is_match(&normalize_name(name));
+        assert!(matches("numpy"));
+        assert!(matches("scipy"));
+        assert!(matches("scikit_learn"));
+        assert!(matches("Scikit.Image"));
+        assert!(matches("pyx"));
+        assert!(!matches("numpy-stubs"));
+        assert!(!matches("pyyaml"));
+        assert!(!matches("core"));
+    }
+
+    #[rstest]
+    #[case("numpy-1.26.4.tar.gz", true)]
+    #[case(
+        "numpy-1.26.4-cp312-cp312-manylinux_2_17_x86_64.manylinux2014_x86_64.whl",
+        true
+    )]
+    #[case("numpy-1.26.4-cp312-cp312-macosx_11_0_arm64.whl", true)]
+    #[case("numpy-1.26.4-cp312-cp312-win_amd64.whl", false)]
+    #[case("numpy-1.26.4-cp312-cp312-musllinux_1_1_x86_64.whl", false)]
+    #[case("six-1.16.0-py2.py3-none-any.whl", true)]
+    #[case("numpy-1.6.2.win32-py2.7.exe", false)]
+    #[case("setuptools-0.6c11-py2.7.egg", true)]
+    fn test_file_filter(#[case] filename: &str, #[case] expected: bool) {
+        let pypi = Pypi::from_iter(["pypi", "--platform-tags", "manylinux2014_x86_64,macosx_*"]);
+        assert_eq!(FileFilter::new(&pypi).unwrap().matches(filename), expected);
+        let pypi = Pypi::from_iter(["pypi", "--only-wheels"]);
+        assert_eq!(
+            FileFilter::new(&pypi).unwrap().matches(filename),
+            filename.ends_with(".whl")
+        );
+    }
+
+    #[test]
+    fn test_parse_project_json() {
+        let page = r#"{
+  "meta": {"_last-serial": 1, "api-version": "1.1"},
+  "name": "foo",
+  "files": [
+    {"filename": "foo-1.0.tar.gz", "url": "https://files.pythonhosted.org/packages/ab/foo-1.0.tar.gz",
+     "hashes": {"sha256": "00"}, "requires-python": ">=3.6", "yanked": false, "size": 1024},
+    {"filename": "foo-1.1.tar.gz", "url": "../../packages/cd/foo-1.1.tar.gz#sha256=11",
+     "hashes": {}, "yanked": "broken"}
+  ]
+}"#;
+        let page_url = url::Url::parse("https://pypi.org/simple/foo/").unwrap();
+        let files = parse_project_json(&page_url, page).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].url,
+            "https://files.pythonhosted.org/packages/ab/foo-1.0.tar.gz"
+        );
+        assert_eq!(files[0].sha256.as_deref(), Some("00"));
+        assert_eq!(files[0].requires_python.as_deref(), Some(">=3.6"));
+        assert_eq!(files[0].yanked, None);
+        assert_eq!(files[0].size, Some(1024));
+        assert_eq!(files[1].url, "https://pypi.org/packages/cd/foo-1.1.tar.gz");
+        assert_eq!(files[1].sha256, None);
+        assert_eq!(files[1].yanked.as_deref(), Some("broken"));
+        assert_eq!(files[1].size, None);
+    }
+
+    #[test]
+    fn test_incremental_state() {
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-pypi-incremental-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let file = PypiFile {
+            url: "https://example.com/foo-1.0.tar.gz".to_string(),
+            filename: "foo-1.0.tar.gz".to_string(),
+            sha256: Some("abc".to_string()),
+            requires_python: None,
+            yanked: None,
+            size: Some(1),
+            upload_time: None,
+            core_metadata: None,
+        };
+        let header = IncrementalHeader {
+            scope: "scope".to_string(),
+            serial: 42,
+            pending: vec!["baz".to_string()],
+        };
+        let packages = vec![
+            ("foo".to_string(), Some(40), vec![file.clone()]),
+            ("bar".to_string(), None, vec![]),
+            ("baz".to_string(), None, vec![file]),
+        ];
+        save_incremental(path, &header, &packages).unwrap();
+        assert!(load_incremental(path, "other").unwrap().is_none());
+        let (header, projects) = load_incremental(path, "scope").unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(header.serial, 42);
+        assert_eq!(header.pending, vec!["baz".to_string()]);
+        assert_eq!(projects.keys().collect::<Vec<_>>(), vec!["foo"]);
+        assert_eq!(projects["foo"].0, Some(40));
+        assert_eq!(projects["foo"].1[0].filename, "foo-1.0.tar.gz");
+    }
+
+    #[test]
+    fn test_render_bq_query() {
+        let query = render_bq_query(BQ_QUERY, 7, 5000);
+        assert!(query.contains("INTERVAL 7 DAY"));
+        assert!(query.contains("LIMIT 5000;"));
+        assert!(!query.contains('{'));
+    }
+
+    #[test]
+    fn test_bq_cache() {
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("2y"),
+            Ok(Duration::from_secs(2 * 365 * 86400))
+        );
+        assert!(parse_duration("1x").is_err());
+        assert!(parse_duration("h").is_err());
+
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-pypi-bq-cache-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        save_bq_cache(path, "query".to_string(), vec!["foo".to_string()]).unwrap();
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            load_bq_cache(path, "query", ttl),
+            Some(vec!["foo".to_string()])
+        );
+        assert_eq!(load_bq_cache(path, "other", ttl), None);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(load_bq_cache(path, "query", ttl), None);
+    }
+
+    #[test]
+    fn test_parse_top_packages() {
+        let data = r#"{
+            "last_update": "2024-01-01 00:00:00",
+            "rows": [
+                {"download_count": 10, "project": "foo"},
+                {"download_count": 30, "project": "bar"},
+                {"download_count": 20, "project": "baz"}
+            ]
+        }"#;
+        assert_eq!(parse_top_packages(data, 2).unwrap(), vec!["bar", "baz"]);
+    }
+
+    #[test]
+    fn test_rewrite_project_json() {
+        let data = r#"{
+            "info": {"name": "foo"},
+            "releases": {
+                "1.0": [
+                    {"filename": "foo-1.0.tar.gz", "url": "https://files.pythonhosted.org/packages/aa/bb/foo-1.0.tar.gz"}
+                ],
+                "2.0": [
+                    {"filename": "foo-2.0.tar.gz", "url": "https://files.pythonhosted.org/packages/cc/dd/foo-2.0.tar.gz"},
+                    {"filename": "foo-2.0-py3-none-any.whl", "url": "https://files.pythonhosted.org/packages/ee/ff/foo-2.0-py3-none-any.whl"}
+                ]
+            },
+            "urls": [
+                {"filename": "foo-2.0.tar.gz", "url": "https://files.pythonhosted.org/packages/cc/dd/foo-2.0.tar.gz"},
+                {"filename": "foo-2.0-py3-none-any.whl", "url": "https://files.pythonhosted.org/packages/ee/ff/foo-2.0-py3-none-any.whl"}
+            ]
+        }"#;
+        let keys = ["aa/bb/foo-1.0.tar.gz", "ee/ff/foo-2.0-py3-none-any.whl"]
+            .iter()
+            .copied()
+            .collect();
+        let json: serde_json::Value =
+            serde_json::from_str(&rewrite_project_json(data, &keys, "../../").unwrap()).unwrap();
+        assert_eq!(json["info"]["name"], "foo");
+        assert_eq!(
+            json["releases"]["1.0"][0]["url"],
+            "../../aa/bb/foo-1.0.tar.gz"
+        );
+        assert_eq!(json["releases"]["2.0"].as_array().unwrap().len(), 1);
+        assert_eq!(json["urls"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            json["urls"][0]["url"],
+            "../../ee/ff/foo-2.0-py3-none-any.whl"
+        );
+    }
+
+    #[test]
+    fn test_keep_recent_overrides() {
+        let keep_recent = KeepRecent {
+            default: Some(2),
+            overrides: parse_keep_recent_overrides(
+                "# large projects\nnumpy = 50\n\"scikit-*\" = 10\n\"scikit-learn-*\" = 5\n\"Scikit-Image\" = 20\n",
+            )
+            .unwrap(),
+        };
+        assert_eq!(keep_recent.get("NumPy"), Some(50));
+        assert_eq!(keep_recent.get("scikit-image"), Some(20));
+        assert_eq!(keep_recent.get("scikit_learn"), Some(10));
+        assert_eq!(keep_recent.get("scikit-learn-intelex"), Some(5));
+        assert_eq!(keep_recent.get("requests"), Some(2));
+        assert!(parse_keep_recent_overrides("numpy = \"all\"").is_err());
+    }
+
+    #[test]
+    fn test_retain_recent() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let file = |filename: &str, upload_time: Option<&str>| PypiFile {
+            url: format!("https://example.com/{}", filename),
+            filename: filename.to_string(),
+            sha256: None,
+            requires_python: None,
+            yanked: None,
+            size: None,
+            upload_time: upload_time.map(str::to_string),
+            core_metadata: None,
+        };
+        let entries = vec![
+            file("foo-1.0.tar.gz", Some("2020-01-01T00:00:00.000000Z")),
+            file("foo-2.0.tar.gz", Some("2023-06-01T00:00:00Z")),
+            file("foo-3.0.tar.gz", Some("2024-01-01T00:00:00.123456Z")),
+            file("foo-0.1.tar.gz", None),
+        ];
+        let protected = vec![(Version::parse("1.0").unwrap(), vec![])];
+        let since = chrono::DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let kept = |keep_recent, since, protected: &[_]| -> Vec<String> {
+            retain_recent(
+                &logger,
+                "foo",
+                entries.clone(),
+                keep_recent,
+                since,
+                protected,
+            )
+            .into_iter()
+            .map(|file| file.filename)
+            .collect()
+        };
+        assert_eq!(
+            upload_time(&entries[2]).map(|time| time.timestamp()),
+            Some(1704067200)
+        );
+        assert_eq!(upload_time(&entries[3]), None);
+        assert_eq!(
+            kept(None, Some(&since), &[]),
+            vec!["foo-2.0.tar.gz", "foo-3.0.tar.gz", "foo-0.1.tar.gz"]
+        );
+        assert_eq!(kept(Some(1), None, &[]), vec!["foo-3.0.tar.gz"]);
+        assert_eq!(
+            kept(Some(1), None, &protected),
+            vec!["foo-1.0.tar.gz", "foo-3.0.tar.gz"]
+        );
+        assert_eq!(
+            kept(Some(3), Some(&since), &[]),
+            vec![
+                "foo-1.0.tar.gz",
+                "foo-2.0.tar.gz",
+                "foo-3.0.tar.gz",
+                "foo-0.1.tar.gz"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matches_pins() {
+        let file = |filename: &str, sha256: &str| PypiFile {
+            url: format!("https://example.com/{}", filename),
+            filename: filename.to_string(),
+            sha256: Some(sha256.to_string()),
+            requires_python: None,
+            yanked: None,
+            size: None,
+            upload_time: None,
+            core_metadata: None,
+        };
+        let pins = vec![
+            (Version::parse("1.26").unwrap(), vec![]),
+            (Version::parse("2.0.0").unwrap(), vec!["abc".to_string()]),
+        ];
+        assert!(matches_pins(
+            "numpy",
+            &file("numpy-1.26.0.tar.gz", "00"),
+            &pins
+        ));
+        assert!(!matches_pins(
+            "numpy",
+            &file("numpy-1.26.1.tar.gz", "00"),
+            &pins
+        ));
+        assert!(matches_pins(
+            "numpy",
+            &file("numpy-2.0.0-cp312-cp312-win_amd64.whl", "abc"),
+            &pins
+        ));
+        assert!(!matches_pins(
+            "numpy",
+            &file("numpy-2.0.0.tar.gz", "def"),
+            &pins
+        ));
+    }
+
+    #[test]
+    fn test_status_file() {
+        let mut pypi = Pypi::from_iter(["pypi", "--status-files"]);
+        pypi.status = PypiStatus {
+            last_serial: Some(42),
+            project_serials: vec![("foo".to_string(), 40)].into_iter().collect(),
+            synced_at: Some(
+                chrono::DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+                    .unwrap()
+                    .with_timezone(&chrono::Utc),
+            ),
+        };
+        let status = |key| pypi.status_file(key).unwrap();
+        assert_eq!(status("last-serial").as_deref(), Some("42\n"));
+        assert_eq!(status("status").as_deref(), Some("42"));
+        assert_eq!(status("generation").as_deref(), Some("5"));
+        assert_eq!(
+            status("web/last-modified").as_deref(),
+            Some("20240102T03:04:05\n")
+        );
+        assert_eq!(status("web/serial/foo").as_deref(), Some("40\n"));
+        assert_eq!(
+            status("status.json").as_deref(),
+            Some(
+                r#"{"generation":5,"last-modified":"20240102T03:04:05","last-serial":42,"projects":1}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_fallback_bases() {
+        let pypi = Pypi::from_iter(["pypi"]);
+        assert_eq!(pypi.simple_base.len(), 1);
+        let pypi = Pypi::from_iter([
+            "pypi",
+            "--simple-base",
+            "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/simple",
+            "--simple-base",
+            "https://pypi.org/simple",
+        ]);
+        assert_eq!(
+            pypi.simple_base,
+            vec![
+                "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/simple",
+                "https://pypi.org/simple"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_official_fallback() {
+        let pypi = Pypi::from_iter(["pypi", "--official-fallback"]);
+        assert_eq!(
+            pypi.simple_bases(),
+            vec![
+                "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/simple",
+                "https://pypi.org/simple"
+            ]
+        );
+        assert_eq!(
+            pypi.package_bases(),
+            vec![
+                "https://mirrors.tuna.tsinghua.edu.cn/pypi/web/packages",
+                "https://files.pythonhosted.org/packages"
+            ]
+        );
+        let pypi = Pypi::from_iter([
+            "pypi",
+            "--official-fallback",
+            "--simple-base",
+            "https://pypi.org/simple/",
+        ]);
+        assert_eq!(pypi.simple_bases(), vec!["https://pypi.org/simple/"]);
+    }
+
+    #[test]
+    fn test_core_metadata() {
+        let page = r#"
+    <a href="../../packages/ab/foo-1.0-py3-none-any.whl#sha256=00" data-dist-info-metadata="sha256=11" data-core-metadata="sha256=11">foo-1.0-py3-none-any.whl</a><br />
+    <a href="../../packages/cd/foo-1.1-py3-none-any.whl#sha256=22" data-core-metadata="true">foo-1.1-py3-none-any.whl</a><br />
+    <a href="../../packages/ef/foo-1.1.tar.gz#sha256=33">foo-1.1.tar.gz</a><br />
+"#;
+        let page_url = url::Url::parse("https://pypi.org/simple/foo/").unwrap();
+        let files = parse_project_page(&page_url, page);
+        let core_metadata: Vec<_> = files
+            .iter()
+            .map(|file| file.core_metadata.as_deref())
+            .collect();
+        assert_eq!(core_metadata, vec![Some("11"), Some(""), None]);
+
+        let json = r#"{"meta": {"api-version": "1.1"}, "name": "foo", "files": [
+            {"filename": "foo-1.0-py3-none-any.whl", "url": "a.whl", "hashes": {}, "core-metadata": {"sha256": "11"}},
+            {"filename": "foo-1.1-py3-none-any.whl", "url": "b.whl", "hashes": {}, "dist-info-metadata": true},
+            {"filename": "foo-1.1.tar.gz", "url": "c.tar.gz", "hashes": {}, "core-metadata": false}
+        ]}"#;
+        let core_metadata: Vec<_> = parse_project_json(&page_url, json)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.core_metadata)
+            .collect();
+        assert_eq!(
+            core_metadata,
+            vec![Some("11".to_string()), Some(String::new()), None]
+        );
+
+        let mut pypi = Pypi::from_iter(["pypi", "--simple-index"]);
+        pypi.index.insert(
+            "foo".to_string(),
+            files
+                .into_iter()
+                .map(|file| (file.url.replace("https://pypi.org/packages/", ""), file))
+                .collect(),
+        );
+        let html = pypi.simple_page("foo/index.html").unwrap().unwrap();
+        assert!(html.contains(r#"data-dist-info-metadata="sha256=11" data-core-metadata="sha256=11">foo-1.0-py3-none-any.whl"#));
+        assert!(html.contains(
+            r#"data-dist-info-metadata="true" data-core-metadata="true">foo-1.1-py3-none-any.whl"#
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&pypi.simple_page("foo/index.v1_json").unwrap().unwrap()).unwrap();
+        assert_eq!(
+            json["files"][0]["core-metadata"],
+            serde_json::json!({ "sha256": "11" })
+        );
+        assert_eq!(json["files"][1]["dist-info-metadata"], true);
+        assert!(json["files"][2].get("core-metadata").is_none());
+    }
+
+    #[test]
+    fn test_dedup_projects() {
+        let projects = [
+            "Flask",
+            "requests",
+            "flask",
+            "zope.interface",
+            "Zope_Interface",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(
+            dedup_projects(projects),
+            vec!["flask", "requests", "zope-interface"]
+        );
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let url = url::Url::parse("https://pypi.org/simple/foo/").unwrap();
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert!(CachedPage::new(&url, &headers, (None, vec![])).is_none());
+        headers.insert(reqwest::header::ETAG, "\"abc\"".parse().unwrap());
+        let page = CachedPage::new(&url, &headers, (Some(1), vec![])).unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-pypi-page-cache-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let pages = vec![("foo".to_string(), page)].into_iter().collect();
+        save_page_cache(path, "scope", &pages).unwrap();
+        assert!(load_page_cache(path, "other").unwrap().is_empty());
+        let pages = load_page_cache(path, "scope").unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(pages["foo"].url, "https://pypi.org/simple/foo/");
+        assert_eq!(pages["foo"].etag.as_deref(), Some("\"abc\""));
+        assert_eq!(pages["foo"].last_modified, None);
+        assert_eq!(pages["foo"].serial, Some(1));
+    }
+
+    #[rstest]
+    #[case("numpy-2.0.0rc1-cp39-cp39-win_amd64.whl", true)]
+    #[case("numpy-2.0.0b1.tar.gz", true)]
+    #[case("numpy-2.0.0.dev0.tar.gz", true)]
+    #[case("numpy-2.0.0.tar.gz", false)]
+    #[case("numpy-2.0.0.post1.tar.gz", false)]
+    #[case("numpy-unknown.tar.gz", false)]
+    fn test_is_prerelease(#[case] filename: &str, #[case] expected: bool) {
+        assert_eq!(is_prerelease("numpy", filename), expected);
+    }
+
+    #[test]
+    fn test_parse_requires_dist() {
+        let requires_dist: Vec<String> = [
+            "charset-normalizer<4,>=2",
+            "idna (<4,>=2.5)",
+            "urllib3<3,>=1.21.1",
+            "PySocks!=1.5.7,>=1.5.6; extra == \"socks\"",
+            "colorama; sys_platform == \"win32\"",
+            "zope.interface>=5",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(
+            parse_requires_dist(&requires_dist),
+            vec![
+                "charset-normalizer",
+                "idna",
+                "urllib3",
+                "colorama",
+                "zope.interface"
+            ]
+        );
     }
 }
@@ -0,0 +1,141 @@
+//! A submodule for pypi source that reads changelog of PyPI with XML-RPC API.
+//!
+//! `changelog_last_serial` returns the latest serial of PyPI, and
+//! `changelog_since_serial` returns projects changed after a serial, so that
+//! only these projects are scanned again.
+
+use std::collections::BTreeSet;
+
+use regex::Regex;
+use reqwest::Client;
+
+use crate::error::{Error, Result};
+
+fn method_call(method: &str, params: &[u64]) -> String {
+    let params: String = params
+        .iter()
+        .map(|param| format!("<param><value><int>{}</int></value></param>", param))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\n<methodCall><methodName>{}</methodName><params>{}</params></methodCall>",
+        method, params
+    )
+}
+
+async fn call(client: &Client, url: &str, method: &str, params: &[u64]) -> Result<String> {
+    let response = crate::http::send(
+        client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "text/xml")
+            .body(method_call(method, params)),
+    )
+    .await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    let body = response.text().await?;
+    if body.contains("<fault>") {
+        return Err(Error::ProcessError(format!(
+            "XML-RPC {} failed: {}",
+            method, body
+        )));
+    }
+    Ok(body)
+}
+
+/// Parse response of `changelog_last_serial`.
+fn parse_last_serial(body: &str) -> Option<u64> {
+    static RE_INT: once_cell::sync::Lazy<Regex> =
+        once_cell::sync::Lazy::new(|| Regex::new(r"<(?:int|i4|i8)>\s*(\d+)\s*</").unwrap());
+    RE_INT.captures(body).and_then(|cap| cap[1].parse().ok())
+}
+
+/// Parse response of `changelog_since_serial`, whose entries are
+/// `[name, version, timestamp, action, serial]`. Returns names of changed
+/// projects, and the max serial in changelog.
+fn parse_changelog(body: &str) -> (BTreeSet<String>, Option<u64>) {
+    const SCALAR: &str = r"<value>\s*(?:<(?:string|int|i4|i8)>([^<]*)</(?:string|int|i4|i8)>|<nil\s*/>|([^<]*))\s*</value>\s*";
+    static RE_ENTRY: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
+        Regex::new(&format!(r"<data>\s*{0}{0}{0}{0}{0}</data>", SCALAR)).unwrap()
+    });
+    let mut projects = BTreeSet::new();
+    let mut serial = None;
+    for entry in RE_ENTRY.captures_iter(body) {
+        let value = |idx: usize| {
+            entry
+                .get(idx * 2 + 1)
+                .or_else(|| entry.get(idx * 2 + 2))
+                .map(|x| html_escape::decode_html_entities(x.as_str()).to_string())
+        };
+        if let Some(name) = value(0) {
+            projects.insert(name);
+        }
+        if let Some(entry_serial) = value(4).and_then(|x| x.trim().parse::<u64>().ok()) {
+            serial = serial.max(Some(entry_serial));
+        }
+    }
+    (projects, serial)
+}
+
+/// Latest serial of PyPI.
+pub async fn last_serial(client: &Client, url: &str) -> Result<u64> {
+    let body = call(client, url, "changelog_last_serial", &[]).await?;
+    parse_last_serial(&body).ok_or_else(|| {
+        Error::ProcessError(format!("invalid changelog_last_serial response: {}", body))
+    })
+}
+
+/// Projects changed after `serial`, and the max serial of these changes.
+pub async fn since_serial(
+    client: &Client,
+    url: &str,
+    serial: u64,
+) -> Result<(BTreeSet<String>, Option<u64>)> {
+    let body = call(client, url, "changelog_since_serial", &[serial]).await?;
+    Ok(parse_changelog(&body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_changelog() {
+        let body = r#"<?xml version='1.0'?>
+<methodResponse>
+<params>
+<param>
+<value><array><data>
+<value><array><data>
+<value><string>foo</string></value>
+<value><string>1.0</string></value>
+<value><int>1700000000</int></value>
+<value><string>new release</string></value>
+<value><int>100</int></value>
+</data></array></value>
+<value><array><data>
+<value><string>bar&amp;baz</string></value>
+<value><nil/></value>
+<value><int>1700000001</int></value>
+<value><string>remove project</string></value>
+<value><int>102</int></value>
+</data></array></value>
+</data></array></value>
+</param>
+</params>
+</methodResponse>
+"#;
+        let (projects, serial) = parse_changelog(body);
+        assert_eq!(
+            projects.into_iter().collect::<Vec<_>>(),
+            vec!["bar&baz".to_string(), "foo".to_string()]
+        );
+        assert_eq!(serial, Some(102));
+        assert_eq!(
+            parse_last_serial(
+                "<methodResponse><params><param><value><int>42</int></value></param></params></methodResponse>"
+            ),
+            Some(42)
+        );
+    }
+}
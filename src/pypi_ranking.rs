@@ -0,0 +1,121 @@
+//! A submodule for pypi source that ranks projects by access logs of our mirror.
+//!
+//! Both nginx (combined format) and Caddy (JSON format) access logs are
+//! supported, optionally gzipped. Each successful request to a distribution
+//! file under `packages/` counts as a download of its project.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::error::Result;
+
+#[derive(Deserialize)]
+struct CaddyRequest {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct CaddyLog {
+    request: CaddyRequest,
+    status: u16,
+}
+
+/// Request path and status code of a log line.
+fn parse_line(line: &str) -> Option<(String, u16)> {
+    static RE_COMBINED: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
+        Regex::new(r#""(?:GET|HEAD) ([^ "]+)[^"]*" (\d{3}) "#).unwrap()
+    });
+    if line.starts_with('{') {
+        let log: CaddyLog = serde_json::from_str(line).ok()?;
+        return Some((log.request.uri, log.status));
+    }
+    let cap = RE_COMBINED.captures(line)?;
+    Some((cap[1].to_string(), cap[2].parse().ok()?))
+}
+
+/// Project name of a distribution file at `path`.
+fn project_of(path: &str) -> Option<String> {
+    let path = path.split(['?', '#']).next()?;
+    if !path.contains("/packages/") {
+        return None;
+    }
+    let filename = path.rsplit('/').next()?;
+    let name = if filename.ends_with(".whl") || filename.ends_with(".egg") {
+        filename.split('-').next()?
+    } else {
+        let stem = [".tar.gz", ".tar.bz2", ".tgz", ".zip"]
+            .iter()
+            .find_map(|ext| filename.strip_suffix(ext))?;
+        stem.rsplit_once('-')?.0
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(crate::pypi::normalize_name(name))
+}
+
+/// Count downloads of each project in access log.
+fn count_downloads(log: impl BufRead, counts: &mut HashMap<String, u64>) -> Result<()> {
+    for line in log.lines() {
+        let line = line?;
+        if let Some((path, status)) = parse_line(&line) {
+            if !(200..300).contains(&status) && status != 304 {
+                continue;
+            }
+            if let Some(project) = project_of(&path) {
+                *counts.entry(project).or_default() += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Projects most downloaded in access logs at `paths`, at most `limit` of
+/// them. Logs ending with `.gz` are decompressed.
+pub fn rank_from_logs(paths: &[String], limit: usize) -> Result<Vec<String>> {
+    let mut counts = HashMap::new();
+    for path in paths {
+        let file = std::fs::File::open(path)?;
+        let reader: Box<dyn Read> = if path.ends_with(".gz") {
+            Box::new(flate2::read::MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        count_downloads(BufReader::new(reader), &mut counts)?;
+    }
+    let mut ranking: Vec<(String, u64)> = counts.into_iter().collect();
+    ranking.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+    Ok(ranking
+        .into_iter()
+        .take(limit)
+        .map(|(project, _)| project)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_downloads() {
+        let log = r#"1.2.3.4 - - [15/Oct/2026:10:00:00 +0800] "GET /pypi/web/packages/ab/cd/ef/numpy-2.1.0-cp312-cp312-manylinux_2_17_x86_64.whl HTTP/1.1" 200 1000 "-" "pip/24.0"
+1.2.3.4 - - [15/Oct/2026:10:00:01 +0800] "GET /pypi/web/packages/ab/cd/ef/Zope.Interface-7.0.tar.gz HTTP/1.1" 200 1000 "-" "pip/24.0"
+1.2.3.4 - - [15/Oct/2026:10:00:02 +0800] "GET /pypi/web/packages/ab/cd/ef/numpy-2.1.0.tar.gz HTTP/1.1" 404 0 "-" "pip/24.0"
+1.2.3.4 - - [15/Oct/2026:10:00:03 +0800] "GET /pypi/web/simple/numpy/ HTTP/1.1" 200 1000 "-" "pip/24.0"
+{"level":"info","request":{"method":"GET","uri":"/pypi/web/packages/ab/cd/ef/numpy-2.1.0.tar.gz?x=1"},"status":200}
+{"level":"info","request":{"method":"GET","uri":"/pypi/web/packages/ab/cd/ef/zope_interface-7.0-py3-none-any.whl"},"status":304}
+not a log line
+"#;
+        let mut counts = HashMap::new();
+        count_downloads(log.as_bytes(), &mut counts).unwrap();
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![("numpy".to_string(), 2), ("zope-interface".to_string(), 2)]
+        );
+    }
+}
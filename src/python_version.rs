@@ -1,27 +1,45 @@
 //! A submodule for pypi source that implements python package version semantics.
+//!
+//! `Version` parses and orders versions, and `SpecifierSet` evaluates version
+//! specifiers like `>=3.8, <4` (e.g. `Requires-Python`) against them.
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 
 use nom::branch::alt;
-use nom::bytes::complete::{is_a, tag_no_case};
+use nom::bytes::complete::tag_no_case;
 use nom::character::complete;
-use nom::character::complete::{char, one_of};
-use nom::combinator::{all_consuming, opt, success};
+use nom::character::complete::{alphanumeric1, char, one_of};
+use nom::combinator::{all_consuming, opt, recognize, success};
 use nom::error::Error;
 use nom::multi::{many0, separated_list1};
 use nom::sequence::{preceded, terminated, tuple};
 use nom::{Finish, IResult, Parser};
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// A version of python package, following [PEP 440].
+///
+/// Trailing zeros of release segment are kept as written, but ignored when
+/// comparing, e.g. `1.0` equals to `1`.
+///
+/// [PEP 440]: https://peps.python.org/pep-0440/
+#[derive(Debug, Clone)]
 pub struct Version {
-    pub epoch: u32,
-    pub chunks: Vec<u32>,
+    pub epoch: u64,
+    pub chunks: Vec<u64>,
     pub pre: Option<PreRelease>,
-    pub post: Option<u32>,
-    pub dev: Option<u32>,
+    pub post: Option<u64>,
+    pub dev: Option<u64>,
+    /// Local version label, normalized to lowercase and separated by `.`.
     pub local: Option<String>,
 }
 
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
 impl PartialOrd for Version {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -37,15 +55,41 @@ fn rev_option_ord<T: Ord>(lhs: &Option<T>, rhs: &Option<T>) -> Ordering {
     }
 }
 
+/// Local version labels are compared segment by segment. Numeric segments
+/// are compared as integers and sort after alphanumeric ones, which are
+/// compared lexicographically. A label sorts after its prefixes.
+fn local_ord(lhs: &Option<String>, rhs: &Option<String>) -> Ordering {
+    let segments = |local: &'_ Option<String>| -> Vec<Result<u64, String>> {
+        local
+            .iter()
+            .flat_map(|local| local.split('.'))
+            .map(|segment| segment.parse().map_err(|_| segment.to_string()))
+            .collect()
+    };
+    let (lhs, rhs) = (segments(lhs), segments(rhs));
+    for (lhs, rhs) in lhs.iter().zip(rhs.iter()) {
+        let ord = match (lhs, rhs) {
+            (Ok(lhs), Ok(rhs)) => lhs.cmp(rhs),
+            (Err(lhs), Err(rhs)) => lhs.cmp(rhs),
+            (Ok(_), Err(_)) => Ordering::Greater,
+            (Err(_), Ok(_)) => Ordering::Less,
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    lhs.len().cmp(&rhs.len())
+}
+
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> Ordering {
         self.epoch
             .cmp(&other.epoch)
-            .then_with(|| self.chunks.cmp(&other.chunks))
-            .then_with(|| rev_option_ord(&self.pre, &other.pre))
+            .then_with(|| self.release().cmp(other.release()))
+            .then_with(|| self.pre_key().cmp(&other.pre_key()))
             .then_with(|| self.post.cmp(&other.post))
             .then_with(|| rev_option_ord(&self.dev, &other.dev))
-            .then_with(|| self.local.cmp(&other.local))
+            .then_with(|| local_ord(&self.local, &other.local))
     }
 }
 
@@ -59,10 +103,41 @@ impl Version {
         let (_, version) = all_consuming(version).parse(s).finish()?;
         Ok(version)
     }
+    /// Release segment without trailing zeros.
+    fn release(&self) -> &[u64] {
+        let len = self
+            .chunks
+            .iter()
+            .rposition(|&v| v != 0)
+            .map_or(0, |idx| idx + 1);
+        &self.chunks[..len]
+    }
+    /// Key to order pre-releases. A developmental release of final release
+    /// (e.g. `1.0.dev1`) sorts before all pre-releases (e.g. `1.0a1`), and
+    /// final release sorts after them.
+    fn pre_key(&self) -> (u8, Option<PreRelease>) {
+        match (self.pre, self.post, self.dev) {
+            (None, None, Some(_)) => (0, None),
+            (Some(pre), _, _) => (1, Some(pre)),
+            (None, _, _) => (2, None),
+        }
+    }
     /// Returns true if this version is stable.
     pub const fn is_stable(&self) -> bool {
         self.pre.is_none() && self.dev.is_none()
     }
+    /// Returns true if `other` has the same epoch and release segment, e.g.
+    /// `1.0rc1`, `1.0.post1` and `1.0+local` share the release of `1.0`.
+    fn same_release(&self, other: &Self) -> bool {
+        self.epoch == other.epoch && self.release() == other.release()
+    }
+    /// This version without local version label.
+    fn public(&self) -> Self {
+        Self {
+            local: None,
+            ..self.clone()
+        }
+    }
 }
 
 impl Display for Version {
@@ -94,9 +169,9 @@ impl Display for Version {
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub enum PreRelease {
-    Alpha(u32),
-    Beta(u32),
-    RC(u32),
+    Alpha(u64),
+    Beta(u64),
+    RC(u64),
 }
 
 impl Display for PreRelease {
@@ -109,6 +184,137 @@ impl Display for PreRelease {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operator {
+    Compatible,
+    Equal,
+    NotEqual,
+    LessEqual,
+    GreaterEqual,
+    Less,
+    Greater,
+    Arbitrary,
+}
+
+/// A version specifier clause, e.g. `>=3.6` or `!=3.0.*`.
+#[derive(Debug, Clone)]
+pub struct Specifier {
+    op: Operator,
+    version: Version,
+    wildcard: bool,
+    raw: String,
+}
+
+impl Specifier {
+    /// Parse a version specifier clause. Wildcards are also accepted (and
+    /// ignored) on ordered comparisons, as some packages use them.
+    pub fn parse(s: &str) -> Option<Self> {
+        const OPERATORS: &[(&str, Operator)] = &[
+            ("===", Operator::Arbitrary),
+            ("~=", Operator::Compatible),
+            ("==", Operator::Equal),
+            ("!=", Operator::NotEqual),
+            ("<=", Operator::LessEqual),
+            (">=", Operator::GreaterEqual),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+        ];
+        let s = s.trim();
+        let (op, rest) = OPERATORS
+            .iter()
+            .find_map(|(tag, op)| s.strip_prefix(tag).map(|rest| (*op, rest.trim())))?;
+        let (rest, wildcard) = match rest.strip_suffix(".*") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let version = match Version::parse(rest) {
+            Ok(version) => version,
+            Err(_) if op == Operator::Arbitrary => Version::parse("0").unwrap(),
+            Err(_) => return None,
+        };
+        if op == Operator::Compatible && version.chunks.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            op,
+            version,
+            wildcard,
+            raw: rest.to_string(),
+        })
+    }
+
+    /// Release segment of `version` starts with `prefix`, padded with zeros.
+    fn prefix_match(version: &Version, epoch: u64, prefix: &[u64]) -> bool {
+        version.epoch == epoch
+            && prefix
+                .iter()
+                .enumerate()
+                .all(|(idx, chunk)| version.chunks.get(idx).copied().unwrap_or(0) == *chunk)
+    }
+
+    /// Returns true if `version` matches this clause.
+    pub fn contains(&self, version: &Version) -> bool {
+        let spec = &self.version;
+        match self.op {
+            Operator::Equal | Operator::NotEqual => {
+                let equal = if self.wildcard {
+                    Self::prefix_match(version, spec.epoch, &spec.chunks)
+                } else if spec.local.is_none() {
+                    version.public() == *spec
+                } else {
+                    version == spec
+                };
+                equal == (self.op == Operator::Equal)
+            }
+            Operator::Compatible => {
+                version >= spec
+                    && Self::prefix_match(
+                        version,
+                        spec.epoch,
+                        &spec.chunks[..spec.chunks.len() - 1],
+                    )
+            }
+            Operator::LessEqual => version.public() <= *spec,
+            Operator::GreaterEqual => version.public() >= *spec,
+            // `<V` excludes pre-releases of V, unless V is a pre-release
+            Operator::Less => {
+                version < spec
+                    && !(spec.is_stable() && !version.is_stable() && version.same_release(spec))
+            }
+            // `>V` excludes post-releases of V unless V is a post-release, and
+            // local versions of V
+            Operator::Greater => {
+                version > spec
+                    && !(spec.post.is_none()
+                        && (version.post.is_some() || version.local.is_some())
+                        && version.same_release(spec))
+            }
+            Operator::Arbitrary => version.to_string() == self.raw,
+        }
+    }
+}
+
+/// A comma separated list of version specifier clauses, e.g. `>=3.6, <4`,
+/// which matches versions matching all clauses.
+#[derive(Debug, Clone)]
+pub struct SpecifierSet(Vec<Specifier>);
+
+impl SpecifierSet {
+    /// Parse version specifiers. Returns `None` if any clause is invalid.
+    pub fn parse(s: &str) -> Option<Self> {
+        s.split(',')
+            .filter(|clause| !clause.trim().is_empty())
+            .map(Specifier::parse)
+            .collect::<Option<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// Returns true if `version` matches all clauses.
+    pub fn contains(&self, version: &Version) -> bool {
+        self.0.iter().all(|clause| clause.contains(version))
+    }
+}
+
 fn pre_release(input: &str) -> IResult<&str, PreRelease> {
     let (input, _) = opt(one_of("._-")).parse(input)?;
 
@@ -121,20 +327,20 @@ fn pre_release(input: &str) -> IResult<&str, PreRelease> {
         tag_no_case("pre"),
     ));
     let (input, f) = alt((
-        alpha.map(|_| PreRelease::Alpha as fn(u32) -> PreRelease),
-        beta.map(|_| PreRelease::Beta as fn(u32) -> PreRelease),
-        rc.map(|_| PreRelease::RC as fn(u32) -> PreRelease),
+        alpha.map(|_| PreRelease::Alpha as fn(u64) -> PreRelease),
+        beta.map(|_| PreRelease::Beta as fn(u64) -> PreRelease),
+        rc.map(|_| PreRelease::RC as fn(u64) -> PreRelease),
     ))
     .parse(input)?;
 
-    let (input, version) = preceded(opt(one_of("._-")), complete::u32)
+    let (input, version) = preceded(opt(one_of("._-")), complete::u64)
         .or(success(0))
         .parse(input)?;
 
     Ok((input, f(version)))
 }
 
-fn post_release(input: &str) -> IResult<&str, u32> {
+fn post_release(input: &str) -> IResult<&str, u64> {
     let (input, sep) = opt(one_of("._-")).parse(input)?;
     let (input, has_tag) =
         match alt((tag_no_case("post"), tag_no_case("rev"), tag_no_case("r"))).parse(input) {
@@ -142,7 +348,7 @@ fn post_release(input: &str) -> IResult<&str, u32> {
             Err(_) if sep == Some('-') => (input, false),
             Err(e) => return Err(e),
         };
-    let (input, version) = match preceded(opt(one_of("._-")), complete::u32).parse(input) {
+    let (input, version) = match preceded(opt(one_of("._-")), complete::u64).parse(input) {
         Ok((input, version)) => (input, version),
         Err(_) if has_tag => (input, 0),
         Err(e) => return Err(e),
@@ -150,26 +356,26 @@ fn post_release(input: &str) -> IResult<&str, u32> {
     Ok((input, version))
 }
 
-fn dev_release(input: &str) -> IResult<&str, u32> {
+fn dev_release(input: &str) -> IResult<&str, u64> {
     let (input, _) = opt(one_of("._-")).parse(input)?;
     let (input, _) = tag_no_case("dev").parse(input)?;
 
-    complete::u32.or(success(0)).parse(input)
+    complete::u64.or(success(0)).parse(input)
 }
 
 fn version(input: &str) -> IResult<&str, Version> {
-    let epoch = terminated(complete::u32, char('!')).or(success(0));
-    let chunks = separated_list1(char('.'), complete::u32);
+    let epoch = terminated(complete::u64, char('!')).or(success(0));
+    let chunks = separated_list1(char('.'), complete::u64);
     let local = preceded(
         char('+'),
-        is_a("0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ-_."),
+        recognize(separated_list1(one_of("-_."), alphanumeric1)),
     );
 
     let trim = || many0(one_of(" \t\n\r\u{0b}\u{0c}"));
 
     let (input, version) = tuple((
         trim(),
-        opt(char('v')),
+        opt(one_of("vV")),
         epoch,
         chunks,
         opt(pre_release),
@@ -178,18 +384,13 @@ fn version(input: &str) -> IResult<&str, Version> {
         opt(local),
         trim(),
     ))
-    .map(|(_, _, epoch, mut chunks, pre, post, dev, local, _)| {
-        if let Some(non_zero) = chunks.iter().rposition(|&v| v != 0) {
-            chunks.truncate(non_zero + 1);
-        }
-        Version {
-            epoch,
-            chunks,
-            pre,
-            post,
-            dev,
-            local: local.map(|s| s.replace(['-', '_'], ".")),
-        }
+    .map(|(_, _, epoch, chunks, pre, post, dev, local, _)| Version {
+        epoch,
+        chunks,
+        pre,
+        post,
+        dev,
+        local: local.map(|s| s.replace(['-', '_'], ".").to_lowercase()),
     })
     .parse(input)?;
     Ok((input, version))
@@ -200,7 +401,7 @@ mod tests {
     use itertools::Itertools;
     use rstest::rstest;
 
-    use super::Version;
+    use super::{SpecifierSet, Version};
 
     #[rstest]
     #[case("0")]
@@ -282,6 +483,124 @@ mod tests {
         assert_eq!(Version::parse(input).unwrap().is_stable(), expect);
     }
 
+    #[rstest]
+    #[case("1.0+")]
+    #[case("1.0+abc.")]
+    #[case("1.0+a..b")]
+    #[case("1.0+a b")]
+    #[case("1.0.")]
+    #[case("!1.0")]
+    #[case("1.0a1a2")]
+    #[case("1.0.post1.post2")]
+    #[case("1.0dev1.post1")]
+    fn test_invalid(#[case] input: &str) {
+        assert!(Version::parse(input).is_err(), "{}", input);
+    }
+
+    #[test]
+    fn test_pep440_ord() {
+        // examples from PEP 440, in ascending order
+        let versions = [
+            "1.dev0",
+            "1.0.dev456",
+            "1.0a1",
+            "1.0a2.dev456",
+            "1.0a12.dev456",
+            "1.0a12",
+            "1.0b1.dev456",
+            "1.0b2",
+            "1.0b2.post345.dev456",
+            "1.0b2.post345",
+            "1.0rc1.dev456",
+            "1.0rc1",
+            "1.0",
+            "1.0+abc.5",
+            "1.0+abc.7",
+            "1.0+5",
+            "1.0.post456.dev34",
+            "1.0.post456",
+            "1.0.15",
+            "1.1.dev1",
+            "1!0.1",
+        ];
+        for (lhs, rhs) in versions.iter().tuple_windows() {
+            assert!(
+                Version::parse(lhs).unwrap() < Version::parse(rhs).unwrap(),
+                "{} < {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn test_real_world_ord() {
+        // versions seen on PyPI, in ascending order
+        let versions = [
+            "0.0.0.20230101",
+            "0.9.9",
+            "0.10.0.dev0",
+            "0.10.0a0",
+            "0.10.0b1.post1",
+            "0.10.0rc2",
+            "0.10.0",
+            "0.10.0.post0.dev1",
+            "0.10.0.post0",
+            "0.10.0.post2",
+            "0.10.0.1",
+            "0.10.1",
+            "1.13.1",
+            "2.0.0.dev20230301",
+            "2.0.0.dev20230302",
+            "2.0.0rc1",
+            "2.0.0",
+            "2.0.0+cpu",
+            "2.0.0+cu117",
+            "2.0.0+cu118",
+            "2.0.0+rocm5.4.2",
+            "2.0.0+rocm5.4.10",
+            "2.0.0.post1",
+            "2.0.1",
+            "2.0.10",
+            "10.0",
+            "2014.4",
+            "20230101.1",
+            "1!0.0.1.dev0",
+            "1!0.0.1",
+            "2!0.0.0",
+        ];
+        for (lhs, rhs) in versions.iter().tuple_windows() {
+            assert!(
+                Version::parse(lhs).unwrap() < Version::parse(rhs).unwrap(),
+                "{} < {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[rstest]
+    #[case("1.0", "1")]
+    #[case("1.0.0", "1.0")]
+    #[case("1.0+Ubuntu-1", "1.0+ubuntu.1")]
+    #[case("V1.0", "1.0")]
+    #[case("1.0c1", "1.0rc1")]
+    #[case("1.0-1", "1.0.post1")]
+    #[case("1.0.dev20230101123456", "1.0.dev20230101123456")]
+    fn test_eq(#[case] lhs: &str, #[case] rhs: &str) {
+        assert_eq!(Version::parse(lhs).unwrap(), Version::parse(rhs).unwrap());
+    }
+
+    #[rstest]
+    #[case("1.0+9", "1.0+10")]
+    #[case("1.0+abc", "1.0+1")]
+    #[case("1.0+abc", "1.0+abc.1")]
+    #[case("1.0.dev1", "1.0a1.dev1")]
+    #[case("1.0rc1", "1.0.0")]
+    fn test_lt(#[case] lhs: &str, #[case] rhs: &str) {
+        assert!(Version::parse(lhs).unwrap() < Version::parse(rhs).unwrap());
+    }
+
     #[test]
     fn test_ord() {
         let versions = [
@@ -311,4 +630,43 @@ mod tests {
         let versions: Vec<_> = versions.into_iter().map(|v| v.to_string()).collect();
         insta::assert_debug_snapshot!(versions);
     }
+
+    #[rstest]
+    #[case(">=3.6", "3.9.1", true)]
+    #[case(">=3.6", "3.5.10", false)]
+    #[case(">=2.7, !=3.0.*, !=3.1.*", "3.1.4", false)]
+    #[case(">=2.7, !=3.0.*, !=3.1.*", "3.2", true)]
+    #[case("~=3.6", "3.12.0", true)]
+    #[case("~=3.6", "4.0", false)]
+    #[case("~=3.6.1", "3.6.0", false)]
+    #[case("~=3.6.1", "3.7.0", false)]
+    #[case("==3.*", "3.12.0", true)]
+    #[case("==3.9", "3.9.0", true)]
+    #[case("==3.9", "3.9.1", false)]
+    #[case("<3.10", "3.10.0", false)]
+    #[case(">3.9", "3.9.0", false)]
+    #[case(">=3.6.*", "3.7.0", true)]
+    #[case("<3.10", "3.10.0rc1", false)]
+    #[case("<3.10", "3.10.0.dev1", false)]
+    #[case("<3.10rc2", "3.10.0rc1", true)]
+    #[case("<3.10", "3.9.20rc1", true)]
+    #[case(">3.9", "3.9.0.post1", false)]
+    #[case(">3.9.post1", "3.9.0.post2", true)]
+    #[case(">3.9", "3.9+local", false)]
+    #[case(">3.9", "3.10+local", true)]
+    #[case("<=3.9", "3.9+local", true)]
+    #[case(">=3.9", "3.9+local", true)]
+    #[case("==3.9", "3.9+local", true)]
+    #[case("==3.9+local", "3.9+other", false)]
+    #[case("!=3.9", "3.9+local", false)]
+    #[case(">=1!0.1", "2.0", false)]
+    #[case("==1!2.*", "1!2.0.1", true)]
+    #[case("", "3.9.0", true)]
+    fn test_specifiers(#[case] specifiers: &str, #[case] version: &str, #[case] expect: bool) {
+        let specifiers = SpecifierSet::parse(specifiers).unwrap();
+        assert_eq!(
+            specifiers.contains(&Version::parse(version).unwrap()),
+            expect
+        );
+    }
 }
@@ -0,0 +1,150 @@
+//! Container registry client
+//!
+//! Registries (e.g. GHCR, Docker Hub) reply requests without token with 401
+//! and a `WWW-Authenticate: Bearer realm=...` challenge. `Registry` requests
+//! an anonymous token as the challenge requires, and keeps it for later
+//! requests to the same repository.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::{ACCEPT, WWW_AUTHENTICATE};
+use reqwest::{Method, Response, StatusCode};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+#[derive(Deserialize)]
+struct RegistryToken {
+    token: String,
+}
+
+/// Token URL and query of a `Bearer realm="...",service="...",scope="..."`
+/// authentication challenge.
+fn parse_challenge(challenge: &str) -> Option<(String, Vec<(String, String)>)> {
+    static PARAM: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(\w+)="([^"]*)""#).unwrap());
+    let params = challenge.strip_prefix("Bearer ")?;
+    let mut realm = None;
+    let mut query = vec![];
+    for capture in PARAM.captures_iter(params) {
+        match &capture[1] {
+            "realm" => realm = Some(capture[2].to_string()),
+            key => query.push((key.to_string(), capture[2].to_string())),
+        }
+    }
+    Some((realm?, query))
+}
+
+#[derive(Default)]
+pub struct Registry {
+    /// Tokens of each repository.
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl Registry {
+    /// Request an anonymous token as `challenge` requires.
+    async fn request_token(&self, client: &reqwest::Client, challenge: &str) -> Result<String> {
+        let (realm, query) = parse_challenge(challenge)
+            .ok_or_else(|| Error::ProcessError(format!("unsupported challenge: {}", challenge)))?;
+        let response = crate::http::send(client.get(&realm).query(&query)).await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        let token: RegistryToken = response.json().await?;
+        Ok(token.token)
+    }
+
+    /// Send `method` request of `url` in `repository` accepting media types
+    /// `accept`, with a token if the registry requires.
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        url: &str,
+        repository: &str,
+        accept: &str,
+    ) -> Result<Response> {
+        let request = |token: Option<&String>| {
+            let request = client.request(method.clone(), url).header(ACCEPT, accept);
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+        };
+        let token = self.tokens.lock().await.get(repository).cloned();
+        let mut response = crate::http::send(request(token.as_ref())).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|x| x.to_str().ok())
+                .ok_or(Error::HTTPError(StatusCode::UNAUTHORIZED))?
+                .to_string();
+            let token = self.request_token(client, &challenge).await?;
+            response = crate::http::send(request(Some(&token))).await?;
+            self.tokens
+                .lock()
+                .await
+                .insert(repository.to_string(), token);
+        }
+        Ok(response)
+    }
+
+    /// Get `url` in `repository` accepting media types `accept`, with a token
+    /// if the registry requires.
+    pub async fn get(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        repository: &str,
+        accept: &str,
+    ) -> Result<Response> {
+        let response = self
+            .send(client, Method::GET, url, repository, accept)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        Ok(response)
+    }
+
+    /// Token with which `url` in `repository` can be requested, if the
+    /// registry requires one. `url` is only requested with HEAD, and its
+    /// response is not checked, as redirected URLs may not accept HEAD.
+    pub async fn token(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        repository: &str,
+    ) -> Result<Option<String>> {
+        self.send(client, Method::HEAD, url, repository, "*/*")
+            .await?;
+        Ok(self.tokens.lock().await.get(repository).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_challenge;
+
+    #[test]
+    fn test_parse_challenge() {
+        let challenge = r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:homebrew/core/wget:pull""#;
+        let (realm, query) = parse_challenge(challenge).unwrap();
+        assert_eq!(realm, "https://ghcr.io/token");
+        assert_eq!(
+            query,
+            vec![
+                ("service".to_string(), "ghcr.io".to_string()),
+                (
+                    "scope".to_string(),
+                    "repository:homebrew/core/wget:pull".to_string()
+                ),
+            ]
+        );
+        assert_eq!(parse_challenge(r#"Basic realm="x""#), None);
+        assert_eq!(parse_challenge(r#"Bearer service="ghcr.io""#), None);
+    }
+}
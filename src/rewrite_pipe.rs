@@ -16,7 +16,7 @@ use slog::warn;
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
 use crate::stream_pipe::{ByteObject, ByteStream};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{SizeStorage, SnapshotStorage, SourceStorage};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 pub struct RewritePipe<Source, RewriteItem, F>
@@ -67,6 +67,20 @@ where
     }
 }
 
+#[async_trait]
+impl<Snapshot, Source, RewriteItem, F> SizeStorage<Snapshot> for RewritePipe<Source, RewriteItem, F>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SizeStorage<Snapshot>,
+    RewriteItem: Send + Sync + 'static,
+    F: Fn(RewriteItem) -> Result<RewriteItem> + Send + Sync + 'static,
+{
+    async fn object_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        // size may change after rewrite, but it's close enough for estimation
+        self.source.object_size(snapshot, mission).await
+    }
+}
+
 // TODO support rewrite functions with `RewriteItem` other than String (eg. Vec<u8>)
 #[async_trait]
 impl<Snapshot, Source, F> SourceStorage<Snapshot, ByteStream> for RewritePipe<Source, String, F>
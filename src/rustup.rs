@@ -2,17 +2,53 @@
 //!
 //! Rustup source provides a file list of recent rustup toolchains.
 //! It is recommended to use it with `--no-delete` flag. This source
-//! yields path snapshots.
+//! yields metadata snapshots.
+//!
+//! With `--keep-nightly-days`, nightlies are retained for the given days,
+//! and all stable releases are kept by their versioned manifests
+//! (`dist/channel-rust-1.x.y.toml`). A manifest and its artifacts are always
+//! kept or pruned together: manifests missing upstream are skipped, while
+//! other failures abort the snapshot instead of dropping artifacts.
+//!
+//! Artifacts are verified against sha256 hashes in manifests before they hit
+//! the target, and their `.sha256` companion files are mirrored as well.
+//! With `--verify-signature <keyring>`, the `.asc` signature of each manifest
+//! is checked by `gpgv` against the keyring (e.g. the Rust release key
+//! exported by `gpg --export`) before any of its artifacts is mirrored.
+//!
+//! With `--dist-base-url <url>`, URLs in channel manifests are rewritten to
+//! the mirror, so that `RUSTUP_DIST_SERVER` can point at it. Rewritten
+//! manifests and their `.sha256` files are generated from manifests fetched
+//! while taking snapshot, and published after all artifacts. As
+//! upstream signatures no longer match, `.asc` files are not mirrored then.
+//!
+//! The latest `rustup-init` of each host (`rustup/dist/<triple>/`, filtered
+//! by `--targets`) and `rustup/release-stable.toml` are mirrored as well, so
+//! that rustup can be bootstrapped from the mirror.
+//!
+//! `--targets` and `--components` only keep artifacts of the given target
+//! triples and components (packages in manifest) respectively. Artifacts
+//! for all targets (`*`, e.g. `rust-src`) are kept regardless of targets.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::common::{Generated, Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{GenerateStorage, SnapshotStorage, SourceStorage};
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use futures_util::{stream, StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
 use regex::Regex;
-use slog::{info, warn};
+use reqwest::StatusCode;
+use sha2::Digest;
+use slog::info;
 use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
 
 #[derive(Debug, StructOpt)]
 pub struct Rustup {
@@ -20,102 +56,441 @@ pub struct Rustup {
     pub base: String,
     #[structopt(long, default_value = "120")]
     pub days_to_retain: usize,
+    /// Channels to mirror.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "stable,beta,nightly",
+        possible_values = &["stable", "beta", "nightly"]
+    )]
+    pub channels: Vec<String>,
+    /// Only keep nightlies of recent N days, together with all stable releases.
+    #[structopt(long)]
+    pub keep_nightly_days: Option<usize>,
+    /// Only mirror artifacts of these target triples, e.g. `x86_64-unknown-linux-gnu`.
+    #[structopt(long, use_delimiter = true)]
+    pub targets: Vec<String>,
+    /// Only mirror these components, e.g. `rustc,cargo,rust-std,rust-src`.
+    #[structopt(long, use_delimiter = true)]
+    pub components: Vec<String>,
+    /// Verify manifest signatures with `gpgv` against this keyring.
+    #[structopt(long, parse(from_os_str))]
+    pub verify_signature: Option<PathBuf>,
+    /// Rewrite URLs in manifests to this base, e.g. `https://mirrors.example.com/rustup`.
+    #[structopt(long)]
+    pub dist_base_url: Option<String>,
+    /// Rewritten manifests and their `.sha256` files.
+    #[structopt(skip)]
+    generated: Mutex<Generated>,
+}
+
+const UPSTREAM_BASE: &str = "https://static.rust-lang.org/";
+
+/// Hosts which `rustup-init` is built for.
+const RUSTUP_HOSTS: &[&str] = &[
+    "aarch64-apple-darwin",
+    "aarch64-linux-android",
+    "aarch64-pc-windows-msvc",
+    "aarch64-unknown-linux-gnu",
+    "aarch64-unknown-linux-musl",
+    "arm-linux-androideabi",
+    "arm-unknown-linux-gnueabi",
+    "arm-unknown-linux-gnueabihf",
+    "armv7-linux-androideabi",
+    "armv7-unknown-linux-gnueabihf",
+    "i686-apple-darwin",
+    "i686-linux-android",
+    "i686-pc-windows-gnu",
+    "i686-pc-windows-msvc",
+    "i686-unknown-linux-gnu",
+    "loongarch64-unknown-linux-gnu",
+    "loongarch64-unknown-linux-musl",
+    "mips-unknown-linux-gnu",
+    "mips64-unknown-linux-gnuabi64",
+    "mips64el-unknown-linux-gnuabi64",
+    "mipsel-unknown-linux-gnu",
+    "powerpc-unknown-linux-gnu",
+    "powerpc64-unknown-linux-gnu",
+    "powerpc64le-unknown-linux-gnu",
+    "powerpc64le-unknown-linux-musl",
+    "riscv64gc-unknown-linux-gnu",
+    "s390x-unknown-linux-gnu",
+    "x86_64-apple-darwin",
+    "x86_64-linux-android",
+    "x86_64-pc-windows-gnu",
+    "x86_64-pc-windows-msvc",
+    "x86_64-unknown-freebsd",
+    "x86_64-unknown-illumos",
+    "x86_64-unknown-linux-gnu",
+    "x86_64-unknown-linux-musl",
+    "x86_64-unknown-netbsd",
+];
+
+/// Path of `rustup-init` for `host`.
+fn rustup_init_path(host: &str) -> String {
+    let exe = if host.contains("windows") { ".exe" } else { "" };
+    format!("rustup/dist/{}/rustup-init{}", host, exe)
 }
 
 fn day_earlier(date_time: DateTime<Utc>, days: i64) -> Option<DateTime<Utc>> {
     date_time.checked_sub_signed(Duration::days(days))
 }
 
+/// Artifacts listed in a channel manifest, of packages (`pkg`) and other
+/// artifacts (`artifacts`, e.g. installers) filtered by `targets` and
+/// `components`. Empty filters select everything.
+/// Artifacts carry their sha256, and those of packages come with `.sha256`
+/// companion files.
+fn parse_manifest(
+    data: &str,
+    targets: &[String],
+    components: &[String],
+) -> Result<Vec<SnapshotMeta>> {
+    let manifest: toml::Table = data
+        .parse()
+        .map_err(|err| Error::ProcessError(format!("invalid manifest: {}", err)))?;
+    let selected = |filter: &[String], x: &str| filter.is_empty() || filter.iter().any(|y| y == x);
+    let mut paths = vec![];
+    for section in ["pkg", "artifacts"] {
+        let packages = match manifest.get(section).and_then(toml::Value::as_table) {
+            Some(packages) => packages,
+            None => continue,
+        };
+        for (component, package) in packages {
+            if !selected(components, component) {
+                continue;
+            }
+            let package_targets = match package.get("target").and_then(toml::Value::as_table) {
+                Some(package_targets) => package_targets,
+                None => continue,
+            };
+            for (target, entries) in package_targets {
+                if target != "*" && !selected(targets, target) {
+                    continue;
+                }
+                let entries = match entries {
+                    toml::Value::Array(entries) => entries.iter().collect(),
+                    entry => vec![entry],
+                };
+                for entry in entries {
+                    for (key, hash) in [("url", "hash"), ("xz_url", "xz_hash")] {
+                        let url = match entry.get(key).and_then(toml::Value::as_str) {
+                            Some(url) => url.replace(UPSTREAM_BASE, ""),
+                            None => continue,
+                        };
+                        // installers in `artifacts` are hashed as `hash-sha256`
+                        let hash = entry
+                            .get(hash)
+                            .or_else(|| entry.get("hash-sha256"))
+                            .and_then(toml::Value::as_str);
+                        if section == "pkg" {
+                            paths.push(SnapshotMeta::new(format!("{}.sha256", url)));
+                        }
+                        let mut artifact = SnapshotMeta::new(url);
+                        if let Some(hash) = hash {
+                            artifact.checksum_method = Some("sha256".to_string());
+                            artifact.checksum = Some(hash.to_string());
+                        }
+                        paths.push(artifact);
+                    }
+                }
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Version `(major, minor, patch)` of rust in a channel manifest.
+fn manifest_version(data: &str) -> Option<(u64, u64, u64)> {
+    static VERSION: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"\[pkg\.rust\]\s*\nversion = "(\d+)\.(\d+)\.(\d+)"#).unwrap());
+    let capture = VERSION.captures(data)?;
+    Some((
+        capture[1].parse().ok()?,
+        capture[2].parse().ok()?,
+        capture[3].parse().ok()?,
+    ))
+}
+
+/// Fetch manifest `target`. Returns `None` if it doesn't exist.
+async fn fetch_manifest(
+    client: &reqwest::Client,
+    base: &str,
+    target: &str,
+) -> Result<Option<String>> {
+    let response = crate::http::send(client.get(&format!("{}/{}", base, target))).await?;
+    match response.status() {
+        StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(None),
+        status if status.is_success() => Ok(Some(response.text().await?)),
+        status => Err(Error::HTTPError(status)),
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", sha2::Sha256::digest(data))
+}
+
+/// Rewrite artifact URLs in manifest to `base`.
+fn rewrite_manifest(data: &str, base: &str) -> String {
+    data.replace(UPSTREAM_BASE, &format!("{}/", base.trim_end_matches('/')))
+}
+
+/// Content of `.sha256` file of manifest `target` with content `data`.
+fn manifest_sha256(target: &str, data: &str) -> String {
+    let name = target.rsplit('/').next().unwrap_or(target);
+    format!("{}  {}\n", sha256_hex(data.as_bytes()), name)
+}
+
+/// Verify detached `signature` of `data` with `gpgv` against `keyring`.
+async fn verify_signature(
+    keyring: &std::path::Path,
+    target: &str,
+    data: &str,
+    signature: &[u8],
+) -> Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let signature_path = std::env::temp_dir().join(format!(
+        "mirror-clone-rustup-{}-{}.asc",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    tokio::fs::write(&signature_path, signature).await?;
+    let result = async {
+        let mut child = tokio::process::Command::new("gpgv")
+            .arg("--keyring")
+            .arg(keyring)
+            .arg(&signature_path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(data.as_bytes()).await?;
+        drop(stdin);
+        child.wait_with_output().await
+    }
+    .await;
+    tokio::fs::remove_file(&signature_path).await?;
+    let output = result?;
+    if !output.status.success() {
+        return Err(Error::ProcessError(format!(
+            "bad signature of {}: {}",
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+impl Rustup {
+    /// Snapshots of manifest `target` with content `data`, together with its
+    /// `.sha256` (and `.asc` unless rewritten) files. Rewritten files are kept
+    /// to be generated later.
+    fn manifest_snapshot(&self, target: String, data: &str) -> Vec<SnapshotMeta> {
+        let pinned = |key: String, content: &str| {
+            let mut meta = SnapshotMeta::force(key);
+            meta.checksum_method = Some("sha256".to_string());
+            meta.checksum = Some(sha256_hex(content.as_bytes()));
+            meta
+        };
+        match &self.dist_base_url {
+            Some(base) => {
+                let data = rewrite_manifest(data, base);
+                let sha256 = manifest_sha256(&target, &data);
+                let snapshot = vec![
+                    pinned(format!("{}.sha256", target), &sha256),
+                    pinned(target.clone(), &data),
+                ];
+                let mut generated = self.generated.lock().unwrap();
+                generated
+                    .0
+                    .insert(format!("{}.sha256", target), sha256.into_bytes());
+                generated.0.insert(target, data.into_bytes());
+                snapshot
+            }
+            None => vec![
+                SnapshotMeta::force(format!("{}.sha256", target)),
+                SnapshotMeta::force(format!("{}.asc", target)),
+                pinned(target, data),
+            ],
+        }
+    }
+
+    /// Fetch manifest `target`, and verify its signature if required.
+    /// Returns `None` if it doesn't exist.
+    async fn manifest(&self, client: &reqwest::Client, target: &str) -> Result<Option<String>> {
+        let data = match fetch_manifest(client, &self.base, target).await? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        if let Some(keyring) = &self.verify_signature {
+            let url = format!("{}/{}.asc", self.base, target);
+            let response = crate::http::send(client.get(&url)).await?;
+            if !response.status().is_success() {
+                return Err(Error::HTTPError(response.status()));
+            }
+            let signature = response.bytes().await?;
+            verify_signature(keyring, target, &data, &signature).await?;
+        }
+        Ok(Some(data))
+    }
+
+    /// `rustup-init` of hosts in `--targets` and its release manifest. Hosts
+    /// without a build upstream are skipped.
+    async fn rustup_init(&self, client: &reqwest::Client) -> Result<Vec<SnapshotMeta>> {
+        let hosts: Vec<String> = RUSTUP_HOSTS
+            .iter()
+            .filter(|host| self.targets.is_empty() || self.targets.iter().any(|x| x == *host))
+            .map(|host| host.to_string())
+            .collect();
+        let binaries: Vec<Option<String>> = stream::iter(hosts)
+            .map(|host| async move {
+                let path = rustup_init_path(&host);
+                let url = format!("{}/{}", self.base, path);
+                let response = crate::http::send(client.head(&url)).await?;
+                match response.status() {
+                    StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(None),
+                    status if status.is_success() => Ok(Some(path)),
+                    status => Err(Error::HTTPError(status)),
+                }
+            })
+            .buffer_unordered(8)
+            .try_collect()
+            .await?;
+        let mut snapshot = vec![];
+        for path in binaries.into_iter().flatten() {
+            snapshot.push(SnapshotMeta::force(format!("{}.sha256", path)));
+            snapshot.push(SnapshotMeta::force(path));
+        }
+        snapshot.push(SnapshotMeta::force(
+            "rustup/release-stable.toml".to_string(),
+        ));
+        Ok(snapshot)
+    }
+
+    /// Dated manifests to mirror, as `(date, channel)`.
+    fn dated_targets(&self, now: DateTime<Utc>) -> Vec<(String, String)> {
+        let mut targets = vec![];
+        for channel in &self.channels {
+            let days = match (channel.as_str(), self.keep_nightly_days) {
+                ("nightly", Some(days)) => days,
+                // cache 4x more stable toolchains
+                ("stable", None) => self.days_to_retain * 4,
+                _ => self.days_to_retain,
+            };
+            for day_back in 0..days {
+                let day = day_earlier(now, day_back as i64).unwrap();
+                targets.push((day.format("%Y-%m-%d").to_string(), channel.clone()));
+            }
+        }
+        targets
+    }
+
+    /// Versioned manifests of all stable releases up to `latest`.
+    async fn stable_releases(
+        &self,
+        client: &reqwest::Client,
+        latest: (u64, u64, u64),
+    ) -> Result<Vec<SnapshotMeta>> {
+        let (major, latest_minor, _) = latest;
+        let releases: Vec<Vec<SnapshotMeta>> = stream::iter(0..=latest_minor)
+            .map(|minor| async move {
+                let mut snapshot = vec![];
+                for patch in 0.. {
+                    let target = format!("dist/channel-rust-{}.{}.{}.toml", major, minor, patch);
+                    let data = match self.manifest(client, &target).await? {
+                        Some(data) => data,
+                        None => break,
+                    };
+                    snapshot.extend(parse_manifest(&data, &self.targets, &self.components)?);
+                    snapshot.extend(self.manifest_snapshot(target, &data));
+                }
+                Ok::<_, Error>(snapshot)
+            })
+            .buffer_unordered(8)
+            .try_collect()
+            .await?;
+        Ok(releases.into_iter().flatten().collect())
+    }
+}
+
 #[async_trait]
-impl SnapshotStorage<SnapshotPath> for Rustup {
+impl SnapshotStorage<SnapshotMeta> for Rustup {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
+    ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        let channels = ["beta", "stable", "nightly"];
-
         info!(logger, "fetching channels...");
 
-        let matcher = Regex::new(r#"url = "(.*)""#).unwrap();
-
-        let mut targets = vec![];
-        for day_back in 0..self.days_to_retain {
-            let now = Utc::now();
-            let day = day_earlier(now, day_back as i64).unwrap();
-            let day_string = day.format("%Y-%m-%d");
-            for channel in &channels {
-                targets.push((day_string.to_string(), channel.to_string()));
-            }
-        }
-
-        // cache 4x more stable toolchains
-        for day_back in self.days_to_retain..self.days_to_retain * 4 {
-            let now = Utc::now();
-            let day = day_earlier(now, day_back as i64).unwrap();
-            let day_string = day.format("%Y-%m-%d");
-            targets.push((day_string.to_string(), "stable".to_string()));
-        }
+        let targets = self.dated_targets(Utc::now());
+        progress.set_length(targets.len() as u64);
 
-        let packages: Result<Vec<Vec<SnapshotPath>>> =
+        let this = &*self;
+        let packages: Vec<Vec<SnapshotMeta>> =
             stream::iter(targets.into_iter().map(|(day_string, channel)| {
                 let client = client.clone();
-                let base = self.base.clone();
                 let progress = progress.clone();
-                let matcher = matcher.clone();
-                let logger = logger.clone();
-                let func = async move {
-                    let mut caps = vec![];
+                async move {
                     let target = format!("dist/{}/channel-rust-{}.toml", day_string, channel);
                     progress.set_message(&target);
-                    let data = client
-                        .get(&format!("{}/{}", base, target))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
-
-                    for capture in matcher.captures_iter(&data) {
-                        let url = &capture[1];
-                        let url = url.replace("https://static.rust-lang.org/", "");
-                        caps.push(SnapshotPath::new(url));
-                    }
-
-                    caps.push(SnapshotPath::force(target));
+                    let data = this.manifest(&client, &target).await?;
                     progress.inc(1);
+                    let data = match data {
+                        Some(data) => data,
+                        // no release on that day
+                        None => return Ok(vec![]),
+                    };
+                    let mut caps = parse_manifest(&data, &this.targets, &this.components)?;
+                    caps.extend(this.manifest_snapshot(target, &data));
                     Ok::<_, Error>(caps)
-                };
-                async move {
-                    match func.await {
-                        Ok(x) => Ok(x),
-                        Err(err) => {
-                            warn!(logger, "failed to fetch index {:?}", err);
-                            Ok(vec![])
-                        }
-                    }
                 }
             }))
             .buffer_unordered(config.concurrent_resolve)
             .try_collect()
-            .await;
-
-        let mut snapshot: Vec<SnapshotPath> = packages?.into_iter().flatten().collect();
-
-        for channel in channels {
-            snapshot.push(SnapshotPath::force(format!(
-                "dist/channel-rust-{}.toml",
-                channel
-            )));
-            snapshot.push(SnapshotPath::force(format!(
-                "dist/channel-rust-{}.toml.sha256",
-                channel
-            )));
+            .await?;
+
+        let mut snapshot: Vec<SnapshotMeta> = packages.into_iter().flatten().collect();
+
+        if self.keep_nightly_days.is_some() && self.channels.iter().any(|x| x == "stable") {
+            info!(logger, "fetching stable releases...");
+            let data = self
+                .manifest(&client, "dist/channel-rust-stable.toml")
+                .await?
+                .ok_or(Error::HTTPError(StatusCode::NOT_FOUND))?;
+            let latest = manifest_version(&data).ok_or_else(|| {
+                Error::ProcessError("no rust version in stable manifest".to_string())
+            })?;
+            snapshot.extend(self.stable_releases(&client, latest).await?);
+        }
+
+        for channel in &self.channels {
+            let target = format!("dist/channel-rust-{}.toml", channel);
+            if self.dist_base_url.is_some() {
+                // pin rewritten manifests, so that they're published together
+                // with `.sha256` files and all artifacts they refer to
+                let data = self
+                    .manifest(&client, &target)
+                    .await?
+                    .ok_or(Error::HTTPError(StatusCode::NOT_FOUND))?;
+                snapshot.extend(parse_manifest(&data, &self.targets, &self.components)?);
+                snapshot.extend(self.manifest_snapshot(target, &data));
+            } else {
+                // channel manifests may be updated at any time, so they're not verified
+                for suffix in ["", ".sha256", ".asc"] {
+                    snapshot.push(SnapshotMeta::force(format!("{}{}", target, suffix)));
+                }
+            }
         }
 
+        info!(logger, "fetching rustup-init...");
+        snapshot.extend(self.rustup_init(&client).await?);
+
         progress.finish_with_message("done");
 
         Ok(snapshot)
@@ -127,8 +502,223 @@ impl SnapshotStorage<SnapshotPath> for Rustup {
 }
 
 #[async_trait]
-impl SourceStorage<SnapshotPath, TransferURL> for Rustup {
-    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.base, snapshot.0)))
+impl SourceStorage<SnapshotMeta, TransferURL> for Rustup {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[async_trait]
+impl GenerateStorage<SnapshotMeta> for Rustup {
+    async fn generate(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.generated.lock().unwrap().0.get(&snapshot.key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use structopt::StructOpt;
+
+    use super::{manifest_version, parse_manifest, rewrite_manifest, rustup_init_path, Rustup};
+
+    #[test]
+    fn test_parse_manifest() {
+        let data = r#"manifest-version = "2"
+date = "2024-10-17"
+
+[pkg.cargo]
+version = "0.83.0 (8f40fc59f 2024-08-21)"
+
+[pkg.rust]
+version = "1.82.0 (f6e511eec 2024-10-15)"
+
+[pkg.rust.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2024-10-17/rust-1.82.0-x86_64-unknown-linux-gnu.tar.gz"
+hash = "aa"
+xz_url = "https://static.rust-lang.org/dist/2024-10-17/rust-1.82.0-x86_64-unknown-linux-gnu.tar.xz"
+
+[pkg.rust-docs.target.x86_64-pc-windows-msvc]
+available = true
+xz_url = "https://static.rust-lang.org/dist/2024-10-17/rust-docs-1.82.0-x86_64-pc-windows-msvc.tar.xz"
+
+[pkg.rust-src.target."*"]
+available = true
+xz_url = "https://static.rust-lang.org/dist/2024-10-17/rust-src-1.82.0.tar.xz"
+
+[[artifacts.installer-msi.target.x86_64-pc-windows-msvc]]
+url = "https://static.rust-lang.org/dist/2024-10-17/rust-1.82.0-x86_64-pc-windows-msvc.msi"
+hash-sha256 = "bb"
+"#;
+        assert_eq!(manifest_version(data), Some((1, 82, 0)));
+        let parse = |targets: &[&str], components: &[&str]| {
+            let targets: Vec<String> = targets.iter().map(|x| x.to_string()).collect();
+            let components: Vec<String> = components.iter().map(|x| x.to_string()).collect();
+            parse_manifest(data, &targets, &components)
+                .unwrap()
+                .into_iter()
+                .map(|x| x.key.trim_start_matches("dist/2024-10-17/").to_string())
+                .filter(|x| !x.ends_with(".sha256"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            parse(&[], &[]),
+            vec![
+                "rust-1.82.0-x86_64-unknown-linux-gnu.tar.gz",
+                "rust-1.82.0-x86_64-unknown-linux-gnu.tar.xz",
+                "rust-docs-1.82.0-x86_64-pc-windows-msvc.tar.xz",
+                "rust-src-1.82.0.tar.xz",
+                "rust-1.82.0-x86_64-pc-windows-msvc.msi",
+            ]
+        );
+        assert_eq!(
+            parse(&["x86_64-unknown-linux-gnu"], &[]),
+            vec![
+                "rust-1.82.0-x86_64-unknown-linux-gnu.tar.gz",
+                "rust-1.82.0-x86_64-unknown-linux-gnu.tar.xz",
+                "rust-src-1.82.0.tar.xz",
+            ]
+        );
+        assert_eq!(
+            parse(&[], &["rust-docs", "rust-src"]),
+            vec![
+                "rust-docs-1.82.0-x86_64-pc-windows-msvc.tar.xz",
+                "rust-src-1.82.0.tar.xz",
+            ]
+        );
+        assert!(parse_manifest("not toml [", &[], &[]).is_err());
+
+        let checksums: Vec<_> = parse_manifest(data, &[], &[])
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.key, x.checksum))
+            .take(3)
+            .collect();
+        assert_eq!(
+            checksums,
+            vec![
+                (
+                    "dist/2024-10-17/rust-1.82.0-x86_64-unknown-linux-gnu.tar.gz.sha256"
+                        .to_string(),
+                    None
+                ),
+                (
+                    "dist/2024-10-17/rust-1.82.0-x86_64-unknown-linux-gnu.tar.gz".to_string(),
+                    Some("aa".to_string())
+                ),
+                (
+                    "dist/2024-10-17/rust-1.82.0-x86_64-unknown-linux-gnu.tar.xz.sha256"
+                        .to_string(),
+                    None
+                ),
+            ]
+        );
+        let msi = parse_manifest(data, &[], &["installer-msi".to_string()]).unwrap();
+        assert_eq!(msi.len(), 1);
+        assert_eq!(msi[0].checksum.as_deref(), Some("bb"));
+    }
+
+    #[test]
+    fn test_manifest_snapshot() {
+        let target = "dist/channel-rust-1.82.0.toml";
+        let rustup = Rustup::from_iter(["rustup"]);
+        let keys = |snapshot: &[super::SnapshotMeta]| {
+            snapshot.iter().map(|x| x.key.clone()).collect::<Vec<_>>()
+        };
+        let snapshot = rustup.manifest_snapshot(target.to_string(), "mirror-clone");
+        assert_eq!(
+            keys(&snapshot),
+            vec![
+                "dist/channel-rust-1.82.0.toml.sha256",
+                "dist/channel-rust-1.82.0.toml.asc",
+                "dist/channel-rust-1.82.0.toml"
+            ]
+        );
+        assert_eq!(
+            snapshot[2].checksum.as_deref(),
+            Some("8c85cec9865753801807fca6a3df460e46cb80b51f2feed73b301d865935a917")
+        );
+
+        let rustup = Rustup::from_iter(["rustup", "--dist-base-url", "https://mirror/rustup/"]);
+        let data = r#"url = "https://static.rust-lang.org/dist/rust.tar.gz""#;
+        let rewritten = rewrite_manifest(data, "https://mirror/rustup/");
+        assert_eq!(
+            rewritten,
+            r#"url = "https://mirror/rustup/dist/rust.tar.gz""#
+        );
+        let snapshot = rustup.manifest_snapshot(target.to_string(), data);
+        assert_eq!(
+            keys(&snapshot),
+            vec![
+                "dist/channel-rust-1.82.0.toml.sha256",
+                "dist/channel-rust-1.82.0.toml"
+            ]
+        );
+        assert_eq!(
+            snapshot[1].checksum,
+            Some(super::sha256_hex(rewritten.as_bytes()))
+        );
+        let sha256_file = super::manifest_sha256(target, &rewritten);
+        assert!(sha256_file.ends_with("  channel-rust-1.82.0.toml\n"));
+        assert_eq!(
+            snapshot[0].checksum,
+            Some(super::sha256_hex(sha256_file.as_bytes()))
+        );
+
+        let generated = rustup.generated.lock().unwrap();
+        assert_eq!(generated.0[target], rewritten.as_bytes());
+        assert_eq!(
+            generated.0["dist/channel-rust-1.82.0.toml.sha256"],
+            sha256_file.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_rustup_init_path() {
+        assert_eq!(
+            rustup_init_path("x86_64-unknown-linux-gnu"),
+            "rustup/dist/x86_64-unknown-linux-gnu/rustup-init"
+        );
+        assert_eq!(
+            rustup_init_path("x86_64-pc-windows-msvc"),
+            "rustup/dist/x86_64-pc-windows-msvc/rustup-init.exe"
+        );
+    }
+
+    #[test]
+    fn test_dated_targets() {
+        let now = chrono::Utc.with_ymd_and_hms(2026, 10, 15, 0, 0, 0).unwrap();
+        let count = |rustup: &Rustup, channel: &str| {
+            rustup
+                .dated_targets(now)
+                .into_iter()
+                .filter(|(_, x)| x == channel)
+                .count()
+        };
+        let rustup = Rustup::from_iter(["rustup", "--days-to-retain", "3"]);
+        assert_eq!(count(&rustup, "stable"), 12);
+        assert_eq!(count(&rustup, "beta"), 3);
+        assert_eq!(count(&rustup, "nightly"), 3);
+
+        let rustup = Rustup::from_iter([
+            "rustup",
+            "--days-to-retain",
+            "3",
+            "--keep-nightly-days",
+            "7",
+            "--channels",
+            "stable,nightly",
+        ]);
+        assert_eq!(count(&rustup, "stable"), 3);
+        assert_eq!(count(&rustup, "beta"), 0);
+        assert_eq!(
+            rustup.dated_targets(now).last().unwrap(),
+            &("2026-10-09".to_string(), "nightly".to_string())
+        );
     }
 }
@@ -14,22 +14,50 @@
 //!
 //! This backend will automatically add a MIME type for object, based on
 //! suffix.
+//!
+//! Server-side encryption (SSE-S3 with `AES256`, or SSE-KMS with `aws:kms`
+//! and an optional key id) is requested for all uploaded objects if set.
+//! Requester-pays buckets are supported by sending `x-amz-request-payer`
+//! with every request. Objects larger than the multipart threshold are
+//! uploaded in parts.
+//!
+//! Buckets are addressed in path style (`<endpoint>/<bucket>/<key>`) by
+//! default. In virtual-hosted style (`<bucket>.<endpoint>/<key>`), which
+//! rusoto doesn't support, requests are built in path style against the
+//! endpoint with bucket host, and bucket is moved out of path before they
+//! are signed and sent.
+//!
+//! Objects are deleted in batches of 1000 with `DeleteObjects`. Keys which
+//! failed to delete, or the whole batch if the request fails, are retried
+//! with backoff.
 
 use std::{collections::HashMap, sync::atomic::AtomicU64};
 
+use crate::checksum_pipe::sha256;
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
-use crate::stream_pipe::ByteStream;
-use crate::traits::{Key, SnapshotStorage, TargetStorage};
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, SnapshotStorage, StateStorage, TargetStorage, STATE_PREFIX};
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt};
-use rusoto_core::Region;
+use rusoto_core::credential::{Anonymous, DefaultCredentialsProvider, ProvideAwsCredentials};
+use rusoto_core::request::{DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, DispatchSignedRequest, HttpClient, Region};
 use rusoto_s3::{
-    DeleteObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, Delete, DeleteObjectRequest,
+    DeleteObjectsRequest, GetObjectError, GetObjectRequest, HeadObjectRequest,
+    ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3Client, UploadPartRequest, S3,
 };
 use slog::{debug, info, warn};
+use tokio::io::AsyncReadExt;
+
+/// Max keys in one `DeleteObjects` request.
+const DELETE_BATCH_SIZE: usize = 1000;
+const DELETE_ATTEMPTS: u32 = 3;
 
 #[derive(Debug)]
 pub struct S3Config {
@@ -39,6 +67,14 @@ pub struct S3Config {
     pub prefix_hint_mode: Option<String>,
     pub scan_metadata: bool,
     pub max_keys: u64,
+    /// `AES256` or `aws:kms`
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    pub request_payer: bool,
+    pub multipart_threshold: u64,
+    pub multipart_part_size: u64,
+    /// Address bucket in virtual-hosted style instead of path style.
+    pub virtual_host: bool,
 }
 
 impl S3Config {
@@ -50,6 +86,12 @@ impl S3Config {
             max_keys: 1000,
             prefix_hint_mode: None,
             scan_metadata,
+            sse: None,
+            sse_kms_key_id: None,
+            request_payer: false,
+            multipart_threshold: 5 << 30,
+            multipart_part_size: 64 << 20,
+            virtual_host: false,
         }
     }
 }
@@ -67,17 +109,186 @@ fn get_s3_client(name: String, endpoint: String) -> S3Client {
     S3Client::new(jcloud_region(name, endpoint))
 }
 
+/// `endpoint` with `bucket` prepended to its host.
+fn virtual_host_endpoint(endpoint: &str, bucket: &str) -> String {
+    match endpoint.split_once("://") {
+        Some((scheme, host)) => format!("{}://{}.{}", scheme, bucket, host),
+        None => format!("{}.{}", bucket, endpoint),
+    }
+}
+
+/// Path of virtual-hosted style request, with `bucket` removed from `path`.
+fn virtual_host_path(path: &str, bucket: &str) -> String {
+    let path = path
+        .strip_prefix('/')
+        .and_then(|x| x.strip_prefix(bucket))
+        .unwrap_or(path);
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Dispatcher of virtual-hosted style requests, which moves bucket out of
+/// path, and signs requests afterwards.
+struct VirtualHostDispatcher {
+    bucket: String,
+    credentials: std::sync::Arc<DefaultCredentialsProvider>,
+    inner: std::sync::Arc<HttpClient>,
+}
+
+impl DispatchSignedRequest for VirtualHostDispatcher {
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<std::time::Duration>,
+    ) -> DispatchSignedRequestFuture {
+        request.path = virtual_host_path(&request.path, &self.bucket);
+        let credentials = self.credentials.clone();
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let credentials = credentials
+                .credentials()
+                .await
+                .map_err(|err| HttpDispatchError::new(err.to_string()))?;
+            if credentials.is_anonymous() {
+                request.complement();
+            } else {
+                request.sign(&credentials);
+            }
+            inner.dispatch(request, timeout).await
+        })
+    }
+}
+
+fn get_virtual_host_s3_client(name: String, endpoint: String, bucket: String) -> S3Client {
+    let dispatcher = VirtualHostDispatcher {
+        credentials: std::sync::Arc::new(
+            DefaultCredentialsProvider::new().expect("failed to create credentials provider"),
+        ),
+        inner: std::sync::Arc::new(HttpClient::new().expect("failed to create request dispatcher")),
+        bucket: bucket.clone(),
+    };
+    S3Client::new_with_client(
+        Client::new_not_signing(dispatcher),
+        jcloud_region(name, virtual_host_endpoint(&endpoint, &bucket)),
+    )
+}
+
 impl S3Backend {
     pub fn new(config: S3Config) -> Self {
-        let client = get_s3_client("jCloud S3".to_string(), config.endpoint.clone());
+        let client = if config.virtual_host {
+            get_virtual_host_s3_client(
+                "jCloud S3".to_string(),
+                config.endpoint.clone(),
+                config.bucket.clone(),
+            )
+        } else {
+            get_s3_client("jCloud S3".to_string(), config.endpoint.clone())
+        };
         Self { config, client }
     }
 
+    fn request_payer(&self) -> Option<String> {
+        if self.config.request_payer {
+            Some("requester".to_string())
+        } else {
+            None
+        }
+    }
+
     pub fn gen_metadata(&self) -> HashMap<String, String> {
         let mut map = HashMap::new();
         map.insert("clone-backend".to_string(), "s3-v1".to_string());
         map
     }
+
+    /// Upload `file` in parts, and abort the upload if any part fails.
+    async fn put_multipart(
+        &self,
+        logger: &slog::Logger,
+        key: String,
+        mut file: tokio::fs::File,
+        metadata: HashMap<String, String>,
+        content_type: Option<String>,
+    ) -> Result<()> {
+        let req = CreateMultipartUploadRequest {
+            bucket: self.config.bucket.clone(),
+            key: key.clone(),
+            metadata: Some(metadata),
+            content_type,
+            server_side_encryption: self.config.sse.clone(),
+            ssekms_key_id: self.config.sse_kms_key_id.clone(),
+            request_payer: self.request_payer(),
+            ..Default::default()
+        };
+        let upload_id = self
+            .client
+            .create_multipart_upload(req)
+            .await?
+            .upload_id
+            .ok_or_else(|| Error::StorageError(format!("no upload id for {}", key)))?;
+        debug!(logger, "multipart upload: {} {}", key, upload_id);
+
+        let mut parts = vec![];
+        let result: Result<()> = async {
+            loop {
+                let mut buffer = Vec::with_capacity(self.config.multipart_part_size as usize);
+                (&mut file)
+                    .take(self.config.multipart_part_size)
+                    .read_to_end(&mut buffer)
+                    .await?;
+                if buffer.is_empty() {
+                    break;
+                }
+                let part_number = parts.len() as i64 + 1;
+                let req = UploadPartRequest {
+                    bucket: self.config.bucket.clone(),
+                    key: key.clone(),
+                    upload_id: upload_id.clone(),
+                    part_number,
+                    content_length: Some(buffer.len() as i64),
+                    body: Some(buffer.into()),
+                    request_payer: self.request_payer(),
+                    ..Default::default()
+                };
+                let output = self.client.upload_part(req).await?;
+                parts.push(CompletedPart {
+                    e_tag: output.e_tag,
+                    part_number: Some(part_number),
+                });
+            }
+            let req = CompleteMultipartUploadRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                request_payer: self.request_payer(),
+                ..Default::default()
+            };
+            self.client.complete_multipart_upload(req).await?;
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            let req = AbortMultipartUploadRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                upload_id,
+                request_payer: self.request_payer(),
+                ..Default::default()
+            };
+            if let Err(err) = self.client.abort_multipart_upload(req).await {
+                warn!(
+                    logger,
+                    "failed to abort multipart upload of {}: {:?}", key, err
+                );
+            }
+        }
+        result
+    }
 }
 
 #[async_trait]
@@ -120,6 +331,7 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
                 let logger = logger.clone();
                 let s3_prefix_base = s3_prefix_base.clone();
                 let max_keys = self.config.max_keys;
+                let request_payer = self.request_payer();
 
                 async move {
                     let mut snapshot = vec![];
@@ -131,6 +343,7 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
                             prefix: prefix.clone(),
                             max_keys: Some(max_keys as i64),
                             continuation_token,
+                            request_payer: request_payer.clone(),
                             ..Default::default()
                         };
 
@@ -190,24 +403,25 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
                     let client = self.client.clone();
                     let progress = progress.clone();
                     let prefix = self.config.prefix.clone();
+                    let request_payer = self.request_payer();
 
                     async move {
                         progress.set_message(&snapshot.key);
                         let req = HeadObjectRequest {
                             bucket,
                             key: format!("{}/{}", prefix, snapshot.key),
+                            request_payer,
                             ..Default::default()
                         };
                         let resp = client.head_object(req).await?;
-                        let last_modified = if let Some(metadata) = resp.metadata {
-                            metadata
-                                .get("clone-last-modified")
-                                .and_then(|x| x.parse::<u64>().ok())
-                        } else {
-                            None
-                        };
+                        let metadata = resp.metadata.unwrap_or_default();
+                        let last_modified = metadata
+                            .get("clone-last-modified")
+                            .and_then(|x| x.parse::<u64>().ok());
                         Ok::<_, Error>(SnapshotMeta {
                             last_modified,
+                            checksum_method: metadata.get("clone-checksum-method").cloned(),
+                            checksum: metadata.get("clone-checksum").cloned(),
                             ..snapshot
                         })
                     }
@@ -320,19 +534,33 @@ where
             content_type,
         } = byte_stream;
 
-        let body = object.as_stream();
-
         let mut metadata = self.gen_metadata();
         metadata.insert("clone-last-modified".to_string(), modified_at.to_string());
         metadata.extend(snapshot.s3_meta());
+        let key = format!("{}/{}", self.config.prefix, snapshot.key());
+        let content_type = content_type.or_else(|| get_mime(snapshot.key()));
+
+        if length > self.config.multipart_threshold {
+            let file = match &mut object {
+                ByteObject::LocalFile { file, .. } => file.take().unwrap(),
+            };
+            return self
+                .put_multipart(logger, key, file, metadata, content_type)
+                .await;
+        }
+
+        let body = object.as_stream();
 
         let req = PutObjectRequest {
             bucket: self.config.bucket.clone(),
-            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            key,
             body: Some(rusoto_s3::StreamingBody::new(body)),
             metadata: Some(metadata),
             content_length: Some(length as i64),
-            content_type: content_type.or_else(|| get_mime(snapshot.key())),
+            content_type,
+            server_side_encryption: self.config.sse.clone(),
+            ssekms_key_id: self.config.sse_kms_key_id.clone(),
+            request_payer: self.request_payer(),
             ..Default::default()
         };
 
@@ -341,13 +569,230 @@ where
         Ok(())
     }
 
+    async fn audit_object(
+        &self,
+        snapshot: &Snapshot,
+        mut item: ByteStream,
+        _mission: &Mission,
+    ) -> Result<Option<bool>>
+    where
+        Snapshot: Sync,
+        ByteStream: Send + 'async_trait,
+    {
+        let expected = item.object.sha256().await?;
+        let req = GetObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            request_payer: self.request_payer(),
+            ..Default::default()
+        };
+        let output = match self.client.get_object(req).await {
+            Ok(output) => output,
+            Err(rusoto_core::RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                return Ok(Some(false))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let got = match output.body {
+            Some(body) => sha256(&mut body.into_async_read()).await?,
+            None => sha256(&mut tokio::io::empty()).await?,
+        };
+        Ok(Some(got == expected))
+    }
+
     async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
         let req = DeleteObjectRequest {
             bucket: self.config.bucket.clone(),
             key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            request_payer: self.request_payer(),
             ..Default::default()
         };
         self.client.delete_object(req).await?;
         Ok(())
     }
+
+    async fn delete_objects(&self, snapshots: &[Snapshot], mission: &Mission) -> Vec<Result<()>>
+    where
+        Snapshot: Sync,
+    {
+        let keys: Vec<String> = snapshots
+            .iter()
+            .map(|snapshot| format!("{}/{}", self.config.prefix, snapshot.key()))
+            .collect();
+        // index of objects to delete, and error of last attempt
+        let mut pending: Vec<(usize, String)> =
+            (0..keys.len()).map(|i| (i, String::new())).collect();
+
+        for attempt in 0..DELETE_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+            }
+            let req = DeleteObjectsRequest {
+                bucket: self.config.bucket.clone(),
+                delete: Delete {
+                    objects: pending
+                        .iter()
+                        .map(|(i, _)| ObjectIdentifier {
+                            key: keys[*i].clone(),
+                            version_id: None,
+                        })
+                        .collect(),
+                    quiet: Some(true),
+                },
+                request_payer: self.request_payer(),
+                ..Default::default()
+            };
+            match self.client.delete_objects(req).await {
+                Ok(output) => {
+                    let errors: HashMap<String, String> = output
+                        .errors
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|err| {
+                            let message = format!("{:?}: {:?}", err.code, err.message);
+                            err.key.map(|key| (key, message))
+                        })
+                        .collect();
+                    pending = pending
+                        .into_iter()
+                        .filter_map(|(i, _)| errors.get(&keys[i]).map(|err| (i, err.clone())))
+                        .collect();
+                }
+                Err(err) => {
+                    let message = format!("{:?}", err);
+                    for (_, last_err) in pending.iter_mut() {
+                        *last_err = message.clone();
+                    }
+                }
+            }
+            if pending.is_empty() {
+                break;
+            }
+            debug!(
+                mission.logger,
+                "{} objects failed to delete in batch, attempt {}",
+                pending.len(),
+                attempt + 1
+            );
+        }
+
+        let mut results: Vec<Result<()>> = keys.iter().map(|_| Ok(())).collect();
+        for (i, err) in pending {
+            results[i] = Err(Error::StorageError(format!(
+                "failed to delete {}: {}",
+                keys[i], err
+            )));
+        }
+        results
+    }
+
+    fn delete_batch_size(&self) -> usize {
+        DELETE_BATCH_SIZE
+    }
+
+    async fn link_object(
+        &self,
+        snapshot: &Snapshot,
+        from: &str,
+        mission: &Mission,
+    ) -> Result<bool> {
+        debug!(mission.logger, "copy: {} -> {}", from, snapshot.key());
+        let mut metadata = self.gen_metadata();
+        metadata.extend(snapshot.s3_meta());
+        let req = CopyObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            copy_source: urlencoding::encode(&format!(
+                "{}/{}/{}",
+                self.config.bucket, self.config.prefix, from
+            ))
+            .into_owned(),
+            metadata: Some(metadata),
+            metadata_directive: Some("REPLACE".to_string()),
+            content_type: get_mime(snapshot.key()),
+            server_side_encryption: self.config.sse.clone(),
+            ssekms_key_id: self.config.sse_kms_key_id.clone(),
+            request_payer: self.request_payer(),
+            ..Default::default()
+        };
+        self.client.copy_object(req).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl StateStorage for S3Backend {
+    async fn load_state(&self, name: &str, path: &std::path::Path) -> Result<bool> {
+        let req = GetObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}/{}", self.config.prefix, STATE_PREFIX, name),
+            request_payer: self.request_payer(),
+            ..Default::default()
+        };
+        let output = match self.client.get_object(req).await {
+            Ok(output) => output,
+            Err(rusoto_core::RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let body = output
+            .body
+            .ok_or_else(|| Error::StorageError(format!("empty state object {}", name)))?;
+        let mut file = tokio::fs::File::create(path).await?;
+        tokio::io::copy(&mut body.into_async_read(), &mut file).await?;
+        file.sync_all().await?;
+        Ok(true)
+    }
+
+    async fn save_state(&self, name: &str, path: &std::path::Path) -> Result<()> {
+        let key = format!("{}/{}/{}", self.config.prefix, STATE_PREFIX, name);
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let req = DeleteObjectRequest {
+                    bucket: self.config.bucket.clone(),
+                    key,
+                    request_payer: self.request_payer(),
+                    ..Default::default()
+                };
+                self.client.delete_object(req).await?;
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let length = file.metadata().await?.len();
+        let body = tokio_util::codec::FramedRead::new(file, tokio_util::codec::BytesCodec::new())
+            .map(|bytes| bytes.map(|bytes| bytes.freeze()));
+        let req = PutObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key,
+            body: Some(rusoto_s3::StreamingBody::new(body)),
+            content_length: Some(length as i64),
+            server_side_encryption: self.config.sse.clone(),
+            ssekms_key_id: self.config.sse_kms_key_id.clone(),
+            request_payer: self.request_payer(),
+            ..Default::default()
+        };
+        self.client.put_object(req).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{virtual_host_endpoint, virtual_host_path};
+
+    #[test]
+    fn test_virtual_host() {
+        assert_eq!(
+            virtual_host_endpoint("https://s3.example.com:9000", "mirror"),
+            "https://mirror.s3.example.com:9000"
+        );
+        assert_eq!(
+            virtual_host_path("/mirror/pypi/index.html", "mirror"),
+            "/pypi/index.html"
+        );
+        assert_eq!(virtual_host_path("/mirror", "mirror"), "/");
+    }
 }
@@ -11,9 +11,10 @@
 use async_trait::async_trait;
 use chrono::DateTime;
 
+use crate::checksum_pipe::calc_checksum;
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
-use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+use crate::traits::{GenerateStorage, Key, Metadata, SizeStorage, SnapshotStorage, SourceStorage};
 use crate::utils::{hash_string, unix_time};
 use futures_core::Stream;
 use futures_util::{StreamExt, TryStreamExt};
@@ -40,6 +41,24 @@ impl ByteObject {
         }
     }
 
+    /// Sha256 of the object, which can still be used afterwards.
+    pub async fn sha256(&mut self) -> Result<String> {
+        match self {
+            ByteObject::LocalFile { file: Some(f), .. } => Ok(calc_checksum(f, "sha256").await?),
+            ByteObject::LocalFile {
+                file: None,
+                path: Some(path),
+            } => {
+                let mut f = tokio::fs::File::open(path).await?;
+                Ok(calc_checksum(&mut f, "sha256").await?)
+            }
+            ByteObject::LocalFile {
+                file: None,
+                path: None,
+            } => Err(Error::PipeError("data missing".to_string())),
+        }
+    }
+
     pub fn use_file(mut self) -> std::path::PathBuf {
         match &mut self {
             ByteObject::LocalFile { file, path } => {
@@ -73,6 +92,36 @@ pub struct ByteStream {
     pub content_type: Option<String>,
 }
 
+impl ByteStream {
+    /// Buffer `content` to a local file under `buffer_path`, and wrap it as a `ByteStream`.
+    pub async fn from_bytes(buffer_path: &str, key: &str, content: &[u8]) -> Result<Self> {
+        let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+        let path = std::path::Path::new(buffer_path).join(pipe_file);
+        let mut f = BufWriter::new(
+            OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        f.write_all(content).await?;
+        f.flush().await?;
+        let mut f = f.into_inner();
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok(ByteStream {
+            object: ByteObject::LocalFile {
+                file: Some(f),
+                path: Some(path),
+            },
+            length: content.len() as u64,
+            modified_at: unix_time(),
+            content_type: None,
+        })
+    }
+}
+
 pub struct ByteStreamPipe<Source> {
     pub source: Source,
     pub buffer_path: String,
@@ -112,6 +161,41 @@ where
     }
 }
 
+#[async_trait]
+impl<Snapshot, Source> GenerateStorage<Snapshot> for ByteStreamPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: GenerateStorage<Snapshot>,
+{
+    async fn generate(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<Vec<u8>>> {
+        self.source.generate(snapshot, mission).await
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SizeStorage<Snapshot> for ByteStreamPipe<Source>
+where
+    Snapshot: Key + Metadata,
+    Source: SourceStorage<Snapshot, TransferURL>,
+{
+    async fn object_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        if let Some(size) = snapshot.size() {
+            return Ok(Some(size));
+        }
+        let transfer_url = self.source.get_object(snapshot, mission).await?;
+        let response = crate::http::send(mission.client.head(&transfer_url.0)).await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::HTTPError(status));
+        }
+        Ok(response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok()))
+    }
+}
+
 #[async_trait]
 impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for ByteStreamPipe<Source>
 where
@@ -138,7 +222,7 @@ where
                 .await?,
         );
 
-        let response = mission.client.get(&transfer_url.0).send().await?;
+        let response = crate::http::send(mission.client.get(&transfer_url.0)).await?;
         let status = response.status();
         if !status.is_success() {
             return Err(Error::HTTPError(status));
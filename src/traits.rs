@@ -17,6 +17,20 @@ pub trait SourceStorage<SnapshotItem, SourceItem>: Send + Sync + 'static {
     async fn get_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<SourceItem>;
 }
 
+#[async_trait]
+pub trait GenerateStorage<SnapshotItem>: Send + Sync + 'static {
+    /// Returns the content of `snapshot` if it is generated by this storage
+    /// instead of being fetched from upstream.
+    async fn generate(&self, snapshot: &SnapshotItem, mission: &Mission)
+        -> Result<Option<Vec<u8>>>;
+}
+
+#[async_trait]
+pub trait SizeStorage<SnapshotItem>: Send + Sync + 'static {
+    /// Size of `snapshot` in upstream, found without downloading it.
+    async fn object_size(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<Option<u64>>;
+}
+
 #[async_trait]
 pub trait TargetStorage<SnapshotItem, TargetItem>: Send + Sync + 'static {
     async fn put_object(
@@ -26,6 +40,66 @@ pub trait TargetStorage<SnapshotItem, TargetItem>: Send + Sync + 'static {
         mission: &Mission,
     ) -> Result<()>;
     async fn delete_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<()>;
+    /// Delete a batch of at most `delete_batch_size` objects, and return
+    /// result of each object in the same order.
+    async fn delete_objects(&self, snapshots: &[SnapshotItem], mission: &Mission) -> Vec<Result<()>>
+    where
+        SnapshotItem: Sync,
+    {
+        let mut results = vec![];
+        for snapshot in snapshots {
+            results.push(self.delete_object(snapshot, mission).await);
+        }
+        results
+    }
+    /// Max number of objects deleted in one `delete_objects` call. Objects
+    /// are deleted one by one if it is 1.
+    fn delete_batch_size(&self) -> usize {
+        1
+    }
+    /// Called after all objects are updated and before any deletion, so
+    /// that target can make objects staged during the run visible.
+    async fn publish(&self, _mission: &Mission) -> Result<()> {
+        Ok(())
+    }
+    /// Compare object `snapshot` on target with `item` fetched from upstream.
+    /// Returns `None` if the target can't read its objects.
+    async fn audit_object(
+        &self,
+        _snapshot: &SnapshotItem,
+        _item: TargetItem,
+        _mission: &Mission,
+    ) -> Result<Option<bool>>
+    where
+        SnapshotItem: Sync,
+        TargetItem: Send + 'async_trait,
+    {
+        Ok(None)
+    }
+    /// Store `snapshot` as a link to object `from`, which is already on
+    /// target and has the same content. Returns false if the target doesn't
+    /// support links.
+    async fn link_object(
+        &self,
+        snapshot: &SnapshotItem,
+        from: &str,
+        mission: &Mission,
+    ) -> Result<bool>;
+}
+
+/// Prefix of state objects on target, which are never transferred.
+pub const STATE_PREFIX: &str = ".mirror-clone";
+
+/// Target storage which can store state files of mirror-clone, so that runs
+/// can be resumed without local state.
+#[async_trait]
+pub trait StateStorage: Send + Sync + 'static {
+    /// Download state object `name` to `path`. Returns false if the state
+    /// object doesn't exist.
+    async fn load_state(&self, name: &str, path: &std::path::Path) -> Result<bool>;
+    /// Upload `path` as state object `name`. If `path` doesn't exist, the
+    /// state object is removed.
+    async fn save_state(&self, name: &str, path: &std::path::Path) -> Result<()>;
 }
 
 pub trait Key: Send + Sync + 'static {
@@ -43,6 +117,10 @@ pub trait Metadata {
         None
     }
 
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
     fn checksum(&self) -> Option<&str> {
         None
     }
@@ -54,6 +132,12 @@ pub trait Metadata {
 
 pub trait Diff {
     fn diff(&self, other: &Self) -> bool;
+
+    /// Whether this object is only kept if it already exists on target,
+    /// instead of being transferred.
+    fn existing_only(&self) -> bool {
+        false
+    }
 }
 
 impl Key for SnapshotPath {
@@ -72,4 +156,13 @@ impl Diff for SnapshotPath {
     }
 }
 
-impl Metadata for SnapshotPath {}
+impl Metadata for SnapshotPath {
+    fn priority(&self) -> isize {
+        // forced objects are usually metadata, which should be transferred last
+        if self.1 {
+            -1
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Database Transfer
+//!
+//! Database transfer keeps snapshots of source and target in an embedded
+//! SQLite database, indexed by key and checksum, and computes diff by walking
+//! the database in key order. Target is only scanned when the database has no
+//! target state yet. Otherwise, target state recorded by previous run is used
+//! as target snapshot. To force a rescan of target, e.g. after target is
+//! modified by others, remove the database.
+//!
+//! After transfer, target state in the database is updated with objects
+//! transferred successfully. Objects failed to transfer keep their previous
+//! state, so that they will be retried in the next run. In dry run, the plan
+//! is computed in memory, and the database is never written.
+//!
+//! Snapshots are still taken, sorted and planned as a whole in memory, and
+//! the target state is loaded from the database as a whole, so peak memory
+//! is no lower than `full-diff`. The database only saves scanning target.
+
+use std::collections::HashSet;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use slog::{info, Logger};
+
+use super::{diff_plan, TransferPlan, TransferResult, TransferStrategy};
+use crate::error::Result;
+use crate::traits::{Diff, Key, Metadata};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS source (
+    key TEXT PRIMARY KEY,
+    checksum TEXT,
+    item TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS target (
+    key TEXT PRIMARY KEY,
+    checksum TEXT,
+    item TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS source_checksum ON source (checksum);
+CREATE INDEX IF NOT EXISTS target_checksum ON target (checksum);
+CREATE TABLE IF NOT EXISTS state (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// Snapshots of source and target stored in SQLite.
+pub struct SnapshotDb {
+    conn: Connection,
+}
+
+impl SnapshotDb {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Open existing database at `path` without writing it.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Self { conn })
+    }
+
+    /// Whether target state has been recorded by previous run.
+    pub fn has_target(&self) -> Result<bool> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM state WHERE name = 'target'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value.is_some())
+    }
+
+    /// Replace snapshot in `table` with `snapshot`.
+    pub fn replace<Snapshot>(&mut self, table: &str, snapshot: Vec<Snapshot>) -> Result<()>
+    where
+        Snapshot: Key + Metadata + Serialize,
+    {
+        let tx = self.conn.transaction()?;
+        tx.execute(&format!("DELETE FROM {}", table), [])?;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT OR REPLACE INTO {} (key, checksum, item) VALUES (?1, ?2, ?3)",
+                table
+            ))?;
+            for item in snapshot {
+                stmt.execute(params![
+                    item.key(),
+                    item.checksum(),
+                    serde_json::to_string(&item)?
+                ])?;
+            }
+        }
+        if table == "target" {
+            tx.execute(
+                "INSERT OR REPLACE INTO state (name, value) VALUES ('target', datetime('now'))",
+                [],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Snapshot in `table`, sorted by key.
+    pub fn load<Snapshot>(&self, table: &str) -> Result<Vec<Snapshot>>
+    where
+        Snapshot: DeserializeOwned,
+    {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT item FROM {} ORDER BY key", table))?;
+        let mut rows = stmt.query([])?;
+        let mut snapshot = vec![];
+        while let Some(row) = rows.next()? {
+            snapshot.push(serde_json::from_str(&row.get::<_, String>(0)?)?);
+        }
+        Ok(snapshot)
+    }
+
+    /// Compare source with target in key order.
+    pub fn diff<Snapshot>(
+        &self,
+        logger: &Logger,
+        print_plan: usize,
+    ) -> Result<TransferPlan<Snapshot>>
+    where
+        Snapshot: Diff + Key + DeserializeOwned,
+    {
+        let mut updates = vec![];
+        let mut deletions = vec![];
+        let mut max_info = 0;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT source.item, target.item FROM source
+             LEFT JOIN target ON source.key = target.key
+             ORDER BY source.key",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let source: Snapshot = serde_json::from_str(&row.get::<_, String>(0)?)?;
+            let sign = match row.get::<_, Option<String>>(1)? {
+                None if source.existing_only() => continue,
+                None => "+",
+                Some(target) => {
+                    let target: Snapshot = serde_json::from_str(&target)?;
+                    if !source.diff(&target) {
+                        continue;
+                    }
+                    "="
+                }
+            };
+            if max_info < print_plan {
+                info!(logger, "{} {:?}", sign, source.key());
+                max_info += 1;
+            }
+            updates.push(source);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT item FROM target
+             WHERE NOT EXISTS (SELECT 1 FROM source WHERE source.key = target.key)
+             ORDER BY key",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let target: Snapshot = serde_json::from_str(&row.get::<_, String>(0)?)?;
+            if max_info < print_plan {
+                info!(logger, "- {:?}", target.key());
+                max_info += 1;
+            }
+            deletions.push(target);
+        }
+
+        Ok(TransferPlan { updates, deletions })
+    }
+
+    /// Record transferred objects in target state.
+    pub fn apply(&mut self, updates: &[String], deletions: &[String]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut update = tx.prepare(
+                "INSERT OR REPLACE INTO target (key, checksum, item)
+                 SELECT key, checksum, item FROM source WHERE key = ?1",
+            )?;
+            for key in updates {
+                update.execute([key])?;
+            }
+            let mut delete = tx.prepare("DELETE FROM target WHERE key = ?1")?;
+            for key in deletions {
+                delete.execute([key])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+pub struct Database {
+    path: String,
+    db: Option<SnapshotDb>,
+    print_plan: usize,
+    no_delete: bool,
+    dry_run: bool,
+    updates: Vec<String>,
+    deletions: Vec<String>,
+}
+
+impl Database {
+    pub fn new(path: &str, print_plan: usize, no_delete: bool, dry_run: bool) -> Self {
+        Self {
+            path: path.to_string(),
+            db: None,
+            print_plan,
+            no_delete,
+            dry_run,
+            updates: vec![],
+            deletions: vec![],
+        }
+    }
+
+    /// Database is opened on first use, as it may be loaded from target
+    /// after the strategy is created.
+    fn db(&mut self) -> Result<&mut SnapshotDb> {
+        if self.db.is_none() {
+            self.db = Some(if self.dry_run {
+                SnapshotDb::open_read_only(&self.path)?
+            } else {
+                SnapshotDb::open(&self.path)?
+            });
+        }
+        Ok(self.db.as_mut().unwrap())
+    }
+}
+
+impl<Snapshot> TransferStrategy<Snapshot> for Database
+where
+    Snapshot: Diff + Key + Metadata + Serialize + DeserializeOwned,
+{
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn baseline(&mut self, logger: &Logger) -> Result<Option<Vec<Snapshot>>> {
+        if self.dry_run && !std::path::Path::new(&self.path).exists() {
+            info!(logger, "no database, scanning target");
+            return Ok(None);
+        }
+        let db = self.db()?;
+        if db.has_target()? {
+            let target = db.load("target")?;
+            info!(
+                logger,
+                "loaded {} objects from target state in database",
+                target.len()
+            );
+            Ok(Some(target))
+        } else {
+            info!(logger, "no target state in database, scanning target");
+            Ok(None)
+        }
+    }
+
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>> {
+        let print_plan = self.print_plan;
+        if self.dry_run {
+            return Ok(diff_plan(logger, source, target, print_plan));
+        }
+        let db = self.db()?;
+        // target may differ from state in database, e.g. objects left dirty
+        // by previous run are removed, and it's empty when forced
+        db.replace("target", target)?;
+        db.replace("source", source)?;
+
+        let plan: TransferPlan<Snapshot> = db.diff(logger, print_plan)?;
+        self.updates = plan.updates.iter().map(|x| x.key().to_string()).collect();
+        self.deletions = plan.deletions.iter().map(|x| x.key().to_string()).collect();
+        Ok(plan)
+    }
+
+    fn finish(&mut self, logger: &Logger, result: &TransferResult) -> Result<()> {
+        let failed_updates: HashSet<&str> =
+            result.failed_updates.iter().map(String::as_str).collect();
+        let failed_deletions: HashSet<&str> =
+            result.failed_deletions.iter().map(String::as_str).collect();
+
+        let updates: Vec<String> = self
+            .updates
+            .drain(..)
+            .filter(|key| !failed_updates.contains(key.as_str()))
+            .collect();
+        let deletions: Vec<String> = if self.no_delete {
+            vec![]
+        } else {
+            self.deletions
+                .drain(..)
+                .filter(|key| !failed_deletions.contains(key.as_str()))
+                .collect()
+        };
+
+        self.db()?.apply(&updates, &deletions)?;
+        info!(
+            logger,
+            "database: {} objects updated, {} objects deleted",
+            updates.len(),
+            deletions.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+
+    #[test]
+    fn test_database_keeps_unfinished_objects() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!("mirror-clone-db-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.db");
+        let path = path.to_str().unwrap();
+
+        let mut strategy = Database::new(path, 0, false, false);
+        let baseline: Option<Vec<SnapshotPath>> = strategy.baseline(&logger).unwrap();
+        assert!(baseline.is_none());
+        let source = vec![SnapshotPath::new("a".into()), SnapshotPath::new("b".into())];
+        let target = vec![SnapshotPath::new("c".into())];
+        let plan = strategy.plan(&logger, source, target).unwrap();
+        assert_eq!(plan.updates.len(), 2);
+        assert_eq!(plan.deletions.len(), 1);
+        let result = TransferResult {
+            failed_updates: vec!["b".into()],
+            failed_deletions: vec![],
+        };
+        TransferStrategy::<SnapshotPath>::finish(&mut strategy, &logger, &result).unwrap();
+
+        let mut strategy = Database::new(path, 0, false, false);
+        let baseline: Vec<SnapshotPath> = strategy.baseline(&logger).unwrap().unwrap();
+        assert_eq!(baseline, vec![SnapshotPath::new("a".into())]);
+        let source = vec![SnapshotPath::new("a".into()), SnapshotPath::new("b".into())];
+        let plan = strategy.plan(&logger, source, baseline).unwrap();
+        let updates: Vec<String> = plan.updates.into_iter().map(|x| x.0).collect();
+        assert_eq!(updates, vec!["b"]);
+        assert!(plan.deletions.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_database_dry_run() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-clone-db-dry-run-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.db");
+        let path = path.to_str().unwrap();
+
+        let mut strategy = Database::new(path, 0, false, true);
+        let baseline: Option<Vec<SnapshotPath>> = strategy.baseline(&logger).unwrap();
+        assert!(baseline.is_none());
+        let source = vec![SnapshotPath::new("a".into())];
+        let plan = strategy.plan(&logger, source, vec![]).unwrap();
+        assert_eq!(plan.updates.len(), 1);
+        assert!(!std::path::Path::new(path).exists());
+
+        let mut strategy = Database::new(path, 0, false, false);
+        let baseline: Option<Vec<SnapshotPath>> = strategy.baseline(&logger).unwrap();
+        assert!(baseline.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Distributed Transfer
+//!
+//! In distributed mode, the coordinator takes snapshots and computes the
+//! transfer plan as usual, but instead of transferring objects itself, it
+//! serves objects to update over HTTP. Workers are started with the same
+//! source and target config, pull objects from coordinator, transfer them,
+//! and report the result.
+//!
+//! * `GET /task` returns the next object as JSON, `204` if all remaining
+//!   objects are being transferred by other workers, or `410` if all objects
+//!   have been transferred.
+//! * `POST /result` reports `{"key": ..., "ok": ...}` of an object.
+//!
+//! Requests without the secret shared by coordinator and workers in
+//! `X-Mirror-Clone-Secret` header are rejected with `401`.
+//!
+//! Workers retry requests to coordinator on connection errors and server
+//! errors, and only give up after repeated failures.
+//!
+//! An object not reported within the lease time is handed out again. If a
+//! journal is kept, the coordinator records objects handed out and those
+//! transferred successfully. Sources generating content from their snapshot
+//! and staged index objects are rejected, as workers never take snapshots nor
+//! publish target.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use indicatif::ProgressBar;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use tokio::sync::Notify;
+
+use super::journal::Journal;
+use crate::auth::Secret;
+use crate::common::Mission;
+use crate::error::{Error, Result};
+use crate::traits::{Key, SourceStorage, TargetStorage};
+
+const LEASE: Duration = Duration::from_secs(600);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const SECRET_HEADER: &str = "x-mirror-clone-secret";
+/// Attempts of a request to coordinator before worker gives up.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Deserialize)]
+struct TaskResult {
+    key: String,
+    ok: bool,
+}
+
+struct Coordinator<Snapshot> {
+    items: HashMap<String, Snapshot>,
+    pending: VecDeque<String>,
+    leased: HashMap<String, Instant>,
+    failed: Vec<String>,
+    journal: Option<Arc<Journal>>,
+}
+
+impl<Snapshot: Key + Serialize> Coordinator<Snapshot> {
+    fn next_task(&mut self) -> Response<Body> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .leased
+            .iter()
+            .filter(|(_, leased_at)| now.duration_since(**leased_at) > LEASE)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.leased.remove(&key);
+            self.pending.push_back(key);
+        }
+
+        while let Some(key) = self.pending.pop_front() {
+            if let Some(journal) = &self.journal {
+                if journal.put(&key).is_err() {
+                    self.failed.push(key);
+                    continue;
+                }
+            }
+            let body = serde_json::to_vec(&self.items[&key]).unwrap();
+            self.leased.insert(key, now);
+            return Response::new(Body::from(body));
+        }
+        status(if self.leased.is_empty() {
+            StatusCode::GONE
+        } else {
+            StatusCode::NO_CONTENT
+        })
+    }
+
+    /// Returns whether the result is accepted.
+    fn finish_task(&mut self, result: TaskResult) -> bool {
+        if self.leased.remove(&result.key).is_none() {
+            return false;
+        }
+        if !result.ok {
+            self.failed.push(result.key);
+        } else if let Some(journal) = &self.journal {
+            // object is transferred again in next run if not recorded
+            journal.done(&result.key).ok();
+        }
+        true
+    }
+
+    fn complete(&self) -> bool {
+        self.pending.is_empty() && self.leased.is_empty()
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+/// Whether `req` carries `secret`.
+fn authorized(req: &Request<Body>, secret: &str) -> bool {
+    match req.headers().get(SECRET_HEADER) {
+        // compare in constant time
+        Some(value) if value.len() == secret.len() => {
+            value
+                .as_bytes()
+                .iter()
+                .zip(secret.as_bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+async fn handle<Snapshot: Key + Serialize>(
+    coordinator: Arc<Mutex<Coordinator<Snapshot>>>,
+    secret: Arc<Secret>,
+    progress: ProgressBar,
+    complete: Arc<Notify>,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if !authorized(&req, &secret.0) {
+        return Ok(status(StatusCode::UNAUTHORIZED));
+    }
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/task") => coordinator.lock().unwrap().next_task(),
+        (&Method::POST, "/result") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+            };
+            let result: TaskResult = match serde_json::from_slice(&body) {
+                Ok(result) => result,
+                Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+            };
+            progress.set_message(&result.key);
+            let mut coordinator = coordinator.lock().unwrap();
+            if coordinator.finish_task(result) {
+                progress.inc(1);
+            }
+            if coordinator.complete() {
+                complete.notify_one();
+            }
+            status(StatusCode::OK)
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+/// Serve `updates` to workers with `secret` at `addr`, and return keys of
+/// objects which failed to transfer.
+pub async fn serve_tasks<Snapshot>(
+    logger: &Logger,
+    addr: &str,
+    secret: &Secret,
+    journal: Option<Arc<Journal>>,
+    updates: Vec<Snapshot>,
+    progress: ProgressBar,
+) -> Result<Vec<String>>
+where
+    Snapshot: Key + Serialize,
+{
+    if updates.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|err| Error::ConfigureError(format!("invalid address {}: {:?}", addr, err)))?;
+    let coordinator = Arc::new(Mutex::new(Coordinator {
+        pending: updates.iter().map(|x| x.key().to_string()).collect(),
+        items: updates
+            .into_iter()
+            .map(|x| (x.key().to_string(), x))
+            .collect(),
+        leased: HashMap::new(),
+        failed: vec![],
+        journal,
+    }));
+    let complete = Arc::new(Notify::new());
+    let secret = Arc::new(secret.clone());
+
+    let make_service = {
+        let coordinator = coordinator.clone();
+        let complete = complete.clone();
+        make_service_fn(move |_| {
+            let coordinator = coordinator.clone();
+            let secret = secret.clone();
+            let progress = progress.clone();
+            let complete = complete.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(
+                        coordinator.clone(),
+                        secret.clone(),
+                        progress.clone(),
+                        complete.clone(),
+                        req,
+                    )
+                }))
+            }
+        })
+    };
+
+    info!(logger, "serving tasks at {}", addr);
+    let server = Server::try_bind(&addr)
+        .map_err(|err| Error::ProcessError(format!("failed to bind {}: {:?}", addr, err)))?
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            complete.notified().await;
+            // let workers know that all tasks are done
+            tokio::time::sleep(POLL_INTERVAL * 2).await;
+        });
+    server
+        .await
+        .map_err(|err| Error::ProcessError(format!("coordinator error: {:?}", err)))?;
+
+    let failed = std::mem::take(&mut coordinator.lock().unwrap().failed);
+    Ok(failed)
+}
+
+/// Send request to coordinator, retrying with exponential backoff on
+/// connection errors and server errors.
+async fn send_retry(
+    logger: &Logger,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        let err = match request().send().await {
+            Ok(response) if !response.status().is_server_error() => return Ok(response),
+            Ok(response) => Error::HTTPError(response.status()),
+            Err(err) => err.into(),
+        };
+        attempt += 1;
+        if attempt >= MAX_ATTEMPTS {
+            return Err(err);
+        }
+        warn!(
+            logger,
+            "error while requesting coordinator, retrying: {:?}", err
+        );
+        tokio::time::sleep(POLL_INTERVAL * 2u32.pow(attempt - 1)).await;
+    }
+}
+
+/// Pull objects from coordinator at `url` with `secret` and transfer them,
+/// until all objects have been transferred.
+pub async fn run_worker<Snapshot, Source, Target, Item>(
+    url: &str,
+    secret: &Secret,
+    source: &Source,
+    target: &Target,
+    source_mission: &Mission,
+    target_mission: &Mission,
+) -> Result<()>
+where
+    Snapshot: Key + DeserializeOwned,
+    Source: SourceStorage<Snapshot, Item>,
+    Target: TargetStorage<Snapshot, Item>,
+{
+    let logger = &target_mission.logger;
+    let client = &target_mission.client;
+    let url = url.trim_end_matches('/');
+
+    loop {
+        let response = send_retry(logger, || {
+            client
+                .get(format!("{}/task", url))
+                .header(SECRET_HEADER, &secret.0)
+        })
+        .await?;
+        match response.status() {
+            StatusCode::GONE => break,
+            StatusCode::NO_CONTENT => {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            StatusCode::OK => {}
+            code => return Err(Error::HTTPError(code)),
+        }
+        let snapshot: Snapshot = response.json().await?;
+        source_mission.progress.set_message(snapshot.key());
+
+        let result = match source.get_object(&snapshot, source_mission).await {
+            Ok(item) => target.put_object(&snapshot, item, target_mission).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            warn!(logger, "error while transfer {}: {:?}", snapshot.key(), err);
+        }
+
+        let task_result = TaskResult {
+            key: snapshot.key().to_string(),
+            ok: result.is_ok(),
+        };
+        send_retry(logger, || {
+            client
+                .post(format!("{}/result", url))
+                .header(SECRET_HEADER, &secret.0)
+                .json(&task_result)
+        })
+        .await?
+        .error_for_status()?;
+        source_mission.progress.inc(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+
+    #[test]
+    fn test_authorized() {
+        let request = |secret: Option<&str>| {
+            let mut request = Request::builder().uri("/task");
+            if let Some(secret) = secret {
+                request = request.header(SECRET_HEADER, secret);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        assert!(authorized(&request(Some("secret")), "secret"));
+        assert!(!authorized(&request(Some("secreT")), "secret"));
+        assert!(!authorized(&request(Some("secret!")), "secret"));
+        assert!(!authorized(&request(None), "secret"));
+    }
+
+    #[test]
+    fn test_coordinator_journal() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-clone-distributed-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.jsonl");
+        let path = path.to_str().unwrap();
+
+        let updates = vec![SnapshotPath::new("a".into()), SnapshotPath::new("b".into())];
+        let mut coordinator = Coordinator {
+            pending: updates.iter().map(|x| x.0.clone()).collect(),
+            items: updates.into_iter().map(|x| (x.0.clone(), x)).collect(),
+            leased: HashMap::new(),
+            failed: vec![],
+            journal: Some(Arc::new(Journal::open(path).unwrap())),
+        };
+        for _ in 0..2 {
+            assert_eq!(coordinator.next_task().status(), StatusCode::OK);
+        }
+        assert_eq!(coordinator.next_task().status(), StatusCode::NO_CONTENT);
+        assert!(coordinator.finish_task(TaskResult {
+            key: "a".into(),
+            ok: true
+        }));
+        assert!(coordinator.finish_task(TaskResult {
+            key: "b".into(),
+            ok: false
+        }));
+        assert!(coordinator.complete());
+        assert_eq!(coordinator.failed, vec!["b"]);
+        drop(coordinator);
+
+        let dirty = Journal::recover(path).unwrap();
+        assert_eq!(dirty.into_iter().collect::<Vec<_>>(), vec!["b"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
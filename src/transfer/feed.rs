@@ -0,0 +1,203 @@
+//! Feed of newly mirrored objects
+//!
+//! After each run, objects added to target in this run are prepended to a
+//! feed, which is stored on target as `.mirror-clone/feed.json` (JSON Feed
+//! 1.1) and `.mirror-clone/feed.xml` (RSS 2.0). Downstream users and bots
+//! can subscribe to it instead of diffing listings.
+//!
+//! Package name and version are guessed from file name in the form of
+//! `<name>-<version>.<ext>`, and omitted if file name doesn't look like that.
+
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use crate::error::Result;
+use crate::traits::StateStorage;
+
+const JSON_FEED: &str = "feed.json";
+const RSS_FEED: &str = "feed.xml";
+const TITLE: &str = "New on the mirror";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorItem {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedItem {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub title: String,
+    pub date_published: String,
+    #[serde(rename = "_mirror_clone")]
+    pub mirror: MirrorItem,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonFeed {
+    version: String,
+    title: String,
+    items: Vec<FeedItem>,
+}
+
+/// Guess package name and version from file name of `key`.
+pub(super) fn name_version(key: &str) -> Option<(String, String)> {
+    let file_name = key.rsplit('/').next()?;
+    let pos = file_name
+        .char_indices()
+        .find(|(i, c)| {
+            *c == '-'
+                && file_name[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_digit())
+        })
+        .map(|(i, _)| i)?;
+    let name = &file_name[..pos];
+    let rest = &file_name[pos + 1..];
+    // version ends at the first `-` or an extension not starting with digit
+    let mut end = rest.len();
+    for (i, c) in rest.char_indices() {
+        if c == '-' || c == '_' {
+            end = i;
+            break;
+        }
+        if c == '.'
+            && !rest[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_digit())
+        {
+            end = i;
+            break;
+        }
+    }
+    if name.is_empty() || end == 0 {
+        return None;
+    }
+    Some((name.to_string(), rest[..end].to_string()))
+}
+
+impl FeedItem {
+    pub fn new(key: &str, size: Option<u64>, base_url: Option<&str>, date: &str) -> Self {
+        let (name, version) = match name_version(key) {
+            Some((name, version)) => (Some(name), Some(version)),
+            None => (None, None),
+        };
+        let title = match (&name, &version) {
+            (Some(name), Some(version)) => format!("{} {}", name, version),
+            _ => key.to_string(),
+        };
+        Self {
+            id: format!("{}#{}", key, date),
+            url: base_url.map(|url| format!("{}/{}", url.trim_end_matches('/'), key)),
+            title,
+            date_published: date.to_string(),
+            mirror: MirrorItem {
+                path: key.to_string(),
+                name,
+                version,
+                size,
+            },
+        }
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rss(items: &[FeedItem]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n",
+    );
+    xml.push_str(&format!("<title>{}</title>\n", TITLE));
+    xml.push_str(&format!("<description>{}</description>\n", TITLE));
+    for item in items {
+        xml.push_str("<item>\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&item.title)));
+        if let Some(url) = &item.url {
+            xml.push_str(&format!("<link>{}</link>\n", escape_xml(url)));
+        }
+        xml.push_str(&format!(
+            "<guid isPermaLink=\"false\">{}</guid>\n",
+            escape_xml(&item.id)
+        ));
+        if let Ok(date) = chrono::DateTime::parse_from_rfc3339(&item.date_published) {
+            xml.push_str(&format!("<pubDate>{}</pubDate>\n", date.to_rfc2822()));
+        }
+        xml.push_str(&format!(
+            "<description>{}</description>\n",
+            escape_xml(&item.mirror.path)
+        ));
+        xml.push_str("</item>\n");
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+/// Prepend `items` to feed on target, keeping at most `size` items.
+pub async fn publish<Target: StateStorage>(
+    logger: &Logger,
+    target: &Target,
+    mut items: Vec<FeedItem>,
+    size: usize,
+) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("mirror-clone-feed-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await?;
+    let json_path = dir.join(JSON_FEED);
+    let rss_path = dir.join(RSS_FEED);
+
+    let added = items.len();
+    if target.load_state(JSON_FEED, &json_path).await? {
+        let feed: JsonFeed = serde_json::from_slice(&tokio::fs::read(&json_path).await?)?;
+        items.extend(feed.items);
+    }
+    items.truncate(size);
+
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1".to_string(),
+        title: TITLE.to_string(),
+        items,
+    };
+    tokio::fs::write(&json_path, serde_json::to_vec_pretty(&feed)?).await?;
+    tokio::fs::write(&rss_path, rss(&feed.items)).await?;
+    target.save_state(JSON_FEED, &json_path).await?;
+    target.save_state(RSS_FEED, &rss_path).await?;
+    tokio::fs::remove_dir_all(&dir).await?;
+
+    info!(logger, "feed: {} new objects", added);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_version() {
+        assert_eq!(
+            name_version("packages/n/numpy/numpy-1.26.4.tar.gz"),
+            Some(("numpy".to_string(), "1.26.4".to_string()))
+        );
+        assert_eq!(
+            name_version("crates/serde-json/serde-json-1.0.0.crate"),
+            Some(("serde-json".to_string(), "1.0.0".to_string()))
+        );
+        assert_eq!(
+            name_version("foo-2.0-py3-none-any.whl"),
+            Some(("foo".to_string(), "2.0".to_string()))
+        );
+        assert_eq!(name_version("index.html"), None);
+    }
+}
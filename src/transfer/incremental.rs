@@ -0,0 +1,223 @@
+//! Incremental Transfer
+//!
+//! Incremental transfer compares source with a manifest saved by previous
+//! run instead of scanning target, which saves a lot of requests on large
+//! targets. The manifest records what's on target after each run. Objects
+//! failed to transfer are not recorded, so that they will be retried in the
+//! next run.
+//!
+//! If the manifest doesn't exist, target is scanned as in simple diff
+//! transfer, and the manifest is created after transfer.
+//!
+//! The manifest is stored as JSON lines of snapshot items.
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use slog::{info, Logger};
+
+use super::{diff_plan, TransferPlan, TransferResult, TransferStrategy};
+use crate::error::{Error, Result};
+use crate::traits::{Diff, Key};
+
+pub struct Incremental<Snapshot> {
+    manifest_path: String,
+    print_plan: usize,
+    no_delete: bool,
+    source: Vec<Snapshot>,
+    deletions: Vec<Snapshot>,
+}
+
+impl<Snapshot> Incremental<Snapshot> {
+    pub fn new(manifest_path: String, print_plan: usize, no_delete: bool) -> Self {
+        Self {
+            manifest_path,
+            print_plan,
+            no_delete,
+            source: vec![],
+            deletions: vec![],
+        }
+    }
+}
+
+pub fn read_manifest<Snapshot: DeserializeOwned>(path: &Path) -> Result<Vec<Snapshot>> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut manifest = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            manifest.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(manifest)
+}
+
+pub fn write_manifest<'a, Snapshot: Serialize + 'a>(
+    path: &Path,
+    manifest: impl Iterator<Item = &'a Snapshot>,
+) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(std::fs::File::create(&tmp_path)?);
+    for item in manifest {
+        serde_json::to_writer(&mut writer, item)?;
+        writer.write_all(b"\n")?;
+    }
+    writer
+        .into_inner()
+        .map_err(|err| Error::IoError(err.into_error()))?
+        .sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl<Snapshot> TransferStrategy<Snapshot> for Incremental<Snapshot>
+where
+    Snapshot: Diff + Key + Clone + Serialize + DeserializeOwned,
+{
+    fn name(&self) -> &'static str {
+        "incremental"
+    }
+
+    fn baseline(&mut self, logger: &Logger) -> Result<Option<Vec<Snapshot>>> {
+        let path = Path::new(&self.manifest_path);
+        if !path.exists() {
+            info!(
+                logger,
+                "manifest {} not found, scanning target", self.manifest_path
+            );
+            return Ok(None);
+        }
+        let manifest = read_manifest(path)?;
+        info!(
+            logger,
+            "loaded {} objects from manifest {}",
+            manifest.len(),
+            self.manifest_path
+        );
+        Ok(Some(manifest))
+    }
+
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>> {
+        // objects kept only if existing are never transferred, so they're on
+        // target only if in baseline
+        self.source = source
+            .iter()
+            .filter(|item| {
+                !item.existing_only()
+                    || target.binary_search_by(|x| x.key().cmp(item.key())).is_ok()
+            })
+            .cloned()
+            .collect();
+        let plan = diff_plan(logger, source, target, self.print_plan);
+        self.deletions = plan.deletions.clone();
+        Ok(plan)
+    }
+
+    fn finish(&mut self, logger: &Logger, result: &TransferResult) -> Result<()> {
+        let failed_updates: HashSet<&str> =
+            result.failed_updates.iter().map(String::as_str).collect();
+        let failed_deletions: HashSet<&str> =
+            result.failed_deletions.iter().map(String::as_str).collect();
+
+        let mut manifest: Vec<&Snapshot> = self
+            .source
+            .iter()
+            .filter(|item| !failed_updates.contains(item.key()))
+            .collect();
+        manifest.extend(
+            self.deletions
+                .iter()
+                .filter(|item| self.no_delete || failed_deletions.contains(item.key())),
+        );
+        manifest.sort_by(|a, b| a.key().cmp(b.key()));
+
+        write_manifest(Path::new(&self.manifest_path), manifest.into_iter())?;
+        info!(logger, "manifest saved to {}", self.manifest_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+    use crate::metadata::SnapshotMeta;
+
+    fn keys(manifest: Vec<SnapshotPath>) -> Vec<String> {
+        manifest.into_iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn test_manifest_keeps_unfinished_objects() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!("mirror-clone-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("manifest.jsonl");
+        let path_str = path.to_str().unwrap().to_string();
+
+        write_manifest(
+            &path,
+            [SnapshotPath::new("a".into()), SnapshotPath::new("d".into())].iter(),
+        )
+        .unwrap();
+
+        let mut strategy = Incremental::new(path_str, 0, false);
+        let baseline = strategy.baseline(&logger).unwrap().unwrap();
+        let source = vec![
+            SnapshotPath::new("a".into()),
+            SnapshotPath::new("b".into()),
+            SnapshotPath::new("c".into()),
+        ];
+        let plan = strategy.plan(&logger, source, baseline).unwrap();
+        assert_eq!(plan.updates.len(), 2);
+        assert_eq!(plan.deletions.len(), 1);
+
+        let result = TransferResult {
+            failed_updates: vec!["c".into()],
+            failed_deletions: vec!["d".into()],
+        };
+        strategy.finish(&logger, &result).unwrap();
+
+        let manifest: Vec<SnapshotPath> = read_manifest(&path).unwrap();
+        assert_eq!(keys(manifest), vec!["a", "b", "d"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_skips_absent_existing_only_objects() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir =
+            std::env::temp_dir().join(format!("mirror-clone-test-existing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("manifest.jsonl");
+        let existing_only = |key: &str| {
+            let mut snapshot = SnapshotMeta::new(key.to_string());
+            snapshot.flags.existing_only = true;
+            snapshot
+        };
+
+        let mut strategy = Incremental::new(path.to_str().unwrap().to_string(), 0, false);
+        let source = vec![existing_only("a"), existing_only("b")];
+        let baseline = vec![SnapshotMeta::new("a".into())];
+        let plan = strategy.plan(&logger, source, baseline).unwrap();
+        assert!(plan.updates.is_empty());
+        strategy
+            .finish(&logger, &TransferResult::default())
+            .unwrap();
+
+        let manifest: Vec<SnapshotMeta> = read_manifest(&path).unwrap();
+        let keys: Vec<String> = manifest.into_iter().map(|x| x.key).collect();
+        assert_eq!(keys, vec!["a"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Write-ahead journal of transfer operations.
+//!
+//! Before an object is uploaded to or deleted from target, the intent is
+//! appended to the journal and synced to disk, and after the operation succeeds, its completion
+//! is appended. The journal is removed when transfer completes.
+//!
+//! If a journal is found when transfer starts, the previous run crashed, and
+//! objects with unfinished operations may be in any state on target. These
+//! objects are considered dirty, and will be transferred again if they still
+//! exist in source, regardless of what target snapshot says. Dirty objects
+//! are carried over to the new journal until they are transferred.
+//!
+//! The journal is stored as JSON lines.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JournalEntry {
+    Put(String),
+    Delete(String),
+    Done(String),
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+fn entry_line(entry: &JournalEntry) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn replay(path: &PathBuf) -> Result<HashSet<String>> {
+    let mut dirty = HashSet::new();
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        // the last line may be partially written
+        match serde_json::from_str(&line) {
+            Ok(JournalEntry::Put(key)) | Ok(JournalEntry::Delete(key)) => {
+                dirty.insert(key);
+            }
+            Ok(JournalEntry::Done(key)) => {
+                dirty.remove(&key);
+            }
+            Err(_) => break,
+        }
+    }
+    Ok(dirty)
+}
+
+impl Journal {
+    /// Objects with unfinished operations in the journal at `path`.
+    pub fn recover(path: &str) -> Result<HashSet<String>> {
+        let path = PathBuf::from(path);
+        if path.exists() {
+            replay(&path)
+        } else {
+            Ok(HashSet::new())
+        }
+    }
+
+    /// Open journal at `path`, carrying over dirty objects from previous run.
+    pub fn open(path: &str) -> Result<Self> {
+        let dirty = Self::recover(path)?;
+        let path = PathBuf::from(path);
+
+        // carry dirty objects over to the new journal
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        for key in &dirty {
+            file.write_all(&entry_line(&JournalEntry::Put(key.clone()))?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(Self {
+            file: Mutex::new(OpenOptions::new().append(true).open(&path)?),
+            path,
+        })
+    }
+
+    fn append(&self, entry: &JournalEntry) -> Result<()> {
+        let line = entry_line(entry)?;
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| Error::ProcessError("journal lock poisoned".to_string()))?;
+        file.write_all(&line)?;
+        // intents must be durable before the operation is applied, while a
+        // lost completion only makes the object transferred again
+        if !matches!(entry, JournalEntry::Done(_)) {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn put(&self, key: &str) -> Result<()> {
+        self.append(&JournalEntry::Put(key.to_string()))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.append(&JournalEntry::Delete(key.to_string()))
+    }
+
+    pub fn done(&self, key: &str) -> Result<()> {
+        self.append(&JournalEntry::Done(key.to_string()))
+    }
+
+    /// Finish the journal after all operations are applied. Objects which
+    /// failed to transfer are left in the journal, so that they are treated
+    /// as dirty next time.
+    pub fn complete(&self) -> Result<()> {
+        let file = self
+            .file
+            .lock()
+            .map_err(|_| Error::ProcessError("journal lock poisoned".to_string()))?;
+        file.sync_all()?;
+        if replay(&self.path)?.is_empty() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1513 @@
+//! Transfer
+//!
+//! A transfer takes snapshots of source and target, asks a `TransferStrategy`
+//! for a transfer plan, and then concurrently transfers the objects between
+//! two endpoints. The plan consists of objects to update and objects to
+//! delete. Objects are transferred from highest priority to lowest priority.
+//!
+//! The following strategies are available:
+//!
+//! * `full-diff` (`SimpleDiff`): compare source with target, and make target
+//!   exactly the same as source.
+//! * `incremental` (`Incremental`): compare source with the manifest saved
+//!   by previous run, without scanning target.
+//! * `verify` (`Verify`): compare source with target, report the difference,
+//!   and never modify target.
+//! * `repair` (`Repair`): only transfer objects missing on target, or with
+//!   different size on target, and never delete.
+//! * `database` (`Database`): same as `full-diff`, but keep snapshots in a
+//!   SQLite database, and reuse target state recorded by previous run.
+//!
+//! If transfer of an object fails, it will be simply ignored, and reported
+//! to the strategy when the transfer completes.
+//!
+//! When a journal path is given, all operations are recorded in a write-ahead
+//! journal, so that objects touched by a crashed run are transferred again.
+//!
+//! When a queue path is given, objects to update are popped from a persistent
+//! priority queue, and failed objects are retried with increasing delay.
+//!
+//! When a coordinator address is given, objects to update are transferred by
+//! workers connected to this process. See `distributed` for more details.
+//!
+//! In estimate mode, only source is snapshotted, and the number and size of
+//! objects under each top-level prefix are reported. Size of objects without
+//! size in snapshot is extrapolated from a random sample of them.
+//!
+//! In audit mode, random objects on target are compared with objects fetched
+//! from upstream again, and the corruption rate of target is estimated from
+//! the sample. Objects differing from upstream are written to the report.
+//!
+//! When a corruption report is given, objects listed in it are transferred
+//! again, without taking snapshots or planning. The report is either written
+//! by `verify`, or a list of keys, one per line.
+//!
+//! When state on target is enabled, state files (manifest, journal, database
+//! and queue) are loaded from target under `.mirror-clone/` before transfer,
+//! and saved back after transfer, so that runners need no persistent volume.
+//!
+//! When feed is enabled, objects added in this run are published in a feed
+//! on target. See `feed` for more details.
+//!
+//! When stats are enabled, statistics of this run are appended to history
+//! on target, and rendered as a dashboard. See `stats` for more details.
+//!
+//! When dedup is enabled, objects with the same checksum as another object
+//! are linked to that object on target after it is transferred, instead of
+//! being transferred again.
+
+mod database;
+mod distributed;
+mod feed;
+mod incremental;
+mod journal;
+mod queue;
+mod repair;
+mod simple_diff;
+mod stats;
+mod verify;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::{stream, FutureExt, StreamExt};
+use indicatif::ProgressBar;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::ClientBuilder;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::auth::Secret;
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::{Error, Result};
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{
+    Diff, Key, Metadata, SizeStorage, SnapshotStorage, SourceStorage, StateStorage, TargetStorage,
+    STATE_PREFIX,
+};
+use crate::utils::{create_logger, spinner, HttpHeader};
+
+use iter_set::{classify_by, Inclusion};
+use rand::prelude::*;
+use slog::{debug, info, o, warn, Logger};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use database::Database;
+pub use incremental::Incremental;
+use journal::Journal;
+use queue::WorkQueue;
+pub use repair::Repair;
+pub use simple_diff::SimpleDiff;
+pub use verify::Verify;
+
+enum PlanType {
+    Update,
+    Delete,
+    /// Link to an object with the same content on target.
+    Link(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    FullDiff,
+    Incremental,
+    Verify,
+    Repair,
+    Database,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full-diff" => Ok(Self::FullDiff),
+            "incremental" => Ok(Self::Incremental),
+            "verify" => Ok(Self::Verify),
+            "repair" => Ok(Self::Repair),
+            "database" => Ok(Self::Database),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported transfer strategy {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    pub progress: bool,
+    pub concurrent_transfer: usize,
+    pub no_delete: bool,
+    pub dry_run: bool,
+    pub snapshot_config: SnapshotConfig,
+    pub print_plan: usize,
+    pub force_all: bool,
+    pub strategy: Strategy,
+    pub manifest_path: Option<String>,
+    pub journal_path: Option<String>,
+    pub snapshot_db: Option<String>,
+    pub dedup: bool,
+    pub queue_path: Option<String>,
+    pub max_attempts: u64,
+    pub retry_delay: u64,
+    pub coordinator_listen: Option<String>,
+    pub coordinator_url: Option<String>,
+    pub coordinator_secret: Option<Secret>,
+    pub state_on_target: bool,
+    pub user_agent: String,
+    pub headers: Vec<HttpHeader>,
+    pub estimate: bool,
+    pub estimate_samples: usize,
+    pub verify_report: Option<String>,
+    pub repair_from: Option<String>,
+    /// Number of objects sampled in audit mode.
+    pub audit_sample: Option<usize>,
+    /// Seconds between status lines of transfer progress.
+    pub progress_interval: Option<u64>,
+    pub feed: bool,
+    pub feed_size: usize,
+    pub feed_base_url: Option<String>,
+    pub stats: bool,
+    /// Name of source, which identifies runs in stats.
+    pub source_name: String,
+}
+
+/// Objects to be transferred to and deleted from target.
+pub struct TransferPlan<Snapshot> {
+    pub updates: Vec<Snapshot>,
+    pub deletions: Vec<Snapshot>,
+}
+
+/// Keys of objects which failed to transfer.
+#[derive(Debug, Default)]
+pub struct TransferResult {
+    pub failed_updates: Vec<String>,
+    pub failed_deletions: Vec<String>,
+}
+
+pub trait TransferStrategy<Snapshot>: Send {
+    fn name(&self) -> &'static str;
+
+    /// Snapshot to compare source with. If `None` is returned, target will
+    /// be scanned.
+    fn baseline(&mut self, _logger: &Logger) -> Result<Option<Vec<Snapshot>>> {
+        Ok(None)
+    }
+
+    /// Generate transfer plan from sorted and deduplicated snapshots.
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>>;
+
+    /// Objects whose size is unknown in source, with their size on target.
+    /// After planning, the executor finds their size in source with HEAD
+    /// requests, and updates those with a different size.
+    fn size_checks(&mut self) -> Vec<(Snapshot, u64)> {
+        vec![]
+    }
+
+    /// Whether the plan should be applied to target.
+    fn execute(&self) -> bool {
+        true
+    }
+
+    /// Called after the plan is applied, or right after planning if the plan
+    /// shouldn't be applied.
+    fn finish(&mut self, _logger: &Logger, _result: &TransferResult) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Build transfer strategy from config.
+pub fn build_strategy<Snapshot>(config: &TransferConfig) -> Box<dyn TransferStrategy<Snapshot>>
+where
+    Snapshot: Diff + Key + Metadata + Clone + Serialize + DeserializeOwned,
+{
+    match config.strategy {
+        Strategy::FullDiff => Box::new(SimpleDiff::new(config.print_plan)),
+        Strategy::Incremental => Box::new(Incremental::new(
+            config
+                .manifest_path
+                .clone()
+                .expect("manifest path is required by incremental transfer"),
+            config.print_plan,
+            config.no_delete,
+        )),
+        Strategy::Verify => Box::new(Verify::new(config.print_plan, config.verify_report.clone())),
+        Strategy::Repair => Box::new(Repair::new(config.print_plan)),
+        Strategy::Database => Box::new(Database::new(
+            config
+                .snapshot_db
+                .as_deref()
+                .expect("snapshot database is required by database transfer"),
+            config.print_plan,
+            config.no_delete,
+            config.dry_run,
+        )),
+    }
+}
+
+/// Compare sorted source with sorted target. Objects not in target or different
+/// from target are updated, and objects not in source are deleted.
+pub fn diff_plan<Snapshot>(
+    logger: &Logger,
+    source: Vec<Snapshot>,
+    target: Vec<Snapshot>,
+    print_plan: usize,
+) -> TransferPlan<Snapshot>
+where
+    Snapshot: Diff + Key,
+{
+    let mut updates = vec![];
+    let mut deletions = vec![];
+
+    let mut max_info = 0;
+    for result in classify_by(source, target, |a, b| a.key().cmp(b.key())) {
+        match result {
+            Inclusion::Left(source) if source.existing_only() => {}
+            Inclusion::Left(source) => {
+                if max_info < print_plan {
+                    info!(logger, "+ {:?}", source.key());
+                    max_info += 1;
+                }
+                updates.push(source);
+            }
+            Inclusion::Both(l, r) => {
+                if l.diff(&r) {
+                    if max_info < print_plan {
+                        info!(logger, "= {:?}", l.key());
+                        max_info += 1;
+                    }
+                    updates.push(l);
+                }
+            }
+            Inclusion::Right(target) => {
+                if max_info < print_plan {
+                    info!(logger, "- {:?}", target.key());
+                    max_info += 1;
+                }
+                deletions.push(target);
+            }
+        }
+    }
+
+    TransferPlan { updates, deletions }
+}
+
+/// Upper bound of 95% Wilson score interval of failure rate, given `failures`
+/// in `samples`.
+fn wilson_upper_bound(failures: usize, samples: usize) -> f64 {
+    const Z: f64 = 1.96;
+    let n = samples as f64;
+    let p = failures as f64 / n;
+    let z2 = Z * Z;
+    (p + z2 / (2.0 * n) + Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt()) / (1.0 + z2 / n)
+}
+
+/// Content of an object, identified by its checksum.
+fn content_id<Snapshot: Metadata>(snapshot: &Snapshot) -> Option<String> {
+    snapshot.checksum().map(|checksum| {
+        format!(
+            "{}:{}",
+            snapshot.checksum_method().unwrap_or_default(),
+            checksum
+        )
+    })
+}
+
+/// Split updates into objects to transfer, and objects to be linked to
+/// another object with the same content. `contents` contains content and
+/// key of all objects in source.
+fn dedup_plan<Snapshot: Key + Metadata>(
+    updates: Vec<Snapshot>,
+    contents: Vec<(String, String)>,
+) -> (Vec<Snapshot>, Vec<(Snapshot, String)>) {
+    let updated: HashSet<String> = updates.iter().map(|x| x.key().to_string()).collect();
+
+    // objects already on target
+    let mut existing: HashMap<String, String> = HashMap::new();
+    for (content, key) in contents {
+        if !updated.contains(&key) {
+            existing.entry(content).or_insert(key);
+        }
+    }
+
+    let mut transfers = vec![];
+    let mut links = vec![];
+    for item in updates {
+        match content_id(&item) {
+            Some(content) => match existing.get(&content) {
+                Some(from) => links.push((item, from.clone())),
+                None => {
+                    existing.insert(content, item.key().to_string());
+                    transfers.push(item);
+                }
+            },
+            None => transfers.push(item),
+        }
+    }
+    (transfers, links)
+}
+
+pub struct Transfer<Snapshot, Source, Target, Item>
+where
+    Snapshot: Diff + Key + Metadata,
+    Source: SourceStorage<Snapshot, Item> + SnapshotStorage<Snapshot> + SizeStorage<Snapshot>,
+    Target: TargetStorage<Snapshot, Item> + SnapshotStorage<Snapshot> + StateStorage,
+{
+    source: Source,
+    target: Target,
+    strategy: Box<dyn TransferStrategy<Snapshot>>,
+    config: TransferConfig,
+    _phantom: std::marker::PhantomData<Item>,
+}
+
+impl<Snapshot, Source, Target, Item> Transfer<Snapshot, Source, Target, Item>
+where
+    Snapshot: Diff + Key + Metadata + Clone + Default + Serialize + DeserializeOwned,
+    Source: SourceStorage<Snapshot, Item> + SnapshotStorage<Snapshot> + SizeStorage<Snapshot>,
+    Target: TargetStorage<Snapshot, Item> + SnapshotStorage<Snapshot> + StateStorage,
+    Item: Send,
+{
+    pub fn new(
+        source: Source,
+        target: Target,
+        strategy: Box<dyn TransferStrategy<Snapshot>>,
+        config: TransferConfig,
+    ) -> Self {
+        Self {
+            source,
+            target,
+            strategy,
+            config,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    fn debug_snapshot(logger: slog::Logger, snapshot: &[Snapshot]) {
+        let mut selected: Vec<_> = snapshot
+            .choose_multiple(&mut rand::thread_rng(), 50)
+            .collect();
+        selected.sort_by(|a, b| a.key().cmp(b.key()));
+        for item in selected {
+            debug!(logger, "{}", item.key());
+        }
+    }
+
+    async fn sort_snapshot(
+        logger: &Logger,
+        name: &str,
+        snapshot: Vec<Snapshot>,
+    ) -> Result<Vec<Snapshot>> {
+        let count = snapshot.len();
+
+        let snapshot = tokio::task::spawn_blocking(move || {
+            let mut snapshot: Vec<Snapshot> = snapshot;
+            snapshot.sort_by(|a, b| a.key().cmp(b.key()));
+            snapshot.dedup_by(|a, b| a.key().eq(b.key()));
+            snapshot
+        })
+        .await
+        .map_err(|err| Error::ProcessError(format!("error while sorting: {:?}", err)))?;
+
+        if count != snapshot.len() {
+            warn!(
+                logger,
+                "{}: {} duplicated items",
+                name,
+                count - snapshot.len()
+            );
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Local state files, and their names on target.
+    fn state_files(&self) -> Vec<(String, std::path::PathBuf)> {
+        [
+            &self.config.manifest_path,
+            &self.config.journal_path,
+            &self.config.snapshot_db,
+            &self.config.queue_path,
+        ]
+        .iter()
+        .filter_map(|path| path.as_ref())
+        .map(|path| {
+            let path = std::path::PathBuf::from(path);
+            let name = path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            (name, path)
+        })
+        .collect()
+    }
+
+    async fn save_state(
+        logger: &Logger,
+        target: &Target,
+        state_files: &[(String, std::path::PathBuf)],
+    ) -> Result<()> {
+        for (name, path) in state_files {
+            target.save_state(name, path).await?;
+            info!(logger, "saved state {} to target", name);
+        }
+        Ok(())
+    }
+
+    /// Report size of source without transferring.
+    async fn estimate(mut self, logger: Logger, client: reqwest::Client) -> Result<()> {
+        info!(logger, "estimating size of source"; "source" => self.source.info());
+
+        let progress = if self.config.progress {
+            crate::utils::progress_spinner()
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(spinner());
+        progress.set_prefix("[source]");
+        let mission = Mission {
+            client,
+            progress,
+            logger: logger.new(o!("task" => "estimate")),
+        };
+
+        let snapshot = self
+            .source
+            .snapshot(mission.clone(), &self.config.snapshot_config)
+            .await?;
+        let snapshot = Self::sort_snapshot(&logger, "source", snapshot).await?;
+        mission.progress.finish_with_message("done");
+
+        let prefix_of = |key: &str| key.split('/').next().unwrap_or_default().to_string();
+
+        // sample objects without size
+        let unknown: Vec<&Snapshot> = snapshot.iter().filter(|x| x.size().is_none()).collect();
+        let samples: Vec<&Snapshot> = unknown
+            .choose_multiple(&mut rand::thread_rng(), self.config.estimate_samples)
+            .copied()
+            .collect();
+        if !samples.is_empty() {
+            info!(
+                logger,
+                "{} objects have no size, sampling {} of them",
+                unknown.len(),
+                samples.len()
+            );
+        }
+        let source = &self.source;
+        let mission = &mission;
+        let sampled: Vec<(String, u64)> = stream::iter(samples)
+            .map(|item| async move {
+                match source.object_size(item, mission).await {
+                    Ok(size) => size.map(|size| (prefix_of(item.key()), size)),
+                    Err(err) => {
+                        warn!(
+                            mission.logger,
+                            "failed to get size of {}: {:?}",
+                            item.key(),
+                            err
+                        );
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(self.config.concurrent_transfer)
+            .filter_map(|x| async move { x })
+            .collect()
+            .await;
+
+        // average size of sampled objects, by prefix and overall
+        let mut sampled_by_prefix: HashMap<String, (u64, u64)> = HashMap::new();
+        for (prefix, size) in &sampled {
+            let entry = sampled_by_prefix.entry(prefix.clone()).or_default();
+            entry.0 += 1;
+            entry.1 += size;
+        }
+        let overall_average = if sampled.is_empty() {
+            0
+        } else {
+            sampled.iter().map(|(_, size)| size).sum::<u64>() / sampled.len() as u64
+        };
+
+        // objects, objects without size, known bytes
+        let mut prefixes: std::collections::BTreeMap<String, (u64, u64, u64)> =
+            std::collections::BTreeMap::new();
+        for item in &snapshot {
+            let entry = prefixes.entry(prefix_of(item.key())).or_default();
+            entry.0 += 1;
+            match item.size() {
+                Some(size) => entry.2 += size,
+                None => entry.1 += 1,
+            }
+        }
+
+        let mut total_objects = 0;
+        let mut total_bytes = 0;
+        for (prefix, (objects, unknown, known_bytes)) in prefixes {
+            let average = match sampled_by_prefix.get(&prefix) {
+                Some((count, bytes)) => bytes / count,
+                None => overall_average,
+            };
+            let bytes = known_bytes + unknown * average;
+            info!(
+                logger,
+                "{}: {} objects, {} ({} objects estimated)",
+                prefix,
+                objects,
+                indicatif::HumanBytes(bytes),
+                unknown
+            );
+            total_objects += objects;
+            total_bytes += bytes;
+        }
+        info!(
+            logger,
+            "total: {} objects, {}",
+            total_objects,
+            indicatif::HumanBytes(total_bytes)
+        );
+
+        Ok(())
+    }
+
+    /// Compare `samples` random objects on target with upstream.
+    async fn audit(self, logger: Logger, client: reqwest::Client, samples: usize) -> Result<()> {
+        info!(logger, "auditing {} objects", samples; "source" => self.source.info(), "target" => self.target.info());
+
+        let mut source = self.source;
+        let mut target = self.target;
+        let snapshot_config = &self.config.snapshot_config;
+        let source_mission = Mission {
+            client: client.clone(),
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "audit.source")),
+        };
+        let target_mission = Mission {
+            client,
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "audit.target")),
+        };
+        let (source_snapshot, target_snapshot) = tokio::join!(
+            source.snapshot(source_mission.clone(), snapshot_config),
+            target.snapshot(target_mission.clone(), snapshot_config)
+        );
+        let (source_snapshot, target_snapshot) = tokio::join!(
+            Self::sort_snapshot(&logger, "source", source_snapshot?),
+            Self::sort_snapshot(&logger, "target", target_snapshot?)
+        );
+        let target_snapshot = target_snapshot?;
+
+        // objects both in source and on target
+        let mirrored: Vec<Snapshot> = source_snapshot?
+            .into_iter()
+            .filter(|item| {
+                !item.key().starts_with(STATE_PREFIX)
+                    && target_snapshot
+                        .binary_search_by(|x| x.key().cmp(item.key()))
+                        .is_ok()
+            })
+            .collect();
+        drop(target_snapshot);
+        let total = mirrored.len();
+        let sampled: Vec<Snapshot> = mirrored
+            .choose_multiple(&mut rand::thread_rng(), samples)
+            .cloned()
+            .collect();
+        drop(mirrored);
+        info!(
+            logger,
+            "sampled {} of {} mirrored objects",
+            sampled.len(),
+            total
+        );
+
+        let progress = if self.config.progress {
+            crate::utils::progress_bar(sampled.len() as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(crate::utils::bar());
+        progress.set_prefix("audit");
+
+        let (source, target) = (&source, &target);
+        let (source_mission, target_mission, progress) =
+            (&source_mission, &target_mission, &progress);
+        let results: Vec<(Snapshot, Result<Option<bool>>)> = stream::iter(sampled)
+            .map(|item| async move {
+                progress.set_message(item.key());
+                let result = match source.get_object(&item, source_mission).await {
+                    Ok(object) => target.audit_object(&item, object, target_mission).await,
+                    Err(err) => Err(err),
+                };
+                progress.inc(1);
+                (item, result)
+            })
+            .buffer_unordered(self.config.concurrent_transfer)
+            .collect()
+            .await;
+        progress.finish_with_message("done");
+
+        let mut matched = 0;
+        let mut mismatched = vec![];
+        let mut failed = 0;
+        for (item, result) in results {
+            match result {
+                Ok(Some(true)) => matched += 1,
+                Ok(Some(false)) => {
+                    warn!(logger, "{} differs from upstream", item.key());
+                    mismatched.push(item);
+                }
+                Ok(None) => {
+                    return Err(Error::ConfigureError(
+                        "target doesn't support audit".to_string(),
+                    ))
+                }
+                Err(err) => {
+                    warn!(logger, "failed to audit {}: {:?}", item.key(), err);
+                    failed += 1;
+                }
+            }
+        }
+
+        let audited = matched + mismatched.len();
+        info!(
+            logger,
+            "audited {} objects: {} matched, {} mismatched, {} failed",
+            audited,
+            matched,
+            mismatched.len(),
+            failed
+        );
+        if audited > 0 {
+            let rate = mismatched.len() as f64 / audited as f64;
+            let upper = wilson_upper_bound(mismatched.len(), audited);
+            info!(
+                logger,
+                "estimated corruption rate {:.4}% (95% upper bound {:.4}%), about {:.0} of {} objects (at most {:.0})",
+                rate * 100.0,
+                upper * 100.0,
+                rate * total as f64,
+                total,
+                upper * total as f64
+            );
+        }
+
+        if let Some(path) = &self.config.verify_report {
+            let mut report = String::new();
+            for item in &mismatched {
+                report.push_str(&serde_json::to_string(item)?);
+                report.push('\n');
+            }
+            tokio::fs::write(path, report).await?;
+            info!(logger, "report written to {}", path);
+        }
+
+        if !mismatched.is_empty() {
+            return Err(Error::ProcessError(format!(
+                "{} of {} audited objects differ from upstream",
+                mismatched.len(),
+                audited
+            )));
+        }
+        Ok(())
+    }
+
+    /// Transfer objects listed in corruption report at `path` again.
+    async fn repair_from(self, logger: Logger, client: reqwest::Client, path: &str) -> Result<()> {
+        info!(logger, "repairing objects in {}", path; "source" => self.source.info(), "target" => self.target.info());
+
+        let content = tokio::fs::read_to_string(path).await?;
+        let mut items = vec![];
+        for line in content.lines().map(str::trim).filter(|x| !x.is_empty()) {
+            let item = match serde_json::from_str::<Snapshot>(line) {
+                Ok(item) => item,
+                Err(_) => {
+                    let mut item = Snapshot::default();
+                    *item.key_mut() = line.to_string();
+                    item
+                }
+            };
+            items.push(item);
+        }
+        info!(logger, "{} objects to repair", items.len());
+
+        let progress = if self.config.progress {
+            crate::utils::progress_bar(items.len() as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(crate::utils::bar());
+        progress.set_prefix("repair");
+
+        let source_mission = Mission {
+            client: client.clone(),
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "repair.source")),
+        };
+        let target_mission = Mission {
+            client,
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "repair.target")),
+        };
+
+        let source = &self.source;
+        let target = &self.target;
+        let (source_mission, target_mission, progress) =
+            (&source_mission, &target_mission, &progress);
+        let failed = stream::iter(items)
+            .map(|item| async move {
+                progress.set_message(item.key());
+                let result = match source.get_object(&item, source_mission).await {
+                    Ok(object) => target.put_object(&item, object, target_mission).await,
+                    Err(err) => Err(err),
+                };
+                progress.inc(1);
+                match result {
+                    Ok(()) => None,
+                    Err(err) => {
+                        warn!(
+                            target_mission.logger,
+                            "error while repair {}: {:?}",
+                            item.key(),
+                            err
+                        );
+                        Some(item.key().to_string())
+                    }
+                }
+            })
+            .buffer_unordered(self.config.concurrent_transfer)
+            .filter_map(|x| async move { x })
+            .collect::<Vec<_>>()
+            .await;
+
+        progress.finish_with_message("done");
+        if !failed.is_empty() {
+            return Err(Error::ProcessError(format!(
+                "failed to repair {} objects",
+                failed.len()
+            )));
+        }
+        info!(logger, "all objects are repaired");
+        Ok(())
+    }
+
+    /// Run as a worker of coordinator at `url`.
+    async fn work(self, logger: Logger, client: reqwest::Client, url: &str) -> Result<()> {
+        info!(logger, "working for {}", url; "source" => self.source.info(), "target" => self.target.info());
+
+        let progress = if self.config.progress {
+            crate::utils::progress_spinner()
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(spinner());
+        progress.set_prefix("worker");
+
+        let source_mission = Mission {
+            client: client.clone(),
+            progress,
+            logger: logger.new(o!("task" => "worker.source")),
+        };
+        let target_mission = Mission {
+            client,
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "worker.target")),
+        };
+
+        let secret = self.config.coordinator_secret.as_ref().ok_or_else(|| {
+            Error::ConfigureError("coordinator secret is required by workers".to_string())
+        })?;
+        futures_util::future::try_join_all((0..self.config.concurrent_transfer).map(|_| {
+            distributed::run_worker(
+                url,
+                secret,
+                &self.source,
+                &self.target,
+                &source_mission,
+                &target_mission,
+            )
+        }))
+        .await?;
+
+        source_mission.progress.finish_with_message("done");
+        info!(logger, "all tasks are done");
+        Ok(())
+    }
+
+    pub async fn transfer(mut self) -> Result<()> {
+        let logger = create_logger();
+        let mut headers = HeaderMap::new();
+        for HttpHeader(name, value) in &self.config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| {
+                Error::ConfigureError(format!("invalid header {}: {}", name, err))
+            })?;
+            let value = HeaderValue::from_str(value).map_err(|err| {
+                Error::ConfigureError(format!("invalid header {}: {}", name, err))
+            })?;
+            headers.append(name, value);
+        }
+        let client = ClientBuilder::new()
+            .user_agent(self.config.user_agent.as_str())
+            .default_headers(headers)
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+
+        if let Some(url) = self.config.coordinator_url.clone() {
+            return self.work(logger, client, &url).await;
+        }
+
+        if let Some(path) = self.config.repair_from.clone() {
+            return self.repair_from(logger, client, &path).await;
+        }
+
+        if self.config.estimate {
+            return self.estimate(logger, client).await;
+        }
+
+        if let Some(samples) = self.config.audit_sample {
+            return self.audit(logger, client, samples).await;
+        }
+        info!(logger, "using {} transfer", self.strategy.name(); "config" => format!("{:?}", self.config));
+        info!(logger, "begin transfer"; "source" => self.source.info(), "target" => self.target.info());
+
+        let state_files = if self.config.state_on_target {
+            self.state_files()
+        } else {
+            vec![]
+        };
+        for (name, path) in &state_files {
+            if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            if self.target.load_state(name, path).await? {
+                info!(logger, "loaded state {} from target", name);
+            }
+        }
+
+        let baseline = self.strategy.baseline(&logger)?;
+
+        info!(logger, "taking snapshot...");
+
+        let all_progress = crate::utils::multi_progress(self.config.progress);
+        let source_progress = all_progress.add(ProgressBar::new(0));
+        source_progress.set_style(spinner());
+        source_progress.set_prefix("[source]");
+        let target_progress = all_progress.add(ProgressBar::new(0));
+        target_progress.set_style(spinner());
+        target_progress.set_prefix("[target]");
+
+        let source_mission = Mission {
+            client: client.clone(),
+            progress: source_progress,
+            logger: logger.new(o!("task" => "snapshot.source")),
+        };
+
+        let target_mission = Mission {
+            client: client.clone(),
+            progress: target_progress,
+            logger: logger.new(o!("task" => "snapshot.target")),
+        };
+
+        let config_progress = self.config.progress;
+
+        let handle = tokio::task::spawn_blocking(move || {
+            if config_progress {
+                all_progress.join().unwrap()
+            }
+        });
+
+        let source_snapshot = self
+            .source
+            .snapshot(source_mission, &self.config.snapshot_config)
+            .await?;
+
+        let target_snapshot = match baseline {
+            Some(baseline) => {
+                target_mission.progress.finish_with_message("skipped");
+                baseline
+            }
+            None => {
+                self.target
+                    .snapshot(target_mission, &self.config.snapshot_config)
+                    .await?
+            }
+        };
+
+        handle.await.ok();
+
+        Self::debug_snapshot(logger.clone(), &source_snapshot);
+        Self::debug_snapshot(logger.clone(), &target_snapshot);
+
+        info!(logger, "mirror in progress...");
+
+        let progress = if self.config.progress {
+            crate::utils::progress_bar(source_snapshot.len() as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(crate::utils::bar());
+        progress.set_prefix("mirror");
+        let reporter = self.config.progress_interval.map(|interval| {
+            crate::utils::report_progress(
+                logger.clone(),
+                "mirror",
+                progress.clone(),
+                Duration::from_secs(interval),
+            )
+        });
+
+        let source_mission = Arc::new(Mission {
+            client: client.clone(),
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "mirror.source")),
+        });
+
+        let target_mission = Arc::new(Mission {
+            client: client.clone(),
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => "mirror.target")),
+        });
+
+        info!(logger, "generating transfer plan...");
+
+        let (source_snapshot, target_snapshot) = tokio::join!(
+            Self::sort_snapshot(&logger, "source", source_snapshot),
+            Self::sort_snapshot(&logger, "target", target_snapshot)
+        );
+        let source_snapshot = source_snapshot?;
+        let mut target_snapshot = target_snapshot?;
+
+        // state objects are never transferred, and never deleted
+        target_snapshot.retain(|item| !item.key().starts_with(STATE_PREFIX));
+
+        let mut run_stats = if self.config.stats {
+            Some(stats::RunStats::new(
+                &self.config.source_name,
+                &source_snapshot,
+            ))
+        } else {
+            None
+        };
+
+        // keys on target before this run, to find newly added objects
+        let target_keys: Option<HashSet<String>> = if self.config.feed || self.config.stats {
+            Some(
+                target_snapshot
+                    .iter()
+                    .map(|item| item.key().to_string())
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        if let Some(journal_path) = &self.config.journal_path {
+            let dirty = Journal::recover(journal_path)?;
+            if !dirty.is_empty() {
+                warn!(
+                    logger,
+                    "previous run didn't complete, {} objects are dirty",
+                    dirty.len()
+                );
+                // Dirty objects still in source are transferred again. Others
+                // are left in target snapshot, so that they can be deleted.
+                target_snapshot.retain(|item| {
+                    !(dirty.contains(item.key())
+                        && source_snapshot
+                            .binary_search_by(|x| x.key().cmp(item.key()))
+                            .is_ok())
+                });
+            }
+        }
+
+        let contents: Vec<(String, String)> = if self.config.dedup {
+            // objects kept only if existing may be absent from target, and
+            // can't be linked from then
+            source_snapshot
+                .iter()
+                .filter(|item| {
+                    !item.existing_only()
+                        || target_snapshot
+                            .binary_search_by(|x| x.key().cmp(item.key()))
+                            .is_ok()
+                })
+                .filter_map(|item| {
+                    content_id(item).map(|content| (content, item.key().to_string()))
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        if self.config.force_all {
+            info!(logger, "force transfer all objects");
+            target_snapshot = vec![];
+        }
+
+        info!(
+            logger,
+            "source {} objects -> target {} objects",
+            source_snapshot.len(),
+            target_snapshot.len()
+        );
+
+        let TransferPlan {
+            mut updates,
+            mut deletions,
+        } = self
+            .strategy
+            .plan(&logger, source_snapshot, target_snapshot)?;
+
+        let size_checks = self.strategy.size_checks();
+        if !size_checks.is_empty() {
+            let source = &self.source;
+            let source_mission = &source_mission;
+            let mismatched: Vec<Snapshot> = stream::iter(size_checks)
+                .map(|(item, size)| async move {
+                    match source.object_size(&item, source_mission).await {
+                        Ok(Some(expected)) if expected != size => Some(item),
+                        Ok(_) => None,
+                        Err(err) => {
+                            warn!(
+                                source_mission.logger,
+                                "failed to get size of {}: {:?}",
+                                item.key(),
+                                err
+                            );
+                            None
+                        }
+                    }
+                })
+                .buffer_unordered(self.config.concurrent_transfer)
+                .filter_map(|x| async move { x })
+                .collect()
+                .await;
+            info!(logger, "{} objects have different size", mismatched.len());
+            updates.extend(mismatched);
+        }
+
+        // sort plan by priority
+        updates.sort_by_key(|snapshot| -snapshot.priority());
+        deletions.sort_by_key(|snapshot| -snapshot.priority());
+
+        let (updates, links) = if self.config.dedup {
+            dedup_plan(updates, contents)
+        } else {
+            (updates, vec![])
+        };
+
+        info!(
+            logger,
+            "update {} objects, link {} objects, delete {} objects",
+            updates.len(),
+            links.len(),
+            deletions.len()
+        );
+
+        if self.config.dry_run {
+            return Ok(());
+        }
+
+        if !self.strategy.execute() {
+            self.strategy.finish(&logger, &TransferResult::default())?;
+            return Self::save_state(&logger, &self.target, &state_files).await;
+        }
+
+        let added: Vec<(String, Option<u64>)> = match &target_keys {
+            Some(target_keys) => updates
+                .iter()
+                .chain(links.iter().map(|(item, _)| item))
+                .filter(|item| !target_keys.contains(item.key()))
+                .map(|item| (item.key().to_string(), item.size()))
+                .collect(),
+            None => vec![],
+        };
+        drop(target_keys);
+
+        let journal = match &self.config.journal_path {
+            Some(journal_path) => Some(Arc::new(Journal::open(journal_path)?)),
+            None => None,
+        };
+
+        let queue = match &self.config.queue_path {
+            Some(queue_path) => {
+                let queue = WorkQueue::open(
+                    queue_path,
+                    self.config.max_attempts,
+                    self.config.retry_delay,
+                )?;
+                queue.push(&updates)?;
+                queue.report(&logger)?;
+                Some(queue)
+            }
+            None => None,
+        };
+
+        info!(logger, "updating objects");
+
+        let source = Arc::new(self.source);
+        let target = Arc::new(self.target);
+
+        progress.set_length(updates.len() as u64);
+        progress.set_position(0);
+
+        let linked_objects = AtomicU64::new(0);
+        let linked_size = AtomicU64::new(0);
+
+        let map_snapshot = |snapshot: Snapshot, plan: PlanType| {
+            progress.set_message(snapshot.key());
+            let source = source.clone();
+            let target = target.clone();
+            let source_mission = source_mission.clone();
+            let target_mission = target_mission.clone();
+            let journal = journal.clone();
+            let linked_objects = &linked_objects;
+            let linked_size = &linked_size;
+
+            async move {
+                let key = snapshot.key();
+                if let Some(journal) = &journal {
+                    let intent = match plan {
+                        PlanType::Update | PlanType::Link(_) => journal.put(key),
+                        PlanType::Delete => journal.delete(key),
+                    };
+                    if let Err(err) = intent {
+                        warn!(
+                            target_mission.logger,
+                            "failed to write journal {}: {:?}", key, err
+                        );
+                        return Some(key.to_string());
+                    }
+                }
+
+                let linked = match &plan {
+                    PlanType::Link(from) => target
+                        .link_object(&snapshot, from, &target_mission)
+                        .await
+                        .map_err(|err| ("link", err)),
+                    _ => Ok(false),
+                };
+
+                let result = match (linked, plan) {
+                    (Err(err), _) => Err(err),
+                    (Ok(true), _) => {
+                        linked_objects.fetch_add(1, Ordering::Relaxed);
+                        linked_size.fetch_add(snapshot.size().unwrap_or(0), Ordering::Relaxed);
+                        Ok(())
+                    }
+                    // fall back to transfer if target doesn't support links
+                    (Ok(false), PlanType::Update) | (Ok(false), PlanType::Link(_)) => {
+                        match source.get_object(&snapshot, &source_mission).await {
+                            Ok(source_object) => target
+                                .put_object(&snapshot, source_object, &target_mission)
+                                .await
+                                .map_err(|err| ("put", err)),
+                            Err(err) => Err(("get", err)),
+                        }
+                    }
+                    (Ok(false), PlanType::Delete) => target
+                        .delete_object(&snapshot, &target_mission)
+                        .timeout(Duration::from_secs(60))
+                        .await
+                        .into_result()
+                        .map_err(|err| ("delete", err)),
+                };
+
+                match result {
+                    Ok(()) => {
+                        if let Some(journal) = &journal {
+                            if let Err(err) = journal.done(key) {
+                                warn!(
+                                    target_mission.logger,
+                                    "failed to write journal {}: {:?}", key, err
+                                );
+                            }
+                        }
+                        None
+                    }
+                    Err((op, err)) => {
+                        warn!(
+                            target_mission.logger,
+                            "error while {} {}: {:?}",
+                            op,
+                            snapshot.key(),
+                            err
+                        );
+                        Some(snapshot.key().to_string())
+                    }
+                }
+            }
+        };
+
+        let mut result = TransferResult::default();
+
+        if let Some(addr) = &self.config.coordinator_listen {
+            let secret = self.config.coordinator_secret.as_ref().ok_or_else(|| {
+                Error::ConfigureError("coordinator secret is required by coordinator".to_string())
+            })?;
+            result.failed_updates = distributed::serve_tasks(
+                &logger,
+                addr,
+                secret,
+                journal.clone(),
+                updates,
+                progress.clone(),
+            )
+            .await?;
+        } else if let Some(queue) = &queue {
+            let updates: HashMap<String, Snapshot> = updates
+                .into_iter()
+                .map(|item| (item.key().to_string(), item))
+                .collect();
+            let mut running = FuturesUnordered::new();
+            let mut last_report = std::time::Instant::now();
+
+            loop {
+                while running.len() < self.config.concurrent_transfer {
+                    let key = match queue.pop()? {
+                        Some(key) => key,
+                        None => break,
+                    };
+                    let item = updates[&key].clone();
+                    running.push(map_snapshot(item, PlanType::Update).map(|failed| (key, failed)));
+                }
+
+                if running.is_empty() {
+                    match queue.next_ready()? {
+                        Some(delay) => {
+                            queue.report(&logger)?;
+                            info!(logger, "waiting {}s for retry", delay.as_secs());
+                            tokio::time::sleep(delay).await;
+                            continue;
+                        }
+                        None => break,
+                    }
+                }
+
+                let (key, failed) = running.next().await.unwrap();
+                match failed {
+                    None => {
+                        queue.done(&key)?;
+                        progress.inc(1);
+                    }
+                    Some(failed) => {
+                        if !queue.retry(&key)? {
+                            result.failed_updates.push(failed);
+                            progress.inc(1);
+                        }
+                    }
+                }
+
+                if last_report.elapsed() > Duration::from_secs(60) {
+                    queue.report(&logger)?;
+                    last_report = std::time::Instant::now();
+                }
+            }
+
+            queue.report(&logger)?;
+        } else {
+            let mut results = stream::iter(
+                updates
+                    .into_iter()
+                    .map(|plan| map_snapshot(plan, PlanType::Update)),
+            )
+            .buffer_unordered(self.config.concurrent_transfer);
+
+            while let Some(failed) = results.next().await {
+                result.failed_updates.extend(failed);
+                progress.inc(1);
+            }
+        }
+
+        if !links.is_empty() {
+            info!(logger, "linking objects");
+
+            progress.set_length(links.len() as u64);
+            progress.set_position(0);
+
+            // objects whose content failed to transfer are transferred separately
+            let failed_updates: HashSet<String> = result.failed_updates.iter().cloned().collect();
+            let mut results = stream::iter(links.into_iter().map(|(snapshot, from)| {
+                if failed_updates.contains(&from) {
+                    map_snapshot(snapshot, PlanType::Update)
+                } else {
+                    map_snapshot(snapshot, PlanType::Link(from))
+                }
+            }))
+            .buffer_unordered(self.config.concurrent_transfer);
+
+            while let Some(failed) = results.next().await {
+                result.failed_updates.extend(failed);
+                progress.inc(1);
+            }
+
+            info!(
+                logger,
+                "deduplicated {} objects, saved {} bytes",
+                linked_objects.load(Ordering::Relaxed),
+                linked_size.load(Ordering::Relaxed)
+            );
+        }
+
+        target.publish(&target_mission).await?;
+
+        let deleted = if self.config.no_delete {
+            0
+        } else {
+            deletions.len() as u64
+        };
+
+        if !self.config.no_delete {
+            info!(logger, "deleting objects");
+
+            progress.set_length(deletions.len() as u64);
+            progress.set_position(0);
+
+            let batch_size = target.delete_batch_size();
+            if batch_size > 1 {
+                let batches: Vec<Vec<Snapshot>> = deletions
+                    .chunks(batch_size)
+                    .map(|batch| batch.to_vec())
+                    .collect();
+                let delete_batch = |batch: Vec<Snapshot>| {
+                    let target = target.clone();
+                    let target_mission = target_mission.clone();
+                    let journal = journal.clone();
+                    async move {
+                        let mut failed = vec![];
+                        let batch: Vec<Snapshot> = match &journal {
+                            Some(journal) => batch
+                                .into_iter()
+                                .filter(|snapshot| match journal.delete(snapshot.key()) {
+                                    Ok(()) => true,
+                                    Err(err) => {
+                                        warn!(
+                                            target_mission.logger,
+                                            "failed to write journal {}: {:?}",
+                                            snapshot.key(),
+                                            err
+                                        );
+                                        failed.push(snapshot.key().to_string());
+                                        false
+                                    }
+                                })
+                                .collect(),
+                            None => batch,
+                        };
+                        let results = target.delete_objects(&batch, &target_mission).await;
+                        for (snapshot, result) in batch.iter().zip(results) {
+                            match result {
+                                Ok(()) => {
+                                    if let Some(journal) = &journal {
+                                        if let Err(err) = journal.done(snapshot.key()) {
+                                            warn!(
+                                                target_mission.logger,
+                                                "failed to write journal {}: {:?}",
+                                                snapshot.key(),
+                                                err
+                                            );
+                                        }
+                                    }
+                                }
+                                Err(err) => {
+                                    warn!(
+                                        target_mission.logger,
+                                        "error while delete {}: {:?}",
+                                        snapshot.key(),
+                                        err
+                                    );
+                                    failed.push(snapshot.key().to_string());
+                                }
+                            }
+                        }
+                        failed
+                    }
+                };
+                let mut results = stream::iter(batches.into_iter().map(|batch| {
+                    let len = batch.len() as u64;
+                    delete_batch(batch).map(move |failed| (failed, len))
+                }))
+                .buffer_unordered(self.config.concurrent_transfer);
+
+                while let Some((failed, len)) = results.next().await {
+                    result.failed_deletions.extend(failed);
+                    progress.inc(len);
+                }
+            } else {
+                let mut results = stream::iter(
+                    deletions
+                        .into_iter()
+                        .map(|plan| map_snapshot(plan, PlanType::Delete)),
+                )
+                .buffer_unordered(self.config.concurrent_transfer);
+
+                while let Some(failed) = results.next().await {
+                    result.failed_deletions.extend(failed);
+                    progress.inc(1);
+                }
+            }
+        }
+
+        if !result.failed_updates.is_empty() || !result.failed_deletions.is_empty() {
+            warn!(
+                logger,
+                "{} updates and {} deletions failed",
+                result.failed_updates.len(),
+                result.failed_deletions.len()
+            );
+        }
+
+        if let Some(reporter) = reporter {
+            reporter.abort();
+        }
+
+        if let Some(journal) = &journal {
+            journal.complete()?;
+        }
+
+        if self.config.feed {
+            let failed: HashSet<&String> = result.failed_updates.iter().collect();
+            let date = chrono::Utc::now().to_rfc3339();
+            let base_url = self.config.feed_base_url.as_deref();
+            let items = added
+                .iter()
+                .filter(|(key, _)| !failed.contains(key))
+                .map(|(key, size)| feed::FeedItem::new(key, *size, base_url, &date))
+                .collect();
+            if let Err(err) = feed::publish(&logger, &*target, items, self.config.feed_size).await {
+                warn!(logger, "failed to publish feed: {:?}", err);
+            }
+        }
+
+        if let Some(mut run) = run_stats.take() {
+            let failed: HashSet<&String> = result.failed_updates.iter().collect();
+            for (key, size) in &added {
+                if !failed.contains(key) {
+                    run.added_objects += 1;
+                    run.added_bytes += size.unwrap_or(0);
+                }
+            }
+            run.deleted_objects = deleted - result.failed_deletions.len() as u64;
+            run.failed_objects =
+                (result.failed_updates.len() + result.failed_deletions.len()) as u64;
+            run.date = chrono::Utc::now().to_rfc3339();
+            if let Err(err) = stats::publish(&logger, &*target, run).await {
+                warn!(logger, "failed to publish stats: {:?}", err);
+            }
+        }
+
+        self.strategy.finish(&logger, &result)?;
+        Self::save_state(&logger, &target, &state_files).await?;
+
+        info!(logger, "transfer complete");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Persistent work queue of transfer tasks.
+//!
+//! Objects to update are stored in a SQLite database, and popped by priority:
+//! objects with higher priority first, and then smaller objects first. When
+//! transfer of an object fails, it is requeued with exponentially increasing
+//! delay, until it has been attempted `max_attempts` times.
+//!
+//! The queue survives restarts. Objects still in the queue when a run starts
+//! keep their retry delay, and objects popped by a crashed run or given up
+//! by previous run are queued again.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use slog::{info, Logger};
+
+use crate::error::{Error, Result};
+use crate::traits::{Key, Metadata};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS queue (
+    key TEXT PRIMARY KEY,
+    priority INTEGER NOT NULL,
+    size INTEGER,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    not_before INTEGER NOT NULL DEFAULT 0,
+    state INTEGER NOT NULL DEFAULT 0,
+    planned INTEGER NOT NULL DEFAULT 1
+);
+CREATE INDEX IF NOT EXISTS queue_order ON queue (priority DESC, size);
+";
+
+/// Number of objects in the queue.
+#[derive(Debug, Default)]
+pub struct QueueDepth {
+    pub ready: u64,
+    pub delayed: u64,
+    pub running: u64,
+    pub failed: u64,
+}
+
+const QUEUED: i64 = 0;
+const RUNNING: i64 = 1;
+const FAILED: i64 = 2;
+
+pub struct WorkQueue {
+    conn: Mutex<Connection>,
+    max_attempts: u64,
+    retry_delay: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+impl WorkQueue {
+    pub fn open(path: &str, max_attempts: u64, retry_delay: u64) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            max_attempts,
+            retry_delay,
+        })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| Error::ProcessError("queue lock poisoned".to_string()))
+    }
+
+    /// Replace queue with `updates`. Retry delay of objects already in the
+    /// queue is kept, and objects no longer planned are removed.
+    pub fn push<Snapshot: Key + Metadata>(&self, updates: &[Snapshot]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE queue SET planned = 0", [])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO queue (key, priority, size) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (key) DO UPDATE SET
+                    priority = excluded.priority, size = excluded.size,
+                    attempts = 0, state = 0, planned = 1",
+            )?;
+            for item in updates {
+                stmt.execute(params![
+                    item.key(),
+                    item.priority() as i64,
+                    item.size().map(|x| x as i64)
+                ])?;
+            }
+        }
+        tx.execute("DELETE FROM queue WHERE planned = 0", [])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Pop the object with highest priority which is ready to transfer.
+    pub fn pop(&self) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let key: Option<String> = conn
+            .query_row(
+                "SELECT key FROM queue WHERE state = ?1 AND not_before <= ?2
+                 ORDER BY priority DESC, size IS NULL, size LIMIT 1",
+                params![QUEUED, now() as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(key) = &key {
+            conn.execute(
+                "UPDATE queue SET state = ?2 WHERE key = ?1",
+                params![key, RUNNING],
+            )?;
+        }
+        Ok(key)
+    }
+
+    /// Remove a transferred object from the queue.
+    pub fn done(&self, key: &str) -> Result<()> {
+        self.conn()?
+            .execute("DELETE FROM queue WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    /// Requeue a failed object with delay. Returns false if the object has
+    /// been attempted too many times, and it won't be popped again in this run.
+    pub fn retry(&self, key: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let attempts: i64 =
+            conn.query_row("SELECT attempts FROM queue WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })?;
+        let attempts = attempts as u64 + 1;
+        let delay = self.retry_delay << (attempts - 1).min(16);
+        let state = if attempts >= self.max_attempts {
+            FAILED
+        } else {
+            QUEUED
+        };
+        conn.execute(
+            "UPDATE queue SET attempts = ?2, state = ?3, not_before = ?4 WHERE key = ?1",
+            params![key, attempts as i64, state, (now() + delay) as i64],
+        )?;
+        Ok(state == QUEUED)
+    }
+
+    /// Time to wait until the next queued object is ready, or `None` if the
+    /// queue is drained in this run.
+    pub fn next_ready(&self) -> Result<Option<Duration>> {
+        let not_before: Option<i64> = self.conn()?.query_row(
+            "SELECT MIN(not_before) FROM queue WHERE state = ?1",
+            [QUEUED],
+            |row| row.get(0),
+        )?;
+        Ok(not_before.map(|x| Duration::from_secs((x as u64).saturating_sub(now()))))
+    }
+
+    pub fn depth(&self) -> Result<QueueDepth> {
+        let conn = self.conn()?;
+        let mut depth = QueueDepth::default();
+        let mut stmt =
+            conn.prepare("SELECT state, not_before <= ?1, COUNT(*) FROM queue GROUP BY 1, 2")?;
+        let mut rows = stmt.query([now() as i64])?;
+        while let Some(row) = rows.next()? {
+            let state: i64 = row.get(0)?;
+            let ready: bool = row.get(1)?;
+            let count = row.get::<_, i64>(2)? as u64;
+            match (state, ready) {
+                (RUNNING, _) => depth.running += count,
+                (FAILED, _) => depth.failed += count,
+                (_, true) => depth.ready += count,
+                (_, false) => depth.delayed += count,
+            }
+        }
+        Ok(depth)
+    }
+
+    pub fn report(&self, logger: &Logger) -> Result<()> {
+        let depth = self.depth()?;
+        info!(
+            logger,
+            "queue: {} ready, {} delayed, {} running, {} failed",
+            depth.ready,
+            depth.delayed,
+            depth.running,
+            depth.failed
+        );
+        Ok(())
+    }
+}
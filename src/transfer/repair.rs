@@ -0,0 +1,119 @@
+//! Repair Transfer
+//!
+//! Repair transfer only transfers objects which exist in source but are
+//! missing on target, or whose size on target differs from source. It never
+//! deletes objects on target, which makes it safe to heal a mirror (e.g. one
+//! seeded from an incomplete rsync) without disturbing what's already there.
+//!
+//! If size of an object is unknown in source snapshot, it is found with a
+//! HEAD request to source by the executor, see `size_checks`.
+
+use iter_set::{classify_by, Inclusion};
+use slog::{info, Logger};
+
+use super::{TransferPlan, TransferStrategy};
+use crate::error::Result;
+use crate::traits::{Diff, Key, Metadata};
+
+pub struct Repair<Snapshot> {
+    print_plan: usize,
+    size_checks: Vec<(Snapshot, u64)>,
+}
+
+impl<Snapshot> Repair<Snapshot> {
+    pub fn new(print_plan: usize) -> Self {
+        Self {
+            print_plan,
+            size_checks: vec![],
+        }
+    }
+}
+
+impl<Snapshot: Diff + Key + Metadata> TransferStrategy<Snapshot> for Repair<Snapshot> {
+    fn name(&self) -> &'static str {
+        "repair"
+    }
+
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>> {
+        let mut updates = vec![];
+        for result in classify_by(source, target, |a, b| a.key().cmp(b.key())) {
+            let (sign, source) = match result {
+                Inclusion::Left(source) if source.existing_only() => continue,
+                Inclusion::Left(source) => ("+", source),
+                Inclusion::Both(source, target) => match (source.size(), target.size()) {
+                    (Some(expected), Some(size)) if expected != size => ("~", source),
+                    (None, Some(size)) => {
+                        self.size_checks.push((source, size));
+                        continue;
+                    }
+                    _ => continue,
+                },
+                Inclusion::Right(_) => continue,
+            };
+            if updates.len() < self.print_plan {
+                info!(logger, "{} {:?}", sign, source.key());
+            }
+            updates.push(source);
+        }
+        if !self.size_checks.is_empty() {
+            info!(
+                logger,
+                "{} objects have no size in source, checking with HEAD requests",
+                self.size_checks.len()
+            );
+        }
+        Ok(TransferPlan {
+            updates,
+            deletions: vec![],
+        })
+    }
+
+    fn size_checks(&mut self) -> Vec<(Snapshot, u64)> {
+        std::mem::take(&mut self.size_checks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SnapshotMeta;
+
+    fn meta(key: &str, size: Option<u64>) -> SnapshotMeta {
+        SnapshotMeta {
+            key: key.to_string(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_repair_compares_sizes() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut strategy = Repair::new(0);
+        let source = vec![
+            meta("missing", Some(1)),
+            meta("ok", Some(2)),
+            meta("truncated", Some(3)),
+            meta("unknown", None),
+        ];
+        let target = vec![
+            meta("extra", Some(1)),
+            meta("ok", Some(2)),
+            meta("truncated", Some(1)),
+            meta("unknown", Some(4)),
+        ];
+        let plan = strategy.plan(&logger, source, target).unwrap();
+        let updates: Vec<&str> = plan.updates.iter().map(|x| x.key()).collect();
+        assert_eq!(updates, vec!["missing", "truncated"]);
+        assert!(plan.deletions.is_empty());
+        let checks = strategy.size_checks();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].0.key(), "unknown");
+        assert_eq!(checks[0].1, 4);
+    }
+}
@@ -0,0 +1,73 @@
+//! Simple Diff Transfer
+//!
+//! Simple Diff Transfer simply takes snapshots of source and target,
+//! compare them, and generate a transfer plan. The plan is constructed
+//! as follows:
+//!
+//! 1. Snapshot object not in source but in target, delete
+//! 2. Snapshot object not in target but in source, add, unless it's only
+//!    kept if existing
+//! 3. Snapshot object in both source and target but different, update
+//!
+//! The snapshot object should support `Metadata` trait, and simple diff
+//! transfer will transfer them from highest priority to lowest priority.
+
+use slog::Logger;
+
+use super::{diff_plan, TransferPlan, TransferStrategy};
+use crate::error::Result;
+use crate::traits::{Diff, Key};
+
+pub struct SimpleDiff {
+    print_plan: usize,
+}
+
+impl SimpleDiff {
+    pub fn new(print_plan: usize) -> Self {
+        Self { print_plan }
+    }
+}
+
+impl<Snapshot: Diff + Key> TransferStrategy<Snapshot> for SimpleDiff {
+    fn name(&self) -> &'static str {
+        "full-diff"
+    }
+
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>> {
+        Ok(diff_plan(logger, source, target, self.print_plan))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::Logger;
+
+    use super::SimpleDiff;
+    use crate::metadata::SnapshotMeta;
+    use crate::transfer::TransferStrategy;
+
+    #[test]
+    fn test_existing_only() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let existing_only = |key: &str| {
+            let mut snapshot = SnapshotMeta::new(key.to_string());
+            snapshot.flags.existing_only = true;
+            snapshot
+        };
+        let source = vec![
+            SnapshotMeta::new("a".into()),
+            existing_only("b"),
+            existing_only("c"),
+        ];
+        let target = vec![SnapshotMeta::new("c".into()), SnapshotMeta::new("d".into())];
+        let plan = SimpleDiff::new(0).plan(&logger, source, target).unwrap();
+        let keys = |x: Vec<SnapshotMeta>| x.into_iter().map(|x| x.key).collect::<Vec<_>>();
+        assert_eq!(keys(plan.updates), vec!["a"]);
+        assert_eq!(keys(plan.deletions), vec!["d"]);
+    }
+}
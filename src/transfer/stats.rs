@@ -0,0 +1,250 @@
+//! Mirror statistics
+//!
+//! After each run, statistics of the run (objects and bytes in source,
+//! objects added and deleted, and largest packages) are appended to the
+//! history stored on target as `.mirror-clone/stats.json`. A static dashboard
+//! is rendered from the history to `.mirror-clone/stats.html`, which can be
+//! served as a public transparency page.
+//!
+//! Runs of different sources to the same target are kept apart by source
+//! name, so that growth of each source is shown separately.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use super::feed::name_version;
+use crate::error::Result;
+use crate::traits::{Key, Metadata, StateStorage};
+
+const JSON_STATS: &str = "stats.json";
+const HTML_STATS: &str = "stats.html";
+/// Number of runs kept in history.
+const HISTORY_SIZE: usize = 1000;
+/// Number of packages in the largest package list.
+const TOP_PACKAGES: usize = 20;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunStats {
+    pub source: String,
+    pub date: String,
+    pub objects: u64,
+    pub bytes: u64,
+    pub added_objects: u64,
+    pub added_bytes: u64,
+    pub deleted_objects: u64,
+    pub failed_objects: u64,
+    pub top_packages: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    runs: Vec<RunStats>,
+}
+
+/// Package an object belongs to, which is package name guessed from file
+/// name, or parent directory.
+fn package(key: &str) -> String {
+    match name_version(key) {
+        Some((name, _)) => name,
+        None => key
+            .rsplit_once('/')
+            .map_or("/", |(parent, _)| parent)
+            .to_string(),
+    }
+}
+
+impl RunStats {
+    /// Count objects and bytes of source snapshot. Objects with unknown size
+    /// are counted as empty.
+    pub fn new<Snapshot: Key + Metadata>(source: &str, snapshot: &[Snapshot]) -> Self {
+        let mut packages: HashMap<String, u64> = HashMap::new();
+        let mut bytes = 0;
+        for item in snapshot {
+            let size = item.size().unwrap_or(0);
+            bytes += size;
+            *packages.entry(package(item.key())).or_default() += size;
+        }
+        let mut top_packages: Vec<(String, u64)> = packages.into_iter().collect();
+        top_packages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_packages.truncate(TOP_PACKAGES);
+        Self {
+            source: source.to_string(),
+            objects: snapshot.len() as u64,
+            bytes,
+            top_packages,
+            ..Default::default()
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+fn signed_bytes(delta: i128) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, human_bytes(delta.unsigned_abs() as u64))
+}
+
+fn html(history: &History) -> String {
+    // latest and previous run of each source
+    let mut sources: BTreeMap<&str, (&RunStats, Option<&RunStats>)> = BTreeMap::new();
+    for run in &history.runs {
+        let previous = sources.get(run.source.as_str()).map(|(latest, _)| *latest);
+        sources.insert(&run.source, (run, previous));
+    }
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Mirror Statistics</title>\n</head>\n<body>\n<h1>Mirror Statistics</h1>\n",
+    );
+
+    html.push_str(
+        "<h2>Sources</h2>\n<table>\n<tr><th>Source</th><th>Last Run</th><th>Objects</th>\
+         <th>Size</th><th>Growth</th></tr>\n",
+    );
+    for (source, (latest, previous)) in &sources {
+        let growth = match previous {
+            Some(previous) => format!(
+                "{:+} objects, {}",
+                latest.objects as i128 - previous.objects as i128,
+                signed_bytes(latest.bytes as i128 - previous.bytes as i128)
+            ),
+            None => "-".to_string(),
+        };
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(source),
+            escape_html(&latest.date),
+            latest.objects,
+            human_bytes(latest.bytes),
+            growth
+        ));
+    }
+    html.push_str("</table>\n");
+
+    for (source, (latest, _)) in &sources {
+        html.push_str(&format!(
+            "<h2>Largest Packages of {}</h2>\n<table>\n<tr><th>Package</th><th>Size</th></tr>\n",
+            escape_html(source)
+        ));
+        for (name, size) in &latest.top_packages {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                escape_html(name),
+                human_bytes(*size)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str(
+        "<h2>Recent Runs</h2>\n<table>\n<tr><th>Date</th><th>Source</th><th>Added</th>\
+         <th>Deleted</th><th>Failed</th></tr>\n",
+    );
+    for run in history.runs.iter().rev().take(100) {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{} ({})</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&run.date),
+            escape_html(&run.source),
+            run.added_objects,
+            human_bytes(run.added_bytes),
+            run.deleted_objects,
+            run.failed_objects
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Append `run` to history on target, and render the dashboard.
+pub async fn publish<Target: StateStorage>(
+    logger: &Logger,
+    target: &Target,
+    run: RunStats,
+) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("mirror-clone-stats-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await?;
+    let json_path = dir.join(JSON_STATS);
+    let html_path = dir.join(HTML_STATS);
+
+    let mut history: History = if target.load_state(JSON_STATS, &json_path).await? {
+        serde_json::from_slice(&tokio::fs::read(&json_path).await?)?
+    } else {
+        History::default()
+    };
+    info!(
+        logger,
+        "stats: {} objects ({}), {} added, {} deleted",
+        run.objects,
+        human_bytes(run.bytes),
+        run.added_objects,
+        run.deleted_objects
+    );
+    history.runs.push(run);
+    if history.runs.len() > HISTORY_SIZE {
+        let excess = history.runs.len() - HISTORY_SIZE;
+        history.runs.drain(..excess);
+    }
+
+    tokio::fs::write(&json_path, serde_json::to_vec_pretty(&history)?).await?;
+    tokio::fs::write(&html_path, html(&history)).await?;
+    target.save_state(JSON_STATS, &json_path).await?;
+    target.save_state(HTML_STATS, &html_path).await?;
+    tokio::fs::remove_dir_all(&dir).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SnapshotMeta;
+
+    #[test]
+    fn test_run_stats() {
+        let snapshot: Vec<SnapshotMeta> = [
+            ("numpy/numpy-1.26.4.tar.gz", Some(10)),
+            ("numpy/numpy-2.0.0.tar.gz", Some(20)),
+            ("six/six-1.16.0.tar.gz", Some(5)),
+            ("index/repodata.json", None),
+        ]
+        .iter()
+        .map(|(key, size)| SnapshotMeta {
+            key: key.to_string(),
+            size: *size,
+            ..Default::default()
+        })
+        .collect();
+        let stats = RunStats::new("pypi", &snapshot);
+        assert_eq!(stats.objects, 4);
+        assert_eq!(stats.bytes, 35);
+        assert_eq!(
+            stats.top_packages,
+            vec![
+                ("numpy".to_string(), 30),
+                ("six".to_string(), 5),
+                ("index".to_string(), 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Verify Transfer
+//!
+//! Verify transfer compares source with target in the same way as simple
+//! diff transfer, but never modifies target. It reports objects missing on
+//! target, objects which should have been removed from target, and objects
+//! which differ. If target diverges from source, the transfer fails.
+//!
+//! If a report path is given, source snapshot of objects missing or different
+//! on target is written to it as JSON lines, which can be healed later with
+//! `--repair-from`.
+
+use std::io::Write;
+
+use iter_set::{classify_by, Inclusion};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+
+use super::{TransferPlan, TransferResult, TransferStrategy};
+use crate::error::{Error, Result};
+use crate::traits::{Diff, Key};
+
+pub struct Verify {
+    print_plan: usize,
+    report_path: Option<String>,
+    missing: usize,
+    extra: usize,
+    different: usize,
+}
+
+impl Verify {
+    pub fn new(print_plan: usize, report_path: Option<String>) -> Self {
+        Self {
+            print_plan,
+            report_path,
+            missing: 0,
+            extra: 0,
+            different: 0,
+        }
+    }
+}
+
+impl<Snapshot: Diff + Key + Serialize> TransferStrategy<Snapshot> for Verify {
+    fn name(&self) -> &'static str {
+        "verify"
+    }
+
+    fn plan(
+        &mut self,
+        logger: &Logger,
+        source: Vec<Snapshot>,
+        target: Vec<Snapshot>,
+    ) -> Result<TransferPlan<Snapshot>> {
+        let mut max_info = 0;
+        let mut corrupted = vec![];
+        for result in classify_by(source, target, |a, b| a.key().cmp(b.key())) {
+            let (sign, key) = match &result {
+                Inclusion::Left(source) if source.existing_only() => continue,
+                Inclusion::Left(source) => {
+                    self.missing += 1;
+                    corrupted.push(serde_json::to_string(source)?);
+                    ("missing", source.key())
+                }
+                Inclusion::Both(l, r) if l.diff(r) => {
+                    self.different += 1;
+                    corrupted.push(serde_json::to_string(l)?);
+                    ("different", l.key())
+                }
+                Inclusion::Both(_, _) => continue,
+                Inclusion::Right(target) => {
+                    self.extra += 1;
+                    ("extra", target.key())
+                }
+            };
+            if max_info < self.print_plan {
+                info!(logger, "{} {:?}", sign, key);
+                max_info += 1;
+            }
+        }
+        if let Some(path) = &self.report_path {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            for line in corrupted {
+                writeln!(file, "{}", line)?;
+            }
+            file.flush()?;
+            info!(logger, "corruption report written to {}", path);
+        }
+        Ok(TransferPlan {
+            updates: vec![],
+            deletions: vec![],
+        })
+    }
+
+    fn execute(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self, logger: &Logger, _result: &TransferResult) -> Result<()> {
+        info!(
+            logger,
+            "verify: {} missing, {} extra, {} different", self.missing, self.extra, self.different
+        );
+        if self.missing + self.extra + self.different > 0 {
+            warn!(logger, "target diverges from source");
+            return Err(Error::ProcessError(format!(
+                "target diverges from source: {} missing, {} extra, {} different",
+                self.missing, self.extra, self.different
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 use std::convert::Infallible;
 use std::str::FromStr;
 
-use indicatif::ProgressStyle;
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use regex::Regex;
-use slog::{o, Drain};
+use slog::{info, o, Drain};
 
-use crate::common::SnapshotPath;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 
 #[derive(Debug, Clone, Default)]
@@ -31,6 +32,26 @@ impl From<CommaSplitVecString> for Vec<String> {
     }
 }
 
+/// HTTP header in `Name: value` form.
+#[derive(Debug, Clone)]
+pub struct HttpHeader(pub String, pub String);
+
+impl FromStr for HttpHeader {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok(Self(name.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(Error::ConfigureError(format!(
+                "invalid header {}, expect `Name: value`",
+                s
+            ))),
+        }
+    }
+}
+
 pub fn create_logger() -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -46,6 +67,69 @@ pub fn spinner() -> ProgressStyle {
         .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
 }
 
+/// Max redraws of progress bars per second.
+const PROGRESS_HZ: u64 = 4;
+
+/// Progress bar which redraws at most every 0.1% of progress, as redrawing on
+/// every object costs notable CPU at high concurrency.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    let progress =
+        ProgressBar::with_draw_target(len, ProgressDrawTarget::stderr_with_hz(PROGRESS_HZ));
+    progress.set_draw_delta(len / 1000);
+    progress
+}
+
+pub fn progress_spinner() -> ProgressBar {
+    ProgressBar::with_draw_target(!0, ProgressDrawTarget::stderr_with_hz(PROGRESS_HZ))
+}
+
+pub fn multi_progress(visible: bool) -> MultiProgress {
+    MultiProgress::with_draw_target(if visible {
+        ProgressDrawTarget::stderr_with_hz(PROGRESS_HZ)
+    } else {
+        ProgressDrawTarget::hidden()
+    })
+}
+
+/// Log position of `progress` every `interval`, for runs without progress bar.
+pub fn report_progress(
+    logger: slog::Logger,
+    name: &'static str,
+    progress: ProgressBar,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last = progress.position();
+        loop {
+            tokio::time::sleep(interval).await;
+            if progress.is_finished() {
+                break;
+            }
+            let (pos, len) = (progress.position(), progress.length());
+            let rate = (pos - last.min(pos)) as f64 / interval.as_secs_f64();
+            let eta = if rate > 0.0 {
+                indicatif::HumanDuration(Duration::from_secs_f64(
+                    len.saturating_sub(pos) as f64 / rate,
+                ))
+                .to_string()
+            } else {
+                "unknown".to_string()
+            };
+            info!(
+                logger,
+                "{}: {}/{} ({:.1}%), {:.1}/s, eta {}",
+                name,
+                pos,
+                len,
+                pos as f64 * 100.0 / len.max(1) as f64,
+                rate,
+                eta
+            );
+            last = pos;
+        }
+    })
+}
+
 pub fn bar() -> ProgressStyle {
     ProgressStyle::default_bar()
         .template(
@@ -54,17 +138,14 @@ pub fn bar() -> ProgressStyle {
         .progress_chars("=> ")
 }
 
-pub fn snapshot_string_to_path(snapshot: Vec<String>) -> Vec<SnapshotPath> {
-    snapshot.into_iter().map(SnapshotPath::new).collect()
-}
-
 pub fn snapshot_string_to_meta(snapshot: Vec<String>) -> Vec<SnapshotMeta> {
     snapshot.into_iter().map(SnapshotMeta::new).collect()
 }
 
+/// Default User-Agent, identifying the mirror site in `MIRROR_CLONE_SITE`.
 pub fn user_agent() -> String {
     format!(
-        "mirror-clone / {} ({})",
+        "mirror-clone / {} ({}; +https://github.com/sjtug/mirror-clone)",
         env!("CARGO_PKG_VERSION"),
         std::env::var("MIRROR_CLONE_SITE").expect("No MIRROR_CLONE_SITE env variable")
     )
@@ -120,6 +201,19 @@ pub fn fn_regex_rewrite(
     }
 }
 
+/// Convert glob pattern with `*` and `?` to regex matching whole string.
+pub fn glob_regex(pattern: &str) -> String {
+    let pattern: String = pattern
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect();
+    format!("^{}$", pattern)
+}
+
 pub fn hash_string(key: &str) -> String {
     use std::hash::{Hash, Hasher};
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
@@ -134,3 +228,66 @@ pub fn unix_time() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+/// An `<a>` element in HTML page, with attributes and text decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub attributes: Vec<(String, String)>,
+    pub text: String,
+}
+
+impl Link {
+    /// Value of attribute `name`, which is empty for attributes without value.
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn href(&self) -> Option<&str> {
+        self.attr("href")
+    }
+}
+
+/// Parse all `<a>` elements of HTML `page` in document order.
+pub fn parse_link_list(page: &str) -> Vec<Link> {
+    static SELECTOR: once_cell::sync::Lazy<scraper::Selector> =
+        once_cell::sync::Lazy::new(|| scraper::Selector::parse("a").unwrap());
+    scraper::Html::parse_document(page)
+        .select(&SELECTOR)
+        .map(|anchor| Link {
+            attributes: anchor
+                .value()
+                .attrs()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            text: anchor.text().collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_link_list() {
+        let page = r#"<html><body>
+<a data-requires-python="&gt;=3.8"
+   href="foo-1.0.tar.gz#sha256=00">foo-1.0.tar.gz</a><br/>
+<a href='bar%2B1.whl' data-yanked>bar&amp;1.whl</a>
+<A HREF="baz/"><span>baz</span></A>
+</body></html>"#;
+        let links = parse_link_list(page);
+        assert_eq!(links.len(), 3);
+        assert_eq!(links[0].href(), Some("foo-1.0.tar.gz#sha256=00"));
+        assert_eq!(links[0].attr("data-requires-python"), Some(">=3.8"));
+        assert_eq!(links[0].text, "foo-1.0.tar.gz");
+        assert_eq!(links[1].href(), Some("bar%2B1.whl"));
+        assert_eq!(links[1].attr("data-yanked"), Some(""));
+        assert_eq!(links[1].text, "bar&1.whl");
+        assert_eq!(links[2].href(), Some("baz/"));
+        assert_eq!(links[2].text, "baz");
+    }
+}
@@ -0,0 +1,163 @@
+//! A submodule for pypi source that parses file names of distributions.
+//!
+//! Wheels are named as `{distribution}-{version}(-{build})?-{python}-{abi}-{platform}.whl`
+//! ([PEP 427], with compatibility tags of [PEP 425]), and source distributions
+//! as `{distribution}-{version}.tar.gz`. Legacy source distributions may have
+//! dashes in their distribution names, so the project name is needed to
+//! split them.
+//!
+//! [PEP 425]: https://peps.python.org/pep-0425/
+//! [PEP 427]: https://peps.python.org/pep-0427/
+
+use crate::pypi::normalize_name;
+
+/// Extensions of source distributions, including legacy ones.
+pub const SDIST_EXTENSIONS: &[&str] = &[
+    ".tar.gz", ".tar.bz2", ".tar.xz", ".tar.Z", ".tgz", ".tbz", ".tar", ".zip",
+];
+
+/// Components of a wheel file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WheelName {
+    pub distribution: String,
+    pub version: String,
+    pub build: Option<String>,
+    /// Python tags, e.g. `py2.py3`.
+    pub python: String,
+    pub abi: String,
+    /// Platform tags, e.g. `manylinux_2_17_x86_64.manylinux2014_x86_64`.
+    pub platform: String,
+}
+
+impl WheelName {
+    /// Parse a wheel file name. Returns `None` if it's not a wheel.
+    pub fn parse(filename: &str) -> Option<Self> {
+        let stem = filename.strip_suffix(".whl")?;
+        let parts: Vec<&str> = stem.split('-').collect();
+        let (distribution, version, build, tags) = match parts.as_slice() {
+            [distribution, version, tags @ ..] if tags.len() == 3 => {
+                (distribution, version, None, tags)
+            }
+            [distribution, version, build, tags @ ..] if tags.len() == 3 => {
+                if !build.starts_with(|c: char| c.is_ascii_digit()) {
+                    return None;
+                }
+                (distribution, version, Some(build.to_string()), tags)
+            }
+            _ => return None,
+        };
+        if distribution.is_empty() || version.is_empty() || tags.iter().any(|x| x.is_empty()) {
+            return None;
+        }
+        Some(Self {
+            distribution: distribution.to_string(),
+            version: version.to_string(),
+            build,
+            python: tags[0].to_string(),
+            abi: tags[1].to_string(),
+            platform: tags[2].to_string(),
+        })
+    }
+
+    /// Each platform tag of compressed tag set.
+    pub fn platforms(&self) -> impl Iterator<Item = &str> {
+        self.platform.split('.')
+    }
+}
+
+/// Components of a source distribution file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdistName {
+    pub distribution: String,
+    pub version: String,
+    pub extension: &'static str,
+}
+
+impl SdistName {
+    /// Parse a source distribution file name of `project`. Returns `None` if
+    /// it's not a source distribution.
+    pub fn parse(project: &str, filename: &str) -> Option<Self> {
+        let (stem, extension) = SDIST_EXTENSIONS.iter().find_map(|extension| {
+            filename
+                .strip_suffix(extension)
+                .map(|stem| (stem, *extension))
+        })?;
+        let (distribution, version) = split_name_version(project, stem)?;
+        Some(Self {
+            distribution: distribution.to_string(),
+            version: version.to_string(),
+            extension,
+        })
+    }
+}
+
+/// Split `{distribution}-{version}` at the dash after the name of `project`,
+/// or before the first component starting with a digit if the name doesn't
+/// match, e.g. the project is renamed.
+pub fn split_name_version<'a>(project: &str, stem: &'a str) -> Option<(&'a str, &'a str)> {
+    let project = normalize_name(project);
+    let idx = stem
+        .match_indices('-')
+        .map(|(idx, _)| idx)
+        .find(|&idx| normalize_name(&stem[..idx]) == project)
+        .or_else(|| {
+            stem.match_indices('-')
+                .map(|(idx, _)| idx)
+                .find(|&idx| stem[idx + 1..].starts_with(|c: char| c.is_ascii_digit()))
+        })?;
+    Some((&stem[..idx], &stem[idx + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{SdistName, WheelName};
+
+    #[test]
+    fn test_wheel_name() {
+        assert_eq!(
+            WheelName::parse(
+                "numpy-1.26.4-cp312-cp312-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+            ),
+            Some(WheelName {
+                distribution: "numpy".to_string(),
+                version: "1.26.4".to_string(),
+                build: None,
+                python: "cp312".to_string(),
+                abi: "cp312".to_string(),
+                platform: "manylinux_2_17_x86_64.manylinux2014_x86_64".to_string(),
+            })
+        );
+        let wheel = WheelName::parse("foo-1.0+local-1abc-py2.py3-none-any.whl").unwrap();
+        assert_eq!(wheel.version, "1.0+local");
+        assert_eq!(wheel.build.as_deref(), Some("1abc"));
+        assert_eq!(wheel.python, "py2.py3");
+        assert_eq!(wheel.platforms().collect::<Vec<_>>(), vec!["any"]);
+        assert_eq!(WheelName::parse("foo-1.0-x-py3-none-any.whl"), None);
+        assert_eq!(WheelName::parse("foo-1.0-py3-none.whl"), None);
+        assert_eq!(WheelName::parse("foo-1.0.tar.gz"), None);
+    }
+
+    #[rstest]
+    #[case("numpy", "numpy-1.26.4.tar.gz", "numpy", "1.26.4")]
+    #[case("zope-interface", "zope.interface-6.1.tar.gz", "zope.interface", "6.1")]
+    #[case(
+        "python-dateutil",
+        "python-dateutil-2.8.2.tar.gz",
+        "python-dateutil",
+        "2.8.2"
+    )]
+    #[case("sentry-sdk", "sentry-sdk-0.1.0.tar.gz", "sentry-sdk", "0.1.0")]
+    #[case("renamed", "old-name-1.0.zip", "old-name", "1.0")]
+    fn test_sdist_name(
+        #[case] project: &str,
+        #[case] filename: &str,
+        #[case] distribution: &str,
+        #[case] version: &str,
+    ) {
+        let sdist = SdistName::parse(project, filename).unwrap();
+        assert_eq!(sdist.distribution, distribution);
+        assert_eq!(sdist.version, version);
+    }
+}
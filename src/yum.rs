@@ -0,0 +1,575 @@
+//! Yum source
+//!
+//! Yum source mirrors rpm-md repositories (yum and dnf), listed in a YAML
+//! config or given by `--repo name=url`, each stored under its name:
+//!
+//! ```yaml
+//! repos:
+//!   - name: epel/9/x86_64
+//!     base: https://dl.fedoraproject.org/pub/epel/9/Everything/x86_64
+//! ```
+//!
+//! `repodata/repomd.xml` lists metadata of a repo, which are all mirrored.
+//! Packages are enumerated from `primary.xml`, or from `primary.sqlite` if
+//! the former is compressed in an unsupported format, and verified by their
+//! SHA256 in it. With `--arches`, only packages of these architectures (and
+//! `noarch`) are mirrored, while metadata is kept as is. `repomd.xml` and its
+//! signature are always transferred at the end.
+
+use std::collections::HashSet;
+use std::io::{self, Read};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use slog::info;
+use structopt::StructOpt;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::http::exists;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct YumConfig {
+    /// Config file listing repos to mirror.
+    pub repo_config: Option<String>,
+    /// Also mirror these repos, as `name=url`.
+    #[structopt(long = "repo", parse(try_from_str = parse_repo), number_of_values = 1)]
+    pub repos: Vec<YumRepo>,
+    /// Only mirror packages of these architectures, e.g. `x86_64,aarch64`. Packages of `noarch`
+    /// are always mirrored.
+    #[structopt(long, use_delimiter = true)]
+    pub arches: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct YumRepos {
+    #[serde(default)]
+    repos: Vec<YumRepo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct YumRepo {
+    pub name: String,
+    pub base: String,
+}
+
+fn parse_repo(s: &str) -> std::result::Result<YumRepo, String> {
+    match s.split_once('=') {
+        Some((name, base)) if !name.is_empty() => Ok(YumRepo {
+            name: name.trim_matches('/').to_string(),
+            base: base.trim_end_matches('/').to_string(),
+        }),
+        _ => Err(format!("invalid repo {}, expected name=url", s)),
+    }
+}
+
+/// A metadata file listed in `repomd.xml`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct RepoData {
+    pub data_type: String,
+    pub href: String,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+impl RepoData {
+    fn snapshot(&self, prefix: &str) -> SnapshotMeta {
+        SnapshotMeta {
+            key: format!("{}{}", prefix, self.href),
+            size: self.size,
+            checksum_method: self.sha256.as_ref().map(|_| "sha256".to_string()),
+            checksum: self.sha256.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Attribute `name` of an XML element.
+fn attribute<'a>(attributes: &'a [xml::attribute::OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|x| x.name.prefix.is_none() && x.name.local_name == name)
+        .map(|x| x.value.as_str())
+}
+
+/// Metadata files listed in `repomd.xml`.
+pub(crate) fn parse_repomd(reader: impl Read) -> Result<Vec<RepoData>> {
+    let mut files = vec![];
+    let mut current: Option<RepoData> = None;
+    let mut element = String::new();
+    let mut checksum_type = String::new();
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                match name.local_name.as_str() {
+                    "data" => {
+                        current = Some(RepoData {
+                            data_type: attribute(&attributes, "type")
+                                .unwrap_or_default()
+                                .to_string(),
+                            ..Default::default()
+                        })
+                    }
+                    "location" => {
+                        if let Some(data) = &mut current {
+                            data.href = attribute(&attributes, "href")
+                                .unwrap_or_default()
+                                .to_string();
+                        }
+                    }
+                    "checksum" => {
+                        checksum_type = attribute(&attributes, "type")
+                            .unwrap_or_default()
+                            .to_string()
+                    }
+                    _ => (),
+                }
+                element = name.local_name;
+            }
+            XmlEvent::Characters(text) => {
+                if let Some(data) = &mut current {
+                    match element.as_str() {
+                        "checksum" if checksum_type == "sha256" => data.sha256 = Some(text),
+                        "size" => data.size = text.trim().parse().ok(),
+                        _ => (),
+                    }
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "data" {
+                    files.extend(current.take().filter(|x| !x.href.is_empty()));
+                }
+                element.clear();
+            }
+            _ => (),
+        }
+    }
+    Ok(files)
+}
+
+/// A package listed in primary metadata.
+#[derive(Debug, Default)]
+pub(crate) struct Package {
+    pub href: String,
+    pub arch: String,
+    pub size: Option<u64>,
+    pub checksum_type: String,
+    pub checksum: String,
+}
+
+impl Package {
+    /// Whether this package is of `arches`, which selects all if empty.
+    /// Packages of `noarch` are always selected.
+    pub fn is_selected(&self, arches: &[String]) -> bool {
+        arches.is_empty() || self.arch == "noarch" || arches.contains(&self.arch)
+    }
+
+    pub fn snapshot(self, prefix: &str) -> SnapshotMeta {
+        let checksum = (self.checksum_type == "sha256").then_some(self.checksum);
+        SnapshotMeta {
+            key: format!("{}{}", prefix, self.href),
+            size: self.size,
+            checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+            checksum,
+            ..Default::default()
+        }
+    }
+}
+
+/// Packages listed in `primary.xml`.
+pub(crate) fn parse_primary(reader: impl Read) -> Result<Vec<Package>> {
+    let mut packages = vec![];
+    let mut current: Option<Package> = None;
+    let mut element = String::new();
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                // elements of `rpm` namespace in `format` are skipped
+                if name.prefix.is_some() {
+                    element.clear();
+                    continue;
+                }
+                match (name.local_name.as_str(), &mut current) {
+                    ("package", _) => current = Some(Package::default()),
+                    ("location", Some(package)) => {
+                        package.href = attribute(&attributes, "href")
+                            .unwrap_or_default()
+                            .to_string()
+                    }
+                    ("size", Some(package)) => {
+                        package.size =
+                            attribute(&attributes, "package").and_then(|x| x.parse().ok())
+                    }
+                    ("checksum", Some(package)) => {
+                        package.checksum_type = attribute(&attributes, "type")
+                            .unwrap_or_default()
+                            .to_string()
+                    }
+                    _ => (),
+                }
+                element = name.local_name;
+            }
+            XmlEvent::Characters(text) => match (element.as_str(), &mut current) {
+                ("arch", Some(package)) => package.arch = text,
+                ("checksum", Some(package)) => package.checksum = text,
+                _ => (),
+            },
+            XmlEvent::EndElement { name } => {
+                if name.prefix.is_none() && name.local_name == "package" {
+                    packages.extend(current.take().filter(|x| !x.href.is_empty()));
+                }
+                element.clear();
+            }
+            _ => (),
+        }
+    }
+    Ok(packages)
+}
+
+/// Packages listed in `primary.sqlite` at `path`.
+fn query_primary_db(path: &std::path::Path) -> Result<Vec<Package>> {
+    let db = rusqlite::Connection::open(path)?;
+    let mut statement =
+        db.prepare("SELECT location_href, arch, size_package, checksum_type, pkgId FROM packages")?;
+    let packages = statement
+        .query_map([], |row| {
+            Ok(Package {
+                href: row.get(0)?,
+                arch: row.get(1)?,
+                size: row.get::<_, Option<i64>>(2)?.map(|x| x as u64),
+                checksum_type: row.get(3)?,
+                checksum: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(packages)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Zstd,
+    Plain,
+}
+
+/// Compression of metadata file, if supported.
+fn compression(href: &str) -> Option<Compression> {
+    let extension = href.rsplit_once('.').map_or("", |(_, x)| x);
+    match extension {
+        "gz" => Some(Compression::Gzip),
+        "bz2" => Some(Compression::Bzip2),
+        "zst" => Some(Compression::Zstd),
+        "xml" | "sqlite" => Some(Compression::Plain),
+        _ => None,
+    }
+}
+
+fn decompress<'a>(
+    compression: Compression,
+    reader: impl Read + 'a,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Plain => Box::new(reader),
+    })
+}
+
+/// Fetch metadata file `href` of repo at `base`.
+async fn fetch(client: &reqwest::Client, base: &str, href: &str) -> Result<bytes::Bytes> {
+    let response = crate::http::send(client.get(format!("{}/{}", base, href))).await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    Ok(response.bytes().await?)
+}
+
+/// Packages of repo at `base`, enumerated from primary metadata in `files`.
+pub(crate) async fn packages(
+    client: &reqwest::Client,
+    base: &str,
+    files: &[RepoData],
+) -> Result<Vec<Package>> {
+    let find = |data_type: &str| {
+        files
+            .iter()
+            .find(|x| x.data_type == data_type)
+            .and_then(|x| Some((x, compression(&x.href)?)))
+    };
+    if let Some((primary, compression)) = find("primary") {
+        let data = fetch(client, base, &primary.href).await?;
+        return tokio::task::spawn_blocking(move || {
+            parse_primary(decompress(compression, &data[..])?)
+        })
+        .await
+        .expect("task panicked");
+    }
+    if let Some((primary_db, compression)) = find("primary_db") {
+        let data = fetch(client, base, &primary_db.href).await?;
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-primary-{}-{}.sqlite",
+            std::process::id(),
+            primary_db.sha256.as_deref().unwrap_or("db")
+        ));
+        return tokio::task::spawn_blocking(move || {
+            io::copy(
+                &mut decompress(compression, &data[..])?,
+                &mut std::fs::File::create(&path)?,
+            )?;
+            let packages = query_primary_db(&path);
+            std::fs::remove_file(&path)?;
+            packages
+        })
+        .await
+        .expect("task panicked");
+    }
+    Err(Error::ProcessError(format!(
+        "no supported primary metadata in {}",
+        base
+    )))
+}
+
+/// Metadata files and signatures of repo at `base`, with keys prefixed by
+/// `prefix`. `repomd.xml` is forced and listed last.
+pub(crate) async fn repo_metadata(
+    client: &reqwest::Client,
+    base: &str,
+    prefix: &str,
+) -> Result<(Vec<RepoData>, Vec<SnapshotMeta>)> {
+    let repomd = fetch(client, base, "repodata/repomd.xml").await?;
+    let files = parse_repomd(&repomd[..])?;
+    let mut snapshot: Vec<SnapshotMeta> = files.iter().map(|x| x.snapshot(prefix)).collect();
+    for name in ["repodata/repomd.xml.asc", "repodata/repomd.xml.key"] {
+        if exists(client, &format!("{}/{}", base, name)).await? {
+            snapshot.push(SnapshotMeta::force(format!("{}{}", prefix, name)));
+        }
+    }
+    snapshot.push(SnapshotMeta::force(format!(
+        "{}repodata/repomd.xml",
+        prefix
+    )));
+    Ok((files, snapshot))
+}
+
+#[derive(Debug)]
+pub struct Yum {
+    config: YumConfig,
+    repos: Vec<YumRepo>,
+}
+
+impl Yum {
+    pub fn new(config: YumConfig) -> Self {
+        let mut repos: YumRepos = match &config.repo_config {
+            Some(path) => {
+                let content = std::fs::read(path).unwrap();
+                serde_yaml::from_str(std::str::from_utf8(&content).unwrap()).unwrap()
+            }
+            None => Default::default(),
+        };
+        repos.repos.extend(config.repos.iter().cloned());
+        assert!(!repos.repos.is_empty(), "no yum repos to mirror");
+        let mut names = HashSet::new();
+        for repo in &mut repos.repos {
+            repo.base = repo.base.trim_end_matches('/').to_string();
+            assert!(
+                names.insert(repo.name.clone()),
+                "duplicated repo {}",
+                repo.name
+            );
+        }
+        Self {
+            config,
+            repos: repos.repos,
+        }
+    }
+
+    /// Packages and metadata of `repo`.
+    async fn repo(
+        &self,
+        client: &reqwest::Client,
+        repo: &YumRepo,
+    ) -> Result<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> {
+        let prefix = format!("{}/", repo.name);
+        let (files, metadata) = repo_metadata(client, &repo.base, &prefix).await?;
+        let packages = packages(client, &repo.base, &files)
+            .await?
+            .into_iter()
+            .filter(|x| x.is_selected(&self.config.arches))
+            .map(|x| x.snapshot(&prefix))
+            .collect();
+        Ok((packages, metadata))
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Yum {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching {} repos...", self.repos.len());
+        progress.set_length(self.repos.len() as u64);
+        let this = &*self;
+        let results: Vec<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> = stream::iter(self.repos.clone())
+            .map(|repo| {
+                let client = client.clone();
+                let progress = progress.clone();
+                async move {
+                    progress.set_message(&repo.name);
+                    let result = this.repo(&client, &repo).await;
+                    progress.inc(1);
+                    result
+                }
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        let mut snapshot = vec![];
+        let mut metadata = vec![];
+        for (repo_packages, repo_metadata) in results {
+            snapshot.extend(repo_packages);
+            metadata.extend(repo_metadata);
+        }
+        info!(logger, "{} packages", snapshot.len());
+        snapshot.extend(metadata);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("yum, {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Yum {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        self.repos
+            .iter()
+            .find_map(|repo| {
+                let path = snapshot.key.strip_prefix(&repo.name)?.strip_prefix('/')?;
+                Some(TransferURL(format!("{}/{}", repo.base, path)))
+            })
+            .ok_or_else(|| Error::ProcessError(format!("unknown repo of {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compression, parse_primary, parse_repo, parse_repomd, query_primary_db, Compression,
+    };
+
+    #[test]
+    fn test_parse_repomd() {
+        let files = parse_repomd(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo" xmlns:rpm="http://linux.duke.edu/metadata/rpm">
+  <revision>1700000000</revision>
+  <data type="primary">
+    <checksum type="sha256">0123</checksum>
+    <open-checksum type="sha256">4567</open-checksum>
+    <location href="repodata/0123-primary.xml.gz"/>
+    <timestamp>1700000000</timestamp>
+    <size>42</size>
+    <open-size>420</open-size>
+  </data>
+  <data type="group">
+    <checksum type="sha1">89ab</checksum>
+    <location href="repodata/comps.xml"/>
+  </data>
+</repomd>"# as &[u8],
+        )
+        .unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].data_type, "primary");
+        assert_eq!(files[0].href, "repodata/0123-primary.xml.gz");
+        assert_eq!(files[0].size, Some(42));
+        assert_eq!(files[0].sha256.as_deref(), Some("0123"));
+        assert_eq!(files[1].sha256, None);
+        assert_eq!(compression(&files[0].href), Some(Compression::Gzip));
+        assert_eq!(compression("repodata/primary.xml.xz"), None);
+    }
+
+    #[test]
+    fn test_parse_primary() {
+        let packages = parse_primary(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="2">
+<package type="rpm">
+  <name>foo</name>
+  <arch>x86_64</arch>
+  <checksum type="sha256" pkgid="YES">abcd</checksum>
+  <size package="100" installed="200" archive="300"/>
+  <location href="Packages/f/foo-1.0-1.x86_64.rpm"/>
+  <format>
+    <rpm:license>MIT</rpm:license>
+    <rpm:header-range start="1" end="2"/>
+  </format>
+</package>
+<package type="rpm">
+  <name>bar</name>
+  <arch>noarch</arch>
+  <checksum type="sha1" pkgid="YES">ef01</checksum>
+  <size package="7"/>
+  <location href="Packages/b/bar-2.0-1.noarch.rpm"/>
+</package>
+</metadata>"# as &[u8],
+        )
+        .unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].arch, "x86_64");
+        let foo = packages.into_iter().next().unwrap().snapshot("epel/");
+        assert_eq!(foo.key, "epel/Packages/f/foo-1.0-1.x86_64.rpm");
+        assert_eq!(foo.size, Some(100));
+        assert_eq!(foo.checksum.as_deref(), Some("abcd"));
+    }
+
+    #[test]
+    fn test_query_primary_db() {
+        let path = std::env::temp_dir().join(format!(
+            "mirror-clone-yum-test-{}.sqlite",
+            std::process::id()
+        ));
+        {
+            let db = rusqlite::Connection::open(&path).unwrap();
+            db.execute_batch(
+                "CREATE TABLE packages (pkgKey INTEGER PRIMARY KEY, pkgId TEXT, name TEXT,
+                    arch TEXT, checksum_type TEXT, size_package INTEGER, location_href TEXT);
+                 INSERT INTO packages VALUES (1, 'abcd', 'foo', 'aarch64', 'sha256', 100,
+                    'Packages/f/foo-1.0-1.aarch64.rpm');",
+            )
+            .unwrap();
+        }
+        let packages = query_primary_db(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].href, "Packages/f/foo-1.0-1.aarch64.rpm");
+        assert_eq!(packages[0].arch, "aarch64");
+        assert_eq!(packages[0].size, Some(100));
+
+        assert_eq!(
+            parse_repo("epel=https://example.com/epel/").unwrap().base,
+            "https://example.com/epel"
+        );
+        assert!(parse_repo("https://example.com/epel").is_err());
+    }
+}
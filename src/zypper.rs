@@ -0,0 +1,246 @@
+//! Zypper source
+//!
+//! Zypper source mirrors openSUSE repositories from download.opensuse.org
+//! (or a mirror of it), keeping its layout, e.g.
+//! `distribution/leap/<version>/repo/<repo>` for Leap and
+//! `tumbleweed/repo/<repo>` for Tumbleweed. With `--updates`, update repos
+//! (`update/leap/<version>/<repo>`, `update/tumbleweed`) are mirrored as well.
+//!
+//! Repos are rpm-md repos, whose metadata (including patterns) and packages are
+//! found with the yum source. Besides, the media description in `media.1`,
+//! SUSE tags `content` files, and GPG keys at the root of each repo are
+//! mirrored, which are transferred at the end together with `repomd.xml`.
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use slog::{info, warn, Logger};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::http::exists;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::yum;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Zypper {
+    #[structopt(long, default_value = "https://download.opensuse.org")]
+    pub base: String,
+    /// Distribution to mirror.
+    #[structopt(long, default_value = "leap", possible_values = &["leap", "tumbleweed"])]
+    pub distribution: String,
+    /// Versions of Leap to mirror, e.g. `15.5,15.6`.
+    #[structopt(long, use_delimiter = true)]
+    pub versions: Vec<String>,
+    /// Repos of each version.
+    #[structopt(long, use_delimiter = true, default_value = "oss,non-oss")]
+    pub repos: Vec<String>,
+    /// Also mirror update repos.
+    #[structopt(long)]
+    pub updates: bool,
+    /// Only mirror packages of these architectures, e.g. `x86_64,aarch64`. Packages of `noarch`
+    /// are always mirrored.
+    #[structopt(long, use_delimiter = true)]
+    pub arches: Vec<String>,
+}
+
+/// Optional files of a repo besides rpm-md metadata.
+const EXTRA_FILES: &[&str] = &[
+    "media.1/media",
+    "media.1/products",
+    "content",
+    "content.asc",
+    "content.key",
+];
+
+/// Whether `name` at the root of a repo is a GPG key.
+fn is_key_file(name: &str) -> bool {
+    !name.contains('/')
+        && ((name.starts_with("gpg-pubkey-") && name.ends_with(".asc")) || name.ends_with(".key"))
+}
+
+impl Zypper {
+    /// Paths of repos to mirror, relative to base.
+    fn repo_paths(&self) -> Vec<String> {
+        let mut paths = vec![];
+        match self.distribution.as_str() {
+            "tumbleweed" => {
+                paths.extend(
+                    self.repos
+                        .iter()
+                        .map(|repo| format!("tumbleweed/repo/{}", repo)),
+                );
+                if self.updates {
+                    paths.push("update/tumbleweed".to_string());
+                }
+            }
+            _ => {
+                assert!(!self.versions.is_empty(), "no versions of leap specified");
+                for version in &self.versions {
+                    paths.extend(
+                        self.repos
+                            .iter()
+                            .map(|repo| format!("distribution/leap/{}/repo/{}", version, repo)),
+                    );
+                    if self.updates {
+                        paths.extend(
+                            self.repos
+                                .iter()
+                                .map(|repo| format!("update/leap/{}/{}", version, repo)),
+                        );
+                    }
+                }
+            }
+        }
+        paths
+    }
+
+    /// GPG keys at the root of repo at `url`, found in its index page.
+    async fn keys(&self, client: &reqwest::Client, url: &str) -> Result<Vec<String>> {
+        let index = crate::http::send(client.get(format!("{}/", url)))
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let mut keys: Vec<String> = crate::utils::parse_link_list(&index)
+            .iter()
+            .filter_map(|link| link.href())
+            .map(|href| href.trim_start_matches("./"))
+            .filter(|href| is_key_file(href))
+            .map(str::to_string)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Packages and metadata of repo at `path`.
+    async fn repo(
+        &self,
+        logger: &Logger,
+        client: &reqwest::Client,
+        path: &str,
+    ) -> Result<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> {
+        let url = format!("{}/{}", self.base, path);
+        let prefix = format!("{}/", path);
+        let (files, mut metadata) = yum::repo_metadata(client, &url, &prefix).await?;
+        let packages = yum::packages(client, &url, &files)
+            .await?
+            .into_iter()
+            .filter(|x| x.is_selected(&self.arches))
+            .map(|x| x.snapshot(&prefix))
+            .collect();
+
+        // `repomd.xml` is kept last
+        let repomd = metadata.pop();
+        for name in EXTRA_FILES {
+            if exists(client, &format!("{}/{}", url, name)).await? {
+                metadata.push(SnapshotMeta::force(format!("{}{}", prefix, name)));
+            }
+        }
+        match self.keys(client, &url).await {
+            Ok(keys) => metadata.extend(
+                keys.into_iter()
+                    .map(|name| SnapshotMeta::force(format!("{}{}", prefix, name))),
+            ),
+            Err(err) => warn!(logger, "failed to list GPG keys of {}: {:?}", url, err),
+        }
+        metadata.extend(repomd);
+        Ok((packages, metadata))
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Zypper {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let paths = self.repo_paths();
+        info!(logger, "fetching {} repos...", paths.len());
+        progress.set_length(paths.len() as u64);
+        let this = &*self;
+        let results: Vec<(Vec<SnapshotMeta>, Vec<SnapshotMeta>)> = stream::iter(paths)
+            .map(|path| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let logger = logger.clone();
+                async move {
+                    progress.set_message(&path);
+                    let result = this.repo(&logger, &client, &path).await;
+                    progress.inc(1);
+                    result
+                }
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        let mut snapshot = vec![];
+        let mut metadata = vec![];
+        for (repo_packages, repo_metadata) in results {
+            snapshot.extend(repo_packages);
+            metadata.extend(repo_metadata);
+        }
+        info!(logger, "{} packages", snapshot.len());
+        snapshot.extend(metadata);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("zypper, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Zypper {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::{is_key_file, Zypper};
+
+    #[test]
+    fn test_repo_paths() {
+        let zypper = Zypper::from_iter(["zypper", "--versions", "15.5,15.6", "--repos", "oss"]);
+        assert_eq!(
+            zypper.repo_paths(),
+            vec![
+                "distribution/leap/15.5/repo/oss",
+                "distribution/leap/15.6/repo/oss"
+            ]
+        );
+        let zypper = Zypper::from_iter(["zypper", "--distribution", "tumbleweed", "--updates"]);
+        assert_eq!(
+            zypper.repo_paths(),
+            vec![
+                "tumbleweed/repo/oss",
+                "tumbleweed/repo/non-oss",
+                "update/tumbleweed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_key_file() {
+        assert!(is_key_file("gpg-pubkey-29b700a4-62b07e22.asc"));
+        assert!(is_key_file("repomd.xml.key"));
+        assert!(!is_key_file("ChangeLog"));
+        assert!(!is_key_file("repodata/repomd.xml.key"));
+        assert!(!is_key_file("https://example.com/gpg-pubkey-0.asc"));
+    }
+}